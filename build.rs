@@ -1,9 +1,9 @@
+#[cfg(feature = "ffi")]
 extern crate cbindgen;
 
-use std::env;
-
+#[cfg(feature = "ffi")]
 fn main() {
-    let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
     cbindgen::generate(crate_dir).map_or_else(
         |error| match error {
             cbindgen::Error::ParseSyntaxError { .. } => {}
@@ -14,3 +14,7 @@ fn main() {
         },
     );
 }
+
+// The header is only needed for the C API.
+#[cfg(not(feature = "ffi"))]
+fn main() {}
@@ -0,0 +1,81 @@
+use clap::Parser;
+use p8020::group::{DeviceGroup, COMPARISON_WINDOW};
+use p8020::{DeviceId, DeviceNotification};
+use std::time::Duration;
+
+/// Compares the concentrations measured by several devices sampling the same
+/// air, to determine whether any of them need servicing. Leave all devices'
+/// sample tubes open (without masks) next to each other.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Serial ports of the devices to compare (at least two).
+    #[arg(required = true, num_args = 2..)]
+    devices: Vec<String>,
+
+    /// How long to sample for.
+    #[arg(long, default_value_t = 5)]
+    minutes: u64,
+
+    /// Index of the device that all other devices are compared against.
+    #[arg(long, default_value_t = 0)]
+    reference: usize,
+}
+
+fn main() {
+    let args = Args::parse();
+    if args.reference >= args.devices.len() {
+        eprintln!(
+            "Invalid reference device {} (must be less than {})",
+            args.reference,
+            args.devices.len()
+        );
+        std::process::exit(1);
+    }
+    eprintln!(
+        "8020 comparison (v{}).\nSampling for {} minute(s) on {} device(s), reference: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        args.minutes,
+        args.devices.len(),
+        args.devices[args.reference]
+    );
+
+    let group = match DeviceGroup::connect(
+        args.devices.clone(),
+        None::<fn(&DeviceId, DeviceNotification)>,
+    ) {
+        Ok(group) => group,
+        Err(e) => {
+            eprintln!("Unable to connect to devices: {e}");
+            std::process::exit(1);
+        }
+    };
+    let comparison =
+        group.compare_concentrations(Duration::from_secs(args.minutes * 60), args.reference);
+
+    let column_width = args.devices.iter().map(String::len).max().unwrap_or(0);
+    println!(
+        "{:column_width$}  {:>8}  {:>10}  {:>8}  {:>12}",
+        "Device", "Samples", "Mean", "Bias", "Drift/min"
+    );
+    for (path, comparison) in args.devices.iter().zip(&comparison.devices) {
+        let Some(comparison) = comparison else {
+            println!("{path:column_width$}  (no samples received)");
+            continue;
+        };
+        let drift = match comparison.drift_per_minute {
+            Some(drift) => format!("{:+.2}%", drift * 100.0),
+            None => "-".to_string(),
+        };
+        println!(
+            "{path:column_width$}  {:>8}  {:>10.1}  {:>+7.2}%  {drift:>12}",
+            comparison.sample_count,
+            comparison.mean_concentration,
+            comparison.relative_bias * 100.0,
+        );
+    }
+    eprintln!(
+        "\nDrift is calculated from {}s averages.",
+        COMPARISON_WINDOW.as_secs()
+    );
+}
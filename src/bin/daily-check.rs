@@ -0,0 +1,160 @@
+use clap::Parser;
+use p8020::checks::{self, CheckThresholds, DailyCheckReport};
+use p8020::{Device, DeviceId, DeviceNotification, TestNotification, TestOptions, ValvePosition};
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, SystemTime};
+
+// TODO: enumerate devices dynamically
+const DEVICE: &str = "/dev/ttyUSB0";
+
+// Samples discarded after changing the setup, while the tubes are purged.
+const PURGE_SAMPLES: usize = 10;
+// Samples used for the particle and zero checks.
+const CHECK_SAMPLES: usize = 20;
+
+/// Walks you through the daily checks (particle check, zero check, max FF
+/// check, and valve check), and prints a dated pass/fail report.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Serial port of the device.
+    #[arg(default_value = DEVICE)]
+    device: String,
+
+    /// Append the report to this file.
+    #[arg(long)]
+    report: Option<std::path::PathBuf>,
+}
+
+enum Event {
+    Device(DeviceNotification),
+    Test(TestNotification),
+}
+
+fn prompt(instructions: &str) {
+    print!("\n{instructions}\nPress Enter to continue...");
+    std::io::stdout().flush().unwrap();
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+}
+
+// Discards PURGE_SAMPLES samples, then returns the next count samples.
+fn collect_samples(rx_event: &Receiver<Event>, count: usize) -> Vec<f64> {
+    // Anything received while waiting for the operator is stale.
+    while rx_event.try_recv().is_ok() {}
+    let mut samples = Vec::with_capacity(count);
+    let mut purged = 0;
+    while samples.len() < count {
+        let particle_conc = match rx_event.recv_timeout(Duration::from_secs(15)) {
+            Ok(Event::Device(DeviceNotification::Sample { particle_conc })) => particle_conc,
+            Ok(Event::Device(DeviceNotification::ConnectionClosed)) => {
+                eprintln!("\nConnection closed");
+                std::process::exit(1);
+            }
+            _ => continue,
+        };
+        if purged < PURGE_SAMPLES {
+            purged += 1;
+        } else {
+            samples.push(particle_conc);
+        }
+        eprint!(
+            "\r{} / {} samples",
+            purged + samples.len(),
+            PURGE_SAMPLES + count
+        );
+    }
+    eprintln!();
+    samples
+}
+
+fn main() {
+    let args = Args::parse();
+    eprintln!("P8020A daily checks (v{}).", env!("CARGO_PKG_VERSION"));
+
+    let (tx_event, rx_event) = mpsc::channel();
+    let tx_device_event = tx_event.clone();
+    let device = match Device::connect_path(
+        args.device.clone(),
+        Some(move |_: &DeviceId, notification: DeviceNotification| {
+            let _ = tx_device_event.send(Event::Device(notification));
+        }),
+    ) {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("Unable to connect to {}: {e}", args.device);
+            std::process::exit(1);
+        }
+    };
+    let thresholds = CheckThresholds::default();
+    let mut results = Vec::new();
+
+    prompt("Particle check: remove any filter from the sample tube, and leave it open to the room air.");
+    let samples = collect_samples(&rx_event, CHECK_SAMPLES);
+    results.push(checks::particle_check(&samples, &thresholds));
+
+    prompt("Zero check: attach a HEPA filter to the sample tube.");
+    let samples = collect_samples(&rx_event, CHECK_SAMPLES);
+    results.push(checks::zero_check(&samples, &thresholds));
+
+    prompt("Max FF and valve checks: leave the HEPA filter attached to the sample tube.");
+    let started = device.start_test(
+        checks::max_fit_factor_config(),
+        TestOptions::default(),
+        Some(Box::new(move |notification: &TestNotification| {
+            let _ = tx_event.send(Event::Test(notification.clone()));
+        })),
+    );
+    if started.is_err() {
+        eprintln!("\nConnection closed");
+        std::process::exit(1);
+    }
+    let mut fit_factor = None;
+    let (mut ambient_confirmed, mut specimen_confirmed) = (false, false);
+    for event in &rx_event {
+        match event {
+            Event::Test(TestNotification::ValveSwitchConfirmed { position, .. }) => {
+                match position {
+                    ValvePosition::Ambient => ambient_confirmed = true,
+                    ValvePosition::Specimen => specimen_confirmed = true,
+                }
+            }
+            Event::Test(TestNotification::ExerciseResult { raw_fit_factor, .. }) => {
+                eprintln!("Max FF: {raw_fit_factor:.1}");
+                fit_factor = Some(raw_fit_factor);
+            }
+            Event::Device(
+                DeviceNotification::TestCompleted { .. }
+                | DeviceNotification::TestCancelled
+                | DeviceNotification::TestAborted
+                | DeviceNotification::ConnectionClosed,
+            ) => break,
+            _ => (),
+        }
+    }
+    results.push(checks::max_fit_factor_check(fit_factor, &thresholds));
+    results.push(checks::valve_check(ambient_confirmed, specimen_confirmed));
+
+    let report = DailyCheckReport {
+        performed_at: SystemTime::now(),
+        serial_number: device.id().serial_number,
+        results,
+    };
+    let text = report.to_text();
+    println!("\n{text}");
+    if let Some(path) = args.report {
+        let appended = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{text}"));
+        if let Err(e) = appended {
+            eprintln!("Unable to write report to {}: {e}", path.display());
+            std::process::exit(1);
+        }
+    }
+    if !report.passed() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,92 @@
+use clap::Parser;
+use std::time::Duration;
+
+/// Lists serial ports (with USB details), and optionally checks which of them
+/// are connected to a PortaCount.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Only show these ports (e.g. to probe a specific port), instead of all
+    /// available ports.
+    ports: Vec<String>,
+
+    /// Include non-USB ports.
+    #[arg(long)]
+    all: bool,
+
+    /// Request the settings of the device on each port, to detect
+    /// PortaCounts. Do not use this while a port is in use (e.g. during a
+    /// test).
+    #[arg(long)]
+    probe: bool,
+
+    /// How long to wait for a response when probing, in seconds.
+    #[arg(long, default_value_t = 5)]
+    probe_timeout: u64,
+}
+
+fn main() {
+    let args = Args::parse();
+    let ports = match p8020::list_ports(!args.all && args.ports.is_empty()) {
+        Ok(ports) if args.ports.is_empty() => ports,
+        // Requested ports may be missing from the list (e.g. pseudo-terminals
+        // aren't listed), those are shown without any details.
+        Ok(ports) => args
+            .ports
+            .iter()
+            .map(|name| {
+                ports
+                    .iter()
+                    .find(|port| &port.port_name == name)
+                    .cloned()
+                    .unwrap_or_else(|| serialport::SerialPortInfo {
+                        port_name: name.clone(),
+                        port_type: serialport::SerialPortType::Unknown,
+                    })
+            })
+            .collect(),
+        Err(e) => {
+            eprintln!("Unable to enumerate serial ports: {e}");
+            std::process::exit(1);
+        }
+    };
+    if ports.is_empty() {
+        eprintln!("No serial ports found.");
+        return;
+    }
+
+    for port in ports {
+        match port.port_type {
+            serialport::SerialPortType::UsbPort(info) => {
+                println!("{} (USB {:04x}:{:04x})", port.port_name, info.vid, info.pid);
+                let details = [
+                    ("Manufacturer", &info.manufacturer),
+                    ("Product", &info.product),
+                    ("Serial number", &info.serial_number),
+                ];
+                for (name, value) in details {
+                    if let Some(value) = value {
+                        println!("  {name}: {value}");
+                    }
+                }
+            }
+            _ => println!("{}", port.port_name),
+        }
+
+        if !args.probe {
+            continue;
+        }
+        match p8020::probe_port(&port.port_name, Duration::from_secs(args.probe_timeout)) {
+            Ok(Some(properties)) => println!(
+                "  Looks like a PortaCount: {:?}, serial number {}, last serviced {:02}/{}, firmware fingerprint {}",
+                properties.model,
+                properties.serial_number,
+                properties.last_service_month,
+                properties.last_service_year,
+                properties.firmware_fingerprint()
+            ),
+            Ok(None) => println!("  No PortaCount detected"),
+            Err(e) => println!("  Unable to probe: {e}"),
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use p8020::cadence::EXPECTED_SAMPLE_INTERVAL;
+use p8020::test_config::{builtin, ConfigWarning, DurationUnit, TestConfig, TestStage};
+use std::path::{Path, PathBuf};
+
+/// Lints and converts test configs (protocols).
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validates a config, and prints warnings, the expanded stage list, and
+    /// the estimated duration. Exits with an error if the config is invalid.
+    Lint {
+        /// Config file (.csv, or .json/.toml), or the short name of a builtin
+        /// config.
+        config: String,
+    },
+    /// Converts a config into another format.
+    Convert {
+        /// Config file (.csv, or .json/.toml), or the short name of a builtin
+        /// config.
+        config: String,
+
+        #[arg(long, value_enum)]
+        to: Format,
+
+        /// Write the converted config to this file instead of printing it.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Format {
+    Csv,
+    Json,
+}
+
+fn load_config(config: &str) -> Result<TestConfig, String> {
+    let path = Path::new(config);
+    if !path.exists() {
+        return builtin::load(config)
+            .ok_or_else(|| format!("{config} is neither a file nor a builtin config"));
+    }
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read config {}: {e}", path.display()))?;
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let parsed = match extension {
+        #[cfg(feature = "serde")]
+        Some("json") => TestConfig::parse_json(&contents).map_err(|e| format!("{e:?}")),
+        #[cfg(feature = "serde")]
+        Some("toml") => TestConfig::parse_toml(&contents).map_err(|e| format!("{e:?}")),
+        #[cfg(not(feature = "serde"))]
+        Some("json" | "toml") => Err("JSON/TOML configs require the serde feature".to_string()),
+        _ => TestConfig::parse_from_csv(&mut contents.as_bytes()).map_err(|e| format!("{e:?}")),
+    };
+    parsed.map_err(|e| format!("Unable to parse config {}: {e}", path.display()))
+}
+
+fn describe_warning(config: &TestConfig, warning: &ConfigWarning) -> String {
+    match warning {
+        ConfigWarning::MissingAmbientPurge { index } => format!(
+            "stage {index}: ambient stage follows an exercise without purging, its first samples will contain specimen air"
+        ),
+        ConfigWarning::MissingSpecimenPurge { index } => format!(
+            "stage {index}: exercise follows an ambient stage without purging, its first samples will contain ambient air"
+        ),
+        ConfigWarning::PassLevelAboveMaxFitFactor { index: Some(index) } => format!(
+            "stage {index}: exercise pass level exceeds the max FF ({})",
+            config.max_fit_factor.unwrap_or_default()
+        ),
+        ConfigWarning::PassLevelAboveMaxFitFactor { index: None } => format!(
+            "test pass level exceeds the max FF ({})",
+            config.max_fit_factor.unwrap_or_default()
+        ),
+    }
+}
+
+fn print_stages(config: &TestConfig) {
+    println!(
+        "{:>5}  {:<10}  {:>6}  {:>6}  {:<4}  Exercise",
+        "Stage", "Type", "Purge", "Sample", "Unit"
+    );
+    let mut exercises = config.exercises().into_iter();
+    for (index, stage) in config.stages.iter().enumerate() {
+        let counts = stage.counts();
+        let unit = match counts.unit {
+            DurationUnit::Samples => "",
+            DurationUnit::Seconds => "s",
+        };
+        let (stage_type, description) = match stage {
+            TestStage::AmbientSample { .. } => ("Ambient", String::new()),
+            TestStage::Exercise { .. } | TestStage::Grimace { .. } => {
+                let exercise = exercises.next().expect("exercises must match stages");
+                let mut description = format!("{}. {}", exercise.index + 1, exercise.name);
+                if exercise.weight != 1.0 && !exercise.is_grimace {
+                    description.push_str(&format!(", weight={}", exercise.weight));
+                }
+                if let Some(pass_level) = exercise.pass_level {
+                    description.push_str(&format!(", pass={pass_level}"));
+                }
+                if exercise.exclude_from_overall {
+                    description.push_str(" (excluded from overall)");
+                }
+                let stage_type = if stage.is_grimace() {
+                    "Grimace"
+                } else {
+                    "Exercise"
+                };
+                (stage_type, description)
+            }
+        };
+        let row = format!(
+            "{index:>5}  {stage_type:<10}  {:>6}  {:>6}  {unit:<4}  {description}",
+            counts.purge_count, counts.sample_count
+        );
+        println!("{}", row.trim_end());
+    }
+}
+
+fn lint(config: &TestConfig) -> bool {
+    println!(
+        "{} ({}), version {}",
+        config.name, config.short_name, config.version
+    );
+    if let Some(pass_level) = config.pass_level {
+        println!("Pass level: {pass_level}");
+    }
+    if let Some(max_fit_factor) = config.max_fit_factor {
+        println!("Max FF: {max_fit_factor}");
+    }
+    if let Some(min_concentration) = config.min_concentration {
+        println!("Min concentration: {min_concentration}");
+    }
+    println!();
+    print_stages(config);
+
+    let duration = config.estimated_duration(EXPECTED_SAMPLE_INTERVAL);
+    println!(
+        "\nEstimated duration: {}m {:02}s",
+        duration.as_secs() / 60,
+        duration.as_secs() % 60
+    );
+
+    let warnings = config.warnings();
+    if !warnings.is_empty() {
+        println!("\nWarnings:");
+        for warning in &warnings {
+            println!("  {}", describe_warning(config, warning));
+        }
+    }
+    match config.validate() {
+        Ok(()) => {
+            println!("\nConfig is valid.");
+            true
+        }
+        Err(e) => {
+            println!("\nConfig is invalid: {e:?}");
+            false
+        }
+    }
+}
+
+fn convert(config: &TestConfig, format: Format) -> Result<String, String> {
+    match format {
+        Format::Csv => Ok(config.to_csv()),
+        #[cfg(feature = "serde")]
+        Format::Json => Ok(config.to_json() + "\n"),
+        #[cfg(not(feature = "serde"))]
+        Format::Json => Err("JSON output requires the serde feature".to_string()),
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let config_arg = match &args.command {
+        Command::Lint { config } | Command::Convert { config, .. } => config,
+    };
+    let config = match load_config(config_arg) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    match args.command {
+        Command::Lint { .. } => {
+            if !lint(&config) {
+                std::process::exit(1);
+            }
+        }
+        Command::Convert { to, output, .. } => {
+            if let Err(e) = config.validate() {
+                eprintln!("Not converting invalid config: {e:?}");
+                std::process::exit(1);
+            }
+            let converted = match convert(&config, to) {
+                Ok(converted) => converted,
+                Err(e) => {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            };
+            match output {
+                Some(path) => {
+                    if let Err(e) = std::fs::write(&path, converted) {
+                        eprintln!("Unable to write {}: {e}", path.display());
+                        std::process::exit(1);
+                    }
+                }
+                None => print!("{converted}"),
+            }
+        }
+    }
+}
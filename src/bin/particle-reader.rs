@@ -1,9 +1,189 @@
 extern crate serialport;
-use std::io::BufRead;
+use clap::Parser;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 // TODO: enumerate devices dynamically
 const DEVICE: &str = "/dev/ttyUSB0";
 
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Append readings to this file instead of printing them.
+    #[arg(long)]
+    out: Option<PathBuf>,
+
+    /// Rotate the output file once it exceeds this size (in MB).
+    #[arg(long, requires = "out")]
+    rotate_size_mb: Option<u64>,
+
+    /// Rotate the output file after this many hours.
+    #[arg(long, requires = "out")]
+    rotate_hours: Option<u64>,
+
+    /// Use local time (including the UTC offset) instead of UTC for
+    /// timestamps.
+    #[arg(long)]
+    local_time: bool,
+
+    /// Emit min/mean/max concentrations for every interval of this many
+    /// seconds (as "<timestamp>,aggregate,<min>,<mean>,<max>,<count>", where
+    /// timestamp is the end of the interval) instead of raw readings.
+    #[arg(long)]
+    aggregate_seconds: Option<u64>,
+
+    /// Emit raw readings in addition to aggregates.
+    #[arg(long, requires = "aggregate_seconds")]
+    keep_raw: bool,
+}
+
+// Accumulates concentrations over one aggregation interval.
+struct Aggregate {
+    started_at: Instant,
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: usize,
+}
+
+impl Aggregate {
+    fn new() -> Aggregate {
+        Aggregate {
+            started_at: Instant::now(),
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn add(&mut self, concentration: f64) {
+        self.min = self.min.min(concentration);
+        self.max = self.max.max(concentration);
+        self.sum += concentration;
+        self.count += 1;
+    }
+
+    // Returns the record for this interval, or None if it contains no
+    // readings.
+    fn record(&self, timestamp: &str) -> Option<String> {
+        (self.count > 0).then(|| {
+            format!(
+                "{timestamp},aggregate,{},{},{},{}",
+                self.min,
+                self.sum / self.count as f64,
+                self.max,
+                self.count
+            )
+        })
+    }
+}
+
+// Appends lines to a file, which is moved aside (by appending the rotation
+// time to its name) once it exceeds max_size or max_age.
+struct RotatingFile {
+    path: PathBuf,
+    file: std::fs::File,
+    size: u64,
+    opened_at: Instant,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+}
+
+impl RotatingFile {
+    fn open(
+        path: PathBuf,
+        max_size: Option<u64>,
+        max_age: Option<Duration>,
+    ) -> std::io::Result<RotatingFile> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        Ok(RotatingFile {
+            size: file.metadata()?.len(),
+            path,
+            file,
+            opened_at: Instant::now(),
+            max_size,
+            max_age,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let needs_rotation = self.max_size.is_some_and(|max_size| self.size >= max_size)
+            || self
+                .max_age
+                .is_some_and(|max_age| self.opened_at.elapsed() >= max_age);
+        if needs_rotation {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let format = time::macros::format_description!("[year][month][day]T[hour][minute][second]");
+        let suffix = time::OffsetDateTime::now_utc().format(&format).unwrap();
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let rotated_name = match self.path.extension() {
+            Some(extension) => format!("{stem}-{suffix}.{}", extension.to_string_lossy()),
+            None => format!("{stem}-{suffix}"),
+        };
+        std::fs::rename(&self.path, self.path.with_file_name(rotated_name))?;
+        *self = RotatingFile::open(self.path.clone(), self.max_size, self.max_age)?;
+        Ok(())
+    }
+}
+
+// The time crate can only determine the local offset with its local-offset
+// feature, which refuses to do so once other threads are running (as they are
+// here). localtime_r provides the same on unix.
+#[cfg(unix)]
+fn local_offset() -> time::UtcOffset {
+    let offset_seconds = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            0
+        } else {
+            tm.tm_gmtoff as i32
+        }
+    };
+    time::UtcOffset::from_whole_seconds(offset_seconds).unwrap_or(time::UtcOffset::UTC)
+}
+
+#[cfg(not(unix))]
+fn local_offset() -> time::UtcOffset {
+    eprintln!("Local time is not supported on this platform, using UTC.");
+    time::UtcOffset::UTC
+}
+
+fn format_now(offset: Option<time::UtcOffset>) -> String {
+    match offset {
+        Some(offset) => {
+            let format = time::macros::format_description!(
+                version = 2,
+                "[year]-[month]-[day]T[hour]:[minute]:[second][offset_hour sign:mandatory]:[offset_minute]"
+            );
+            time::OffsetDateTime::now_utc()
+                .to_offset(offset)
+                .format(&format)
+                .unwrap()
+        }
+        None => {
+            let format = time::macros::format_description!(
+                version = 2,
+                "[year]-[month]-[day]T[hour]:[minute]:[second]"
+            );
+            time::OffsetDateTime::now_utc().format(&format).unwrap()
+        }
+    }
+}
+
 fn send(port: &mut Box<dyn serialport::SerialPort>, msg: &str) {
     if !msg.is_ascii() {
         eprintln!("Unexpected non-ascii msg: {}", msg);
@@ -24,6 +204,7 @@ fn send(port: &mut Box<dyn serialport::SerialPort>, msg: &str) {
 }
 
 fn main() {
+    let args = Args::parse();
     eprintln!(
         "P8020A reader binary (v{}). (Please note: all I can do is log raw data.)",
         env!("CARGO_PKG_VERSION")
@@ -43,17 +224,49 @@ fn main() {
 
     let reader = std::io::BufReader::new(port);
 
-    for line in reader.lines() {
-        let date_time = time::OffsetDateTime::now_utc();
-        let format = time::macros::format_description!(
-            version = 2,
-            "[year]-[month]-[day]T[hour]:[minute]:[second]"
-        );
-        let formatted_date_time = date_time.format(&format).unwrap();
+    let mut out = args.out.map(|path| {
+        RotatingFile::open(
+            path,
+            args.rotate_size_mb.map(|mb| mb * 1024 * 1024),
+            args.rotate_hours
+                .map(|hours| Duration::from_secs(hours * 60 * 60)),
+        )
+        .expect("Unable to open output file")
+    });
+    // Determined once: readers of the log would struggle with offsets that
+    // change mid-file anyway.
+    let offset = args.local_time.then(local_offset);
+
+    let mut emit = |record: &str| match &mut out {
+        Some(out) => out.write_line(record).expect("Unable to write output file"),
+        None => println!("{record}"),
+    };
+    let aggregate_interval = args.aggregate_seconds.map(Duration::from_secs);
+    let mut aggregate = Aggregate::new();
 
+    for line in reader.lines() {
+        let formatted_date_time = format_now(offset);
         // Note: will contain trailing CR (8020A sends CR+LF, BufReader removes the LF).
-        // println!("Received: {} @ {}", line.unwrap(), formatted_date_time);
-        println!("{},{}", formatted_date_time, line.unwrap().trim());
+        let line = line.unwrap();
+        let message = line.trim();
+
+        let Some(aggregate_interval) = aggregate_interval else {
+            // println!("Received: {} @ {}", line.unwrap(), formatted_date_time);
+            emit(&format!("{},{}", formatted_date_time, message));
+            continue;
+        };
+        if aggregate.started_at.elapsed() >= aggregate_interval {
+            if let Some(record) = aggregate.record(&formatted_date_time) {
+                emit(&record);
+            }
+            aggregate = Aggregate::new();
+        }
+        if let Ok(concentration) = message.parse::<f64>() {
+            aggregate.add(concentration);
+        }
+        if args.keep_raw {
+            emit(&format!("{},{}", formatted_date_time, message));
+        }
     }
 
     // TODO: check N95 companion.
@@ -0,0 +1,182 @@
+use clap::Parser;
+use p8020::harness::{self, HarnessOutput};
+use p8020::protocol::{parse_message, Message};
+use p8020::test_config::{builtin, TestConfig};
+use p8020::{TestNotification, TestOptions};
+use std::io::BufRead;
+
+/// Replays a capture of a device's serial output (as recorded by spy, with or
+/// without --timestamps) through the test engine, and prints the resulting
+/// notifications and FFs. Useful for investigating reported anomalies
+/// offline.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// The capture to replay.
+    capture: std::path::PathBuf,
+
+    /// Short name of the builtin protocol that was run (default: osha).
+    #[arg(long, default_value = "osha")]
+    protocol: String,
+
+    /// Path of the custom protocol config that was run instead of a builtin
+    /// protocol (CSV, or JSON/TOML if built with the serde feature).
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+
+    /// Skip this many lines at the start of the capture, e.g. to start at
+    /// the test's first valve switch.
+    #[arg(long, default_value_t = 0)]
+    skip_lines: usize,
+
+    /// Confirm valve switches immediately, instead of relying on the
+    /// device's responses in the capture (for captures without responses,
+    /// e.g. those only containing samples).
+    #[arg(long)]
+    confirm_valves: bool,
+
+    /// Also print per-sample notifications (samples and live FFs).
+    #[arg(long)]
+    verbose: bool,
+}
+
+fn load_config(path: &std::path::Path) -> TestConfig {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Unable to read config {}: {e}", path.display());
+            std::process::exit(1);
+        }
+    };
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let config = match extension {
+        #[cfg(feature = "serde")]
+        Some("json") => TestConfig::parse_json(&contents).map_err(|e| format!("{e:?}")),
+        #[cfg(feature = "serde")]
+        Some("toml") => TestConfig::parse_toml(&contents).map_err(|e| format!("{e:?}")),
+        _ => TestConfig::parse_from_csv(&mut contents.as_bytes()).map_err(|e| format!("{e:?}")),
+    };
+    match config.and_then(|config| {
+        config
+            .validate()
+            .map(|_| config)
+            .map_err(|e| format!("{e:?}"))
+    }) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid config {}: {e}", path.display());
+            std::process::exit(1);
+        }
+    }
+}
+
+// Strips spy's --timestamps prefix (e.g. "2024-01-31T12:34:56.789 "), if
+// present.
+fn strip_timestamp(line: &str) -> &str {
+    match line.split_once(' ') {
+        Some((timestamp, rest)) if timestamp.len() == 23 && timestamp.as_bytes()[10] == b'T' => {
+            rest
+        }
+        _ => line,
+    }
+}
+
+// Returns the parsed messages, and the number of lines that could not be
+// parsed.
+fn read_capture(capture: &std::path::Path, skip_lines: usize) -> (Vec<Message>, usize) {
+    let file = match std::fs::File::open(capture) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Unable to open capture {}: {e}", capture.display());
+            std::process::exit(1);
+        }
+    };
+    let mut messages = Vec::new();
+    let mut unparseable = 0;
+    for line in std::io::BufReader::new(file).split(b'\n').skip(skip_lines) {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("Unable to read capture {}: {e}", capture.display());
+                std::process::exit(1);
+            }
+        };
+        let line = String::from_utf8_lossy(&line);
+        let message = strip_timestamp(line.trim()).trim();
+        if message.is_empty() {
+            continue;
+        }
+        match parse_message(message) {
+            Ok(message) => messages.push(message),
+            Err(e) => {
+                eprintln!("Ignoring unparseable message {:?}: {}", message, e.reason);
+                unparseable += 1;
+            }
+        }
+    }
+    (messages, unparseable)
+}
+
+fn print_output(config: &TestConfig, output: &HarnessOutput, verbose: bool) {
+    for notification in &output.notifications {
+        if !verbose
+            && matches!(
+                notification,
+                TestNotification::Sample(_) | TestNotification::LiveFF { .. }
+            )
+        {
+            continue;
+        }
+        println!("{notification:?}");
+    }
+
+    println!();
+    for (exercise, name) in config.exercise_names().iter().enumerate() {
+        match output.exercise_ffs.get(exercise) {
+            Some(fit_factor) => println!("{:>2}. {name}: {fit_factor:.1}", exercise + 1),
+            None => println!("{:>2}. {name}: -", exercise + 1),
+        }
+    }
+    match &output.result {
+        Some(result) => println!("Overall: {:.1}", result.overall_fit_factor),
+        None if output.aborted => println!("Test aborted"),
+        None => println!("Test incomplete: the capture ended before the test completed"),
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let config = match &args.config {
+        Some(path) => load_config(path),
+        None => match builtin::load(&args.protocol) {
+            Some(config) => config,
+            None => {
+                eprintln!("Unknown protocol {}", args.protocol);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let (messages, unparseable) = read_capture(&args.capture, args.skip_lines);
+    eprintln!(
+        "Replaying {} messages ({unparseable} unparseable) using {}",
+        messages.len(),
+        config.short_name
+    );
+    let output = if args.confirm_valves {
+        harness::run_concentrations(
+            config.clone(),
+            TestOptions::default(),
+            messages.into_iter().filter_map(|message| match message {
+                Message::Sample(concentration) => Some(concentration),
+                _ => None,
+            }),
+        )
+    } else {
+        harness::run_messages(config.clone(), TestOptions::default(), messages)
+    };
+    print_output(&config, &output, args.verbose);
+    if !output.completed {
+        std::process::exit(1);
+    }
+}
@@ -1,9 +1,36 @@
 extern crate serialport;
+use clap::Parser;
+use p8020::protocol::{parse_message, Command, Indicator, Message};
+use std::io::BufRead;
+use std::time::{Duration, Instant};
 
-// TODO: enumerate devices dynamically
+// Used if no port is specified.
 const DEVICE: &str = "/dev/ttyUSB0";
 
-fn send(port: &mut Box<dyn serialport::SerialPort>, msg: &str) {
+// See bin/test.rs: the device ignores commands sent in quick succession.
+const COMMAND_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Rescues a device that was left in a weird state (e.g. by a crashed test):
+/// clears the display and indicators, and releases the device from external
+/// control.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Serial port of the device.
+    #[arg(default_value = DEVICE)]
+    port: String,
+
+    /// List available serial ports and exit.
+    #[arg(long)]
+    list: bool,
+
+    /// Print the device's settings before releasing it.
+    #[arg(long)]
+    dump_settings: bool,
+}
+
+fn send(port: &mut Box<dyn serialport::SerialPort>, command: Command) {
+    let msg = command.to_wire().expect("recovery commands must be valid");
     if !msg.is_ascii() {
         eprintln!("Unexpected non-ascii msg: {}", msg);
         // TODO: switch to proper error handling.
@@ -20,17 +47,49 @@ fn send(port: &mut Box<dyn serialport::SerialPort>, msg: &str) {
         );
         std::process::exit(0);
     }
+    std::thread::sleep(COMMAND_INTERVAL);
+}
+
+fn list_ports() {
+    match serialport::available_ports() {
+        Ok(ports) if ports.is_empty() => eprintln!("No serial ports found."),
+        Ok(ports) => {
+            for port in ports {
+                match port.port_type {
+                    serialport::SerialPortType::UsbPort(info) => println!(
+                        "{} (USB {:04x}:{:04x}{})",
+                        port.port_name,
+                        info.vid,
+                        info.pid,
+                        info.product
+                            .map_or(String::new(), |product| format!(", {product}"))
+                    ),
+                    _ => println!("{}", port.port_name),
+                }
+            }
+        }
+        Err(e) => {
+            eprintln!("Unable to enumerate serial ports: {e}");
+            std::process::exit(1);
+        }
+    }
 }
 
 fn main() {
+    let args = Args::parse();
+    if args.list {
+        list_ports();
+        return;
+    }
     eprintln!(
-        "P8020A reader binary (v{}). (Please note: all I can do is log raw data.)",
-        env!("CARGO_PKG_VERSION")
+        "P8020A reset binary (v{}). Resetting {}",
+        env!("CARGO_PKG_VERSION"),
+        args.port
     );
 
     // See "PortaCount Plus Model 8020 Technical Addendum" for specs.
     // Note: baud is configurable on the devices itself, 1200 is the default.
-    let mut port = serialport::new(DEVICE, /* baud_rate */ 1200)
+    let mut port = serialport::new(&args.port, /* baud_rate */ 1200)
         .data_bits(serialport::DataBits::Eight)
         .parity(serialport::Parity::None)
         .stop_bits(serialport::StopBits::One)
@@ -38,5 +97,35 @@ fn main() {
         .open()
         .expect("Unable to open serial port, sorry");
 
-    send(&mut port, "G");
+    // The display and indicators can only be controlled in external control
+    // mode (entering it again is harmless if the device is already in it).
+    send(&mut port, Command::EnterExternalControl);
+    send(&mut port, Command::ClearDisplay);
+    send(&mut port, Command::Indicator(Indicator::empty()));
+
+    if args.dump_settings {
+        // (The timeout must be set before cloning, clones don't share it.)
+        port.set_timeout(Duration::from_secs(2)).unwrap();
+        let mut reader = std::io::BufReader::new(port.try_clone().unwrap());
+        send(&mut port, Command::RequestSettings);
+        // Settings are sent in quick succession, interleaved with samples: the
+        // device is done once no settings have arrived for a while.
+        let mut last_setting = Instant::now();
+        let mut line = Vec::new();
+        while last_setting.elapsed() < Duration::from_secs(2) {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => (),
+            }
+            let message = String::from_utf8_lossy(&line);
+            if let Ok(Message::Setting(_)) = parse_message(message.trim()) {
+                println!("{}", message.trim());
+                last_setting = Instant::now();
+            }
+        }
+    }
+
+    send(&mut port, Command::ExitExternalControl);
+    eprintln!("Done.");
 }
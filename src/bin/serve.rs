@@ -0,0 +1,400 @@
+use clap::Parser;
+use p8020::test_config::{builtin, TestConfig};
+#[cfg(feature = "websocket")]
+use p8020::websocket::Broadcaster;
+use p8020::{ConnectionStats, Device, DeviceId, DeviceNotification, TestNotification, TestOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, OnceLock};
+
+// The largest request body that is accepted (custom configs are far
+// smaller).
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// Set from Args::allow_origin.
+static ALLOW_ORIGIN: OnceLock<String> = OnceLock::new();
+
+/// Exposes a device over HTTP, such that web front-ends can run fit tests:
+///
+///   GET  /ports                     list serial ports
+///   POST /connect?port=<path>       connect to a device
+///   POST /disconnect                disconnect from the device
+///   POST /test?protocol=<id>        start a builtin protocol, or
+///   POST /test (CSV config as body) start a custom protocol
+///   POST /cancel                    cancel the running test
+///   GET  /stats                     connection statistics (see
+///                                   p8020::ConnectionStats)
+///   GET  /events                    WebSocket streaming samples and test
+///                                   notifications as JSON (if built with
+///                                   the websocket feature, see
+///                                   p8020::websocket)
+///   GET  /metrics                   Prometheus metrics (if built with the
+///                                   metrics feature)
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, verbatim_doc_comment)]
+struct Args {
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:8020")]
+    listen: String,
+    /// Allow web front-ends served from this origin (e.g.
+    /// https://fit.example.com) to access the API from the browser. Without
+    /// this, only same-origin front-ends (and non-browser clients) can use
+    /// the API.
+    #[arg(long)]
+    allow_origin: Option<String>,
+}
+
+#[derive(Default)]
+struct Server {
+    device: Option<Device>,
+    // Connected WebSocket clients.
+    #[cfg(feature = "websocket")]
+    events: Arc<Broadcaster>,
+    // Shared by all devices, such that counters persist across connections.
+    #[cfg(feature = "metrics")]
+    metrics: Arc<p8020::metrics::Metrics>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+enum RequestError {
+    Io(std::io::Error),
+    // The body is larger than MAX_BODY_SIZE, and was not read.
+    BodyTooLarge,
+}
+
+impl From<std::io::Error> for RequestError {
+    fn from(e: std::io::Error) -> Self {
+        RequestError::Io(e)
+    }
+}
+
+impl Request {
+    fn read(stream: &TcpStream) -> Result<Request, RequestError> {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let target = parts.next().unwrap_or_default();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(key), percent_decode(value))
+            })
+            .collect();
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+            }
+        }
+        let mut request = Request {
+            method,
+            path: path.to_string(),
+            query,
+            headers,
+            body: Vec::new(),
+        };
+        let content_length = request
+            .header("content-length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(0);
+        if content_length > MAX_BODY_SIZE {
+            return Err(RequestError::BodyTooLarge);
+        }
+        request.body.resize(content_length, 0);
+        reader.read_exact(&mut request.body)?;
+        Ok(request)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) {
+    let allow_origin = match ALLOW_ORIGIN.get() {
+        Some(origin) => format!("Access-Control-Allow-Origin: {origin}\r\nVary: Origin\r\n"),
+        None => String::new(),
+    };
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{allow_origin}Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+}
+
+fn respond_error(stream: &mut TcpStream, status: &str, message: &str) {
+    respond(
+        stream,
+        status,
+        &format!("{{\"error\": {}}}", json_string(message)),
+    );
+}
+
+fn stats_json(stats: &ConnectionStats) -> String {
+    let last_message_at = match stats.last_message_at {
+        Some(timestamp) => json_string(
+            &time::OffsetDateTime::from(timestamp)
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap(),
+        ),
+        None => "null".to_string(),
+    };
+    format!(
+        "{{\"samples_received\": {}, \"commands_sent\": {}, \"parse_errors\": {}, \"discarded_samples\": {}, \"reconnects\": {}, \"dropped_notifications\": {}, \"last_message_at\": {last_message_at}}}",
+        stats.samples_received,
+        stats.commands_sent,
+        stats.parse_errors,
+        stats.discarded_samples,
+        stats.reconnects,
+        stats.dropped_notifications,
+    )
+}
+
+fn list_ports() -> String {
+    let ports = serialport::available_ports().unwrap_or_default();
+    format!(
+        "[{}]",
+        ports
+            .iter()
+            .map(|port| json_string(&port.port_name))
+            .collect::<Vec<String>>()
+            .join(", ")
+    )
+}
+
+fn connect(server: &Arc<Mutex<Server>>, port: &str) -> serialport::Result<()> {
+    #[cfg(feature = "websocket")]
+    let events = server.lock().unwrap().events.clone();
+    let options = p8020::ConnectOptions {
+        #[cfg(feature = "metrics")]
+        metrics: Some(server.lock().unwrap().metrics.clone()),
+        ..p8020::ConnectOptions::default()
+    };
+    let device = Device::connect_path_with_options(
+        port.to_string(),
+        options,
+        Some(move |id: &DeviceId, notification: DeviceNotification| {
+            #[cfg(feature = "websocket")]
+            events.broadcast_device_notification(id, &notification);
+            #[cfg(not(feature = "websocket"))]
+            let _ = (id, notification);
+        }),
+    )?;
+    server.lock().unwrap().device = Some(device);
+    Ok(())
+}
+
+fn load_config(request: &Request) -> Result<TestConfig, String> {
+    if let Some(protocol) = request.query("protocol") {
+        return builtin::load(protocol).ok_or_else(|| format!("unknown protocol: {protocol}"));
+    }
+    let config = TestConfig::parse_from_csv(&mut request.body.as_slice())
+        .map_err(|e| format!("invalid config: {e:?}"))?;
+    config
+        .validate()
+        .map_err(|e| format!("invalid config: {e:?}"))?;
+    Ok(config)
+}
+
+fn handle(server: Arc<Mutex<Server>>, mut stream: TcpStream) {
+    let request = match Request::read(&stream) {
+        Ok(request) => request,
+        Err(RequestError::BodyTooLarge) => {
+            respond_error(
+                &mut stream,
+                "413 Payload Too Large",
+                "request body must not exceed 1 MiB",
+            );
+            return;
+        }
+        Err(RequestError::Io(e)) => {
+            eprintln!("failed to read request: {e}");
+            return;
+        }
+    };
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/ports") => respond(&mut stream, "200 OK", &list_ports()),
+        ("POST", "/connect") => {
+            let Some(port) = request.query("port") else {
+                respond_error(&mut stream, "400 Bad Request", "port must be specified");
+                return;
+            };
+            match connect(&server, port) {
+                Ok(()) => respond(&mut stream, "200 OK", "{}"),
+                Err(e) => respond_error(&mut stream, "502 Bad Gateway", &e.to_string()),
+            }
+        }
+        ("POST", "/disconnect") => {
+            let device = server.lock().unwrap().device.take();
+            if let Some(device) = device {
+                if let Err(e) = device.disconnect() {
+                    eprintln!("failed to disconnect: {e:?}");
+                }
+            }
+            respond(&mut stream, "200 OK", "{}");
+        }
+        ("POST", "/test") => {
+            let config = match load_config(&request) {
+                Ok(config) => config,
+                Err(e) => {
+                    respond_error(&mut stream, "400 Bad Request", &e);
+                    return;
+                }
+            };
+            let server = server.lock().unwrap();
+            #[cfg(feature = "websocket")]
+            let events = server.events.clone();
+            let Some(device) = &server.device else {
+                respond_error(&mut stream, "409 Conflict", "not connected");
+                return;
+            };
+            let started = device.start_test(
+                config,
+                TestOptions::default(),
+                Some(Box::new(move |notification: &TestNotification| {
+                    #[cfg(feature = "websocket")]
+                    events.broadcast_test_notification(0, notification);
+                    #[cfg(not(feature = "websocket"))]
+                    let _ = notification;
+                })),
+            );
+            match started {
+                Ok(()) => respond(&mut stream, "200 OK", "{}"),
+                Err(_) => respond_error(&mut stream, "409 Conflict", "not connected"),
+            }
+        }
+        ("POST", "/cancel") => match &server.lock().unwrap().device {
+            Some(device) if device.cancel_test().is_ok() => {
+                respond(&mut stream, "200 OK", "{}");
+            }
+            _ => respond_error(&mut stream, "409 Conflict", "not connected"),
+        },
+        ("GET", "/stats") => match &server.lock().unwrap().device {
+            Some(device) => respond(&mut stream, "200 OK", &stats_json(&device.stats())),
+            None => respond_error(&mut stream, "409 Conflict", "not connected"),
+        },
+        #[cfg(feature = "websocket")]
+        ("GET", "/events") => {
+            let Some(key) = request.header("sec-websocket-key") else {
+                respond_error(
+                    &mut stream,
+                    "400 Bad Request",
+                    "expected a WebSocket upgrade",
+                );
+                return;
+            };
+            let events = server.lock().unwrap().events.clone();
+            if let Err(e) = events.upgrade(stream, key) {
+                eprintln!("failed to upgrade /events request: {e}");
+            }
+        }
+        #[cfg(feature = "metrics")]
+        ("GET", "/metrics") => {
+            let body = server.lock().unwrap().metrics.render();
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+        }
+        _ => respond_error(&mut stream, "404 Not Found", "no such endpoint"),
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Some(origin) = args.allow_origin {
+        ALLOW_ORIGIN.set(origin).unwrap();
+    }
+    let listener = match TcpListener::bind(&args.listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Unable to listen on {}: {e}", args.listen);
+            std::process::exit(1);
+        }
+    };
+    eprintln!(
+        "P8020A server (v{}), listening on {}",
+        env!("CARGO_PKG_VERSION"),
+        args.listen
+    );
+    let server = Arc::new(Mutex::new(Server::default()));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let server = server.clone();
+                std::thread::spawn(move || handle(server, stream));
+            }
+            Err(e) => eprintln!("failed to accept connection: {e}"),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use clap::Parser;
+use p8020::simulator::{Simulator, SimulatorOptions};
+
+/// Exposes a simulated 8020 on a pseudo-terminal, such that any libp8020
+/// based application (or FitPro) can be pointed at it instead of a real
+/// device.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Mean ambient concentration, in particles/cm3.
+    #[arg(long, default_value_t = 3000.0)]
+    ambient: f64,
+
+    /// FF of the simulated mask.
+    #[arg(long, default_value_t = 200.0)]
+    fit_factor: f64,
+
+    /// Relative noise applied to each sample.
+    #[arg(long, default_value_t = 0.05)]
+    noise: f64,
+
+    /// Seed for the noise.
+    #[arg(long, default_value_t = 8020)]
+    seed: u64,
+
+    /// Serial number reported by the simulated device.
+    #[arg(long, default_value = "80200000")]
+    serial_number: String,
+
+    /// Print commands and responses.
+    #[arg(long)]
+    verbose: bool,
+}
+
+#[cfg(unix)]
+fn main() {
+    use serialport::{SerialPort, TTYPort};
+    use std::io::{BufRead, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    let args = Args::parse();
+    let simulator = Arc::new(Mutex::new(Simulator::new(SimulatorOptions {
+        ambient_concentration: args.ambient,
+        fit_factor: args.fit_factor,
+        noise: args.noise,
+        seed: args.seed,
+        serial_number: args.serial_number,
+        ..SimulatorOptions::default()
+    })));
+
+    // The slave must stay open for the lifetime of the simulator: otherwise
+    // the master would be closed as soon as the first client disconnects.
+    let (mut master, slave) = match TTYPort::pair() {
+        Ok(pair) => pair,
+        Err(e) => {
+            eprintln!("Unable to create pseudo-terminal: {e}");
+            std::process::exit(1);
+        }
+    };
+    eprintln!(
+        "P8020A simulator (v{}), listening on {}",
+        env!("CARGO_PKG_VERSION"),
+        slave.name().unwrap_or_default()
+    );
+
+    master
+        .set_timeout(Duration::from_secs(60 * 60 * 24))
+        .expect("Unable to configure pseudo-terminal");
+    let writer = Arc::new(Mutex::new(
+        master
+            .try_clone_native()
+            .expect("Unable to clone pseudo-terminal"),
+    ));
+    let send = {
+        let writer = writer.clone();
+        move |message: &str, verbose: bool| {
+            if verbose {
+                eprintln!("> {message}");
+            }
+            let mut writer = writer.lock().unwrap();
+            if let Err(e) = writer.write_all(format!("{message}\r\n").as_bytes()) {
+                eprintln!("Unable to send {message}: {e}");
+            }
+        }
+    };
+
+    {
+        let simulator = simulator.clone();
+        let send = send.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(1));
+            if let Some(sample) = simulator.lock().unwrap().sample() {
+                send(&sample, false);
+            }
+        });
+    }
+
+    // Commands are terminated with \r (see bin/reset.rs).
+    let mut reader = std::io::BufReader::new(master);
+    let mut command = Vec::new();
+    loop {
+        // Partial commands are kept on timeout, read_until appends the rest.
+        match reader.read_until(b'\r', &mut command) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+            Err(e) => {
+                eprintln!("Unable to read command: {e}");
+                break;
+            }
+        }
+        let line = String::from_utf8_lossy(&command).trim().to_string();
+        command.clear();
+        if line.is_empty() {
+            continue;
+        }
+        if args.verbose {
+            eprintln!("< {line}");
+        }
+        let responses = simulator.lock().unwrap().handle_command(&line);
+        for response in responses {
+            send(&response, args.verbose);
+        }
+    }
+    drop(slave);
+}
+
+#[cfg(not(unix))]
+fn main() {
+    let _ = Args::parse();
+    eprintln!("The simulator requires pseudo-terminal support, which is only available on unix.");
+    std::process::exit(1);
+}
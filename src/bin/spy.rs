@@ -1,18 +1,65 @@
 extern crate serialport;
+use clap::Parser;
 use std::io::BufRead;
 
-// TODO: enumerate devices dynamically
+// Used if no port is specified and none can be detected.
 const DEVICE: &str = "/dev/ttyUSB0";
 
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Serial port of the device (default: the first USB serial port, or
+    /// /dev/ttyUSB0).
+    port: Option<String>,
+
+    /// Prefix every line with the time at which it was received.
+    #[arg(long)]
+    timestamps: bool,
+
+    /// Show the raw bytes of every line in hex (followed by their printable
+    /// characters), e.g. for output that isn't valid UTF-8.
+    #[arg(long)]
+    hex: bool,
+}
+
+fn detect_port() -> Option<String> {
+    serialport::available_ports()
+        .ok()?
+        .into_iter()
+        .find(|port| matches!(port.port_type, serialport::SerialPortType::UsbPort(_)))
+        .map(|port| port.port_name)
+}
+
+fn format_hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    let printable: String = bytes
+        .iter()
+        .map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    format!("{}  |{printable}|", hex.join(" "))
+}
+
 fn main() {
+    let args = Args::parse();
     eprintln!(
         "P8020A spy (v{}). (This binary simply dumps your Portacount's serial output, because I'm too lazy to remember the appropriate commands.)",
         env!("CARGO_PKG_VERSION")
     );
+    let port_name = args
+        .port
+        .or_else(detect_port)
+        .unwrap_or_else(|| DEVICE.to_string());
+    eprintln!("Listening on {port_name}");
 
     // See "PortaCount Plus Model 8020 Technical Addendum" for specs.
     // Note: baud is configurable on the devices itself, 1200 is the default.
-    let port = serialport::new(DEVICE, /* baud_rate */ 1200)
+    let port = serialport::new(&port_name, /* baud_rate */ 1200)
         .data_bits(serialport::DataBits::Eight)
         .parity(serialport::Parity::None)
         .stop_bits(serialport::StopBits::One)
@@ -21,9 +68,31 @@ fn main() {
         .open()
         .expect("Unable to open serial port, sorry");
 
-    let reader = std::io::BufReader::new(port);
+    let mut reader = std::io::BufReader::new(port);
+    let timestamp_format = time::macros::format_description!(
+        version = 2,
+        "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]"
+    );
 
-    for line in reader.lines() {
-        println!("{}", line.unwrap().trim());
+    // Lines are read as bytes, as the output isn't necessarily valid UTF-8.
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).unwrap() == 0 {
+            break;
+        }
+        let contents = if args.hex {
+            format_hex(&line)
+        } else {
+            String::from_utf8_lossy(&line).trim().to_string()
+        };
+        if args.timestamps {
+            let timestamp = time::OffsetDateTime::now_utc()
+                .format(&timestamp_format)
+                .unwrap();
+            println!("{timestamp} {contents}");
+        } else {
+            println!("{contents}");
+        }
     }
 }
@@ -1,14 +1,141 @@
 extern crate serialport;
 use clap::Parser;
+use p8020::group::{DeviceGroup, DeviceOutcome, GroupOptions, GroupTestCallback, GroupTestResult};
+use p8020::parse_failures::{ParseFailureLog, ParseFailureLogOptions};
+use p8020::protocol::CommandClass;
+use p8020::record::{self, OshaRecord, TestMetadata};
+use p8020::samples::{self, SampleRecorder};
+use p8020::test_config::{builtin, TestConfig};
+use p8020::{DeviceId, DeviceNotification, TestNotification, TestOptions, TestState};
 use std::io::BufRead;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 // TODO: enumerate devices dynamically
 const DEVICE: &str = "/dev/ttyUSB0";
 
+// The protocol used when testing several devices without specifying --protocol.
+const DEFAULT_PROTOCOL: &str = "osha";
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Serial ports of the devices to test (default: /dev/ttyUSB0). If
+    /// several devices are specified, the protocol is run on all of them
+    /// simultaneously.
+    devices: Vec<String>,
+
+    /// Short name of the builtin protocol to run (e.g. osha, crash2.5). The
+    /// exercise and timing options below are ignored if this, --config,
+    /// --output, --osha-record, --samples, --parse-failure-log,
+    /// --command-interval, --command-class-interval, --mqtt, or --influx is
+    /// set (default: osha).
+    #[arg(long)]
+    protocol: Option<String>,
+
+    /// Path of a custom protocol config to run instead of a builtin protocol
+    /// (CSV, or JSON/TOML if built with the serde feature).
+    #[arg(long, conflicts_with = "protocol")]
+    config: Option<std::path::PathBuf>,
+
+    /// Write the full results to this file, as JSON or CSV (depending on the
+    /// file extension).
+    #[arg(long)]
+    output: Option<std::path::PathBuf>,
+
+    /// Write the records required by OSHA (29 CFR 1910.134(m)(2)) for every
+    /// completed test to this file, as JSON or CSV (depending on the file
+    /// extension).
+    #[arg(long)]
+    osha_record: Option<std::path::PathBuf>,
+
+    /// Write every sample (annotated with its stage and exercise) to this CSV
+    /// file. When testing several devices, each device's samples are written
+    /// to a separate file, named by inserting the device's index before the
+    /// extension (e.g. samples-0.csv).
+    #[arg(long)]
+    samples: Option<std::path::PathBuf>,
+
+    /// The test subject's name or ID, recorded in --output and --osha-record.
+    #[arg(long)]
+    subject: Option<String>,
+
+    /// The respirator's manufacturer, recorded in --osha-record.
+    #[arg(long)]
+    mask_make: Option<String>,
+
+    /// The respirator's model, recorded in --output and
+    /// --osha-record.
+    #[arg(long)]
+    mask_model: Option<String>,
+
+    /// The respirator's style (e.g. half mask, full facepiece), recorded in
+    /// --osha-record.
+    #[arg(long)]
+    mask_style: Option<String>,
+
+    /// The respirator's size, recorded in --output and --osha-record.
+    #[arg(long)]
+    mask_size: Option<String>,
+
+    /// The name or ID of the person administering the test, recorded in
+    /// --output and --osha-record.
+    #[arg(long)]
+    operator: Option<String>,
+
+    /// Publish samples, test progress, and results to this MQTT broker
+    /// (host or host:port). Devices are identified by their index in
+    /// topics, e.g. p8020/0/sample.
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    mqtt: Option<String>,
+
+    /// Prefix of the MQTT topics, see --mqtt.
+    #[cfg(feature = "mqtt")]
+    #[arg(long, default_value = "p8020")]
+    mqtt_topic_prefix: String,
+
+    /// Write samples, test progress, and results as InfluxDB line protocol
+    /// to this target: - (stdout), a file, or an HTTP write endpoint (e.g.
+    /// http://localhost:8086/api/v2/write?org=o&bucket=b). Devices are
+    /// identified by their index in the device tag.
+    #[cfg(feature = "influx")]
+    #[arg(long)]
+    influx: Option<p8020::influx::InfluxTarget>,
+
+    /// API token for --influx HTTP endpoints.
+    #[cfg(feature = "influx")]
+    #[arg(long, requires = "influx")]
+    influx_token: Option<String>,
+
+    /// Append all messages that could not be parsed to this file (which is
+    /// rotated once it reaches 1MiB), for reporting firmware quirks.
+    #[arg(long)]
+    parse_failure_log: Option<std::path::PathBuf>,
+
+    /// Declares that an N95-Companion (Model 8095) is attached and enabled on
+    /// all devices, which caps FFs at 200.
+    #[arg(long)]
+    n95_companion: bool,
+
+    /// The minimum interval between commands, in milliseconds (default:
+    /// 100). 8020As have been observed to ignore commands sent within ~52ms
+    /// of the previous command.
+    #[arg(long)]
+    command_interval: Option<u64>,
+
+    /// Overrides --command-interval for a class of commands (control, valve,
+    /// display, or beep), e.g. display=60. May be repeated.
+    #[arg(long, value_parser = parse_command_class_interval)]
+    command_class_interval: Vec<(CommandClass, Duration)>,
+
+    /// Print library diagnostics up to this level (off, error, warn, info,
+    /// debug, or trace). trace includes all raw serial traffic.
+    #[arg(long, default_value = "warn")]
+    log_level: log::LevelFilter,
+
     /// Number of exercises
     #[arg(long, default_value_t = 8)]
     exercises: usize,
@@ -66,8 +193,742 @@ fn send(port: &mut Box<dyn serialport::SerialPort>, msg: &str) {
     }
 }
 
+// Loads and validates the config at path, exiting if it can't be used.
+// Parses CLASS=MS, see --command-class-interval.
+fn parse_command_class_interval(value: &str) -> Result<(CommandClass, Duration), String> {
+    let Some((class, interval)) = value.split_once('=') else {
+        return Err(format!("expected CLASS=MS, got {value}"));
+    };
+    let interval = u64::from_str(interval).map_err(|e| format!("invalid interval: {e}"))?;
+    Ok((class.parse()?, Duration::from_millis(interval)))
+}
+
+fn load_config(path: &std::path::Path) -> TestConfig {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Unable to read config {}: {e}", path.display());
+            std::process::exit(1);
+        }
+    };
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let config = match extension {
+        #[cfg(feature = "serde")]
+        Some("json") => TestConfig::parse_json(&contents).map_err(|e| format!("{e:?}")),
+        #[cfg(feature = "serde")]
+        Some("toml") => TestConfig::parse_toml(&contents).map_err(|e| format!("{e:?}")),
+        _ => TestConfig::parse_from_csv(&mut contents.as_bytes()).map_err(|e| format!("{e:?}")),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Unable to parse config {}: {e}", path.display());
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = config.validate() {
+        eprintln!("Invalid config {}: {e:?}", path.display());
+        std::process::exit(1);
+    }
+    config
+}
+
+// Prints library diagnostics to stderr, prefixed with the index of the device
+// they relate to (if any).
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        match record.key_values().get(log::kv::Key::from("device")) {
+            Some(device) => eprintln!("[{device}] {}: {}", record.level(), record.args()),
+            None => eprintln!("{}: {}", record.level(), record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Set once Ctrl-C was pressed, see install_interrupt_handler.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Installs a SIGINT handler that sets INTERRUPTED, such that the running test
+// can be cancelled cleanly. A second Ctrl-C exits immediately.
+#[cfg(unix)]
+fn install_interrupt_handler() {
+    const SIGINT: std::ffi::c_int = 2;
+    extern "C" {
+        fn signal(signum: std::ffi::c_int, handler: extern "C" fn(std::ffi::c_int)) -> usize;
+        fn _exit(status: std::ffi::c_int) -> !;
+    }
+    extern "C" fn handle_interrupt(_: std::ffi::c_int) {
+        // Only async-signal-safe operations are allowed here.
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            unsafe { _exit(130) };
+        }
+    }
+    unsafe {
+        signal(SIGINT, handle_interrupt);
+    }
+}
+
+#[cfg(not(unix))]
+fn install_interrupt_handler() {}
+
+// Forwards notifications to the MQTT broker specified via --mqtt, and the
+// InfluxDB target specified via --influx, if any.
+#[derive(Clone, Default)]
+struct Publisher {
+    #[cfg(feature = "mqtt")]
+    mqtt: Option<Arc<p8020::mqtt::MqttPublisher>>,
+    // Errors are only reported once, instead of once per sample.
+    #[cfg(feature = "mqtt")]
+    failed: Arc<AtomicBool>,
+    #[cfg(feature = "influx")]
+    influx: Option<Arc<p8020::influx::InfluxSink>>,
+}
+
+impl Publisher {
+    fn connect(args: &Args) -> Publisher {
+        #[cfg(not(any(feature = "mqtt", feature = "influx")))]
+        let _ = args;
+        Publisher {
+            #[cfg(feature = "mqtt")]
+            mqtt: args
+                .mqtt
+                .as_deref()
+                .map(|broker| connect_mqtt(broker, args)),
+            #[cfg(feature = "mqtt")]
+            failed: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "influx")]
+            influx: args.influx.clone().map(|target| {
+                let options = p8020::influx::InfluxOptions {
+                    target,
+                    token: args.influx_token.clone(),
+                    ..p8020::influx::InfluxOptions::default()
+                };
+                match p8020::influx::InfluxSink::open(options) {
+                    Ok(influx) => Arc::new(influx),
+                    Err(e) => {
+                        eprintln!("Unable to open InfluxDB target: {e}");
+                        std::process::exit(1);
+                    }
+                }
+            }),
+        }
+    }
+
+    #[cfg(feature = "mqtt")]
+    fn report(&self, result: std::io::Result<()>) {
+        if let Err(e) = result {
+            if !self.failed.swap(true, Ordering::SeqCst) {
+                eprintln!("Unable to publish to MQTT broker: {e}");
+            }
+        }
+    }
+
+    fn device_notification(&self, id: &DeviceId, notification: &DeviceNotification) {
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            self.report(mqtt.publish_device_notification(&id.index.to_string(), notification));
+        }
+        #[cfg(feature = "influx")]
+        if let Some(influx) = &self.influx {
+            influx.write_device_notification(&id.index.to_string(), notification);
+        }
+        #[cfg(not(any(feature = "mqtt", feature = "influx")))]
+        let _ = (id, notification);
+    }
+
+    fn test_notification(&self, index: usize, notification: &TestNotification) {
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            self.report(mqtt.publish_test_notification(&index.to_string(), notification));
+        }
+        #[cfg(feature = "influx")]
+        if let Some(influx) = &self.influx {
+            influx.write_test_notification(&index.to_string(), notification);
+        }
+        #[cfg(not(any(feature = "mqtt", feature = "influx")))]
+        let _ = (index, notification);
+    }
+}
+
+#[cfg(feature = "mqtt")]
+fn connect_mqtt(broker: &str, args: &Args) -> Arc<p8020::mqtt::MqttPublisher> {
+    let (host, port) = match broker.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => (host, port),
+            Err(_) => {
+                eprintln!("Invalid MQTT broker port: {port}");
+                std::process::exit(1);
+            }
+        },
+        None => (broker, 1883),
+    };
+    let prefix = &args.mqtt_topic_prefix;
+    let options = p8020::mqtt::MqttOptions {
+        host: host.to_string(),
+        port,
+        topics: p8020::mqtt::MqttTopics {
+            sample: format!("{prefix}/{{device}}/sample"),
+            state: format!("{prefix}/{{device}}/state"),
+            exercise: format!("{prefix}/{{device}}/exercise"),
+            result: format!("{prefix}/{{device}}/result"),
+        },
+        ..p8020::mqtt::MqttOptions::default()
+    };
+    match p8020::mqtt::MqttPublisher::connect(options) {
+        Ok(mqtt) => Arc::new(mqtt),
+        Err(e) => {
+            eprintln!("Unable to connect to MQTT broker {broker}: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// The files that run_group writes to, see the corresponding Args.
+struct OutputPaths {
+    output: Option<std::path::PathBuf>,
+    osha_record: Option<std::path::PathBuf>,
+    samples: Option<std::path::PathBuf>,
+}
+
+// Runs config on all devices simultaneously, prints a table containing
+// every device's FFs, and writes the results to outputs.
+fn run_group(
+    paths: Vec<String>,
+    config: TestConfig,
+    options: GroupOptions,
+    outputs: &OutputPaths,
+    metadata: &TestMetadata,
+    publisher: Publisher,
+) {
+    eprintln!(
+        "Running {} on {} device(s): {}\n",
+        config.name,
+        paths.len(),
+        paths.join(", ")
+    );
+    let device_publisher = publisher.clone();
+    let mut group = match DeviceGroup::connect_with_options(
+        paths.clone(),
+        options,
+        Some(move |id: &DeviceId, notification: DeviceNotification| {
+            device_publisher.device_notification(id, &notification)
+        }),
+    ) {
+        Ok(group) => group,
+        Err(e) => {
+            eprintln!("Unable to connect to devices: {e}");
+            std::process::exit(1);
+        }
+    };
+    for (index, path) in paths.iter().enumerate() {
+        group.set_label(index, path.clone());
+    }
+
+    let exercise_names = config.exercise_names();
+    let progress_names = exercise_names.clone();
+    // Exercise FFs received so far, which are reported if the test is
+    // interrupted.
+    let partial_fit_factors = Arc::new(Mutex::new(vec![
+        vec![None; exercise_names.len()];
+        paths.len()
+    ]));
+    let partial_fit_factors_write = partial_fit_factors.clone();
+    let recorders = Arc::new(Mutex::new(vec![SampleRecorder::new(&config); paths.len()]));
+    let recorders_write = recorders.clone();
+    let finished = AtomicBool::new(false);
+    install_interrupt_handler();
+    let result = std::thread::scope(|scope| {
+        scope.spawn(|| {
+            while !finished.load(Ordering::SeqCst) {
+                if INTERRUPTED.load(Ordering::SeqCst) {
+                    eprintln!(
+                        "\nInterrupted, cancelling test (press Ctrl-C again to exit immediately)"
+                    );
+                    group.cancel_test();
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+        });
+        let result = group.run_test(
+            &config,
+            TestOptions::default(),
+            GroupTestCallback::merged(move |index, notification| {
+                publisher.test_notification(index, notification);
+                recorders_write.lock().unwrap()[index].record(notification);
+                match notification {
+                    TestNotification::StateChange(TestState::StartedExercise(exercise)) => {
+                        eprintln!(
+                            "[{index}] Started exercise {}: {}",
+                            exercise + 1,
+                            progress_names[*exercise]
+                        );
+                    }
+                    TestNotification::ExerciseResult {
+                        exercise,
+                        fit_factor,
+                        ..
+                    } => {
+                        partial_fit_factors_write.lock().unwrap()[index][*exercise] =
+                            Some(*fit_factor);
+                        eprintln!("[{index}] Exercise {}: FF {fit_factor:.1}", exercise + 1);
+                    }
+                    _ => (),
+                }
+            }),
+        );
+        finished.store(true, Ordering::SeqCst);
+        result
+    });
+    let partial_fit_factors = partial_fit_factors.lock().unwrap().clone();
+    print_result_table(&exercise_names, &paths, &result, &partial_fit_factors);
+
+    if let Some(output) = &outputs.output {
+        write_results(
+            output,
+            &config,
+            metadata,
+            &group,
+            &paths,
+            &result,
+            &partial_fit_factors,
+        );
+    }
+    if let Some(osha_record) = &outputs.osha_record {
+        write_osha_records(osha_record, metadata, &group, &result);
+    }
+    if let Some(samples) = &outputs.samples {
+        write_samples(samples, &recorders.lock().unwrap());
+    }
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        std::process::exit(130);
+    }
+}
+
+fn write_results(
+    output: &std::path::Path,
+    config: &TestConfig,
+    metadata: &TestMetadata,
+    group: &DeviceGroup,
+    paths: &[String],
+    result: &GroupTestResult,
+    partial_fit_factors: &[Vec<Option<f64>>],
+) {
+    let records: Vec<DeviceRecord> = paths
+        .iter()
+        .zip(&result.outcomes)
+        .enumerate()
+        .map(|(index, (path, outcome))| DeviceRecord {
+            path,
+            serial_number: group
+                .device(index)
+                .and_then(|device| device.id().serial_number),
+            outcome,
+            partial_fit_factors: &partial_fit_factors[index],
+        })
+        .collect();
+    let contents = match output.extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => results_csv(config, metadata, &records),
+        _ => results_json(config, metadata, &records),
+    };
+    if let Err(e) = std::fs::write(output, contents) {
+        eprintln!("Unable to write results to {}: {e}", output.display());
+        std::process::exit(1);
+    }
+}
+
+// Writes each device's samples to path, or to a separate file per device if
+// there are several (see Args::samples). Samples are written even if the test
+// did not complete.
+fn write_samples(path: &std::path::Path, recorders: &[SampleRecorder]) {
+    for (index, recorder) in recorders.iter().enumerate() {
+        let path = if recorders.len() == 1 {
+            path.to_path_buf()
+        } else {
+            let mut name = path.file_stem().unwrap_or_default().to_os_string();
+            name.push(format!("-{index}"));
+            if let Some(extension) = path.extension() {
+                name.push(".");
+                name.push(extension);
+            }
+            path.with_file_name(name)
+        };
+        if let Err(e) = std::fs::write(&path, samples::to_csv(recorder.samples())) {
+            eprintln!("Unable to write samples to {}: {e}", path.display());
+            std::process::exit(1);
+        }
+    }
+}
+
+// Writes a record for every completed test, skipping devices that did not
+// complete (there is nothing to record for them).
+fn write_osha_records(
+    path: &std::path::Path,
+    metadata: &TestMetadata,
+    group: &DeviceGroup,
+    result: &GroupTestResult,
+) {
+    let records: Vec<OshaRecord> = result
+        .outcomes
+        .iter()
+        .enumerate()
+        .filter_map(|(index, outcome)| {
+            Some(OshaRecord {
+                instrument_serial_number: group
+                    .device(index)
+                    .and_then(|device| device.id().serial_number),
+                ..OshaRecord::new(outcome.result()?, metadata)
+            })
+        })
+        .collect();
+    let contents = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => record::to_csv(&records),
+        _ => record::to_json(&records),
+    };
+    if let Err(e) = std::fs::write(path, contents) {
+        eprintln!("Unable to write OSHA records to {}: {e}", path.display());
+        std::process::exit(1);
+    }
+}
+
+// The metadata written to --output (which applies to all devices). The
+// respirator's make and style are only part of --osha-record.
+fn metadata_fields(metadata: &TestMetadata) -> [(&'static str, Option<&str>); 4] {
+    [
+        ("subject", metadata.subject.as_deref()),
+        ("mask_model", metadata.respirator_model.as_deref()),
+        ("mask_size", metadata.respirator_size.as_deref()),
+        ("operator", metadata.operator.as_deref()),
+    ]
+}
+
+// A single device's outcome, as written to --output.
+struct DeviceRecord<'a> {
+    path: &'a str,
+    serial_number: Option<String>,
+    outcome: &'a DeviceOutcome,
+    // The exercise FFs received before the test ended, used if the test was
+    // cancelled.
+    partial_fit_factors: &'a [Option<f64>],
+}
+
+impl DeviceRecord<'_> {
+    fn status(&self) -> &'static str {
+        match self.outcome {
+            DeviceOutcome::Completed(_) => "completed",
+            DeviceOutcome::Cancelled => "cancelled",
+            DeviceOutcome::Stalled { .. } => "stalled",
+            DeviceOutcome::Absent => "absent",
+        }
+    }
+
+    fn timestamps(&self) -> Option<(String, String)> {
+        let result = self.outcome.result()?;
+        Some((
+            format_timestamp(result.started_at),
+            format_timestamp(result.completed_at),
+        ))
+    }
+
+    // The FF and error of each exercise, as far as available.
+    fn exercises(&self, exercise_count: usize) -> Vec<(Option<f64>, Option<f64>)> {
+        (0..exercise_count)
+            .map(|exercise| match self.outcome {
+                DeviceOutcome::Completed(result) => (
+                    Some(result.fit_factors[exercise]),
+                    Some(result.errors[exercise]),
+                ),
+                DeviceOutcome::Stalled { fit_factors } => (fit_factors[exercise], None),
+                DeviceOutcome::Cancelled => (self.partial_fit_factors[exercise], None),
+                DeviceOutcome::Absent => (None, None),
+            })
+            .collect()
+    }
+}
+
+fn format_timestamp(timestamp: std::time::SystemTime) -> String {
+    time::OffsetDateTime::from(timestamp)
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap()
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_number(value: Option<f64>) -> String {
+    match value {
+        Some(value) if value.is_finite() => value.to_string(),
+        _ => "null".to_string(),
+    }
+}
+
+fn json_optional_string(value: Option<&str>) -> String {
+    value.map_or("null".to_string(), json_string)
+}
+
+fn results_json(config: &TestConfig, metadata: &TestMetadata, records: &[DeviceRecord]) -> String {
+    let exercise_names = config.exercise_names();
+    let devices: Vec<String> = records
+        .iter()
+        .map(|record| {
+            let exercises: Vec<String> = exercise_names
+                .iter()
+                .zip(record.exercises(exercise_names.len()))
+                .map(|(name, (fit_factor, error))| {
+                    format!(
+                        "{{\"name\": {}, \"fit_factor\": {}, \"error\": {}}}",
+                        json_string(name),
+                        json_number(fit_factor),
+                        json_number(error)
+                    )
+                })
+                .collect();
+            let timestamps = record.timestamps();
+            let result = record.outcome.result();
+            format!(
+                concat!(
+                    "    {{\n",
+                    "      \"path\": {},\n",
+                    "      \"serial_number\": {},\n",
+                    "      \"status\": {},\n",
+                    "      \"started_at\": {},\n",
+                    "      \"completed_at\": {},\n",
+                    "      \"overall_fit_factor\": {},\n",
+                    "      \"passed\": {},\n",
+                    "      \"exercises\": [\n        {}\n      ]\n",
+                    "    }}"
+                ),
+                json_string(record.path),
+                json_optional_string(record.serial_number.as_deref()),
+                json_string(record.status()),
+                json_optional_string(timestamps.as_ref().map(|t| t.0.as_str())),
+                json_optional_string(timestamps.as_ref().map(|t| t.1.as_str())),
+                json_number(result.map(|result| result.overall_fit_factor)),
+                result
+                    .and_then(|result| result.passed)
+                    .map_or("null".to_string(), |passed| passed.to_string()),
+                exercises.join(",\n        ")
+            )
+        })
+        .collect();
+    format!(
+        concat!(
+            "{{\n",
+            "  \"protocol\": {{\"name\": {}, \"short_name\": {}, \"version\": {}}},\n",
+            "  \"metadata\": {{{}}},\n",
+            "  \"devices\": [\n{}\n  ]\n",
+            "}}\n"
+        ),
+        json_string(&config.name),
+        json_string(&config.short_name),
+        config.version,
+        metadata_fields(metadata)
+            .iter()
+            .map(|(name, value)| format!("\"{name}\": {}", json_optional_string(*value)))
+            .collect::<Vec<String>>()
+            .join(", "),
+        devices.join(",\n")
+    )
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// One row per device and exercise, plus one row per device for the overall
+// FF (exercise = "overall").
+fn results_csv(config: &TestConfig, metadata: &TestMetadata, records: &[DeviceRecord]) -> String {
+    let format_number = |value: Option<f64>| value.map_or(String::new(), |value| value.to_string());
+    let mut csv = String::from(
+        "subject,mask_model,mask_size,operator,device,serial_number,status,started_at,completed_at,protocol,protocol_version,exercise,name,fit_factor,error\n",
+    );
+    let exercise_names = config.exercise_names();
+    for record in records {
+        let (started_at, completed_at) = record.timestamps().unwrap_or_default();
+        let prefix = metadata_fields(metadata)
+            .iter()
+            .map(|(_, value)| csv_field(value.unwrap_or("")))
+            .chain([
+                csv_field(record.path),
+                csv_field(record.serial_number.as_deref().unwrap_or("")),
+                record.status().to_string(),
+                started_at,
+                completed_at,
+                csv_field(&config.short_name),
+                config.version.to_string(),
+            ])
+            .collect::<Vec<String>>()
+            .join(",");
+        for (exercise, (name, (fit_factor, error))) in exercise_names
+            .iter()
+            .zip(record.exercises(exercise_names.len()))
+            .enumerate()
+        {
+            csv.push_str(&format!(
+                "{prefix},{},{},{},{}\n",
+                exercise + 1,
+                csv_field(name),
+                format_number(fit_factor),
+                format_number(error)
+            ));
+        }
+        let overall = record
+            .outcome
+            .result()
+            .map(|result| result.overall_fit_factor);
+        csv.push_str(&format!("{prefix},overall,,{},\n", format_number(overall)));
+    }
+    csv
+}
+
+// Cancelled devices are shown with the exercise FFs in partial_fit_factors.
+fn print_result_table(
+    exercise_names: &[String],
+    paths: &[String],
+    result: &GroupTestResult,
+    partial_fit_factors: &[Vec<Option<f64>>],
+) {
+    let name_width = exercise_names
+        .iter()
+        .map(|name| name.len() + 4)
+        .chain(std::iter::once("Overall".len()))
+        .max()
+        .unwrap_or(0);
+    let column_width = paths.iter().map(String::len).max().unwrap_or(0).max(8);
+
+    print!("{:name_width$}", "");
+    for path in paths {
+        print!("  {path:>column_width$}");
+    }
+    println!();
+
+    let format_ff = |fit_factor: Option<f64>| match fit_factor {
+        Some(fit_factor) => format!("{fit_factor:.1}"),
+        None => "-".to_string(),
+    };
+    for (exercise, name) in exercise_names.iter().enumerate() {
+        print!("{:name_width$}", format!("{:>2}. {name}", exercise + 1));
+        for (index, outcome) in result.outcomes.iter().enumerate() {
+            let fit_factor = match outcome {
+                DeviceOutcome::Completed(result) => Some(result.fit_factors[exercise]),
+                DeviceOutcome::Stalled { fit_factors } => fit_factors[exercise],
+                DeviceOutcome::Cancelled => partial_fit_factors[index][exercise],
+                DeviceOutcome::Absent => None,
+            };
+            print!("  {:>column_width$}", format_ff(fit_factor));
+        }
+        println!();
+    }
+    print!("{:name_width$}", "Overall");
+    for outcome in &result.outcomes {
+        let fit_factor = outcome.result().map(|result| result.overall_fit_factor);
+        print!("  {:>column_width$}", format_ff(fit_factor));
+    }
+    println!();
+}
+
 fn main() {
     let args = Args::parse();
+    if log::set_logger(&StderrLogger).is_ok() {
+        log::set_max_level(args.log_level);
+    }
+    let publishing = [
+        #[cfg(feature = "mqtt")]
+        args.mqtt.is_some(),
+        #[cfg(feature = "influx")]
+        args.influx.is_some(),
+    ]
+    .contains(&true);
+    if args.protocol.is_some()
+        || args.config.is_some()
+        || args.output.is_some()
+        || args.osha_record.is_some()
+        || args.samples.is_some()
+        || args.parse_failure_log.is_some()
+        || args.command_interval.is_some()
+        || !args.command_class_interval.is_empty()
+        || args.devices.len() > 1
+        || publishing
+    {
+        let publisher = Publisher::connect(&args);
+        let config = match &args.config {
+            Some(path) => load_config(path),
+            None => {
+                let short_name = args.protocol.as_deref().unwrap_or(DEFAULT_PROTOCOL);
+                let Some(config) = builtin::load(short_name) else {
+                    eprintln!("Unknown protocol: {short_name}");
+                    std::process::exit(1);
+                };
+                config
+            }
+        };
+        let paths = if args.devices.is_empty() {
+            vec![DEVICE.to_string()]
+        } else {
+            args.devices
+        };
+        let metadata = TestMetadata {
+            subject: args.subject,
+            respirator_make: args.mask_make,
+            respirator_model: args.mask_model,
+            respirator_style: args.mask_style,
+            respirator_size: args.mask_size,
+            operator: args.operator,
+        };
+        let mut options = GroupOptions::default();
+        options.connect_options.n95_companion = args.n95_companion;
+        if let Some(interval) = args.command_interval {
+            options.connect_options.command_interval = Duration::from_millis(interval);
+        }
+        options
+            .connect_options
+            .command_class_intervals
+            .extend(args.command_class_interval);
+        if let Some(path) = args.parse_failure_log {
+            let log_options = ParseFailureLogOptions {
+                path: path.clone(),
+                ..ParseFailureLogOptions::default()
+            };
+            match ParseFailureLog::open(log_options) {
+                Ok(log) => options.connect_options.parse_failure_log = Some(Arc::new(log)),
+                Err(e) => {
+                    eprintln!("Unable to open {}: {e}", path.display());
+                    std::process::exit(1);
+                }
+            }
+        }
+        let outputs = OutputPaths {
+            output: args.output,
+            osha_record: args.osha_record,
+            samples: args.samples,
+        };
+        run_group(paths, config, options, &outputs, &metadata, publisher);
+        return;
+    }
+    let device = args.devices.first().map_or(DEVICE, String::as_str);
     eprintln!(
         "8020A tester (v{}).\nPerforming {} exercise(s) ({}s/{}s/{}s/{}s)\n\n",
         env!("CARGO_PKG_VERSION"),
@@ -80,7 +941,7 @@ fn main() {
 
     // See "PortaCount Plus Model 8020 Technical Addendum" for specs.
     // Note: baud is configurable on the devices itself, 1200 is the default.
-    let mut port = serialport::new(DEVICE, /* baud_rate */ 1200)
+    let mut port = serialport::new(device, /* baud_rate */ 1200)
         .data_bits(serialport::DataBits::Eight)
         .parity(serialport::Parity::None)
         .stop_bits(serialport::StopBits::One)
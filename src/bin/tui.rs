@@ -0,0 +1,468 @@
+use clap::Parser;
+use p8020::cadence::EXPECTED_SAMPLE_INTERVAL;
+use p8020::queue::{BoundedQueue, OverflowPolicy};
+use p8020::test_config::{builtin, TestConfig};
+use p8020::{
+    Device, DeviceId, DeviceNotification, SampleData, SampleType, TestNotification, TestOptions,
+    TestState,
+};
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+
+// TODO: enumerate devices dynamically
+const DEVICE: &str = "/dev/ttyUSB0";
+
+// Samples are dropped beyond this many pending events, e.g. if the terminal
+// is slow to render.
+const MAX_QUEUED_EVENTS: usize = 64;
+
+// How often the terminal size is checked for changes (in the absence of
+// keypresses).
+const RESIZE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Operator console: runs a fit test and shows live concentrations, the
+/// current exercise, FFs, and the final results. Press q (or Ctrl-C) to quit,
+/// which cancels the test if it's still running.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Serial port of the device.
+    #[arg(default_value = DEVICE)]
+    device: String,
+
+    /// Short name of the builtin protocol to run (e.g. osha, crash2.5).
+    #[arg(long, default_value = "osha")]
+    protocol: String,
+}
+
+enum Event {
+    Device(DeviceNotification),
+    Test(TestNotification),
+    Resize(TerminalSize),
+    Quit,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct TerminalSize {
+    columns: usize,
+    rows: usize,
+}
+
+impl TerminalSize {
+    fn query() -> TerminalSize {
+        // SAFETY: winsize is plain old data, and is only read if the ioctl
+        // succeeded.
+        let mut winsize: libc::winsize = unsafe { std::mem::zeroed() };
+        let ret = unsafe {
+            libc::ioctl(
+                std::io::stdout().as_raw_fd(),
+                libc::TIOCGWINSZ,
+                &mut winsize,
+            )
+        };
+        if ret != 0 || winsize.ws_col == 0 || winsize.ws_row == 0 {
+            // Not a terminal (or one that doesn't know its size).
+            return TerminalSize {
+                columns: 80,
+                rows: 24,
+            };
+        }
+        TerminalSize {
+            columns: winsize.ws_col.into(),
+            rows: winsize.ws_row.into(),
+        }
+    }
+}
+
+// Puts the terminal into raw mode (keypresses are delivered immediately, and
+// aren't echoed; Ctrl-C is read as a key instead of raising SIGINT), and
+// switches to the alternate screen. Both are undone on drop.
+struct RawTerminal {
+    original: Option<libc::termios>,
+}
+
+impl RawTerminal {
+    fn enter() -> RawTerminal {
+        let fd = std::io::stdin().as_raw_fd();
+        // SAFETY: termios is plain old data, and is only used if tcgetattr
+        // succeeded.
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        let original = if unsafe { libc::tcgetattr(fd, &mut termios) } == 0 {
+            let original = termios;
+            // Output processing (OPOST) is left alone so that \n still
+            // returns to the start of the line.
+            termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+            unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &termios) };
+            Some(original)
+        } else {
+            // stdin isn't a terminal, there is nothing to restore.
+            None
+        };
+        // Switch to the alternate screen, and hide the cursor.
+        print!("\x1b[?1049h\x1b[?25l");
+        let _ = std::io::stdout().flush();
+        RawTerminal { original }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = std::io::stdout().flush();
+        if let Some(original) = &self.original {
+            unsafe { libc::tcsetattr(std::io::stdin().as_raw_fd(), libc::TCSAFLUSH, original) };
+        }
+    }
+}
+
+// Reads keypresses, and watches for terminal size changes. Exits after
+// queueing Event::Quit.
+fn run_input(events: Arc<BoundedQueue<Event>>, mut size: TerminalSize) {
+    use nix::poll::{PollFd, PollFlags};
+    let mut stdin = std::io::stdin();
+    loop {
+        let mut fds = [PollFd::new(stdin.as_raw_fd(), PollFlags::POLLIN)];
+        let readable = match nix::poll::poll(&mut fds, RESIZE_POLL_INTERVAL.as_millis() as i32) {
+            Ok(count) => count > 0,
+            Err(nix::errno::Errno::EINTR) => false,
+            Err(_) => return,
+        };
+        if readable {
+            let mut buf = [0u8; 32];
+            match stdin.read(&mut buf) {
+                // EOF: there will never be any keypresses, but resizes are
+                // still worth handling.
+                Ok(0) => std::thread::sleep(RESIZE_POLL_INTERVAL),
+                Ok(len) => {
+                    // q, or Ctrl-C (which doesn't raise SIGINT in raw mode).
+                    if buf[..len].iter().any(|&key| key == b'q' || key == 0x03) {
+                        events.push(Event::Quit, OverflowPolicy::Block);
+                        return;
+                    }
+                }
+                Err(_) => std::thread::sleep(RESIZE_POLL_INTERVAL),
+            }
+        }
+        let new_size = TerminalSize::query();
+        if new_size != size {
+            size = new_size;
+            events.push(Event::Resize(size), OverflowPolicy::DropOldest);
+        }
+    }
+}
+
+// Everything that is shown on screen.
+struct Console {
+    title: String,
+    // Resolved into sample counts, used for the countdown.
+    config: TestConfig,
+    exercise_names: Vec<String>,
+    concentration: Option<f64>,
+    exercise: usize,
+    stage: usize,
+    stage_samples: usize,
+    sample_type: Option<SampleType>,
+    live_ff: Option<f64>,
+    interim_ff: Option<f64>,
+    fit_factors: Vec<Option<f64>>,
+    overall_fit_factor: Option<f64>,
+    // For the entire test, see TestNotification::TimeRemaining.
+    seconds_remaining: Option<f64>,
+    status: String,
+    size: TerminalSize,
+}
+
+impl Console {
+    fn new(device: &str, config: &TestConfig, size: TerminalSize) -> Console {
+        let exercise_names = config.exercise_names();
+        Console {
+            title: format!("{} on {device}", config.name),
+            config: config.resolve_durations(EXPECTED_SAMPLE_INTERVAL),
+            fit_factors: vec![None; exercise_names.len()],
+            exercise_names,
+            concentration: None,
+            exercise: 0,
+            stage: 0,
+            stage_samples: 0,
+            sample_type: None,
+            live_ff: None,
+            interim_ff: None,
+            overall_fit_factor: None,
+            seconds_remaining: None,
+            status: "Connecting...".to_string(),
+            size,
+        }
+    }
+
+    // Returns true once the test has ended (for whatever reason). Quit is
+    // handled by the caller.
+    fn update(&mut self, event: Event) -> bool {
+        match event {
+            Event::Resize(size) => self.size = size,
+            Event::Quit => (),
+            Event::Device(DeviceNotification::Sample { particle_conc }) => {
+                self.concentration = Some(particle_conc);
+            }
+            Event::Device(DeviceNotification::TestStarted) => {
+                self.status = "Test running".to_string();
+            }
+            Event::Device(DeviceNotification::TestCompleted { result }) => {
+                self.overall_fit_factor = Some(result.overall_fit_factor);
+                self.status = match result.passed {
+                    Some(true) => "Test completed: PASS".to_string(),
+                    Some(false) => "Test completed: FAIL".to_string(),
+                    None => "Test completed".to_string(),
+                };
+                return true;
+            }
+            Event::Device(DeviceNotification::TestCancelled) => {
+                self.status = "Test cancelled".to_string();
+                return true;
+            }
+            Event::Device(DeviceNotification::TestAborted) => {
+                self.status = "Test aborted (insufficient ambient concentration?)".to_string();
+                return true;
+            }
+            Event::Device(DeviceNotification::ConnectionClosed) => {
+                self.status = "Connection closed".to_string();
+                return true;
+            }
+            Event::Device(DeviceNotification::ConnectionLost) => {
+                self.status = "Connection lost, reconnecting...".to_string();
+            }
+            // The running test is aborted too, which would otherwise be
+            // misreported as insufficient ambient concentration.
+            Event::Device(DeviceNotification::Fault { message, .. }) => {
+                self.status = format!("Internal error: {message}");
+                return true;
+            }
+            Event::Device(DeviceNotification::DeviceUnresponsive) => {
+                self.status = "Device not responding, is it switched on?".to_string();
+            }
+            Event::Device(_) => (),
+            Event::Test(TestNotification::StateChange(TestState::StartedExercise(exercise))) => {
+                self.exercise = exercise;
+                self.live_ff = None;
+                self.interim_ff = None;
+            }
+            Event::Test(TestNotification::Sample(SampleData { sample_type, .. })) => {
+                self.stage_samples += 1;
+                self.sample_type = Some(sample_type);
+            }
+            Event::Test(TestNotification::StageComplete { stage, .. }) => {
+                self.stage = stage + 1;
+                self.stage_samples = 0;
+            }
+            Event::Test(TestNotification::LiveFF { fit_factor, .. }) => {
+                self.live_ff = Some(fit_factor);
+            }
+            Event::Test(TestNotification::InterimFF { fit_factor, .. }) => {
+                self.interim_ff = Some(fit_factor);
+            }
+            Event::Test(TestNotification::TimeRemaining {
+                seconds_remaining, ..
+            }) => {
+                self.seconds_remaining = Some(seconds_remaining);
+            }
+            Event::Test(TestNotification::ExerciseResult {
+                exercise,
+                fit_factor,
+                ..
+            }) => {
+                self.fit_factors[exercise] = Some(fit_factor);
+            }
+            Event::Test(_) => (),
+        }
+        false
+    }
+
+    // Approximate, as stages may be extended or shortened during the test.
+    fn stage_seconds_remaining(&self) -> Option<u64> {
+        let counts = self.config.stages.get(self.stage)?.counts();
+        let remaining =
+            (counts.purge_count + counts.sample_count).saturating_sub(self.stage_samples);
+        Some((remaining as f64 * EXPECTED_SAMPLE_INTERVAL.as_secs_f64()).round() as u64)
+    }
+
+    fn render(&self, done: bool) -> String {
+        let format_value = |value: Option<f64>| match value {
+            Some(value) => format!("{value:.1}"),
+            None => "-".to_string(),
+        };
+        // Lines are collected without escape codes so that they can be
+        // clipped to the terminal width. Bold lines are marked as such.
+        let mut lines: Vec<(String, bool)> =
+            vec![(self.title.clone(), true), (String::new(), false)];
+        lines.push((format!("Status:        {}", self.status), false));
+        if let Some(seconds) = self.seconds_remaining {
+            let seconds = seconds.round() as u64;
+            lines.push((
+                format!("Time left:     about {}:{:02}", seconds / 60, seconds % 60),
+                false,
+            ));
+        }
+        lines.push((
+            format!(
+                "Concentration: {} particles/cm3",
+                format_value(self.concentration)
+            ),
+            false,
+        ));
+        lines.push((String::new(), false));
+        if let Some(name) = self.exercise_names.get(self.exercise) {
+            let phase = match self.sample_type {
+                Some(SampleType::AmbientPurge) => "ambient purge",
+                Some(SampleType::AmbientSample) => "ambient sample",
+                Some(SampleType::SpecimenPurge) => "specimen purge",
+                Some(SampleType::SpecimenSample) => "specimen sample",
+                None => "starting",
+            };
+            let remaining = self
+                .stage_seconds_remaining()
+                .map_or(String::new(), |seconds| format!(", ~{seconds}s remaining"));
+            lines.push((
+                format!(
+                    "Exercise {}/{}: {name} ({phase}{remaining})",
+                    self.exercise + 1,
+                    self.exercise_names.len()
+                ),
+                true,
+            ));
+        }
+        lines.push((
+            format!(
+                "Live FF: {}    Interim FF: {}",
+                format_value(self.live_ff),
+                format_value(self.interim_ff)
+            ),
+            false,
+        ));
+        lines.push((String::new(), false));
+        let name_width = self
+            .exercise_names
+            .iter()
+            .map(String::len)
+            .max()
+            .unwrap_or(0);
+        for (exercise, (name, fit_factor)) in self
+            .exercise_names
+            .iter()
+            .zip(&self.fit_factors)
+            .enumerate()
+        {
+            lines.push((
+                format!(
+                    "{:>2}. {name:name_width$}  {:>8}",
+                    exercise + 1,
+                    format_value(*fit_factor)
+                ),
+                false,
+            ));
+        }
+        lines.push((
+            format!(
+                "    {:name_width$}  {:>8}",
+                "Overall",
+                format_value(self.overall_fit_factor)
+            ),
+            false,
+        ));
+        // Shown on the last row, regardless of how much else fits.
+        let footer = if done {
+            "Press q to quit"
+        } else {
+            "Press q to cancel the test and quit"
+        };
+
+        // Clear the screen, and move to the top left corner.
+        let mut screen = String::from("\x1b[2J\x1b[H");
+        let clip = |line: &str| line.chars().take(self.size.columns).collect::<String>();
+        for (line, bold) in lines.iter().take(self.size.rows.saturating_sub(1)) {
+            if *bold {
+                screen.push_str(&format!("\x1b[1m{}\x1b[0m\n", clip(line)));
+            } else {
+                screen.push_str(&format!("{}\n", clip(line)));
+            }
+        }
+        screen.push_str(&format!("\x1b[{};1H{}", self.size.rows, clip(footer)));
+        screen
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let Some(config) = builtin::load(&args.protocol) else {
+        eprintln!("Unknown protocol: {}", args.protocol);
+        std::process::exit(1);
+    };
+
+    let events = Arc::new(BoundedQueue::new(MAX_QUEUED_EVENTS));
+    let device_events = events.clone();
+    let device = match Device::connect_path(
+        args.device.clone(),
+        Some(move |_: &DeviceId, notification: DeviceNotification| {
+            let policy = match notification {
+                DeviceNotification::Sample { .. } => OverflowPolicy::DropOldest,
+                _ => OverflowPolicy::Block,
+            };
+            device_events.push(Event::Device(notification), policy);
+        }),
+    ) {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("Unable to connect to {}: {e}", args.device);
+            std::process::exit(1);
+        }
+    };
+
+    let size = TerminalSize::query();
+    let mut console = Console::new(&args.device, &config, size);
+    let test_events = events.clone();
+    // If the device has already disconnected, ConnectionClosed is delivered
+    // (and displayed) instead.
+    let _ = device.start_test(
+        config,
+        TestOptions::default(),
+        Some(Box::new(move |notification: &TestNotification| {
+            let policy = match notification {
+                TestNotification::Sample(_)
+                | TestNotification::LiveFF { .. }
+                | TestNotification::TimeRemaining { .. } => OverflowPolicy::DropOldest,
+                _ => OverflowPolicy::Block,
+            };
+            test_events.push(Event::Test(notification.clone()), policy);
+        })),
+    );
+
+    let terminal = RawTerminal::enter();
+    let input_events = events.clone();
+    std::thread::spawn(move || run_input(input_events, size));
+
+    let mut stdout = std::io::stdout();
+    // The results stay on screen until the operator quits.
+    let mut done = false;
+    'events: while let Some(pending) = events.pop_all() {
+        for event in pending {
+            if let Event::Quit = event {
+                break 'events;
+            }
+            done |= console.update(event);
+        }
+        if done {
+            console.seconds_remaining = None;
+        }
+        let _ = stdout.write_all(console.render(done).as_bytes());
+        let _ = stdout.flush();
+    }
+    drop(terminal);
+    if !done {
+        let _ = device.cancel_test();
+    }
+    if events.dropped() > 0 {
+        eprintln!("{} sample(s) were not shown", events.dropped());
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The interval at which the 8020 is expected to deliver samples.
+pub const EXPECTED_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// The number of recent intervals used to detect drift. This needs to be long
+// enough to smooth over jitter introduced by the OS/serial adapter/etc. (which
+// is frequently 100ms or more for an individual sample), but short enough to
+// spot problems before a typical exercise has completed.
+const RECENT_WINDOW: usize = 20;
+
+// Relative deviation (from EXPECTED_SAMPLE_INTERVAL) of the recent mean
+// interval that triggers a warning.
+const DRIFT_TOLERANCE: f64 = 0.1;
+
+// Any individual interval exceeding this is assumed to indicate that at least
+// one reading was dropped.
+const GAP_THRESHOLD: Duration = Duration::from_millis(1800);
+
+/// CadenceStatistics summarises the intervals between samples received from
+/// the device. Intervals are None until at least two samples were received.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CadenceStatistics {
+    pub sample_count: usize,
+    pub mean_interval: Option<Duration>,
+    pub min_interval: Option<Duration>,
+    pub max_interval: Option<Duration>,
+    /// The mean interval over (up to) the last 20 samples.
+    pub recent_mean_interval: Option<Duration>,
+    /// The number of intervals that were long enough to indicate that the
+    /// device dropped (or we lost) at least one reading.
+    pub gap_count: usize,
+}
+
+/// CadenceWarning is produced whenever the recent sample cadence starts to
+/// deviate significantly from the expected 1Hz, and when it recovers.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CadenceWarning {
+    Drifting { recent_mean_interval: Duration },
+    Recovered,
+}
+
+pub(crate) struct CadenceTracker {
+    last_sample: Option<Instant>,
+    sample_count: usize,
+    total_interval: Duration,
+    min_interval: Option<Duration>,
+    max_interval: Option<Duration>,
+    recent_intervals: VecDeque<Duration>,
+    gap_count: usize,
+    drifting: bool,
+}
+
+impl CadenceTracker {
+    pub(crate) fn new() -> CadenceTracker {
+        CadenceTracker {
+            last_sample: None,
+            sample_count: 0,
+            total_interval: Duration::ZERO,
+            min_interval: None,
+            max_interval: None,
+            recent_intervals: VecDeque::with_capacity(RECENT_WINDOW),
+            gap_count: 0,
+            drifting: false,
+        }
+    }
+
+    /// Record a sample received at the specified time. Returns a warning if
+    /// the cadence has started (or stopped) deviating from the expected
+    /// cadence.
+    pub(crate) fn record(&mut self, now: Instant) -> Option<CadenceWarning> {
+        self.sample_count += 1;
+        let last_sample = self.last_sample.replace(now)?;
+        let interval = now.saturating_duration_since(last_sample);
+        self.total_interval += interval;
+        self.min_interval = Some(self.min_interval.map_or(interval, |min| min.min(interval)));
+        self.max_interval = Some(self.max_interval.map_or(interval, |max| max.max(interval)));
+        if interval > GAP_THRESHOLD {
+            self.gap_count += 1;
+        }
+        if self.recent_intervals.len() == RECENT_WINDOW {
+            self.recent_intervals.pop_front();
+        }
+        self.recent_intervals.push_back(interval);
+
+        // Don't warn until we have enough data to smooth over jitter.
+        if self.recent_intervals.len() < RECENT_WINDOW {
+            return None;
+        }
+        let recent_mean_interval = self.recent_mean_interval().unwrap();
+        let deviation =
+            (recent_mean_interval.as_secs_f64() - EXPECTED_SAMPLE_INTERVAL.as_secs_f64()).abs()
+                / EXPECTED_SAMPLE_INTERVAL.as_secs_f64();
+        match (self.drifting, deviation > DRIFT_TOLERANCE) {
+            (false, true) => {
+                self.drifting = true;
+                Some(CadenceWarning::Drifting {
+                    recent_mean_interval,
+                })
+            }
+            (true, false) => {
+                self.drifting = false;
+                Some(CadenceWarning::Recovered)
+            }
+            _ => None,
+        }
+    }
+
+    fn recent_mean_interval(&self) -> Option<Duration> {
+        if self.recent_intervals.is_empty() {
+            return None;
+        }
+        Some(self.recent_intervals.iter().sum::<Duration>() / self.recent_intervals.len() as u32)
+    }
+
+    pub(crate) fn statistics(&self) -> CadenceStatistics {
+        let interval_count = self.sample_count.saturating_sub(1);
+        CadenceStatistics {
+            sample_count: self.sample_count,
+            mean_interval: if interval_count > 0 {
+                Some(self.total_interval / interval_count as u32)
+            } else {
+                None
+            },
+            min_interval: self.min_interval,
+            max_interval: self.max_interval,
+            recent_mean_interval: self.recent_mean_interval(),
+            gap_count: self.gap_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cadence_tracker() {
+        let mut tracker = CadenceTracker::new();
+        let start = Instant::now();
+        let mut now = start;
+        assert_eq!(tracker.statistics().mean_interval, None);
+
+        for _ in 0..=RECENT_WINDOW {
+            assert_eq!(tracker.record(now), None);
+            now += Duration::from_millis(1000);
+        }
+        // A single dropped sample is not enough to trigger a warning...
+        now += Duration::from_millis(1000);
+        assert_eq!(tracker.record(now), None);
+        // ... but a sustained slowdown is.
+        let mut warnings = Vec::new();
+        for _ in 0..RECENT_WINDOW {
+            now += Duration::from_millis(1300);
+            if let Some(warning) = tracker.record(now) {
+                warnings.push(warning);
+            }
+        }
+        assert_eq!(warnings.len(), 1);
+        assert!(matches!(warnings[0], CadenceWarning::Drifting { .. }));
+
+        for _ in 0..RECENT_WINDOW {
+            now += Duration::from_millis(1000);
+            if let Some(warning) = tracker.record(now) {
+                warnings.push(warning);
+            }
+        }
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[1], CadenceWarning::Recovered);
+
+        let statistics = tracker.statistics();
+        assert_eq!(statistics.sample_count, 3 * RECENT_WINDOW + 2);
+        assert_eq!(statistics.min_interval, Some(Duration::from_millis(1000)));
+        assert_eq!(statistics.max_interval, Some(Duration::from_millis(2000)));
+        assert_eq!(
+            statistics.recent_mean_interval,
+            Some(Duration::from_millis(1000))
+        );
+        assert_eq!(statistics.gap_count, 1);
+    }
+}
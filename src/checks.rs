@@ -0,0 +1,239 @@
+//! Evaluation of the daily checks that should be performed before fit
+//! testing: a particle check (sufficient ambient particles), a zero check
+//! (no particles counted through a HEPA filter), a maximum FF check (the
+//! highest FF that the device can measure), and a valve check (the valve
+//! switches as requested).
+//!
+//! This module only evaluates measurements, collecting them is up to the
+//! caller (see bin/daily-check.rs).
+
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use crate::test_config::{DurationUnit, StageCounts, TestConfig, TestStage};
+
+/// CheckThresholds contains the pass criteria for each check. The defaults
+/// may be adjusted to match local procedures.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckThresholds {
+    /// Minimum mean ambient concentration for the particle check (default:
+    /// 70 particles/cm3).
+    pub min_particle_concentration: f64,
+    /// Maximum mean concentration through a HEPA filter for the zero check
+    /// (default: 1 particle/cm3).
+    pub max_zero_concentration: f64,
+    /// Minimum FF for the maximum FF check (default: 200).
+    pub min_max_fit_factor: f64,
+}
+
+impl Default for CheckThresholds {
+    fn default() -> Self {
+        CheckThresholds {
+            min_particle_concentration: 70.0,
+            max_zero_concentration: 1.0,
+            min_max_fit_factor: 200.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheckKind {
+    Particle,
+    Zero,
+    MaxFitFactor,
+    Valve,
+}
+
+impl CheckKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CheckKind::Particle => "Particle check",
+            CheckKind::Zero => "Zero check",
+            CheckKind::MaxFitFactor => "Max FF check",
+            CheckKind::Valve => "Valve check",
+        }
+    }
+}
+
+/// CheckResult is the outcome of a single check.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckResult {
+    pub kind: CheckKind,
+    /// The measured value (concentration or FF), None for the valve check and
+    /// for checks without any measurements.
+    pub measured: Option<f64>,
+    pub passed: bool,
+}
+
+fn mean(samples: &[f64]) -> Option<f64> {
+    (!samples.is_empty()).then(|| samples.iter().sum::<f64>() / samples.len() as f64)
+}
+
+/// Evaluates the particle check, using ambient samples.
+pub fn particle_check(samples: &[f64], thresholds: &CheckThresholds) -> CheckResult {
+    let measured = mean(samples);
+    CheckResult {
+        kind: CheckKind::Particle,
+        measured,
+        passed: measured.is_some_and(|mean| mean >= thresholds.min_particle_concentration),
+    }
+}
+
+/// Evaluates the zero check, using samples taken through a HEPA filter.
+pub fn zero_check(samples: &[f64], thresholds: &CheckThresholds) -> CheckResult {
+    let measured = mean(samples);
+    CheckResult {
+        kind: CheckKind::Zero,
+        measured,
+        passed: measured.is_some_and(|mean| mean <= thresholds.max_zero_concentration),
+    }
+}
+
+/// Evaluates the maximum FF check, using the FF measured by running
+/// max_fit_factor_config() with a HEPA filter on the sample tube.
+pub fn max_fit_factor_check(fit_factor: Option<f64>, thresholds: &CheckThresholds) -> CheckResult {
+    CheckResult {
+        kind: CheckKind::MaxFitFactor,
+        measured: fit_factor,
+        passed: fit_factor.is_some_and(|fit_factor| fit_factor >= thresholds.min_max_fit_factor),
+    }
+}
+
+/// Evaluates the valve check: the device must have confirmed switching to
+/// both positions (see TestNotification::ValveSwitchConfirmed).
+pub fn valve_check(ambient_confirmed: bool, specimen_confirmed: bool) -> CheckResult {
+    CheckResult {
+        kind: CheckKind::Valve,
+        measured: None,
+        passed: ambient_confirmed && specimen_confirmed,
+    }
+}
+
+/// Returns the (single exercise) config used for the maximum FF check.
+pub fn max_fit_factor_config() -> TestConfig {
+    let counts = |purge_count, sample_count| StageCounts {
+        purge_count,
+        sample_count,
+        unit: DurationUnit::Seconds,
+    };
+    TestConfig {
+        name: "Max FF check".to_string(),
+        short_name: "max_ff_check".to_string(),
+        stages: vec![
+            TestStage::AmbientSample {
+                counts: counts(4, 5),
+            },
+            TestStage::Exercise {
+                name: "HEPA filter".to_string(),
+                counts: counts(11, 30),
+                weight: 1.0,
+                exclude_from_overall: false,
+                pass_level: None,
+                localized_names: BTreeMap::new(),
+            },
+            TestStage::AmbientSample {
+                counts: counts(4, 5),
+            },
+        ],
+        pass_level: None,
+        version: 1,
+        localized_names: BTreeMap::new(),
+        max_fit_factor: None,
+        min_concentration: None,
+    }
+}
+
+/// DailyCheckReport contains the results of all checks performed on a
+/// device on a given day.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DailyCheckReport {
+    pub performed_at: SystemTime,
+    pub serial_number: Option<String>,
+    pub results: Vec<CheckResult>,
+}
+
+impl DailyCheckReport {
+    /// Returns whether all checks passed.
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.passed)
+    }
+
+    /// Formats the report as human readable text.
+    pub fn to_text(&self) -> String {
+        let format = time::macros::format_description!(
+            version = 2,
+            "[year]-[month]-[day] [hour]:[minute] UTC"
+        );
+        let mut text = format!(
+            "Daily checks, {} (device: {})\n",
+            time::OffsetDateTime::from(self.performed_at)
+                .format(&format)
+                .unwrap(),
+            self.serial_number.as_deref().unwrap_or("unknown")
+        );
+        for result in &self.results {
+            let measured = result
+                .measured
+                .map_or(String::new(), |measured| format!("{measured:.1}"));
+            text.push_str(&format!(
+                "  {:<14} {:>10}  {}\n",
+                result.kind.name(),
+                measured,
+                if result.passed { "PASS" } else { "FAIL" }
+            ));
+        }
+        text.push_str(&format!(
+            "Overall: {}\n",
+            if self.passed() { "PASS" } else { "FAIL" }
+        ));
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checks() {
+        let thresholds = CheckThresholds::default();
+        assert!(particle_check(&[1000.0, 2000.0], &thresholds).passed);
+        assert!(!particle_check(&[50.0, 60.0], &thresholds).passed);
+        assert!(!particle_check(&[], &thresholds).passed);
+
+        assert!(zero_check(&[0.0, 0.0, 1.0], &thresholds).passed);
+        assert!(!zero_check(&[5.0, 3.0], &thresholds).passed);
+
+        assert!(max_fit_factor_check(Some(5000.0), &thresholds).passed);
+        assert!(!max_fit_factor_check(Some(150.0), &thresholds).passed);
+        assert!(!max_fit_factor_check(None, &thresholds).passed);
+
+        assert!(valve_check(true, true).passed);
+        assert!(!valve_check(true, false).passed);
+    }
+
+    #[test]
+    fn test_max_fit_factor_config() {
+        let config = max_fit_factor_config();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.exercise_count(), 1);
+    }
+
+    #[test]
+    fn test_report() {
+        let thresholds = CheckThresholds::default();
+        let report = DailyCheckReport {
+            performed_at: SystemTime::UNIX_EPOCH,
+            serial_number: Some("12345".to_string()),
+            results: vec![
+                particle_check(&[1000.0], &thresholds),
+                zero_check(&[5.0], &thresholds),
+            ],
+        };
+        assert!(!report.passed());
+        let text = report.to_text();
+        assert!(text.starts_with("Daily checks, 1970-01-01 00:00 UTC (device: 12345)"));
+        assert!(text.contains("Zero check"));
+        assert!(text.ends_with("Overall: FAIL\n"));
+    }
+}
@@ -0,0 +1,228 @@
+//! Delivery of notifications to user callbacks on a dedicated thread, such
+//! that slow callbacks (e.g. writing to a database) don't delay the device
+//! loop, and therefore valve switching and command pacing.
+
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+
+use crate::queue::{BoundedQueue, OverflowPolicy};
+use crate::test::TestCallback;
+use crate::{DeviceId, DeviceNotification, TestNotification};
+
+pub(crate) type DeviceCallback = Box<dyn Fn(&DeviceId, DeviceNotification) + 'static + Send>;
+
+// The number of notifications that may be pending before the device loop
+// blocks (or, for samples, before samples are dropped). Samples arrive at
+// ~1Hz, i.e. callbacks only need to keep up on average.
+const CALLBACK_QUEUE_CAPACITY: usize = 256;
+
+thread_local! {
+    static IS_CALLBACK_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Returns true if called from a callback, i.e. from any device's executor
+/// thread.
+pub(crate) fn is_callback_thread() -> bool {
+    IS_CALLBACK_THREAD.with(Cell::get)
+}
+
+enum Job {
+    Device(DeviceNotification),
+    // Subsequent test notifications are delivered to this callback, i.e. a
+    // new test replaces the previous test's callback.
+    StartTest(TestCallback),
+    Test(TestNotification),
+}
+
+/// CallbackExecutor runs a device's callbacks on its own thread. Dropping it
+/// waits for all queued notifications to be delivered.
+pub(crate) struct CallbackExecutor {
+    queue: Arc<BoundedQueue<Job>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl CallbackExecutor {
+    pub(crate) fn start(id: DeviceId, device_callback: Option<DeviceCallback>) -> CallbackExecutor {
+        let queue = Arc::new(BoundedQueue::new(CALLBACK_QUEUE_CAPACITY));
+        let thread = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                IS_CALLBACK_THREAD.with(|is_callback_thread| is_callback_thread.set(true));
+                run(&queue, id, device_callback);
+            })
+        };
+        CallbackExecutor {
+            queue,
+            thread: Some(thread),
+        }
+    }
+
+    pub(crate) fn device_notification(&self, notification: DeviceNotification) {
+        // Samples are superseded by the next sample, whereas everything else
+        // (e.g. results) must be delivered.
+        let policy = match notification {
+            DeviceNotification::Sample { .. } => OverflowPolicy::DropOldest,
+            _ => OverflowPolicy::Block,
+        };
+        self.queue.push(Job::Device(notification), policy);
+    }
+
+    /// The number of notifications that were dropped because the callbacks
+    /// did not keep up.
+    pub(crate) fn dropped_notifications(&self) -> u64 {
+        self.queue.dropped()
+    }
+
+    /// Registers callback for a new test, and returns the callback that the
+    /// test should notify (which forwards to callback via the executor).
+    pub(crate) fn start_test(&self, callback: TestCallback) -> TestCallback {
+        callback.as_ref()?;
+        self.queue
+            .push(Job::StartTest(callback), OverflowPolicy::Block);
+        let queue = self.queue.clone();
+        Some(Box::new(move |notification: &TestNotification| {
+            // Test notifications are recorded (e.g. as exported samples),
+            // and are therefore never dropped.
+            queue.push(Job::Test(notification.clone()), OverflowPolicy::Block);
+        }))
+    }
+}
+
+impl Drop for CallbackExecutor {
+    fn drop(&mut self) {
+        self.queue.close();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(queue: &BoundedQueue<Job>, mut id: DeviceId, device_callback: Option<DeviceCallback>) {
+    let mut test_callback: TestCallback = None;
+    while let Some(jobs) = queue.pop_all() {
+        for job in jobs {
+            // A panicking callback must not prevent delivery of later
+            // notifications.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| match job {
+                Job::Device(notification) => {
+                    if let DeviceNotification::DeviceProperties(properties) = &notification {
+                        id.serial_number = Some(properties.serial_number.clone());
+                    }
+                    if let Some(callback) = &device_callback {
+                        callback(&id, notification);
+                    }
+                }
+                Job::StartTest(callback) => test_callback = callback,
+                Job::Test(notification) => {
+                    if let Some(callback) = &test_callback {
+                        callback(&notification);
+                    }
+                }
+            }));
+            if result.is_err() {
+                log::error!(device = id.index; "callback panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_executor() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let device_callback: DeviceCallback = {
+            let delivered = delivered.clone();
+            Box::new(move |id: &DeviceId, notification| {
+                assert!(is_callback_thread());
+                match notification {
+                    DeviceNotification::TestStarted => panic!("callbacks may panic"),
+                    DeviceNotification::ConnectionClosed => delivered
+                        .lock()
+                        .unwrap()
+                        .push(format!("closed {:?}", id.serial_number)),
+                    _ => (),
+                }
+            })
+        };
+        let executor = CallbackExecutor::start(
+            DeviceId {
+                index: 0,
+                serial_number: None,
+            },
+            Some(device_callback),
+        );
+        assert!(executor.start_test(None).is_none());
+        let test_callback = {
+            let delivered = delivered.clone();
+            executor
+                .start_test(Some(Box::new(move |notification: &TestNotification| {
+                    delivered.lock().unwrap().push(format!("{notification:?}"));
+                })))
+                .unwrap()
+        };
+        executor.device_notification(DeviceNotification::TestStarted);
+        test_callback(&TestNotification::InterimFF {
+            exercise: 0,
+            fit_factor: 100.0,
+        });
+        executor.device_notification(DeviceNotification::DeviceProperties(
+            crate::DeviceProperties {
+                model: crate::DeviceModel::Model8020,
+                serial_number: "1234".to_string(),
+                run_time_since_last_service_hours: 0.0,
+                last_service_month: 1,
+                last_service_year: 2024,
+                ambient_purge_seconds: None,
+                ambient_sample_seconds: None,
+                mask_purge_seconds: None,
+                mask_sample_seconds: Vec::new(),
+                fit_factor_pass_levels: Vec::new(),
+                n95_companion: false,
+            },
+        ));
+        executor.device_notification(DeviceNotification::ConnectionClosed);
+        drop(executor);
+
+        assert!(!is_callback_thread());
+        assert_eq!(
+            *delivered.lock().unwrap(),
+            [
+                "InterimFF { exercise: 0, fit_factor: 100.0 }",
+                "closed Some(\"1234\")"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dropped_notifications() {
+        // Blocks the callback thread until released, such that samples pile
+        // up in the queue.
+        let (tx_release, rx_release) = std::sync::mpsc::channel::<()>();
+        let rx_release = Mutex::new(rx_release);
+        let executor = CallbackExecutor::start(
+            DeviceId {
+                index: 0,
+                serial_number: None,
+            },
+            Some(Box::new(move |_: &DeviceId, _| {
+                let _ = rx_release.lock().unwrap().recv();
+            })),
+        );
+        // The first sample may already be in the callback when the rest are
+        // queued.
+        for _ in 0..CALLBACK_QUEUE_CAPACITY + 10 {
+            executor.device_notification(DeviceNotification::Sample {
+                particle_conc: 1000.0,
+            });
+        }
+        let dropped = executor.dropped_notifications();
+        assert!((9..=10).contains(&dropped), "dropped {dropped}");
+        drop(tx_release);
+    }
+}
@@ -1,17 +1,115 @@
 extern crate libc;
 
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::ffi::CString;
 use std::os::raw::c_char;
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use serialport::{SerialPortInfo, SerialPortType};
 
-use crate::test::TestNotification;
-use crate::test_config::builtin::BUILTIN_CONFIGS;
-use crate::test_config::TestConfig;
-use crate::{Action, Device, DeviceNotification, DeviceProperties};
+use crate::group::{
+    DeviceOutcome, DoneReceiver, DoneSender, GroupOptions, GroupRun, GroupTestCallback,
+    GroupTestResult,
+};
+use crate::test::{
+    DiscardReason, SampleData, SampleType, TestNotification, TestOptions, TestResult, TestState,
+    ValvePosition,
+};
+use crate::test_config::builtin::{self, BUILTIN_CONFIGS};
+use crate::test_config::{self, DurationUnit, ParseError, TestConfig, TestStage};
+use crate::{
+    BarrierMode, ConnectOptions, ConnectionStats, Device, DeviceId, DeviceModel,
+    DeviceNotification, DeviceProperties,
+};
+
+/// The version of the FFI (ABI) exposed by this library. This is bumped
+/// whenever a change is made that breaks existing callers, e.g. changing the
+/// layout of a repr(C) type or the signature of a function. Callers should
+/// verify that p8020_abi_version() matches the value in the header that they
+/// compiled against.
+pub const P8020_ABI_VERSION: u32 = 2;
+
+/// Returns the ABI version of the loaded library, see P8020_ABI_VERSION.
+#[export_name = "p8020_abi_version"]
+pub extern "C" fn abi_version() -> u32 {
+    P8020_ABI_VERSION
+}
+
+/// Returns the version of the loaded library (e.g. "0.1.0"). The returned
+/// string is static, and must not be freed.
+#[export_name = "p8020_version_string"]
+pub extern "C" fn version_string() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// Error codes describing why an FFI call failed. See p8020_last_error_code()
+/// and p8020_last_error_message().
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum P8020ErrorCode {
+    /// No error has occurred (on this thread).
+    None,
+    /// The device could not be connected, e.g. because the port does not
+    /// exist or is in use.
+    ConnectionFailed,
+    /// The requested item (e.g. a builtin config) does not exist.
+    NotFound,
+    /// A config could not be parsed, or is invalid.
+    ParseFailed,
+    /// A file could not be read.
+    IoFailed,
+    /// The test was cancelled or aborted.
+    TestCancelled,
+    /// The available ports could not be listed.
+    PortEnumerationFailed,
+    /// An item with the same identifier (e.g. a config's short name) already
+    /// exists.
+    AlreadyExists,
+    /// The device's connection has been closed (or was lost, and could not
+    /// be reestablished).
+    Disconnected,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<(P8020ErrorCode, String)> =
+        const { RefCell::new((P8020ErrorCode::None, String::new())) };
+}
+
+// Records the reason for a failed FFI call, for retrieval via
+// p8020_last_error_code/message. As with errno, the last error is only
+// updated on failure.
+fn set_last_error(code: P8020ErrorCode, message: impl Into<String>) {
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = (code, message.into()));
+}
+
+/// Returns the code of the most recent error on the calling thread, or None
+/// if no FFI call has failed on this thread yet.
+#[export_name = "p8020_last_error_code"]
+pub extern "C" fn last_error_code() -> P8020ErrorCode {
+    LAST_ERROR.with(|last_error| last_error.borrow().0)
+}
+
+/// Returns a human-readable description of the most recent error on the
+/// calling thread, or NULL if no FFI call has failed on this thread yet. The
+/// returned string must be freed using p8020_string_free().
+#[export_name = "p8020_last_error_message"]
+pub extern "C" fn last_error_message() -> *mut c_char {
+    LAST_ERROR.with(|last_error| {
+        let (code, message) = &*last_error.borrow();
+        if *code == P8020ErrorCode::None {
+            return std::ptr::null_mut();
+        }
+        // Messages are (mostly) derived from user input, which could
+        // conceivably contain NULLs.
+        CString::new(message.replace('\0', ""))
+            .expect("NULLs were removed")
+            .into_raw()
+    })
+}
 
 #[repr(C)]
 pub enum P8020DeviceNotification {
@@ -20,18 +118,61 @@ pub enum P8020DeviceNotification {
         particle_conc: f64,
     },
     ConnectionClosed,
+    /// See DeviceNotification::ConnectionLost.
+    ConnectionLost,
+    /// See DeviceNotification::Reconnected.
+    Reconnected,
     // Indicates that device properties can now be retrieved via
     // p8020_device_get_properties.
     DevicePropertiesAvailable,
+    /// See DeviceNotification::DeviceUnresponsive.
+    DeviceUnresponsive,
 }
 
 /// FFI wrapper for Device.
 pub struct P8020Device {
     device: Device,
-    // Receiver for test completion signal. OK(fit_factors) on successful
+    // Receiver for test completion signal. OK(result) on successful
     // completion, Err(()) on cancellation.
-    rx_done: Receiver<Result<Vec<f64>, ()>>,
+    rx_done: Receiver<Result<TestResult, ()>>,
     device_properties: Arc<Mutex<Option<DeviceProperties>>>,
+    // Set by start_test if the caller wants to be notified of completion, in
+    // which case results are delivered via the callback instead of rx_done.
+    completion_callback: Arc<Mutex<Option<CompletionCallback>>>,
+    // Whether a test was started via start_test without a completion
+    // callback, and its outcome hasn't been retrieved via try_get_result.
+    test_pending: bool,
+    sample_subscription: Arc<Mutex<SampleSubscription>>,
+    // Set while the device belongs to a P8020Group, in which case test
+    // outcomes are delivered to the group instead.
+    group_membership: Arc<Mutex<Option<GroupMembership>>>,
+}
+
+struct GroupMembership {
+    index: usize,
+    tx_done: DoneSender,
+    last_samples: Arc<Mutex<Vec<Instant>>>,
+}
+
+type P8020CompletionCallback = extern "C" fn(*mut P8020TestResult, *mut std::ffi::c_void) -> ();
+
+struct CompletionCallback {
+    callback: P8020CompletionCallback,
+    callback_data: FFICallbackDataHandle,
+}
+
+/// The state of a test started via p8020_device_start_test().
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum P8020TestStatus {
+    InProgress,
+    Completed,
+    /// The test was cancelled or aborted (or the device was disconnected).
+    Cancelled,
+    /// No test is pending, i.e. no test was started via
+    /// p8020_device_start_test (without a completion callback), or its
+    /// outcome was already retrieved.
+    NotStarted,
 }
 
 #[allow(dead_code)] // All fields read via FFI
@@ -41,11 +182,61 @@ pub struct P8020DeviceProperties {
     pub run_time_since_last_service_hours: f64,
     pub last_service_month: u8,
     pub last_service_year: u16,
+    pub model: DeviceModel,
+    /// Device test settings, in seconds. 0 if not reported by the device.
+    pub ambient_purge_seconds: usize,
+    pub ambient_sample_seconds: usize,
+    pub mask_purge_seconds: usize,
+    // Use the p8020_device_properties_mask_sample_* and *_pass_level*
+    // accessors instead.
+    mask_sample_seconds: *mut usize,
+    mask_sample_seconds_length: usize,
+    fit_factor_pass_levels: *mut usize,
+    fit_factor_pass_levels_length: usize,
+    /// See DeviceProperties::n95_companion.
+    pub n95_companion: bool,
 }
 
 impl P8020DeviceProperties {
+    /// Returns the number of exercises for which a mask sample time was
+    /// reported.
+    #[export_name = "p8020_device_properties_mask_sample_time_count"]
+    pub extern "C" fn mask_sample_time_count(&self) -> usize {
+        self.mask_sample_seconds_length
+    }
+
+    /// Returns the mask sample time (in seconds) for the specified exercise
+    /// (0-based).
+    #[export_name = "p8020_device_properties_mask_sample_time"]
+    pub extern "C" fn mask_sample_time(&self, index: usize) -> usize {
+        assert!(index < self.mask_sample_seconds_length);
+        unsafe { *self.mask_sample_seconds.add(index) }
+    }
+
+    /// Returns the number of exercises for which a pass level was reported.
+    #[export_name = "p8020_device_properties_pass_level_count"]
+    pub extern "C" fn pass_level_count(&self) -> usize {
+        self.fit_factor_pass_levels_length
+    }
+
+    /// Returns the FF pass level for the specified exercise (0-based).
+    #[export_name = "p8020_device_properties_pass_level"]
+    pub extern "C" fn pass_level(&self, index: usize) -> usize {
+        assert!(index < self.fit_factor_pass_levels_length);
+        unsafe { *self.fit_factor_pass_levels.add(index) }
+    }
+
     #[export_name = "p8020_device_properties_free"]
     pub unsafe extern "C" fn free(&mut self) {
+        drop(CString::from_raw(self.serial_number as *mut c_char));
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            self.mask_sample_seconds,
+            self.mask_sample_seconds_length,
+        )));
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            self.fit_factor_pass_levels,
+            self.fit_factor_pass_levels_length,
+        )));
         drop(Box::from_raw(self));
     }
 }
@@ -61,12 +252,193 @@ impl FFICallbackDataHandle {
     }
 }
 
+/// FFI equivalent of ConnectOptions, with durations in milliseconds.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct P8020ConnectOptions {
+    pub baud_rate: u32,
+    pub read_timeout_ms: u32,
+    pub command_interval_ms: u32,
+    pub auto_reconnect: bool,
+    pub reconnect_interval_ms: u32,
+    /// See ConnectOptions::n95_companion.
+    pub n95_companion: bool,
+    /// See ConnectOptions::unresponsive_timeout. 0 disables the check.
+    pub unresponsive_timeout_ms: u32,
+}
+
+impl Default for P8020ConnectOptions {
+    fn default() -> Self {
+        let defaults = ConnectOptions::default();
+        P8020ConnectOptions {
+            baud_rate: defaults.baud_rate,
+            read_timeout_ms: defaults.read_timeout.as_millis() as u32,
+            command_interval_ms: defaults.command_interval.as_millis() as u32,
+            auto_reconnect: defaults.auto_reconnect,
+            reconnect_interval_ms: defaults.reconnect_interval.as_millis() as u32,
+            n95_companion: defaults.n95_companion,
+            unresponsive_timeout_ms: defaults
+                .unresponsive_timeout
+                .map_or(0, |timeout| timeout.as_millis() as u32),
+        }
+    }
+}
+
+/// FFI equivalent of ConnectionStats, see p8020_device_get_stats.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct P8020ConnectionStats {
+    pub samples_received: u64,
+    pub commands_sent: u64,
+    pub parse_errors: u64,
+    pub discarded_samples: u64,
+    pub reconnects: u64,
+    pub dropped_notifications: u64,
+    /// Whether last_message_at_ms is set, i.e. any message has been received.
+    pub has_last_message: bool,
+    /// When the last message was received, in milliseconds since the Unix
+    /// epoch.
+    pub last_message_at_ms: i64,
+}
+
+impl From<ConnectionStats> for P8020ConnectionStats {
+    fn from(stats: ConnectionStats) -> Self {
+        P8020ConnectionStats {
+            samples_received: stats.samples_received,
+            commands_sent: stats.commands_sent,
+            parse_errors: stats.parse_errors,
+            discarded_samples: stats.discarded_samples,
+            reconnects: stats.reconnects,
+            dropped_notifications: stats.dropped_notifications,
+            has_last_message: stats.last_message_at.is_some(),
+            last_message_at_ms: stats.last_message_at.map_or(0, unix_millis),
+        }
+    }
+}
+
+/// Options controlling the delivery of P8020DeviceNotification::Sample, see
+/// p8020_device_subscribe_samples.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct P8020SampleOptions {
+    /// The number of samples to average over (simple moving average). 0 or 1
+    /// delivers raw samples.
+    pub smoothing_window: usize,
+    /// Only deliver every nth sample. 0 or 1 delivers every sample.
+    pub every_nth: usize,
+}
+
+impl Default for P8020SampleOptions {
+    fn default() -> Self {
+        P8020SampleOptions {
+            smoothing_window: 1,
+            every_nth: 1,
+        }
+    }
+}
+
+/// Returns the default sample options (raw samples, delivered every second).
+#[export_name = "p8020_sample_options_default"]
+pub extern "C" fn sample_options_default() -> P8020SampleOptions {
+    P8020SampleOptions::default()
+}
+
+// Tracks whether, and how, samples are forwarded to the device callback.
+// Devices are subscribed (with default options) after connecting.
+struct SampleSubscription {
+    // None if unsubscribed.
+    options: Option<P8020SampleOptions>,
+    recent_samples: VecDeque<f64>,
+    samples_since_delivery: usize,
+}
+
+impl SampleSubscription {
+    fn new() -> SampleSubscription {
+        SampleSubscription {
+            options: Some(P8020SampleOptions::default()),
+            recent_samples: VecDeque::new(),
+            samples_since_delivery: 0,
+        }
+    }
+
+    fn subscribe(&mut self, options: P8020SampleOptions) {
+        *self = SampleSubscription {
+            options: Some(options),
+            ..SampleSubscription::new()
+        };
+    }
+
+    fn unsubscribe(&mut self) {
+        self.options = None;
+    }
+
+    // Returns the value to deliver for the latest sample, if any.
+    fn process(&mut self, particle_conc: f64) -> Option<f64> {
+        let options = self.options?;
+        self.recent_samples.push_back(particle_conc);
+        while self.recent_samples.len() > options.smoothing_window.max(1) {
+            self.recent_samples.pop_front();
+        }
+        self.samples_since_delivery += 1;
+        if self.samples_since_delivery < options.every_nth.max(1) {
+            return None;
+        }
+        self.samples_since_delivery = 0;
+        Some(self.recent_samples.iter().sum::<f64>() / self.recent_samples.len() as f64)
+    }
+}
+
+impl From<&P8020ConnectOptions> for ConnectOptions {
+    fn from(options: &P8020ConnectOptions) -> Self {
+        ConnectOptions {
+            baud_rate: options.baud_rate,
+            read_timeout: Duration::from_millis(options.read_timeout_ms.into()),
+            command_interval: Duration::from_millis(options.command_interval_ms.into()),
+            auto_reconnect: options.auto_reconnect,
+            reconnect_interval: Duration::from_millis(options.reconnect_interval_ms.into()),
+            n95_companion: options.n95_companion,
+            unresponsive_timeout: (options.unresponsive_timeout_ms > 0)
+                .then(|| Duration::from_millis(options.unresponsive_timeout_ms.into())),
+            ..ConnectOptions::default()
+        }
+    }
+}
+
+/// Returns the default connection options, which callers can then modify as
+/// needed before calling p8020_device_connect_opts.
+#[export_name = "p8020_connect_options_default"]
+pub extern "C" fn connect_options_default() -> P8020ConnectOptions {
+    P8020ConnectOptions::default()
+}
+
 #[repr(C)]
 pub struct P8020TestResult {
     exercise_count: usize,
     fit_factors: *mut f64,
     fit_factors_length: usize,
     fit_factors_capacity: usize,
+    // Only accessible via the p8020_test_result_* accessors.
+    result: *mut TestResult,
+}
+
+/// Whether a test or exercise passed, see p8020_test_result_passed().
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum P8020PassState {
+    /// No pass level was specified.
+    NotApplicable,
+    Passed,
+    Failed,
+}
+
+impl From<Option<bool>> for P8020PassState {
+    fn from(passed: Option<bool>) -> Self {
+        match passed {
+            None => P8020PassState::NotApplicable,
+            Some(true) => P8020PassState::Passed,
+            Some(false) => P8020PassState::Failed,
+        }
+    }
 }
 
 impl P8020Device {
@@ -78,9 +450,27 @@ impl P8020Device {
         path_raw: *const libc::c_char,
         callback: extern "C" fn(&P8020DeviceNotification, *mut std::ffi::c_void) -> (),
         callback_data: *mut std::ffi::c_void,
+    ) -> *mut P8020Device {
+        Self::connect_opts(
+            path_raw,
+            &P8020ConnectOptions::default(),
+            callback,
+            callback_data,
+        )
+    }
+
+    /// As p8020_device_connect, using the specified options (see
+    /// p8020_connect_options_default).
+    #[export_name = "p8020_device_connect_opts"]
+    pub extern "C" fn connect_opts(
+        path_raw: *const libc::c_char,
+        options: &P8020ConnectOptions,
+        callback: extern "C" fn(&P8020DeviceNotification, *mut std::ffi::c_void) -> (),
+        callback_data: *mut std::ffi::c_void,
     ) -> *mut P8020Device {
         let path_cstr = unsafe { std::ffi::CStr::from_ptr(path_raw) };
         let path = String::from_utf8_lossy(path_cstr.to_bytes()).to_string();
+        let path_for_error = path.clone();
 
         let callback_data = FFICallbackDataHandle(callback_data);
         let (tx_done, rx_done) = mpsc::channel();
@@ -89,15 +479,39 @@ impl P8020Device {
         // rustier way to do this.
         let device_properties = Arc::new(Mutex::new(None));
         let device_properties_write = device_properties.clone();
-        let device_callback = move |notification: DeviceNotification| {
+        let completion_callback = Arc::new(Mutex::new(None::<CompletionCallback>));
+        let completion_callback_read = completion_callback.clone();
+        let sample_subscription = Arc::new(Mutex::new(SampleSubscription::new()));
+        let sample_subscription_read = sample_subscription.clone();
+        let group_membership = Arc::new(Mutex::new(None::<GroupMembership>));
+        let group_membership_read = group_membership.clone();
+        let device_callback = move |_: &DeviceId, notification: DeviceNotification| {
+            if let DeviceNotification::Sample { .. } = notification {
+                if let Some(membership) = group_membership_read.lock().unwrap().as_ref() {
+                    membership.last_samples.lock().unwrap()[membership.index] = Instant::now();
+                }
+            }
             let (notification, test_result) = match notification {
                 DeviceNotification::Sample { particle_conc } => (
-                    Some(P8020DeviceNotification::Sample { particle_conc }),
+                    sample_subscription_read
+                        .lock()
+                        .unwrap()
+                        .process(particle_conc)
+                        .map(|particle_conc| P8020DeviceNotification::Sample { particle_conc }),
                     None,
                 ),
                 DeviceNotification::ConnectionClosed => {
                     (Some(P8020DeviceNotification::ConnectionClosed), None)
                 }
+                DeviceNotification::ConnectionLost => {
+                    (Some(P8020DeviceNotification::ConnectionLost), None)
+                }
+                DeviceNotification::Reconnected => {
+                    (Some(P8020DeviceNotification::Reconnected), None)
+                }
+                DeviceNotification::DeviceUnresponsive => {
+                    (Some(P8020DeviceNotification::DeviceUnresponsive), None)
+                }
                 DeviceNotification::DeviceProperties(updated_properties) => {
                     *device_properties_write.lock().unwrap() = Some(updated_properties);
                     (
@@ -106,27 +520,60 @@ impl P8020Device {
                     )
                 }
                 DeviceNotification::TestStarted => (None, None),
-                DeviceNotification::TestCompleted { fit_factors } => (None, Some(Ok(fit_factors))),
-                DeviceNotification::TestCancelled => (None, Some(Err(()))),
+                // Faults are followed by TestAborted (if a test was running)
+                // and ConnectionClosed (unless the loop was restarted).
+                DeviceNotification::SampleCadence(_) | DeviceNotification::Fault { .. } => {
+                    (None, None)
+                }
+                DeviceNotification::TestCompleted { result } => (None, Some(Ok(result))),
+                DeviceNotification::TestCancelled | DeviceNotification::TestAborted => {
+                    (None, Some(Err(())))
+                }
             };
             if let Some(notification) = notification {
                 callback(&notification, callback_data.get());
             }
             if let Some(test_result) = test_result {
-                tx_done.send(test_result).unwrap();
+                if let Some(membership) = group_membership_read.lock().unwrap().as_ref() {
+                    // The group may already have been freed.
+                    let _ = membership
+                        .tx_done
+                        .send((membership.index, test_result.ok()));
+                } else if let Some(completion) = completion_callback_read.lock().unwrap().take() {
+                    let result = match test_result {
+                        Ok(result) => test_result_into_raw(result),
+                        Err(()) => std::ptr::null_mut(),
+                    };
+                    (completion.callback)(result, completion.callback_data.get());
+                } else {
+                    tx_done.send(test_result).unwrap();
+                }
             }
         };
-        match Device::connect_path(path, Some(device_callback)) {
+        match Device::connect_path_with_options(path, options.into(), Some(device_callback)) {
             Ok(device) => Box::into_raw(Box::new(P8020Device {
                 device,
                 rx_done,
                 device_properties,
+                completion_callback,
+                test_pending: false,
+                sample_subscription,
+                group_membership,
             })),
-            Err(_) => std::ptr::null_mut(),
+            Err(e) => {
+                set_last_error(
+                    P8020ErrorCode::ConnectionFailed,
+                    format!("unable to connect to {path_for_error}: {e}"),
+                );
+                std::ptr::null_mut()
+            }
         }
     }
 
-    /// Run a fit test (this API will change a lot soon).
+    /// Run a fit test (this API will change a lot soon). Blocks until the
+    /// test has completed, see p8020_device_start_test for a non-blocking
+    /// alternative. Returns NULL if the test was cancelled or aborted, or if
+    /// the device is disconnected (see p8020_last_error_code).
     #[export_name = "p8020_device_run_test"]
     pub extern "C" fn run_test(
         &mut self,
@@ -134,36 +581,135 @@ impl P8020Device {
         callback: extern "C" fn(&TestNotification, *mut std::ffi::c_void) -> (),
         callback_data: *mut std::ffi::c_void,
     ) -> *mut P8020TestResult {
-        let callback_data = FFICallbackDataHandle(callback_data);
-        let test_callback = move |notification: &TestNotification| {
-            callback(notification, callback_data.get());
-        };
-        self.device
-            .tx_action
-            .send(Action::StartTest {
-                config: test_config.clone(),
-                test_callback: Some(Box::new(test_callback)),
-            })
-            .expect("device connection is (probably) gone");
-
-        let Ok(mut fit_factors) = self.rx_done.recv().expect("rx_done failed") else {
+        *self.completion_callback.lock().unwrap() = None;
+        self.test_pending = false;
+        if !self.send_start_test(test_config, callback, callback_data) {
+            return std::ptr::null_mut();
+        }
+        // The channel is closed if the device thread exits during the test.
+        let Ok(Ok(result)) = self.rx_done.recv() else {
+            set_last_error(
+                P8020ErrorCode::TestCancelled,
+                "the test was cancelled or aborted",
+            );
             return std::ptr::null_mut();
         };
+        test_result_into_raw(result)
+    }
+
+    /// Starts a fit test, and returns immediately. If completion_callback is
+    /// supplied, it will be called (from a background thread) with the
+    /// results once the test is complete - or with NULL if the test was
+    /// cancelled - and the results must be freed using
+    /// p8020_test_result_free. Otherwise, results can be polled for using
+    /// p8020_device_try_get_result. If the device is disconnected, the test
+    /// is reported as cancelled (and the last error is set to
+    /// P8020ErrorCode::Disconnected).
+    #[export_name = "p8020_device_start_test"]
+    pub extern "C" fn start_test(
+        &mut self,
+        test_config: &TestConfig,
+        callback: extern "C" fn(&TestNotification, *mut std::ffi::c_void) -> (),
+        callback_data: *mut std::ffi::c_void,
+        completion_callback: Option<P8020CompletionCallback>,
+        completion_callback_data: *mut std::ffi::c_void,
+    ) {
+        *self.completion_callback.lock().unwrap() =
+            completion_callback.map(|callback| CompletionCallback {
+                callback,
+                callback_data: FFICallbackDataHandle(completion_callback_data),
+            });
+        self.test_pending = completion_callback.is_none();
+        if self.send_start_test(test_config, callback, callback_data) {
+            return;
+        }
+        if let Some(completion) = self.completion_callback.lock().unwrap().take() {
+            (completion.callback)(std::ptr::null_mut(), completion.callback_data.get());
+        }
+    }
 
-        // Could be switched to Vec.into_raw_parts() once it become stable:
-        // https://github.com/rust-lang/rust/issues/65816
-        let (data, length, capacity) = (
-            fit_factors.as_mut_ptr(),
-            fit_factors.len(),
-            fit_factors.capacity(),
+    // Returns false (and sets the last error) if the device is disconnected.
+    fn send_start_test(
+        &self,
+        test_config: &TestConfig,
+        callback: extern "C" fn(&TestNotification, *mut std::ffi::c_void) -> (),
+        callback_data: *mut std::ffi::c_void,
+    ) -> bool {
+        // Discard outcomes of previous tests whose results were never
+        // retrieved, which would otherwise be mistaken for this test's.
+        while self.rx_done.try_recv().is_ok() {}
+        let callback_data = FFICallbackDataHandle(callback_data);
+        let started = self.device.start_test(
+            test_config.clone(),
+            TestOptions::default(),
+            Some(Box::new(move |notification: &TestNotification| {
+                callback(notification, callback_data.get());
+            })),
         );
-        std::mem::forget(fit_factors);
-        Box::into_raw(Box::new(P8020TestResult {
-            exercise_count: 1,
-            fit_factors: data,
-            fit_factors_length: length,
-            fit_factors_capacity: capacity,
-        }))
+        if started.is_err() {
+            set_last_error(
+                P8020ErrorCode::Disconnected,
+                "the device's connection has been closed",
+            );
+        }
+        started.is_ok()
+    }
+
+    /// Checks whether a test started via p8020_device_start_test (without a
+    /// completion callback) has finished. If the test has completed, result
+    /// is set to the test's results, which must be freed using
+    /// p8020_test_result_free. result is not modified otherwise. Returns
+    /// P8020TestStatus::NotStarted (and sets the last error) if there is no
+    /// pending test.
+    #[export_name = "p8020_device_try_get_result"]
+    pub extern "C" fn try_get_result(
+        &mut self,
+        result: &mut *mut P8020TestResult,
+    ) -> P8020TestStatus {
+        if !self.test_pending {
+            set_last_error(P8020ErrorCode::NotFound, "no test is pending");
+            return P8020TestStatus::NotStarted;
+        }
+        match self.rx_done.try_recv() {
+            Ok(Ok(test_result)) => {
+                self.test_pending = false;
+                *result = test_result_into_raw(test_result);
+                P8020TestStatus::Completed
+            }
+            Ok(Err(())) | Err(mpsc::TryRecvError::Disconnected) => {
+                self.test_pending = false;
+                set_last_error(
+                    P8020ErrorCode::TestCancelled,
+                    "the test was cancelled or aborted",
+                );
+                P8020TestStatus::Cancelled
+            }
+            Err(mpsc::TryRecvError::Empty) => P8020TestStatus::InProgress,
+        }
+    }
+
+    /// Starts (or restarts) delivery of P8020DeviceNotification::Sample using
+    /// the specified options (see p8020_sample_options_default). Devices are
+    /// subscribed to raw samples by default. Samples are delivered
+    /// independently of whether a test is running.
+    #[export_name = "p8020_device_subscribe_samples"]
+    pub extern "C" fn subscribe_samples(&mut self, options: &P8020SampleOptions) {
+        self.sample_subscription.lock().unwrap().subscribe(*options);
+    }
+
+    /// Stops delivery of P8020DeviceNotification::Sample, until
+    /// p8020_device_subscribe_samples is called again. Tests are not
+    /// affected.
+    #[export_name = "p8020_device_unsubscribe_samples"]
+    pub extern "C" fn unsubscribe_samples(&mut self) {
+        self.sample_subscription.lock().unwrap().unsubscribe();
+    }
+
+    /// Returns counters describing the connection since connecting (see
+    /// ConnectionStats).
+    #[export_name = "p8020_device_get_stats"]
+    pub extern "C" fn get_stats(&self) -> P8020ConnectionStats {
+        self.device.stats().into()
     }
 
     /// Returns cached deviced properties, or NULL if not available yet. No data
@@ -177,11 +723,32 @@ impl P8020Device {
         let serial_number = CString::new(device_properties.serial_number.clone())
             .expect("serial number should never contain NULLs")
             .into_raw();
+        let mask_sample_seconds_length = device_properties.mask_sample_seconds.len();
+        let fit_factor_pass_levels_length = device_properties.fit_factor_pass_levels.len();
         Box::into_raw(Box::new(P8020DeviceProperties {
             serial_number,
             run_time_since_last_service_hours: device_properties.run_time_since_last_service_hours,
             last_service_month: device_properties.last_service_month,
             last_service_year: device_properties.last_service_year,
+            model: device_properties.model,
+            ambient_purge_seconds: device_properties.ambient_purge_seconds.unwrap_or(0),
+            ambient_sample_seconds: device_properties.ambient_sample_seconds.unwrap_or(0),
+            mask_purge_seconds: device_properties.mask_purge_seconds.unwrap_or(0),
+            n95_companion: device_properties.n95_companion,
+            mask_sample_seconds: Box::into_raw(
+                device_properties
+                    .mask_sample_seconds
+                    .clone()
+                    .into_boxed_slice(),
+            ) as *mut usize,
+            mask_sample_seconds_length,
+            fit_factor_pass_levels: Box::into_raw(
+                device_properties
+                    .fit_factor_pass_levels
+                    .clone()
+                    .into_boxed_slice(),
+            ) as *mut usize,
+            fit_factor_pass_levels_length,
         }))
     }
 
@@ -191,7 +758,98 @@ impl P8020Device {
     }
 }
 
+fn test_result_into_raw(result: TestResult) -> *mut P8020TestResult {
+    let mut fit_factors = result.fit_factors.clone();
+    // Could be switched to Vec.into_raw_parts() once it become stable:
+    // https://github.com/rust-lang/rust/issues/65816
+    let (data, length, capacity) = (
+        fit_factors.as_mut_ptr(),
+        fit_factors.len(),
+        fit_factors.capacity(),
+    );
+    std::mem::forget(fit_factors);
+    Box::into_raw(Box::new(P8020TestResult {
+        exercise_count: length,
+        fit_factors: data,
+        fit_factors_length: length,
+        fit_factors_capacity: capacity,
+        result: Box::into_raw(Box::new(result)),
+    }))
+}
+
+// Converts a timestamp into milliseconds since the Unix epoch.
+fn unix_millis(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
 impl P8020TestResult {
+    fn result(&self) -> &TestResult {
+        unsafe { &*self.result }
+    }
+
+    /// Returns the overall FF, after rounding.
+    #[export_name = "p8020_test_result_overall_fit_factor"]
+    pub extern "C" fn overall_fit_factor(&self) -> f64 {
+        self.result().overall_fit_factor
+    }
+
+    /// Returns the overall FF prior to rounding.
+    #[export_name = "p8020_test_result_raw_overall_fit_factor"]
+    pub extern "C" fn raw_overall_fit_factor(&self) -> f64 {
+        self.result().raw_overall_fit_factor
+    }
+
+    /// Returns the FF for the specified exercise prior to rounding.
+    #[export_name = "p8020_test_result_raw_fit_factor"]
+    pub extern "C" fn raw_fit_factor(&self, index: usize) -> f64 {
+        self.result().raw_fit_factors[index]
+    }
+
+    /// Returns the (absolute, unrounded) uncertainty of the specified
+    /// exercise's FF.
+    #[export_name = "p8020_test_result_error"]
+    pub extern "C" fn error(&self, index: usize) -> f64 {
+        self.result().errors[index]
+    }
+
+    /// Returns the name of the specified exercise. Returned pointers must be
+    /// freed using p8020_string_free().
+    #[export_name = "p8020_test_result_exercise_name"]
+    pub extern "C" fn exercise_name(&self, index: usize) -> *mut c_char {
+        CString::new(self.result().exercise_names[index].clone())
+            .expect("test config names should not contain NULLs")
+            .into_raw()
+    }
+
+    /// Returns the overall verdict, see TestResult::passed.
+    #[export_name = "p8020_test_result_passed"]
+    pub extern "C" fn passed(&self) -> P8020PassState {
+        self.result().passed.into()
+    }
+
+    /// Returns whether the specified exercise passed.
+    #[export_name = "p8020_test_result_exercise_passed"]
+    pub extern "C" fn exercise_passed(&self, index: usize) -> P8020PassState {
+        self.result().exercise_passed[index].into()
+    }
+
+    /// Returns the time at which the test started, in milliseconds since the
+    /// Unix epoch.
+    #[export_name = "p8020_test_result_started_at"]
+    pub extern "C" fn started_at(&self) -> i64 {
+        unix_millis(self.result().started_at)
+    }
+
+    /// Returns the time at which the test completed, in milliseconds since
+    /// the Unix epoch.
+    #[export_name = "p8020_test_result_completed_at"]
+    pub extern "C" fn completed_at(&self) -> i64 {
+        unix_millis(self.result().completed_at)
+    }
+
     #[export_name = "p8020_test_result_free"]
     pub unsafe extern "C" fn test_result_free(&mut self) {
         let _ = Vec::from_raw_parts(
@@ -199,30 +857,678 @@ impl P8020TestResult {
             self.fit_factors_length,
             self.fit_factors_capacity,
         );
+        drop(Box::from_raw(self.result));
         drop(Box::from_raw(self));
     }
 }
 
+/// The kind of a TestNotification, see p8020_test_notification_kind(). Each
+/// kind corresponds to the TestNotification variant of the same name.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum P8020TestNotificationKind {
+    StateChange,
+    ExerciseResult,
+    Sample,
+    LiveFF,
+    InterimFF,
+    StageComplete,
+    AmbientResult,
+    ValveSwitchRequested,
+    ValveSwitchConfirmed,
+    SampleDiscarded,
+    ExerciseEndedEarly,
+    AmbientBelowMinimum,
+    PassLevelAboveCeiling,
+    TimeRemaining,
+}
+
+/// The state reported by a StateChange notification (see TestState).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum P8020TestStateKind {
+    Pending,
+    /// The exercise can be retrieved via p8020_test_notification_exercise().
+    StartedExercise,
+    Finished,
+}
+
+// The accessors below allow bindings to consume TestNotifications without
+// relying on the layout of the (repr(C)) enum. Calling an accessor that does
+// not apply to the notification's kind is a programming error, and aborts.
+fn not_applicable(notification: &TestNotification, accessor: &str) -> ! {
+    panic!("p8020_test_notification_{accessor} does not apply to {notification:?}")
+}
+
+/// Returns the kind of notification, which determines which of the
+/// p8020_test_notification_* accessors may be used.
+#[export_name = "p8020_test_notification_kind"]
+pub extern "C" fn test_notification_kind(
+    notification: &TestNotification,
+) -> P8020TestNotificationKind {
+    match notification {
+        TestNotification::StateChange(_) => P8020TestNotificationKind::StateChange,
+        TestNotification::ExerciseResult { .. } => P8020TestNotificationKind::ExerciseResult,
+        TestNotification::Sample(_) => P8020TestNotificationKind::Sample,
+        TestNotification::LiveFF { .. } => P8020TestNotificationKind::LiveFF,
+        TestNotification::InterimFF { .. } => P8020TestNotificationKind::InterimFF,
+        TestNotification::StageComplete { .. } => P8020TestNotificationKind::StageComplete,
+        TestNotification::AmbientResult { .. } => P8020TestNotificationKind::AmbientResult,
+        TestNotification::ValveSwitchRequested { .. } => {
+            P8020TestNotificationKind::ValveSwitchRequested
+        }
+        TestNotification::ValveSwitchConfirmed { .. } => {
+            P8020TestNotificationKind::ValveSwitchConfirmed
+        }
+        TestNotification::SampleDiscarded { .. } => P8020TestNotificationKind::SampleDiscarded,
+        TestNotification::ExerciseEndedEarly { .. } => {
+            P8020TestNotificationKind::ExerciseEndedEarly
+        }
+        TestNotification::AmbientBelowMinimum { .. } => {
+            P8020TestNotificationKind::AmbientBelowMinimum
+        }
+        TestNotification::PassLevelAboveCeiling { .. } => {
+            P8020TestNotificationKind::PassLevelAboveCeiling
+        }
+        TestNotification::TimeRemaining { .. } => P8020TestNotificationKind::TimeRemaining,
+    }
+}
+
+/// Returns the new state. Applies to: StateChange.
+#[export_name = "p8020_test_notification_state"]
+pub extern "C" fn test_notification_state(notification: &TestNotification) -> P8020TestStateKind {
+    match notification {
+        TestNotification::StateChange(TestState::Pending) => P8020TestStateKind::Pending,
+        TestNotification::StateChange(TestState::StartedExercise(_)) => {
+            P8020TestStateKind::StartedExercise
+        }
+        TestNotification::StateChange(TestState::Finished) => P8020TestStateKind::Finished,
+        _ => not_applicable(notification, "state"),
+    }
+}
+
+/// Returns the (0-based) exercise index. Applies to: StateChange (for
+/// StartedExercise only), ExerciseResult, Sample, LiveFF, InterimFF,
+/// ExerciseEndedEarly.
+#[export_name = "p8020_test_notification_exercise"]
+pub extern "C" fn test_notification_exercise(notification: &TestNotification) -> usize {
+    match notification {
+        TestNotification::StateChange(TestState::StartedExercise(exercise))
+        | TestNotification::ExerciseResult { exercise, .. }
+        | TestNotification::Sample(SampleData { exercise, .. })
+        | TestNotification::LiveFF { exercise, .. }
+        | TestNotification::InterimFF { exercise, .. }
+        | TestNotification::ExerciseEndedEarly { exercise, .. } => *exercise,
+        _ => not_applicable(notification, "exercise"),
+    }
+}
+
+/// Returns the (0-based) stage index, i.e. index into the config's stages.
+/// Applies to: StageComplete, AmbientResult, AmbientBelowMinimum.
+#[export_name = "p8020_test_notification_stage"]
+pub extern "C" fn test_notification_stage(notification: &TestNotification) -> usize {
+    match notification {
+        TestNotification::StageComplete { stage, .. }
+        | TestNotification::AmbientResult { stage, .. }
+        | TestNotification::AmbientBelowMinimum { stage, .. } => *stage,
+        _ => not_applicable(notification, "stage"),
+    }
+}
+
+/// Returns the FF. Applies to: ExerciseResult (after rounding), LiveFF,
+/// InterimFF.
+#[export_name = "p8020_test_notification_fit_factor"]
+pub extern "C" fn test_notification_fit_factor(notification: &TestNotification) -> f64 {
+    match notification {
+        TestNotification::ExerciseResult { fit_factor, .. }
+        | TestNotification::LiveFF { fit_factor, .. }
+        | TestNotification::InterimFF { fit_factor, .. } => *fit_factor,
+        _ => not_applicable(notification, "fit_factor"),
+    }
+}
+
+/// Returns the FF prior to rounding. Applies to: ExerciseResult.
+#[export_name = "p8020_test_notification_raw_fit_factor"]
+pub extern "C" fn test_notification_raw_fit_factor(notification: &TestNotification) -> f64 {
+    match notification {
+        TestNotification::ExerciseResult { raw_fit_factor, .. } => *raw_fit_factor,
+        _ => not_applicable(notification, "raw_fit_factor"),
+    }
+}
+
+/// Returns the (absolute) uncertainty. Applies to: ExerciseResult,
+/// AmbientResult.
+#[export_name = "p8020_test_notification_error"]
+pub extern "C" fn test_notification_error(notification: &TestNotification) -> f64 {
+    match notification {
+        TestNotification::ExerciseResult { error, .. }
+        | TestNotification::AmbientResult { error, .. } => *error,
+        _ => not_applicable(notification, "error"),
+    }
+}
+
+/// Returns the index of the sample within the exercise. Applies to: LiveFF.
+#[export_name = "p8020_test_notification_index"]
+pub extern "C" fn test_notification_index(notification: &TestNotification) -> usize {
+    match notification {
+        TestNotification::LiveFF { index, .. } => *index,
+        _ => not_applicable(notification, "index"),
+    }
+}
+
+/// Returns the particle concentration of the sample. Applies to: Sample,
+/// SampleDiscarded.
+#[export_name = "p8020_test_notification_value"]
+pub extern "C" fn test_notification_value(notification: &TestNotification) -> f64 {
+    match notification {
+        TestNotification::Sample(SampleData { value, .. })
+        | TestNotification::SampleDiscarded { value, .. } => *value,
+        _ => not_applicable(notification, "value"),
+    }
+}
+
+/// Returns the sample type. Applies to: Sample.
+#[export_name = "p8020_test_notification_sample_type"]
+pub extern "C" fn test_notification_sample_type(notification: &TestNotification) -> SampleType {
+    match notification {
+        TestNotification::Sample(sample) => sample.sample_type.clone(),
+        _ => not_applicable(notification, "sample_type"),
+    }
+}
+
+/// Returns the average concentration. Applies to: StageComplete,
+/// AmbientResult, AmbientBelowMinimum.
+#[export_name = "p8020_test_notification_average"]
+pub extern "C" fn test_notification_average(notification: &TestNotification) -> f64 {
+    match notification {
+        TestNotification::StageComplete { average, .. }
+        | TestNotification::AmbientResult { average, .. }
+        | TestNotification::AmbientBelowMinimum { average, .. } => *average,
+        _ => not_applicable(notification, "average"),
+    }
+}
+
+/// Returns the standard deviation of the stage's samples. Applies to:
+/// StageComplete.
+#[export_name = "p8020_test_notification_standard_deviation"]
+pub extern "C" fn test_notification_standard_deviation(notification: &TestNotification) -> f64 {
+    match notification {
+        TestNotification::StageComplete {
+            standard_deviation, ..
+        } => *standard_deviation,
+        _ => not_applicable(notification, "standard_deviation"),
+    }
+}
+
+/// Returns the number of samples collected. Applies to: StageComplete,
+/// ExerciseEndedEarly.
+#[export_name = "p8020_test_notification_sample_count"]
+pub extern "C" fn test_notification_sample_count(notification: &TestNotification) -> usize {
+    match notification {
+        TestNotification::StageComplete { sample_count, .. }
+        | TestNotification::ExerciseEndedEarly { sample_count, .. } => *sample_count,
+        _ => not_applicable(notification, "sample_count"),
+    }
+}
+
+/// Returns the configured minimum ambient concentration. Applies to:
+/// AmbientBelowMinimum.
+#[export_name = "p8020_test_notification_minimum"]
+pub extern "C" fn test_notification_minimum(notification: &TestNotification) -> f64 {
+    match notification {
+        TestNotification::AmbientBelowMinimum { minimum, .. } => *minimum,
+        _ => not_applicable(notification, "minimum"),
+    }
+}
+
+/// Returns the pass level. Applies to: PassLevelAboveCeiling.
+#[export_name = "p8020_test_notification_pass_level"]
+pub extern "C" fn test_notification_pass_level(notification: &TestNotification) -> f64 {
+    match notification {
+        TestNotification::PassLevelAboveCeiling { pass_level, .. } => *pass_level,
+        _ => not_applicable(notification, "pass_level"),
+    }
+}
+
+/// Returns the max FF. Applies to: PassLevelAboveCeiling.
+#[export_name = "p8020_test_notification_max_fit_factor"]
+pub extern "C" fn test_notification_max_fit_factor(notification: &TestNotification) -> f64 {
+    match notification {
+        TestNotification::PassLevelAboveCeiling { max_fit_factor, .. } => *max_fit_factor,
+        _ => not_applicable(notification, "max_fit_factor"),
+    }
+}
+
+/// Returns the number of purges and samples remaining. Applies to:
+/// TimeRemaining.
+#[export_name = "p8020_test_notification_samples_remaining"]
+pub extern "C" fn test_notification_samples_remaining(notification: &TestNotification) -> usize {
+    match notification {
+        TestNotification::TimeRemaining {
+            samples_remaining, ..
+        } => *samples_remaining,
+        _ => not_applicable(notification, "samples_remaining"),
+    }
+}
+
+/// Returns the estimated time remaining, in seconds. Applies to:
+/// TimeRemaining.
+#[export_name = "p8020_test_notification_seconds_remaining"]
+pub extern "C" fn test_notification_seconds_remaining(notification: &TestNotification) -> f64 {
+    match notification {
+        TestNotification::TimeRemaining {
+            seconds_remaining, ..
+        } => *seconds_remaining,
+        _ => not_applicable(notification, "seconds_remaining"),
+    }
+}
+
+/// Returns the valve position. Applies to: ValveSwitchRequested,
+/// ValveSwitchConfirmed.
+#[export_name = "p8020_test_notification_valve_position"]
+pub extern "C" fn test_notification_valve_position(
+    notification: &TestNotification,
+) -> ValvePosition {
+    match notification {
+        TestNotification::ValveSwitchRequested { position }
+        | TestNotification::ValveSwitchConfirmed { position, .. } => *position,
+        _ => not_applicable(notification, "valve_position"),
+    }
+}
+
+/// Returns the number of samples discarded while awaiting the valve switch.
+/// Applies to: ValveSwitchConfirmed.
+#[export_name = "p8020_test_notification_discarded_samples"]
+pub extern "C" fn test_notification_discarded_samples(notification: &TestNotification) -> usize {
+    match notification {
+        TestNotification::ValveSwitchConfirmed {
+            discarded_samples, ..
+        } => *discarded_samples,
+        _ => not_applicable(notification, "discarded_samples"),
+    }
+}
+
+/// Returns the reason that the sample was discarded. Applies to:
+/// SampleDiscarded.
+#[export_name = "p8020_test_notification_discard_reason"]
+pub extern "C" fn test_notification_discard_reason(
+    notification: &TestNotification,
+) -> DiscardReason {
+    match notification {
+        TestNotification::SampleDiscarded { reason, .. } => *reason,
+        _ => not_applicable(notification, "discard_reason"),
+    }
+}
+
+/// A group of devices that run the same test simultaneously, see
+/// DeviceGroup. Devices are not owned by the group, and must outlive it.
+/// While a device belongs to a group, its test results are only delivered via
+/// p8020_group_run_test, i.e. it must not be tested individually until it has
+/// been removed from the group.
+///
+/// Tests are started on all devices at the same time, and the start of each
+/// exercise is synchronised across devices. Devices that stop delivering
+/// samples during a test (for 10s) are considered to have stalled, and their
+/// test is cancelled such that the remaining devices can complete.
+pub struct P8020Group {
+    // NULL for devices that were removed.
+    devices: Vec<*mut P8020Device>,
+    tx_done: DoneSender,
+    rx_done: DoneReceiver,
+    // The time at which each device last delivered a sample.
+    last_samples: Arc<Mutex<Vec<Instant>>>,
+}
+
+/// Results for a test run via p8020_group_run_test, one per device (in the
+/// order that devices were added to the group).
+pub struct P8020GroupResult {
+    result: GroupTestResult,
+    // Raw copies of each completed device's results (NULL otherwise), owned
+    // by the group result.
+    results: Vec<*mut P8020TestResult>,
+}
+
+/// How a test run via p8020_group_run_test ended on a single device, see
+/// DeviceOutcome.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum P8020DeviceOutcome {
+    Completed,
+    /// The test was cancelled or aborted (or the device was disconnected).
+    Cancelled,
+    /// The device stopped delivering samples. Exercise FF ratios are
+    /// available for the exercises that were completed before it stalled.
+    Stalled,
+    /// The device was removed from the group before the test.
+    Absent,
+}
+
+impl P8020Group {
+    /// Creates an empty group, which must be freed using p8020_group_free.
+    #[export_name = "p8020_group_create"]
+    pub extern "C" fn create() -> *mut P8020Group {
+        let (tx_done, rx_done) = mpsc::channel();
+        Box::into_raw(Box::new(P8020Group {
+            devices: Vec::new(),
+            tx_done,
+            rx_done,
+            last_samples: Arc::new(Mutex::new(Vec::new())),
+        }))
+    }
+
+    /// Adds a device to the group, and returns its index within the group.
+    #[export_name = "p8020_group_add_device"]
+    pub extern "C" fn add_device(&mut self, device: *mut P8020Device) -> usize {
+        let index = self.devices.len();
+        self.last_samples.lock().unwrap().push(Instant::now());
+        if let Some(device) = unsafe { device.as_ref() } {
+            *device.group_membership.lock().unwrap() = Some(GroupMembership {
+                index,
+                tx_done: self.tx_done.clone(),
+                last_samples: self.last_samples.clone(),
+            });
+        }
+        self.devices.push(device);
+        index
+    }
+
+    /// Removes the device with the specified index from the group, e.g.
+    /// because it was taken out of service. Indices of the remaining devices
+    /// are unchanged. The device is not freed. Returns false if there was no
+    /// such device.
+    #[export_name = "p8020_group_remove_device"]
+    pub extern "C" fn remove_device(&mut self, index: usize) -> bool {
+        let Some(device) = self.devices.get_mut(index) else {
+            return false;
+        };
+        let Some(removed) = (unsafe { device.as_ref() }) else {
+            return false;
+        };
+        *removed.group_membership.lock().unwrap() = None;
+        *device = std::ptr::null_mut();
+        true
+    }
+
+    /// Runs a fit test on all devices in the group, and blocks until the test
+    /// has completed (or been cancelled, or stalled) on all devices. callback
+    /// receives the index of the device that each notification belongs to.
+    /// The returned results must be freed using p8020_group_result_free.
+    /// Returns NULL if the group contains no devices, or if the test was
+    /// cancelled on all devices (see p8020_last_error_code).
+    #[export_name = "p8020_group_run_test"]
+    pub extern "C" fn run_test(
+        &mut self,
+        test_config: &TestConfig,
+        callback: extern "C" fn(usize, &TestNotification, *mut std::ffi::c_void) -> (),
+        callback_data: *mut std::ffi::c_void,
+    ) -> *mut P8020GroupResult {
+        let devices: Vec<Option<&Device>> = self
+            .devices
+            .iter()
+            .map(|device| unsafe { device.as_ref() }.map(|device| &device.device))
+            .collect();
+        let device_count = devices.iter().flatten().count();
+        if device_count == 0 {
+            set_last_error(P8020ErrorCode::NotFound, "the group contains no devices");
+            return std::ptr::null_mut();
+        }
+        let callback_data = FFICallbackDataHandle(callback_data);
+        let outcomes = GroupRun {
+            devices,
+            rx_done: &self.rx_done,
+            last_samples: &self.last_samples,
+            stall_timeout: GroupOptions::default().stall_timeout,
+            barrier_mode: BarrierMode::default(),
+        }
+        .run(
+            vec![test_config.clone(); device_count],
+            TestOptions::default(),
+            GroupTestCallback::merged(move |index, notification| {
+                callback(index, notification, callback_data.get());
+            }),
+        );
+        if outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, DeviceOutcome::Cancelled | DeviceOutcome::Absent))
+        {
+            set_last_error(
+                P8020ErrorCode::TestCancelled,
+                "the test was cancelled or aborted on all devices",
+            );
+            return std::ptr::null_mut();
+        }
+        let results = outcomes
+            .iter()
+            .map(|outcome| match outcome.result() {
+                Some(result) => test_result_into_raw(result.clone()),
+                None => std::ptr::null_mut(),
+            })
+            .collect();
+        Box::into_raw(Box::new(P8020GroupResult {
+            result: GroupTestResult {
+                labels: vec![None; outcomes.len()],
+                outcomes,
+            },
+            results,
+        }))
+    }
+
+    #[export_name = "p8020_group_free"]
+    pub unsafe extern "C" fn free(&mut self) {
+        for device in &self.devices {
+            if let Some(device) = device.as_ref() {
+                *device.group_membership.lock().unwrap() = None;
+            }
+        }
+        drop(Box::from_raw(self));
+    }
+}
+
+impl P8020GroupResult {
+    #[export_name = "p8020_group_result_device_count"]
+    pub extern "C" fn device_count(&self) -> usize {
+        self.results.len()
+    }
+
+    /// Returns how the test ended on the device with the specified index.
+    #[export_name = "p8020_group_result_device_outcome"]
+    pub extern "C" fn device_outcome(&self, index: usize) -> P8020DeviceOutcome {
+        match self.result.outcomes[index] {
+            DeviceOutcome::Completed(_) => P8020DeviceOutcome::Completed,
+            DeviceOutcome::Cancelled => P8020DeviceOutcome::Cancelled,
+            DeviceOutcome::Stalled { .. } => P8020DeviceOutcome::Stalled,
+            DeviceOutcome::Absent => P8020DeviceOutcome::Absent,
+        }
+    }
+
+    /// Returns the results for the device with the specified index, or NULL
+    /// if the test did not complete on that device (see
+    /// p8020_group_result_device_outcome). The returned results are owned by
+    /// the group result, and must not be freed separately.
+    #[export_name = "p8020_group_result_device_result"]
+    pub extern "C" fn device_result(&self, index: usize) -> *const P8020TestResult {
+        self.results[index]
+    }
+
+    /// Returns the ratio of the overall FFs measured by devices a and b (i.e.
+    /// a / b), or NaN if the test was cancelled on either device.
+    #[export_name = "p8020_group_result_overall_fit_factor_ratio"]
+    pub extern "C" fn overall_fit_factor_ratio(&self, a: usize, b: usize) -> f64 {
+        self.result
+            .overall_fit_factor_ratio(a, b)
+            .unwrap_or(f64::NAN)
+    }
+
+    /// Returns the ratio of the specified exercise's FFs measured by devices
+    /// a and b (i.e. a / b), or NaN if the test was cancelled on either
+    /// device.
+    #[export_name = "p8020_group_result_exercise_fit_factor_ratio"]
+    pub extern "C" fn exercise_fit_factor_ratio(&self, a: usize, b: usize, exercise: usize) -> f64 {
+        self.result
+            .exercise_fit_factor_ratios(a, b)
+            .get(exercise)
+            .copied()
+            .flatten()
+            .unwrap_or(f64::NAN)
+    }
+
+    #[export_name = "p8020_group_result_free"]
+    pub unsafe extern "C" fn free(&mut self) {
+        for result in &self.results {
+            if let Some(result) = result.as_mut() {
+                result.test_result_free();
+            }
+        }
+        drop(Box::from_raw(self));
+    }
+}
+
+// Configs registered via p8020_test_config_register, which can be loaded
+// using p8020_test_config_builtin_load just like builtins.
+static REGISTERED_CONFIGS: Mutex<Vec<TestConfig>> = Mutex::new(Vec::new());
+
+/// Returns the number of builtin configs (excluding registered configs).
 #[export_name = "p8020_test_config_builtin_count"]
 pub extern "C" fn builtin_count() -> usize {
     BUILTIN_CONFIGS.len()
 }
 
+/// Loads the builtin or registered (see p8020_test_config_register) config
+/// with the specified short name. Returns NULL if there is no such config.
+/// The returned config must be freed using p8020_test_config_free().
 #[export_name = "p8020_test_config_builtin_load"]
 pub extern "C" fn load_builtin_config(short_name_raw: *const libc::c_char) -> *mut TestConfig {
     let short_name_cstr = unsafe { std::ffi::CStr::from_ptr(short_name_raw) };
     let short_name = String::from_utf8_lossy(short_name_cstr.to_bytes()).to_string();
 
-    for config_csv in BUILTIN_CONFIGS {
-        let mut cursor = std::io::Cursor::new(config_csv.as_bytes());
-        let config = TestConfig::parse_from_csv(&mut cursor).expect("builtin configs must parse");
-        assert!(config.validate().is_ok(), "builtin configs must be valid");
+    let config = builtin::load(&short_name).or_else(|| {
+        REGISTERED_CONFIGS
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|config| config.short_name == short_name)
+            .cloned()
+    });
+    match config {
+        Some(config) => Box::into_raw(Box::new(config)),
+        None => {
+            set_last_error(
+                P8020ErrorCode::NotFound,
+                format!("no builtin config named {short_name}"),
+            );
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Registers a (copy of a) config, such that it can subsequently be loaded
+/// by short name via p8020_test_config_builtin_load. Returns false if the
+/// config is invalid, or if its short name is already used by a builtin or
+/// registered config. Registrations are process-wide.
+#[export_name = "p8020_test_config_register"]
+pub extern "C" fn config_register(config: &TestConfig) -> bool {
+    if let Err(e) = config.validate() {
+        set_last_error(
+            P8020ErrorCode::ParseFailed,
+            format!("invalid config: {e:?}"),
+        );
+        return false;
+    }
+    let mut registered = REGISTERED_CONFIGS.lock().unwrap();
+    let user_configs = registered
+        .iter()
+        .chain(std::iter::once(config))
+        .map(|config| ("registered".to_string(), config.clone()))
+        .collect();
+    if let Err(e) = test_config::merge_with_builtins(user_configs) {
+        set_last_error(
+            P8020ErrorCode::AlreadyExists,
+            format!("a config named {} already exists", e.short_name),
+        );
+        return false;
+    }
+    registered.push(config.clone());
+    true
+}
+
+/// Removes a config previously registered via p8020_test_config_register.
+/// Returns false if no such config was registered (builtins cannot be
+/// unregistered).
+#[export_name = "p8020_test_config_unregister"]
+pub extern "C" fn config_unregister(short_name_raw: *const libc::c_char) -> bool {
+    let short_name_cstr = unsafe { std::ffi::CStr::from_ptr(short_name_raw) };
+    let short_name = String::from_utf8_lossy(short_name_cstr.to_bytes()).to_string();
+
+    let mut registered = REGISTERED_CONFIGS.lock().unwrap();
+    let Some(index) = registered
+        .iter()
+        .position(|config| config.short_name == short_name)
+    else {
+        set_last_error(
+            P8020ErrorCode::NotFound,
+            format!("no registered config named {short_name}"),
+        );
+        return false;
+    };
+    registered.remove(index);
+    true
+}
+
+/// Parses (and validates) a CSV config. Returns NULL on failure, see
+/// p8020_last_error_message for details. The returned config must be freed
+/// using p8020_test_config_free().
+#[export_name = "p8020_test_config_parse"]
+pub extern "C" fn config_parse(csv_raw: *const libc::c_char) -> *mut TestConfig {
+    let csv_cstr = unsafe { std::ffi::CStr::from_ptr(csv_raw) };
+    parse_and_validate(&mut std::io::Cursor::new(csv_cstr.to_bytes()))
+}
+
+/// Loads (and validates) a CSV config from the file at path. Returns NULL on
+/// failure, see p8020_last_error_message for details. The returned config
+/// must be freed using p8020_test_config_free().
+#[export_name = "p8020_test_config_load_file"]
+pub extern "C" fn config_load_file(path_raw: *const libc::c_char) -> *mut TestConfig {
+    let path_cstr = unsafe { std::ffi::CStr::from_ptr(path_raw) };
+    let path = String::from_utf8_lossy(path_cstr.to_bytes()).to_string();
+    let file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            set_last_error(
+                P8020ErrorCode::IoFailed,
+                format!("unable to open {path}: {e}"),
+            );
+            return std::ptr::null_mut();
+        }
+    };
+    parse_and_validate(&mut std::io::BufReader::new(file))
+}
 
-        if config.short_name == short_name {
-            return Box::into_raw(Box::new(config));
+fn parse_and_validate(csv: &mut dyn std::io::BufRead) -> *mut TestConfig {
+    let config = match TestConfig::parse_from_csv(csv) {
+        Ok(config) => config,
+        Err(ParseError::IoError(e)) => {
+            set_last_error(
+                P8020ErrorCode::IoFailed,
+                format!("unable to read config: {e}"),
+            );
+            return std::ptr::null_mut();
         }
+        Err(e) => {
+            set_last_error(
+                P8020ErrorCode::ParseFailed,
+                format!("unable to parse config: {e:?}"),
+            );
+            return std::ptr::null_mut();
+        }
+    };
+    if let Err(e) = config.validate() {
+        set_last_error(
+            P8020ErrorCode::ParseFailed,
+            format!("invalid config: {e:?}"),
+        );
+        return std::ptr::null_mut();
     }
-    std::ptr::null_mut()
+    Box::into_raw(Box::new(config))
 }
 
 #[export_name = "p8020_test_config_exercise_count"]
@@ -240,9 +1546,77 @@ pub extern "C" fn config_exercise_name(config: &TestConfig, index: usize) -> *mu
         .into_raw()
 }
 
+/// The type of a stage within a test config, see
+/// p8020_test_config_stage_type().
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum P8020StageType {
+    AmbientSample,
+    Exercise,
+    Grimace,
+}
+
+/// How a stage's purge and sample counts are to be interpreted, see
+/// p8020_test_config_stage_unit().
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum P8020DurationUnit {
+    /// Counts are numbers of samples.
+    Samples,
+    /// Counts are durations in seconds.
+    Seconds,
+}
+
+/// Returns the number of stages (ambient samples, exercises, and grimaces)
+/// in the config.
+#[export_name = "p8020_test_config_stage_count"]
+pub extern "C" fn config_stage_count(config: &TestConfig) -> usize {
+    config.stages.len()
+}
+
+/// Returns the type of the specified stage (0-based).
+#[export_name = "p8020_test_config_stage_type"]
+pub extern "C" fn config_stage_type(config: &TestConfig, index: usize) -> P8020StageType {
+    match config.stages[index] {
+        TestStage::AmbientSample { .. } => P8020StageType::AmbientSample,
+        TestStage::Exercise { .. } => P8020StageType::Exercise,
+        TestStage::Grimace { .. } => P8020StageType::Grimace,
+    }
+}
+
+/// Returns the purge duration of the specified stage, see
+/// p8020_test_config_stage_unit().
+#[export_name = "p8020_test_config_stage_purge_count"]
+pub extern "C" fn config_stage_purge_count(config: &TestConfig, index: usize) -> usize {
+    config.stages[index].counts().purge_count
+}
+
+/// Returns the sample duration of the specified stage, see
+/// p8020_test_config_stage_unit().
+#[export_name = "p8020_test_config_stage_sample_count"]
+pub extern "C" fn config_stage_sample_count(config: &TestConfig, index: usize) -> usize {
+    config.stages[index].counts().sample_count
+}
+
+/// Returns the unit of the specified stage's purge and sample counts.
+#[export_name = "p8020_test_config_stage_unit"]
+pub extern "C" fn config_stage_unit(config: &TestConfig, index: usize) -> P8020DurationUnit {
+    match config.stages[index].counts().unit {
+        DurationUnit::Samples => P8020DurationUnit::Samples,
+        DurationUnit::Seconds => P8020DurationUnit::Seconds,
+    }
+}
+
+/// Frees a string returned by libp8020 (e.g. by p8020_last_error_message()).
+/// NULL is ignored.
 #[export_name = "p8020_string_free"]
-pub unsafe extern "C" fn string_free(name: *mut c_char) {
-    drop(Box::from_raw(name));
+pub unsafe extern "C" fn string_free(string: *mut c_char) {
+    if string.is_null() {
+        return;
+    }
+    // Strings are allocated as CStrings (see CString::into_raw), whose
+    // allocation spans the entire string including its terminator.
+    drop(CString::from_raw(string));
 }
 
 #[export_name = "p8020_test_config_free"]
@@ -280,20 +1654,17 @@ impl P8020PortList {
     /// p8020_port_list_free().
     #[export_name = "p8020_ports_list"]
     pub extern "C" fn list_devices(usb_only: bool) -> *mut P8020PortList {
-        let Ok(ports) = serialport::available_ports() else {
-            return std::ptr::null_mut();
-        };
-        let filtered_ports = if usb_only {
-            ports
-                .into_iter()
-                .filter(|port| matches!(port.port_type, SerialPortType::UsbPort(..)))
-                .collect()
-        } else {
-            ports
+        let ports = match crate::list_ports(usb_only) {
+            Ok(ports) => ports,
+            Err(e) => {
+                set_last_error(
+                    P8020ErrorCode::PortEnumerationFailed,
+                    format!("unable to list ports: {e}"),
+                );
+                return std::ptr::null_mut();
+            }
         };
-        Box::into_raw(Box::new(P8020PortList {
-            ports: filtered_ports,
-        }))
+        Box::into_raw(Box::new(P8020PortList { ports }))
     }
 
     #[export_name = "p8020_port_list_count"]
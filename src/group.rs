@@ -0,0 +1,775 @@
+//! Running the same test on several devices at once, e.g. to compare
+//! devices, or to test several subjects simultaneously.
+//!
+//! Tests are started on all devices at the same time, and the start of each
+//! exercise is synchronised across devices (see ExerciseBarrier and
+//! GroupOptions::barrier_mode). Devices may run different configs, as long as
+//! the configs contain the same number of exercises.
+//!
+//! Groups can also compare the concentrations measured by all devices while
+//! sampling the same air (see DeviceGroup::compare_concentrations), e.g. to
+//! determine whether a device needs servicing.
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::test_config::TestConfig;
+use crate::{
+    BarrierMode, ConnectOptions, Device, DeviceId, DeviceNotification, ExerciseBarrier,
+    TestNotification, TestOptions, TestResult,
+};
+
+/// GroupOptions contains settings for a DeviceGroup.
+#[derive(Clone, Debug)]
+pub struct GroupOptions {
+    /// Used for all devices in the group (index is set to each device's
+    /// index within the group).
+    pub connect_options: ConnectOptions,
+    /// How long a device may go without delivering a sample during a test
+    /// before it is considered to have failed (default: 10s). The test is
+    /// then cancelled on that device, such that the remaining devices can
+    /// complete. None to wait indefinitely.
+    pub stall_timeout: Option<Duration>,
+    /// How devices wait for each other at the start of each exercise.
+    pub barrier_mode: BarrierMode,
+}
+
+impl Default for GroupOptions {
+    fn default() -> Self {
+        GroupOptions {
+            connect_options: ConnectOptions::default(),
+            stall_timeout: Some(Duration::from_secs(10)),
+            barrier_mode: BarrierMode::default(),
+        }
+    }
+}
+
+/// DeviceOutcome describes how a test ended on a single device.
+// There's only one outcome per device and test, size is therefore irrelevant.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
+pub enum DeviceOutcome {
+    Completed(TestResult),
+    /// The test was cancelled or aborted (or the device was disconnected).
+    Cancelled,
+    /// The device stopped delivering samples (see
+    /// GroupOptions::stall_timeout). fit_factors contains the FF of each
+    /// exercise that was completed before the device stalled, and None for
+    /// all other exercises.
+    Stalled {
+        fit_factors: Vec<Option<f64>>,
+    },
+    /// The device was removed from the group before the test (see
+    /// DeviceGroup::remove_device).
+    Absent,
+}
+
+impl DeviceOutcome {
+    /// Returns the results, if the test completed.
+    pub fn result(&self) -> Option<&TestResult> {
+        match self {
+            DeviceOutcome::Completed(result) => Some(result),
+            _ => None,
+        }
+    }
+}
+
+/// GroupTestResult contains the outcome of a test run on a DeviceGroup, for
+/// each device in the group (indexed by the devices' indices within the
+/// group).
+#[derive(Clone, Debug)]
+pub struct GroupTestResult {
+    pub outcomes: Vec<DeviceOutcome>,
+    /// The label of each device at the time of the test (see
+    /// DeviceGroup::set_label), e.g. identifying the mask or subject that
+    /// each device was measuring.
+    pub labels: Vec<Option<String>>,
+}
+
+impl GroupTestResult {
+    /// Returns the ratio of the overall FFs measured by devices a and b
+    /// (i.e. a / b), or None if the test did not complete on either device.
+    pub fn overall_fit_factor_ratio(&self, a: usize, b: usize) -> Option<f64> {
+        let (a, b) = (self.outcomes[a].result()?, self.outcomes[b].result()?);
+        Some(a.overall_fit_factor / b.overall_fit_factor)
+    }
+
+    /// Returns the ratio of each exercise's FF measured by devices a and b
+    /// (i.e. a / b). Exercise FFs are available for stalled devices too,
+    /// ratios are None for exercises that were not completed on either
+    /// device.
+    pub fn exercise_fit_factor_ratios(&self, a: usize, b: usize) -> Vec<Option<f64>> {
+        let (a, b) = (self.fit_factors(a), self.fit_factors(b));
+        a.iter()
+            .zip(b.iter())
+            .map(|(a, b)| Some((*a)? / (*b)?))
+            .collect()
+    }
+
+    // Returns the (possibly partial) exercise FFs for the specified device.
+    fn fit_factors(&self, index: usize) -> Vec<Option<f64>> {
+        match &self.outcomes[index] {
+            DeviceOutcome::Completed(result) => {
+                result.fit_factors.iter().copied().map(Some).collect()
+            }
+            DeviceOutcome::Cancelled | DeviceOutcome::Absent => Vec::new(),
+            DeviceOutcome::Stalled { fit_factors } => fit_factors.clone(),
+        }
+    }
+}
+
+pub type MergedTestCallback = Box<dyn Fn(usize, &TestNotification) + 'static + Send + Sync>;
+pub type DeviceTestCallback = Box<dyn Fn(&TestNotification) + 'static + Send>;
+type GroupDeviceCallback = Arc<dyn Fn(&DeviceId, DeviceNotification) + 'static + Send + Sync>;
+
+/// GroupTestCallback determines how test notifications are delivered during
+/// DeviceGroup::run_test.
+pub enum GroupTestCallback {
+    /// A single callback receives all devices' notifications (as one merged
+    /// stream), along with the index of the device that sent them.
+    Merged(MergedTestCallback),
+    /// One callback per device, in the order of DeviceGroup::device_indices().
+    PerDevice(Vec<DeviceTestCallback>),
+}
+
+impl GroupTestCallback {
+    pub fn merged(callback: impl Fn(usize, &TestNotification) + 'static + Send + Sync) -> Self {
+        GroupTestCallback::Merged(Box::new(callback))
+    }
+
+    // Returns the callback to use for each of the devices with the specified
+    // indices.
+    fn into_device_callbacks(self, indices: &[usize]) -> Vec<DeviceTestCallback> {
+        match self {
+            GroupTestCallback::Merged(callback) => {
+                let callback = Arc::new(callback);
+                indices
+                    .iter()
+                    .map(|&index| {
+                        let callback = callback.clone();
+                        Box::new(move |notification: &TestNotification| {
+                            (*callback)(index, notification)
+                        }) as DeviceTestCallback
+                    })
+                    .collect()
+            }
+            GroupTestCallback::PerDevice(callbacks) => {
+                assert_eq!(
+                    callbacks.len(),
+                    indices.len(),
+                    "must supply exactly one callback per device"
+                );
+                callbacks
+            }
+        }
+    }
+}
+
+/// ConcentrationComparison is the result of
+/// DeviceGroup::compare_concentrations. Devices are compared against the
+/// reference device, using the average concentration within consecutive
+/// windows of COMPARISON_WINDOW.
+#[derive(Clone, Debug)]
+pub struct ConcentrationComparison {
+    /// The index of the reference device.
+    pub reference: usize,
+    /// The comparison for each device (indexed by the devices' indices within
+    /// the group), or None for devices that were removed, and devices that
+    /// delivered no samples.
+    pub devices: Vec<Option<DeviceComparison>>,
+    /// The label of each device at the time of the comparison.
+    pub labels: Vec<Option<String>>,
+}
+
+/// DeviceComparison describes how a single device's concentrations compare
+/// to those of the reference device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceComparison {
+    pub sample_count: usize,
+    pub mean_concentration: f64,
+    /// The relative difference between this device's mean concentration and
+    /// the reference device's (e.g. 0.05 if this device reads 5% higher).
+    pub relative_bias: f64,
+    /// The change in the ratio of this device's concentration to the
+    /// reference device's, per minute (i.e. the slope of the per-window
+    /// ratios). None if fewer than two windows were available.
+    pub drift_per_minute: Option<f64>,
+}
+
+/// The window within which concentrations are averaged before comparing
+/// devices: individual samples are too noisy to compare meaningfully.
+pub const COMPARISON_WINDOW: Duration = Duration::from_secs(10);
+
+// Compares each device's samples (time since the start of the comparison,
+// and concentration) against the reference device's.
+fn compare_samples(
+    samples: &[Vec<(Duration, f64)>],
+    reference: usize,
+) -> Vec<Option<DeviceComparison>> {
+    let window_averages = |samples: &[(Duration, f64)]| {
+        let mut windows: BTreeMap<u128, (f64, usize)> = BTreeMap::new();
+        for (elapsed, concentration) in samples {
+            let window = windows
+                .entry(elapsed.as_millis() / COMPARISON_WINDOW.as_millis())
+                .or_default();
+            window.0 += concentration;
+            window.1 += 1;
+        }
+        windows
+            .into_iter()
+            .map(|(window, (sum, count))| (window, sum / count as f64))
+            .collect::<BTreeMap<u128, f64>>()
+    };
+    let mean = |samples: &[(Duration, f64)]| {
+        samples.iter().map(|sample| sample.1).sum::<f64>() / samples.len() as f64
+    };
+    let reference_mean = mean(&samples[reference]);
+    let reference_windows = window_averages(&samples[reference]);
+    samples
+        .iter()
+        .map(|samples| {
+            if samples.is_empty() {
+                return None;
+            }
+            // (Window midpoint in minutes, ratio to the reference.)
+            let ratios: Vec<(f64, f64)> = window_averages(samples)
+                .into_iter()
+                .filter_map(|(window, average)| {
+                    let reference_average = *reference_windows.get(&window)?;
+                    (reference_average > 0.0).then(|| {
+                        let midpoint = (window as f64 + 0.5) * COMPARISON_WINDOW.as_secs_f64();
+                        (midpoint / 60.0, average / reference_average)
+                    })
+                })
+                .collect();
+            let drift_per_minute = (ratios.len() >= 2).then(|| {
+                let n = ratios.len() as f64;
+                let mean_time = ratios.iter().map(|ratio| ratio.0).sum::<f64>() / n;
+                let mean_ratio = ratios.iter().map(|ratio| ratio.1).sum::<f64>() / n;
+                let covariance: f64 = ratios
+                    .iter()
+                    .map(|(time, ratio)| (time - mean_time) * (ratio - mean_ratio))
+                    .sum();
+                let variance: f64 = ratios
+                    .iter()
+                    .map(|(time, _)| (time - mean_time).powi(2))
+                    .sum();
+                covariance / variance
+            });
+            let mean_concentration = mean(samples);
+            Some(DeviceComparison {
+                sample_count: samples.len(),
+                mean_concentration,
+                relative_bias: mean_concentration / reference_mean - 1.0,
+                drift_per_minute,
+            })
+        })
+        .collect()
+}
+
+// The arrival time and concentration of each sample delivered by a device.
+type RecordedSamples = Vec<(Instant, f64)>;
+
+// Test outcomes sent by a group's device callbacks: the results on
+// completion, or None if the test was cancelled or aborted.
+pub(crate) type DoneSender = mpsc::Sender<(usize, Option<TestResult>)>;
+pub(crate) type DoneReceiver = Receiver<(usize, Option<TestResult>)>;
+
+// Runs a single test on a set of devices, and collects their outcomes. Used by
+// DeviceGroup, and by the FFI group (whose devices are owned by the caller).
+// The devices' callbacks must deliver their test outcomes via rx_done, and
+// record the arrival time of each sample in last_samples (both indexed by the
+// devices' indices within devices).
+pub(crate) struct GroupRun<'a> {
+    // None for devices that were removed.
+    pub devices: Vec<Option<&'a Device>>,
+    pub rx_done: &'a DoneReceiver,
+    pub last_samples: &'a Mutex<Vec<Instant>>,
+    pub stall_timeout: Option<Duration>,
+    pub barrier_mode: BarrierMode,
+}
+
+impl GroupRun<'_> {
+    // Starts the test on all devices, and blocks until the test has completed
+    // (or been cancelled, or stalled) on all devices. See
+    // DeviceGroup::run_test_with_configs.
+    pub fn run(
+        self,
+        configs: Vec<TestConfig>,
+        options: TestOptions,
+        test_callback: GroupTestCallback,
+    ) -> Vec<DeviceOutcome> {
+        let indices: Vec<usize> = self
+            .devices
+            .iter()
+            .enumerate()
+            .filter_map(|(index, device)| device.map(|_| index))
+            .collect();
+        assert_eq!(
+            configs.len(),
+            indices.len(),
+            "must supply exactly one config per device"
+        );
+        let specimen_stage_count = |config: &TestConfig| {
+            config
+                .stages
+                .iter()
+                .filter(|stage| stage.is_exercise())
+                .count()
+        };
+        assert!(
+            configs
+                .iter()
+                .all(|config| specimen_stage_count(config) == specimen_stage_count(&configs[0])),
+            "all configs must contain the same number of exercises"
+        );
+
+        // Discard outcomes of any previous tests (e.g. cancellations sent
+        // while no test was running).
+        while self.rx_done.try_recv().is_ok() {}
+
+        // Exercise FFs received so far, which are reported for stalled
+        // devices.
+        let mut fit_factors = vec![Vec::new(); self.devices.len()];
+        for (index, config) in indices.iter().zip(configs.iter()) {
+            fit_factors[*index] = vec![None; config.exercise_count()];
+        }
+        let fit_factors = Arc::new(Mutex::new(fit_factors));
+        // Participants are identified by their device index.
+        let barrier = Arc::new(ExerciseBarrier::with_mode(
+            self.devices.len(),
+            self.barrier_mode,
+        ));
+        for (index, device) in self.devices.iter().enumerate() {
+            if device.is_none() {
+                barrier.leave(index);
+            }
+        }
+        let mut outcomes: Vec<Option<DeviceOutcome>> = self
+            .devices
+            .iter()
+            .map(|device| device.is_none().then_some(DeviceOutcome::Absent))
+            .collect();
+        let test_callbacks = test_callback.into_device_callbacks(&indices);
+        for ((index, test_callback), config) in
+            indices.iter().copied().zip(test_callbacks).zip(configs)
+        {
+            let fit_factors = fit_factors.clone();
+            let started = self.devices[index].unwrap().start_test(
+                config,
+                TestOptions {
+                    exercise_barrier: Some(barrier.clone()),
+                    barrier_participant: index,
+                    ..options.clone()
+                },
+                Some(Box::new(move |notification: &TestNotification| {
+                    if let TestNotification::ExerciseResult {
+                        exercise,
+                        fit_factor,
+                        ..
+                    } = notification
+                    {
+                        fit_factors.lock().unwrap()[index][*exercise] = Some(*fit_factor);
+                    }
+                    test_callback(notification)
+                })),
+            );
+            if started.is_err() {
+                log::warn!(device = index; "device {index} is disconnected, skipping its test");
+                barrier.leave(index);
+                outcomes[index] = Some(DeviceOutcome::Cancelled);
+            }
+        }
+        self.last_samples.lock().unwrap().fill(Instant::now());
+
+        while outcomes.iter().any(Option::is_none) {
+            let received = match self.stall_timeout {
+                // Stalls are checked for (at least) once per second.
+                Some(stall_timeout) => self
+                    .rx_done
+                    .recv_timeout(stall_timeout.min(Duration::from_secs(1))),
+                None => self
+                    .rx_done
+                    .recv()
+                    .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok((index, result)) => {
+                    if outcomes[index].is_none() {
+                        // Other devices must not wait for this one anymore.
+                        barrier.leave(index);
+                        outcomes[index] = Some(match result {
+                            Some(result) => DeviceOutcome::Completed(result),
+                            None => DeviceOutcome::Cancelled,
+                        });
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    unreachable!("tx_done is owned by the group")
+                }
+            }
+            let Some(stall_timeout) = self.stall_timeout else {
+                continue;
+            };
+            let last_samples = self.last_samples.lock().unwrap().clone();
+            for (index, last_sample) in last_samples.into_iter().enumerate() {
+                if outcomes[index].is_some() || last_sample.elapsed() < stall_timeout {
+                    continue;
+                }
+                log::warn!(device = index; "device {index} stalled, cancelling its test");
+                // There's nothing left to cancel if the device has
+                // disconnected in the meantime.
+                let _ = self.devices[index].unwrap().cancel_test();
+                barrier.leave(index);
+                outcomes[index] = Some(DeviceOutcome::Stalled {
+                    fit_factors: fit_factors.lock().unwrap()[index].clone(),
+                });
+            }
+        }
+        outcomes.into_iter().map(Option::unwrap).collect()
+    }
+}
+
+/// DeviceGroup is a set of connected devices, identified by their index
+/// within the group. Indices are assigned in the order that devices are
+/// added (starting with the paths passed to connect()), and remain stable
+/// when other devices are removed.
+pub struct DeviceGroup {
+    // None for devices that were removed.
+    devices: Vec<Option<Device>>,
+    labels: Vec<Option<String>>,
+    options: GroupOptions,
+    device_callback: Option<GroupDeviceCallback>,
+    // Receives the outcome of each device's test. (The Mutex allows tests to
+    // be cancelled from other threads while run_test is blocking.)
+    tx_done: DoneSender,
+    rx_done: Mutex<DoneReceiver>,
+    // The time at which each device last delivered a sample.
+    last_samples: Arc<Mutex<Vec<Instant>>>,
+    // All samples delivered by each device (with their arrival time) while
+    // compare_concentrations is running, None otherwise.
+    recorded_samples: Arc<Mutex<Option<Vec<RecordedSamples>>>>,
+}
+
+impl DeviceGroup {
+    /// Connects to the devices at the specified paths. Fails if any of the
+    /// devices cannot be connected. device_callback receives all
+    /// notifications, along with the identity of the device that sent them
+    /// (DeviceId::index is the device's index within the group).
+    pub fn connect(
+        paths: Vec<String>,
+        device_callback: Option<impl Fn(&DeviceId, DeviceNotification) + 'static + Send + Sync>,
+    ) -> serialport::Result<DeviceGroup> {
+        DeviceGroup::connect_with_options(paths, GroupOptions::default(), device_callback)
+    }
+
+    pub fn connect_with_options(
+        paths: Vec<String>,
+        options: GroupOptions,
+        device_callback: Option<impl Fn(&DeviceId, DeviceNotification) + 'static + Send + Sync>,
+    ) -> serialport::Result<DeviceGroup> {
+        let (tx_done, rx_done) = mpsc::channel();
+        let mut group = DeviceGroup {
+            devices: Vec::new(),
+            labels: Vec::new(),
+            options,
+            device_callback: device_callback
+                .map(|callback| Arc::new(callback) as GroupDeviceCallback),
+            tx_done,
+            rx_done: Mutex::new(rx_done),
+            last_samples: Arc::new(Mutex::new(Vec::new())),
+            recorded_samples: Arc::new(Mutex::new(None)),
+        };
+        for path in paths {
+            group.add_device(path)?;
+        }
+        Ok(group)
+    }
+
+    /// Connects to an additional device, and returns its index within the
+    /// group. The device participates in all subsequent tests.
+    pub fn add_device(&mut self, path: String) -> serialport::Result<usize> {
+        let index = self.devices.len();
+        let tx_done = self.tx_done.clone();
+        let device_callback = self.device_callback.clone();
+        let last_samples = self.last_samples.clone();
+        let recorded_samples = self.recorded_samples.clone();
+        // Samples may arrive as soon as the device is connected.
+        self.last_samples.lock().unwrap().push(Instant::now());
+        let device = Device::connect_path_with_options(
+            path,
+            ConnectOptions {
+                index,
+                ..self.options.connect_options.clone()
+            },
+            Some(move |id: &DeviceId, notification: DeviceNotification| {
+                let outcome = match &notification {
+                    DeviceNotification::Sample { particle_conc } => {
+                        last_samples.lock().unwrap()[index] = Instant::now();
+                        if let Some(recorded_samples) = recorded_samples.lock().unwrap().as_mut() {
+                            if let Some(samples) = recorded_samples.get_mut(index) {
+                                samples.push((Instant::now(), *particle_conc));
+                            }
+                        }
+                        None
+                    }
+                    DeviceNotification::TestCompleted { result } => Some(Some(result.clone())),
+                    DeviceNotification::TestCancelled | DeviceNotification::TestAborted => {
+                        Some(None)
+                    }
+                    _ => None,
+                };
+                if let Some(outcome) = outcome {
+                    // The group may already have been dropped.
+                    let _ = tx_done.send((index, outcome));
+                }
+                if let Some(callback) = &device_callback {
+                    callback(id, notification);
+                }
+            }),
+        );
+        match device {
+            Ok(device) => {
+                self.devices.push(Some(device));
+                self.labels.push(None);
+                Ok(index)
+            }
+            Err(e) => {
+                self.last_samples.lock().unwrap().pop();
+                Err(e)
+            }
+        }
+    }
+
+    /// Removes (and disconnects) the specified device, e.g. because it was
+    /// taken out of service. Returns false if there was no such device.
+    pub fn remove_device(&mut self, index: usize) -> bool {
+        self.devices.get_mut(index).and_then(Option::take).is_some()
+    }
+
+    /// Returns the indices of all devices currently in the group.
+    pub fn device_indices(&self) -> Vec<usize> {
+        self.devices
+            .iter()
+            .enumerate()
+            .filter_map(|(index, device)| device.as_ref().map(|_| index))
+            .collect()
+    }
+
+    /// Returns the device with the specified index, or None if there is no
+    /// such device (or it was removed).
+    pub fn device(&self, index: usize) -> Option<&Device> {
+        self.devices.get(index)?.as_ref()
+    }
+
+    /// Sets a label for the specified device (e.g. the mask or subject that
+    /// it is measuring), which is recorded in GroupTestResult.
+    pub fn set_label(&mut self, index: usize, label: impl Into<String>) {
+        self.labels[index] = Some(label.into());
+    }
+
+    /// Runs config on all devices, and blocks until the test has completed
+    /// (or been cancelled, or stalled) on all devices.
+    pub fn run_test(
+        &self,
+        config: &TestConfig,
+        options: TestOptions,
+        test_callback: GroupTestCallback,
+    ) -> GroupTestResult {
+        self.run_test_with_configs(
+            vec![config.clone(); self.device_indices().len()],
+            options,
+            test_callback,
+        )
+    }
+
+    /// As run_test, but with a separate config for each device (in the order
+    /// of device_indices(), e.g. with purge counts tuned per device).
+    /// Exercises start simultaneously on all devices, regardless of how long
+    /// the preceding stages lasted on each device.
+    ///
+    /// Panics if the number of configs does not match the number of devices,
+    /// or if the configs contain differing numbers of exercises (including
+    /// grimaces).
+    pub fn run_test_with_configs(
+        &self,
+        configs: Vec<TestConfig>,
+        options: TestOptions,
+        test_callback: GroupTestCallback,
+    ) -> GroupTestResult {
+        let devices = self.devices.iter().map(Option::as_ref).collect();
+        let outcomes = GroupRun {
+            devices,
+            rx_done: &self.rx_done.lock().unwrap(),
+            last_samples: &self.last_samples,
+            stall_timeout: self.options.stall_timeout,
+            barrier_mode: self.options.barrier_mode,
+        }
+        .run(configs, options, test_callback);
+        GroupTestResult {
+            outcomes,
+            labels: self.labels.clone(),
+        }
+    }
+
+    /// Records the concentrations measured by all devices for the specified
+    /// duration, and compares them against the reference device's. Blocks
+    /// until the comparison has completed. All devices must sample the same
+    /// air for the comparison to be meaningful: devices sample through the
+    /// specimen tube while no test is running, which should therefore be left
+    /// open (without a mask attached) near the other devices' tubes.
+    ///
+    /// Must not be called while a test is running. Panics if there is no
+    /// device with index reference.
+    pub fn compare_concentrations(
+        &self,
+        duration: Duration,
+        reference: usize,
+    ) -> ConcentrationComparison {
+        assert!(
+            self.device(reference).is_some(),
+            "reference must be a device within the group"
+        );
+        let started_at = Instant::now();
+        *self.recorded_samples.lock().unwrap() = Some(vec![Vec::new(); self.devices.len()]);
+        std::thread::sleep(duration);
+        let recorded_samples = self.recorded_samples.lock().unwrap().take().unwrap();
+
+        let samples: Vec<Vec<(Duration, f64)>> = recorded_samples
+            .into_iter()
+            .map(|samples| {
+                samples
+                    .into_iter()
+                    .map(|(received_at, concentration)| {
+                        (received_at.duration_since(started_at), concentration)
+                    })
+                    .collect()
+            })
+            .collect();
+        ConcentrationComparison {
+            reference,
+            devices: compare_samples(&samples, reference),
+            labels: self.labels.clone(),
+        }
+    }
+
+    /// Cancels the running test on all devices (disconnected devices are
+    /// skipped, as they can't be running a test).
+    pub fn cancel_test(&self) {
+        for device in self.devices.iter().flatten() {
+            let _ = device.cancel_test();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness;
+    use crate::test_config::builtin;
+
+    fn completed(exercise_concentrations: &[f64]) -> DeviceOutcome {
+        let config = builtin::load("osha_fast_ffp").unwrap();
+        let concentrations =
+            harness::stage_concentrations(&config, 1000.0, exercise_concentrations);
+        let output = harness::run_concentrations(config, TestOptions::default(), concentrations);
+        DeviceOutcome::Completed(output.result.unwrap())
+    }
+
+    #[test]
+    fn test_compare_samples() {
+        let samples = |concentration: &dyn Fn(f64) -> f64| -> Vec<(Duration, f64)> {
+            (0..120)
+                .map(|second| {
+                    let elapsed = Duration::from_secs(second);
+                    (elapsed, concentration(elapsed.as_secs_f64() / 60.0))
+                })
+                .collect()
+        };
+        let comparisons = compare_samples(
+            &[
+                samples(&|_| 1000.0),
+                Vec::new(),
+                samples(&|_| 1100.0),
+                // Drifts by 10% per minute.
+                samples(&|minutes| 1000.0 * (1.0 + 0.1 * minutes)),
+            ],
+            0,
+        );
+        let reference = comparisons[0].as_ref().unwrap();
+        assert_eq!(reference.sample_count, 120);
+        assert_eq!(reference.relative_bias, 0.0);
+        assert!(reference.drift_per_minute.unwrap().abs() < 1e-9);
+
+        assert_eq!(comparisons[1], None);
+
+        let biased = comparisons[2].as_ref().unwrap();
+        assert!((biased.relative_bias - 0.1).abs() < 1e-9);
+        assert!(biased.drift_per_minute.unwrap().abs() < 1e-9);
+
+        let drifting = comparisons[3].as_ref().unwrap();
+        assert!((drifting.drift_per_minute.unwrap() - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_merged_callback() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_write = received.clone();
+        let callbacks = GroupTestCallback::merged(move |index, notification| {
+            received_write
+                .lock()
+                .unwrap()
+                .push((index, notification.clone()));
+        })
+        .into_device_callbacks(&[0, 2]);
+        let notification = TestNotification::InterimFF {
+            exercise: 0,
+            fit_factor: 100.0,
+        };
+        callbacks[1](&notification);
+        callbacks[0](&notification);
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![(2, notification.clone()), (0, notification)]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_per_device_callback_count() {
+        GroupTestCallback::PerDevice(vec![Box::new(|_| ())]).into_device_callbacks(&[0, 2]);
+    }
+
+    #[test]
+    fn test_fit_factor_ratios() {
+        let result = GroupTestResult {
+            outcomes: vec![
+                completed(&[10.0, 10.0, 10.0, 10.0]),
+                completed(&[20.0, 20.0, 20.0, 5.0]),
+                DeviceOutcome::Stalled {
+                    fit_factors: vec![Some(100.0), None, None, None],
+                },
+                DeviceOutcome::Cancelled,
+            ],
+            labels: vec![None; 4],
+        };
+        let ratio = result.overall_fit_factor_ratio(0, 1).unwrap();
+        let expected = result.outcomes[0].result().unwrap().overall_fit_factor
+            / result.outcomes[1].result().unwrap().overall_fit_factor;
+        assert_eq!(ratio, expected);
+        assert_eq!(result.overall_fit_factor_ratio(0, 2), None);
+
+        assert_eq!(
+            result.exercise_fit_factor_ratios(0, 1),
+            vec![Some(2.0), Some(2.0), Some(2.0), Some(0.5)]
+        );
+        assert_eq!(
+            result.exercise_fit_factor_ratios(2, 0),
+            vec![Some(1.0), None, None, None]
+        );
+        assert!(result.exercise_fit_factor_ratios(0, 3).is_empty());
+    }
+}
@@ -0,0 +1,587 @@
+//! Output of samples, test progress, and results as InfluxDB line protocol,
+//! e.g. for storing ambient concentrations alongside other environmental data
+//! in an Influx/Grafana stack.
+//!
+//! Each kind of notification is written to its own measurement (e.g.
+//! p8020_sample or p8020_exercise_result), tagged with the device label that
+//! is passed to InfluxSink::write_device_notification or
+//! InfluxSink::write_test_notification. Exercises and stages are 0-indexed.
+//! Lines are written on a background thread, such that slow targets do not
+//! delay the device; write errors are logged. If the target falls too far
+//! behind, sample lines are dropped (see InfluxOptions::max_queued_lines).
+//!
+//! Requires the influx feature.
+
+use std::io::{BufRead, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::queue::{BoundedQueue, OverflowPolicy};
+use crate::{DeviceNotification, SampleType, TestNotification, TestState};
+
+/// InfluxTarget is where lines are written to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InfluxTarget {
+    Stdout,
+    /// Lines are appended to the file.
+    File(PathBuf),
+    /// Lines are POSTed (in batches) to an InfluxDB (or compatible) HTTP
+    /// write endpoint, e.g. path = "/api/v2/write?org=o&bucket=b" for
+    /// InfluxDB 2, or "/write?db=p8020" for InfluxDB 1. Only plain HTTP is
+    /// supported.
+    Http {
+        address: String,
+        path: String,
+    },
+}
+
+impl FromStr for InfluxTarget {
+    type Err = String;
+
+    /// Parses "-" (stdout), "http://host[:port]/path" (default port: 8086),
+    /// or anything else as a file path.
+    fn from_str(target: &str) -> Result<InfluxTarget, String> {
+        if target == "-" {
+            return Ok(InfluxTarget::Stdout);
+        }
+        if target.starts_with("https://") {
+            return Err("https is not supported".to_string());
+        }
+        let Some(url) = target.strip_prefix("http://") else {
+            return Ok(InfluxTarget::File(PathBuf::from(target)));
+        };
+        let (address, path) = match url.find('/') {
+            Some(index) => url.split_at(index),
+            None => return Err(format!("{target} does not contain a path")),
+        };
+        if address.is_empty() {
+            return Err(format!("{target} does not contain a host"));
+        }
+        let address = if address.contains(':') {
+            address.to_string()
+        } else {
+            format!("{address}:8086")
+        };
+        Ok(InfluxTarget::Http {
+            address,
+            path: path.to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InfluxOptions {
+    pub target: InfluxTarget,
+    /// Prepended to all measurement names (default: "p8020").
+    pub measurement_prefix: String,
+    /// Sent as "Authorization: Token <token>" to HTTP targets, if set.
+    pub token: Option<String>,
+    /// The number of lines that may be waiting to be written. Once reached,
+    /// the oldest sample lines (DeviceNotification::Sample,
+    /// TestNotification::Sample, and TestNotification::LiveFF) are dropped,
+    /// whereas writing any other line blocks until there is space
+    /// (default: 10000).
+    pub max_queued_lines: usize,
+}
+
+impl Default for InfluxOptions {
+    fn default() -> Self {
+        InfluxOptions {
+            target: InfluxTarget::Stdout,
+            measurement_prefix: "p8020".to_string(),
+            token: None,
+            max_queued_lines: 10_000,
+        }
+    }
+}
+
+/// InfluxSink formats notifications as line protocol, and writes them to the
+/// configured target. Pending lines are written before the sink is dropped.
+pub struct InfluxSink {
+    measurement_prefix: String,
+    lines: Arc<BoundedQueue<String>>,
+    writer_thread: Option<std::thread::JoinHandle<()>>,
+}
+
+// A destination for batches of lines.
+enum Writer {
+    Stdout,
+    File(std::fs::File),
+    Http {
+        address: String,
+        path: String,
+        token: Option<String>,
+    },
+}
+
+impl Writer {
+    fn write(&mut self, lines: &str) -> std::io::Result<()> {
+        match self {
+            Writer::Stdout => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(lines.as_bytes())?;
+                stdout.flush()
+            }
+            Writer::File(file) => file.write_all(lines.as_bytes()),
+            Writer::Http {
+                address,
+                path,
+                token,
+            } => post(address, path, token.as_deref(), lines),
+        }
+    }
+}
+
+fn post(address: &str, path: &str, token: Option<&str>, body: &str) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+    let authorization = token.map_or(String::new(), |token| {
+        format!("Authorization: Token {token}\r\n")
+    });
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: {address}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n{authorization}Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    let mut status_line = String::new();
+    std::io::BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(std::io::Error::other(format!(
+            "write rejected: {}",
+            status_line.trim()
+        ))),
+    }
+}
+
+impl InfluxSink {
+    /// Opens the target (if it is a file), and starts the writer thread.
+    /// HTTP targets are only connected to once lines are written.
+    pub fn open(options: InfluxOptions) -> std::io::Result<InfluxSink> {
+        let mut writer = match options.target {
+            InfluxTarget::Stdout => Writer::Stdout,
+            InfluxTarget::File(path) => Writer::File(
+                std::fs::File::options()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ),
+            InfluxTarget::Http { address, path } => Writer::Http {
+                address,
+                path,
+                token: options.token,
+            },
+        };
+        let lines = Arc::new(BoundedQueue::new(options.max_queued_lines));
+        let writer_thread = {
+            let lines = lines.clone();
+            std::thread::spawn(move || {
+                // Lines that arrive while a batch is being written are
+                // combined into the next batch.
+                while let Some(batch) = lines.pop_all() {
+                    if let Err(e) = writer.write(&batch.join("")) {
+                        log::warn!("unable to write to InfluxDB target: {e}");
+                    }
+                }
+            })
+        };
+        Ok(InfluxSink {
+            measurement_prefix: options.measurement_prefix,
+            lines,
+            writer_thread: Some(writer_thread),
+        })
+    }
+
+    fn write(&self, line: Option<String>, policy: OverflowPolicy) {
+        if let Some(line) = line {
+            // The queue is only closed once the sink is dropped.
+            self.lines.push(line, policy);
+        }
+    }
+
+    pub fn write_device_notification(&self, device: &str, notification: &DeviceNotification) {
+        let policy = match notification {
+            DeviceNotification::Sample { .. } => OverflowPolicy::DropOldest,
+            _ => OverflowPolicy::Block,
+        };
+        self.write(
+            device_line(
+                &self.measurement_prefix,
+                device,
+                notification,
+                SystemTime::now(),
+            ),
+            policy,
+        );
+    }
+
+    pub fn write_test_notification(&self, device: &str, notification: &TestNotification) {
+        let policy = match notification {
+            TestNotification::Sample(_) | TestNotification::LiveFF { .. } => {
+                OverflowPolicy::DropOldest
+            }
+            _ => OverflowPolicy::Block,
+        };
+        self.write(
+            test_line(
+                &self.measurement_prefix,
+                device,
+                notification,
+                SystemTime::now(),
+            ),
+            policy,
+        );
+    }
+
+    /// The number of lines that were dropped because the target could not
+    /// keep up, see InfluxOptions::max_queued_lines.
+    pub fn dropped_lines(&self) -> u64 {
+        self.lines.dropped()
+    }
+}
+
+impl Drop for InfluxSink {
+    fn drop(&mut self) {
+        self.lines.close();
+        if let Some(writer_thread) = self.writer_thread.take() {
+            let _ = writer_thread.join();
+        }
+    }
+}
+
+// Escapes measurement names, tag keys, and tag values.
+fn escape_key(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+enum Field {
+    Float(f64),
+    Integer(usize),
+    Bool(bool),
+    String(&'static str),
+}
+
+// Formats a single line. Non-finite floats are not representable, and are
+// therefore omitted, as is the entire line if no fields remain.
+fn line(
+    measurement: &str,
+    tags: &[(&str, &str)],
+    fields: &[(&str, Field)],
+    timestamp: SystemTime,
+) -> Option<String> {
+    let fields: Vec<String> = fields
+        .iter()
+        .filter_map(|(name, value)| {
+            let value = match value {
+                Field::Float(value) if value.is_finite() => value.to_string(),
+                Field::Float(_) => return None,
+                Field::Integer(value) => format!("{value}i"),
+                Field::Bool(value) => value.to_string(),
+                Field::String(value) => format!("\"{}\"", value.replace('"', "\\\"")),
+            };
+            Some(format!("{}={value}", escape_key(name)))
+        })
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    let mut line = escape_key(measurement);
+    for (name, value) in tags {
+        line.push_str(&format!(",{}={}", escape_key(name), escape_key(value)));
+    }
+    let timestamp = timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    Some(format!("{line} {} {timestamp}\n", fields.join(",")))
+}
+
+// Cadence and device properties are only relevant for diagnostics, and are
+// therefore omitted.
+fn device_line(
+    prefix: &str,
+    device: &str,
+    notification: &DeviceNotification,
+    timestamp: SystemTime,
+) -> Option<String> {
+    let tags = [("device", device)];
+    let event = |measurement: &str, event: &'static str| {
+        line(
+            &format!("{prefix}_{measurement}"),
+            &tags,
+            &[("event", Field::String(event))],
+            timestamp,
+        )
+    };
+    match notification {
+        DeviceNotification::Sample { particle_conc } => line(
+            &format!("{prefix}_sample"),
+            &tags,
+            &[("concentration", Field::Float(*particle_conc))],
+            timestamp,
+        ),
+        DeviceNotification::TestStarted => event("test", "started"),
+        DeviceNotification::TestCompleted { result } => {
+            let mut fields = vec![
+                (
+                    "overall_fit_factor",
+                    Field::Float(result.overall_fit_factor),
+                ),
+                ("exercises", Field::Integer(result.fit_factors.len())),
+            ];
+            if let Some(passed) = result.passed {
+                fields.push(("passed", Field::Bool(passed)));
+            }
+            line(
+                &format!("{prefix}_test_result"),
+                &[("device", device), ("protocol", &result.config_short_name)],
+                &fields,
+                timestamp,
+            )
+        }
+        DeviceNotification::TestCancelled => event("test", "cancelled"),
+        DeviceNotification::TestAborted => event("test", "aborted"),
+        DeviceNotification::ConnectionClosed => event("connection", "closed"),
+        DeviceNotification::ConnectionLost => event("connection", "lost"),
+        DeviceNotification::Reconnected => event("connection", "reconnected"),
+        DeviceNotification::Fault { .. } => event("connection", "fault"),
+        DeviceNotification::DeviceUnresponsive => event("connection", "unresponsive"),
+        DeviceNotification::DeviceProperties(_) | DeviceNotification::SampleCadence(_) => None,
+    }
+}
+
+// Only samples and FFs are written, the remaining notifications describe the
+// test's internals (which are available via the result's audit log).
+fn test_line(
+    prefix: &str,
+    device: &str,
+    notification: &TestNotification,
+    timestamp: SystemTime,
+) -> Option<String> {
+    match notification {
+        TestNotification::StateChange(TestState::StartedExercise(exercise)) => line(
+            &format!("{prefix}_test"),
+            &[("device", device)],
+            &[
+                ("event", Field::String("exercise_started")),
+                ("exercise", Field::Integer(*exercise)),
+            ],
+            timestamp,
+        ),
+        TestNotification::Sample(sample) => line(
+            &format!("{prefix}_test_sample"),
+            &[
+                ("device", device),
+                (
+                    "sample_type",
+                    match sample.sample_type {
+                        SampleType::AmbientPurge => "ambient_purge",
+                        SampleType::AmbientSample => "ambient_sample",
+                        SampleType::SpecimenPurge => "specimen_purge",
+                        SampleType::SpecimenSample => "specimen_sample",
+                    },
+                ),
+            ],
+            &[
+                ("stage", Field::Integer(sample.stage)),
+                ("exercise", Field::Integer(sample.exercise)),
+                ("concentration", Field::Float(sample.value)),
+            ],
+            timestamp,
+        ),
+        TestNotification::LiveFF {
+            exercise,
+            fit_factor,
+            ..
+        } => line(
+            &format!("{prefix}_live_ff"),
+            &[("device", device), ("exercise", &exercise.to_string())],
+            &[("fit_factor", Field::Float(*fit_factor))],
+            timestamp,
+        ),
+        TestNotification::ExerciseResult {
+            exercise,
+            fit_factor,
+            error,
+            ..
+        } => line(
+            &format!("{prefix}_exercise_result"),
+            &[("device", device), ("exercise", &exercise.to_string())],
+            &[
+                ("fit_factor", Field::Float(*fit_factor)),
+                ("error", Field::Float(*error)),
+            ],
+            timestamp,
+        ),
+        TestNotification::AmbientResult {
+            stage,
+            average,
+            error,
+        } => line(
+            &format!("{prefix}_ambient_result"),
+            &[("device", device), ("stage", &stage.to_string())],
+            &[
+                ("average", Field::Float(*average)),
+                ("error", Field::Float(*error)),
+            ],
+            timestamp,
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SampleData;
+    use std::io::Read;
+    use std::time::Duration;
+
+    const TIMESTAMP: SystemTime = SystemTime::UNIX_EPOCH;
+
+    #[test]
+    fn test_lines() {
+        assert_eq!(
+            device_line(
+                "p8020",
+                "mask fitting, 1",
+                &DeviceNotification::Sample {
+                    particle_conc: 1234.5
+                },
+                TIMESTAMP + Duration::from_millis(1500),
+            )
+            .unwrap(),
+            "p8020_sample,device=mask\\ fitting\\,\\ 1 concentration=1234.5 1500000000\n"
+        );
+        assert_eq!(
+            device_line("p8020", "0", &DeviceNotification::TestCancelled, TIMESTAMP).unwrap(),
+            "p8020_test,device=0 event=\"cancelled\" 0\n"
+        );
+        assert_eq!(
+            test_line(
+                "p8020",
+                "0",
+                &TestNotification::ExerciseResult {
+                    exercise: 2,
+                    fit_factor: 150.0,
+                    error: 3.5,
+                    raw_fit_factor: 150.2,
+                },
+                TIMESTAMP,
+            )
+            .unwrap(),
+            "p8020_exercise_result,device=0,exercise=2 fit_factor=150,error=3.5 0\n"
+        );
+        assert_eq!(
+            test_line(
+                "p8020",
+                "0",
+                &TestNotification::Sample(SampleData {
+                    stage: 1,
+                    exercise: 0,
+                    value: 12.5,
+                    sample_type: SampleType::SpecimenSample,
+                }),
+                TIMESTAMP,
+            )
+            .unwrap(),
+            "p8020_test_sample,device=0,sample_type=specimen_sample stage=1i,exercise=0i,concentration=12.5 0\n"
+        );
+        // Non-finite values cannot be represented.
+        assert_eq!(
+            device_line(
+                "p8020",
+                "0",
+                &DeviceNotification::Sample {
+                    particle_conc: f64::NAN
+                },
+                TIMESTAMP
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!("-".parse(), Ok(InfluxTarget::Stdout));
+        assert_eq!(
+            "samples.lp".parse(),
+            Ok(InfluxTarget::File(PathBuf::from("samples.lp")))
+        );
+        assert_eq!(
+            "http://influx/api/v2/write?org=o&bucket=b".parse(),
+            Ok(InfluxTarget::Http {
+                address: "influx:8086".to_string(),
+                path: "/api/v2/write?org=o&bucket=b".to_string()
+            })
+        );
+        assert_eq!(
+            "http://localhost:9999/write?db=p8020".parse(),
+            Ok(InfluxTarget::Http {
+                address: "localhost:9999".to_string(),
+                path: "/write?db=p8020".to_string()
+            })
+        );
+        assert!("http://influx".parse::<InfluxTarget>().is_err());
+        assert!("https://influx/write".parse::<InfluxTarget>().is_err());
+    }
+
+    #[test]
+    fn test_http_target() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            let mut buf = [0u8; 1024];
+            // Reads until the entire body (see Content-Length) has arrived.
+            loop {
+                if let Some((headers, body)) = request.split_once("\r\n\r\n") {
+                    let content_length = headers
+                        .lines()
+                        .find_map(|header| header.strip_prefix("Content-Length: "))
+                        .unwrap()
+                        .parse::<usize>()
+                        .unwrap();
+                    if body.len() >= content_length {
+                        break;
+                    }
+                }
+                let read = stream.read(&mut buf).unwrap();
+                request.push_str(std::str::from_utf8(&buf[..read]).unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            request
+        });
+
+        let sink = InfluxSink::open(InfluxOptions {
+            target: InfluxTarget::Http {
+                address,
+                path: "/write?db=p8020".to_string(),
+            },
+            token: Some("secret".to_string()),
+            ..InfluxOptions::default()
+        })
+        .unwrap();
+        sink.write_device_notification("0", &DeviceNotification::TestStarted);
+        // Waits for the writer thread to finish.
+        drop(sink);
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /write?db=p8020 HTTP/1.1\r\n"));
+        assert!(request.contains("Authorization: Token secret\r\n"));
+        assert!(request.contains("\r\n\r\np8020_test,device=0 event=\"started\" "));
+    }
+}
@@ -0,0 +1,34 @@
+// Helpers for the hand-written JSON produced by exporters (which must not
+// depend on the serde feature).
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+pub(crate) fn json_optional_string(value: Option<&str>) -> String {
+    value.map_or("null".to_string(), json_string)
+}
+
+// JSON has no representation of NaN/infinity.
+pub(crate) fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+pub(crate) fn json_bool(value: Option<bool>) -> String {
+    value.map_or("null".to_string(), |value| value.to_string())
+}
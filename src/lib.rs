@@ -1,20 +1,62 @@
+#[cfg(feature = "ffi")]
 extern crate libc;
 extern crate serialport;
 
+pub mod cadence;
+pub mod checks;
+mod executor;
+#[cfg(feature = "ffi")]
 mod ffi;
+pub mod group;
+#[cfg(feature = "influx")]
+pub mod influx;
+mod json;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod parse_failures;
 pub mod protocol;
+pub mod queue;
+pub mod record;
+pub mod samples;
+pub mod simulator;
+#[cfg(feature = "store")]
+pub mod store;
 mod test;
 pub mod test_config;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
-use serialport::SerialPortInfo;
-use std::io::BufRead;
+use serialport::{SerialPort, SerialPortInfo};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{BufRead, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use protocol::{Command, Message, SettingMessage};
-use test::{StepOutcome, Test};
+use cadence::{CadenceStatistics, CadenceTracker, CadenceWarning};
+use executor::{CallbackExecutor, DeviceCallback};
+#[cfg(feature = "metrics")]
+use metrics::MetricsRecorder;
 
+use protocol::{Command, CommandClass, Message, SettingMessage};
+use test::{CommandSink, StepOutcome, Test};
+
+pub use test::harness;
+pub use test::{
+    AdaptivePurge, AmbientStrategy, AuditEntry, AuditEvent, BarrierMode, BeepDuration,
+    DiscardPolicy, DiscardReason, DisplayPolicy, EarlyStopping, ExerciseBarrier, MinimumAmbient,
+    MinimumAmbientAction, RoundingPolicy, SampleData, SampleType, SoundPolicy, TestNotification,
+    TestOptions, TestResult, TestState, ValveMode, ValvePosition,
+};
+
+#[derive(Clone)]
 enum ValveState {
     Specimen,
     AwaitingAmbient,
@@ -22,14 +64,102 @@ enum ValveState {
     AwaitingSpecimen,
 }
 
+/// DeviceModel identifies the type of device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub enum DeviceModel {
+    Unknown,
+    /// An 8020 or 8020A: both models report identical settings, and cannot
+    /// be distinguished via the serial protocol.
+    Model8020,
+}
+
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceProperties {
+    pub model: DeviceModel,
     pub serial_number: String,
     pub run_time_since_last_service_hours: f64,
     pub last_service_month: u8,
     pub last_service_year: u16,
+    // The following settings apply to tests run directly on the device (not
+    // to tests run by this library). None if the device did not report them.
+    pub ambient_purge_seconds: Option<usize>,
+    pub ambient_sample_seconds: Option<usize>,
+    pub mask_purge_seconds: Option<usize>,
+    /// Mask sample time for each exercise, in order (see
+    /// SettingMessage::MaskSampleTime).
+    pub mask_sample_seconds: Vec<usize>,
+    /// FF pass level for each exercise, in order.
+    pub fit_factor_pass_levels: Vec<usize>,
+    /// Whether an N95-Companion (Model 8095) is attached and enabled. The
+    /// 8020 does not report this, i.e. this is only true if declared via
+    /// ConnectOptions::n95_companion.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub n95_companion: bool,
 }
 
+impl DeviceProperties {
+    /// Returns a best-effort fingerprint of the device's firmware, e.g.
+    /// "s8-t1-m12-p12". The 8020 family has no command to query the firmware
+    /// revision, instead the fingerprint summarises how the device reports
+    /// its settings: the length of the serial number (s, the Technical
+    /// Addendum specifies 5 digits but 8020As report 8), whether test
+    /// settings were reported (t), and the number of mask sample times (m, 13
+    /// includes 8010 mode) and pass levels (p). The latter may also depend on
+    /// the device's configuration. Fingerprints should therefore only be
+    /// compared as a hint, e.g. when correlating bug reports.
+    pub fn firmware_fingerprint(&self) -> String {
+        let reports_test_settings = self.ambient_purge_seconds.is_some()
+            || self.ambient_sample_seconds.is_some()
+            || self.mask_purge_seconds.is_some();
+        format!(
+            "s{}-t{}-m{}-p{}",
+            self.serial_number.chars().count(),
+            u8::from(reports_test_settings),
+            self.mask_sample_seconds.len(),
+            self.fit_factor_pass_levels.len()
+        )
+    }
+}
+
+/// DeviceId identifies the device that sent a DeviceNotification.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceId {
+    /// See ConnectOptions::index.
+    pub index: usize,
+    /// The device's serial number, once known (see
+    /// DeviceNotification::DeviceProperties).
+    pub serial_number: Option<String>,
+}
+
+/// ConnectionStats contains counters describing a device's connection since
+/// connecting, e.g. to diagnose a station that "feels slow" (see
+/// Device::stats()).
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionStats {
+    pub samples_received: u64,
+    pub commands_sent: u64,
+    /// Messages that could not be parsed (see
+    /// ConnectOptions::parse_failure_log).
+    pub parse_errors: u64,
+    /// Samples that were received during a test, but not used for its
+    /// results (see TestNotification::SampleDiscarded).
+    pub discarded_samples: u64,
+    /// How often the connection was reestablished (see
+    /// ConnectOptions::auto_reconnect).
+    pub reconnects: u64,
+    /// Notifications that were dropped because callbacks did not keep up
+    /// (only samples are dropped, see queue::OverflowPolicy::DropOldest).
+    pub dropped_notifications: u64,
+    /// When the last message (including unparseable messages) was received.
+    pub last_message_at: Option<SystemTime>,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DeviceNotification {
     /// Sample indicates a fresh reading from the PC. It is safe to assume
     /// that it was delivered 1s (plus/minus the 8020's internal delays) after
@@ -42,78 +172,292 @@ pub enum DeviceNotification {
     },
     TestStarted,
     TestCompleted {
-        fit_factors: Vec<f64>,
+        result: TestResult,
     },
     TestCancelled,
+    /// TestAborted indicates that the test was ended by the test engine
+    /// without producing results, e.g. because ambient concentration was
+    /// insufficient (see TestOptions::minimum_ambient), or because of a
+    /// Fault.
+    TestAborted,
     ConnectionClosed,
+    /// ConnectionLost indicates that the connection was lost, and that
+    /// reconnection will be attempted (see ConnectOptions::auto_reconnect).
+    ConnectionLost,
+    /// Reconnected indicates that the connection was reestablished after
+    /// ConnectionLost. Device properties will be sent again.
+    Reconnected,
     DeviceProperties(DeviceProperties),
+    /// SampleCadence indicates that samples are arriving significantly
+    /// faster or slower than the expected 1Hz (or that the cadence has
+    /// recovered). Stage durations are defined in terms of sample counts,
+    /// therefore a drifting cadence means that stages will not last as long as
+    /// expected. See Device::sample_cadence() for more detailed statistics.
+    SampleCadence(CadenceWarning),
+    /// Fault indicates that the device loop panicked because of a bug
+    /// (callbacks are run separately, and may panic without affecting the
+    /// device loop). It is followed by TestAborted if a test was
+    /// running. If restarted is true, the loop was restarted on the existing
+    /// connection (see ConnectOptions::restart_on_fault), otherwise the
+    /// connection is closed (see ConnectionClosed).
+    Fault {
+        message: String,
+        restarted: bool,
+    },
+    /// DeviceUnresponsive indicates that nothing was received from the
+    /// device within ConnectOptions::unresponsive_timeout of connecting, nor
+    /// within the same period after probing it again (by requesting its
+    /// settings), i.e. the port is open but the device is most likely
+    /// switched off. Probing continues: DeviceProperties are sent as usual
+    /// once the device is switched on.
+    DeviceUnresponsive,
 }
 
+// Actions are rare (at most a handful per test), the size of StartTest is
+// therefore irrelevant.
+#[allow(clippy::large_enum_variant)]
 pub enum Action {
     StartTest {
         config: test_config::TestConfig,
+        options: test::TestOptions,
         test_callback: test::TestCallback,
     },
     CancelTest,
+    /// Drops all queued commands that aren't critical (i.e. display and
+    /// beep commands). Valve and control commands are always sent.
+    FlushCommands,
+    /// Confirms that the operator has connected the tube for position, see
+    /// ValveMode::Manual.
+    ConfirmManualSwitch(ValvePosition),
+    /// Cancels the running test (if any), releases the device from external
+    /// control, and closes the connection (see
+    /// DeviceNotification::ConnectionClosed).
+    CloseConnection,
+}
+
+/// ConnectOptions contains settings for the serial connection to a device.
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    /// Must match the baud rate configured on the device (default: 1200, the
+    /// device's default).
+    pub baud_rate: u32,
+    /// How long reads may block for while waiting for the remainder of a
+    /// line (default: 100ms). On non-unix platforms, this also determines
+    /// how quickly actions (e.g. Device::start_test) are handled, and how
+    /// quickly the connection is closed after the Device is dropped.
+    pub read_timeout: Duration,
+    /// The minimum interval between commands sent to the device, which
+    /// ignores commands that are sent in quick succession (default: 100ms).
+    pub command_interval: Duration,
+    /// Overrides command_interval for the commands of specific classes, i.e.
+    /// the interval after sending such a command (default: none). 8020As
+    /// have been observed to ignore commands sent within ~52ms of the
+    /// previous command, i.e. e.g. display updates can safely be sped up
+    /// somewhat.
+    pub command_class_intervals: HashMap<CommandClass, Duration>,
+    /// Whether to reopen the port if the connection is lost (e.g. because the
+    /// device was unplugged), instead of closing the connection. Any running
+    /// test is cancelled when the connection is lost.
+    pub auto_reconnect: bool,
+    /// How long to wait between reconnection attempts (default: 1s).
+    pub reconnect_interval: Duration,
+    /// Whether to restart the device loop on the existing connection if it
+    /// panics, instead of closing the connection (default: false). The loop
+    /// is restarted at most 3 times, see DeviceNotification::Fault.
+    pub restart_on_fault: bool,
+    /// Whether an N95-Companion (Model 8095) is attached and enabled
+    /// (default: false). The 8020 does not report this via the serial
+    /// protocol, i.e. it must be declared by the operator. Applies to all
+    /// tests, see TestOptions::n95_companion.
+    pub n95_companion: bool,
+    /// How long to wait for the device to send anything after connecting,
+    /// before probing it again and then sending
+    /// DeviceNotification::DeviceUnresponsive (default: 5s). None disables
+    /// the check.
+    pub unresponsive_timeout: Option<Duration>,
+    /// Identifies the device in DeviceId, e.g. its position within a
+    /// DeviceGroup (default: 0).
+    pub index: usize,
+    /// Records this device's metrics (labelled with index) if set, see
+    /// metrics::Metrics.
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<Arc<metrics::Metrics>>,
+    /// Records all messages received from the device that could not be
+    /// parsed if set (default: None).
+    pub parse_failure_log: Option<Arc<parse_failures::ParseFailureLog>>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            baud_rate: 1200,
+            read_timeout: Duration::from_millis(100),
+            command_interval: Duration::from_millis(100),
+            command_class_intervals: HashMap::new(),
+            auto_reconnect: false,
+            reconnect_interval: Duration::from_secs(1),
+            restart_on_fault: false,
+            n95_companion: false,
+            unresponsive_timeout: Some(Duration::from_secs(5)),
+            index: 0,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+            parse_failure_log: None,
+        }
+    }
 }
 
+// Without the metrics feature, nothing is recorded (see
+// metrics::MetricsRecorder).
+#[cfg(not(feature = "metrics"))]
+#[derive(Clone)]
+struct MetricsRecorder;
+
+#[cfg(not(feature = "metrics"))]
+impl MetricsRecorder {
+    fn new(_: &ConnectOptions) -> MetricsRecorder {
+        MetricsRecorder
+    }
+
+    fn connection_opened(&self) {}
+
+    fn command_sent(&self) {}
+
+    fn parse_error(&self) {}
+
+    fn notification(&self, _: &DeviceNotification) {}
+
+    fn wrap_test_callback(&self, callback: test::TestCallback) -> test::TestCallback {
+        callback
+    }
+}
+
+/// Returns the available serial ports, optionally only USB ports (all
+/// PortaCounts are connected via USB adapters in practice).
+pub fn list_ports(usb_only: bool) -> serialport::Result<Vec<SerialPortInfo>> {
+    let ports = serialport::available_ports()?;
+    Ok(if usb_only {
+        ports
+            .into_iter()
+            .filter(|port| matches!(port.port_type, serialport::SerialPortType::UsbPort(..)))
+            .collect()
+    } else {
+        ports
+    })
+}
+
+/// Checks whether a PortaCount is connected to the port at path, by
+/// requesting its settings. Returns the device's properties, or None if no
+/// settings were received within timeout. The device is released from
+/// external control afterwards, i.e. this must not be used on a port that is
+/// already in use.
+pub fn probe_port(path: &str, timeout: Duration) -> serialport::Result<Option<DeviceProperties>> {
+    let stats = Arc::new(Mutex::new(ConnectionStats::default()));
+    let mut connection = Connection::open(path, &ConnectOptions::default(), stats)?;
+    // Never woken, there are no actions.
+    let wakeup = Wakeup::new()?;
+    let commands = CommandQueue::default();
+    let _ = commands.send(Command::EnterExternalControl);
+    let _ = commands.send(Command::RequestSettings);
+
+    let deadline = Instant::now() + timeout;
+    let mut collector = DevicePropertiesCollector::new(false);
+    let properties = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break None;
+        }
+        match connection.poll(&commands, &wakeup, Some(remaining)) {
+            Ok(Some(Message::Setting(setting))) => {
+                if let Some(DeviceNotification::DeviceProperties(properties)) =
+                    collector.process(setting)
+                {
+                    break Some(properties);
+                }
+            }
+            Ok(_) => (),
+            // The connection was lost.
+            Err(_) => return Ok(None),
+        }
+    };
+    let _ = commands.send(Command::ExitExternalControl);
+    // Failures are irrelevant, the device can't be controlled either way.
+    let _ = connection.flush(&commands);
+    Ok(properties)
+}
+
+// The number of actions that may be queued before Device's methods block.
+const ACTION_QUEUE_CAPACITY: usize = 16;
+
+// How long dropping a Device waits for the device thread to exit, see
+// Device::close for a graceful alternative.
+const DROP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long Device::disconnect waits for the connection to be closed.
+pub const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// CloseError describes why Device::close failed.
+#[derive(Debug)]
+pub enum CloseError {
+    /// The device thread did not exit within the timeout (e.g. because a
+    /// callback is blocked, or a write to the port is stuck). The thread is
+    /// detached, and will exit once it is unblocked.
+    TimedOut,
+    /// The device could not be released from external control, because the
+    /// connection had already been lost (ErrorKind::NotConnected) or failed
+    /// while closing. The device may therefore still be showing its external
+    /// control screen.
+    Unclean(std::io::Error),
+}
+
+/// DisconnectedError is returned when an action cannot be sent to a device,
+/// because its connection has been closed (or was lost, and reconnection
+/// failed or was not attempted).
+#[derive(Debug)]
+pub struct DisconnectedError;
+
 pub struct Device {
-    tx_action: Sender<Action>,
+    tx_action: SyncSender<Action>,
+    wakeup: Arc<Wakeup>,
+    // None once the thread has been joined or detached.
+    device_thread: Option<thread::JoinHandle<()>>,
+    // Receives any errors that prevented the connection from being closed
+    // cleanly, and is disconnected once the device thread has exited (see
+    // wait_for_device_thread). Wrapped in a Mutex so that Device is Sync.
+    rx_thread_exited: Mutex<Receiver<std::io::Error>>,
+    cadence_tracker: Arc<Mutex<CadenceTracker>>,
+    id: Arc<Mutex<DeviceId>>,
+    stats: Arc<Mutex<ConnectionStats>>,
 }
 
 impl Device {
     // TODO: add proper error handling (once I've figured out what an
     // appropriate approach is in conjunction with FFI)
-    // TODO: switch to a builder pattern for params such as baud rate.
-    // Hopefully no one is using other baud rates, but it'd be interesting to
-    // experiment regardless.
     pub fn connect(
         port_info: SerialPortInfo,
-        device_callback: Option<impl Fn(DeviceNotification) + 'static + std::marker::Send>,
+        device_callback: Option<
+            impl Fn(&DeviceId, DeviceNotification) + 'static + std::marker::Send,
+        >,
     ) -> serialport::Result<Device> {
         Device::connect_path(port_info.port_name, device_callback)
     }
 
     pub fn connect_path(
         path: String,
-        device_callback: Option<impl Fn(DeviceNotification) + 'static + std::marker::Send>,
+        device_callback: Option<
+            impl Fn(&DeviceId, DeviceNotification) + 'static + std::marker::Send,
+        >,
     ) -> serialport::Result<Device> {
-        // See "PortaCount Plus Model 8020 Technical Addendum" for specs.
-        // Note: baud is configurable on the devices itself, 1200 is the default.
-        let port = serialport::new(path, /* baud_rate */ 1200)
-            .data_bits(serialport::DataBits::Eight)
-            .parity(serialport::Parity::None)
-            .stop_bits(serialport::StopBits::One)
-            .flow_control(serialport::FlowControl::Hardware)
-            // The timeout is relevant for receiver_thread's behaviour (below).
-            .timeout(core::time::Duration::from_millis(100))
-            .open()?;
-
-        // OSX-only (possibly AppleUSBFTDI-only): if the device is already
-        // regularly transmitting data (e.g. because it's already in
-        // external-control mode), then the input buffer will start with some
-        // nulls and junk (in my case it's consistently:
-        // [0, 0, 0, 0, 0, 0, 0, 0, 0, 'é', 'r', 'é', 'é', 'j', LF] followed by
-        // normal programming). This breaks BufReader.
-        // The output buffer is also affected by some kind of similar issue,
-        // waiting a little and clearing buffers appears to work well enough so
-        // NBD. This isn't entirely surprising given that the port is opened
-        // first, followed by setting attributes (baud etc.) - but seemingly
-        // this process takes longer on OSX vs Linux.
-        if cfg!(target_os = "macos") {
-            std::thread::sleep(std::time::Duration::from_millis(500));
-            let clear_result = port.clear(serialport::ClearBuffer::All);
-            eprintln!("OSX clear-input-buffer-hack result: {clear_result:?}")
-        }
-
-        // Cloning here is a bit ugly - it's necessary because we want to split reads
-        // and writes, and Serialport implements both in the same object. Read and
-        // writes are mutating, hence an Arc is insufficient. A (rust) Mutex also
-        // doesn't work because reads and writes need to be independent. Writing
-        // some kind of custom wrapper (possibly involving) unsafe might work, but
-        // cloning is good enough.
-        let reader = std::io::BufReader::new(port.try_clone().unwrap());
+        Device::connect_path_with_options(path, ConnectOptions::default(), device_callback)
+    }
 
+    pub fn connect_path_with_options(
+        path: String,
+        options: ConnectOptions,
+        device_callback: Option<
+            impl Fn(&DeviceId, DeviceNotification) + 'static + std::marker::Send,
+        >,
+    ) -> serialport::Result<Device> {
         // Implementing a test is quite easy - all you need is a big loop (which is
         // what the prototype did). Most of the complexity stems from handling:
         // - Cancellation: users may wish to stop a test, so we need some kind of
@@ -121,22 +465,212 @@ impl Device {
         // - Disconnection: the user may wish to disconnect (independently of the
         //   test), or the device may disconnect. Handling this gracefully likewise
         //   adds complexity.
-        // Therefore we end up with a more complex multi-thread implementation. An
-        // async design is probably also feasible, tbc.
+        // Therefore each device is handled by a single thread, running an event
+        // loop that multiplexes reads from the port, command pacing, and
+        // actions sent by Device (see run_connection).
+        let stats = Arc::new(Mutex::new(ConnectionStats::default()));
+        let connection = Connection::open(&path, &options, stats.clone())?;
+        // Actions must never be dropped, instead callers block if the device
+        // thread falls behind (which should only happen if callbacks are
+        // extremely slow).
+        let (tx_action, rx_action) = mpsc::sync_channel(ACTION_QUEUE_CAPACITY);
+        let wakeup = Arc::new(Wakeup::new()?);
+
+        let cadence_tracker = Arc::new(Mutex::new(CadenceTracker::new()));
+        let id = Arc::new(Mutex::new(DeviceId {
+            index: options.index,
+            serial_number: None,
+        }));
+
+        let metrics = MetricsRecorder::new(&options);
+        let restart_on_fault = options.restart_on_fault;
+        let reconnector = options.auto_reconnect.then(|| {
+            let reconnect_interval = options.reconnect_interval;
+            let stats = stats.clone();
+            let reconnector: Reconnector =
+                Box::new(move || Connection::open(&path, &options, stats.clone()));
+            (reconnect_interval, reconnector)
+        });
+        let (tx_thread_exited, rx_thread_exited) = mpsc::channel();
+        let actions = ActionReceiver {
+            rx_action,
+            wakeup: wakeup.clone(),
+            tx_thread_exited,
+        };
+        let device_thread = start_device_thread(
+            actions,
+            connection,
+            Recovery {
+                reconnector,
+                restart_on_fault,
+            },
+            cadence_tracker.clone(),
+            id.clone(),
+            metrics,
+            device_callback,
+        );
+
+        Ok(Device {
+            tx_action,
+            wakeup,
+            device_thread: Some(device_thread),
+            rx_thread_exited: Mutex::new(rx_thread_exited),
+            cadence_tracker,
+            id,
+            stats,
+        })
+    }
+
+    /// Returns the identity of this device (the serial number is only
+    /// available once the device has reported its properties).
+    pub fn id(&self) -> DeviceId {
+        self.id.lock().unwrap().clone()
+    }
 
-        let (tx_action, rx_action): (Sender<Action>, Receiver<Action>) = mpsc::channel();
-        let (tx_command, rx_command): (Sender<Command>, Receiver<Command>) = mpsc::channel();
-        // Option::None is used as a check-alive signal (see details in
-        // start_receiver_thread).
-        let (tx_message, rx_message): (Sender<Option<Message>>, Receiver<Option<Message>>) =
-            mpsc::channel();
+    /// Returns statistics about the interval between samples received from
+    /// the device since connecting.
+    pub fn sample_cadence(&self) -> CadenceStatistics {
+        self.cadence_tracker.lock().unwrap().statistics()
+    }
+
+    /// Returns counters describing the connection since connecting
+    /// (including any reconnections).
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.lock().unwrap().clone()
+    }
 
-        let _device_thread =
-            start_device_thread(rx_action, rx_message, tx_command, device_callback);
-        let _sender_thread = start_sender_thread(port, rx_command);
-        let _receiver_thread = start_receiver_thread(reader, tx_message);
+    /// Starts a test, replacing any test that is already running. Results
+    /// are delivered via DeviceNotification::TestCompleted. Fails if the
+    /// connection has been closed, in which case no notifications will be
+    /// delivered for the test.
+    pub fn start_test(
+        &self,
+        config: test_config::TestConfig,
+        options: TestOptions,
+        test_callback: test::TestCallback,
+    ) -> Result<(), DisconnectedError> {
+        self.send_action(Action::StartTest {
+            config,
+            options,
+            test_callback,
+        })
+    }
 
-        Ok(Device { tx_action })
+    /// Cancels the running test (if any), see
+    /// DeviceNotification::TestCancelled. Fails if the connection has been
+    /// closed (in which case no test can be running).
+    pub fn cancel_test(&self) -> Result<(), DisconnectedError> {
+        self.send_action(Action::CancelTest)
+    }
+
+    /// Drops all queued display and beep commands, see Action::FlushCommands.
+    pub fn flush_commands(&self) -> Result<(), DisconnectedError> {
+        self.send_action(Action::FlushCommands)
+    }
+
+    /// Confirms that the operator has connected the ambient or specimen tube,
+    /// as requested via TestNotification::ValveSwitchRequested. Only relevant
+    /// for tests using ValveMode::Manual.
+    pub fn confirm_manual_switch(&self, position: ValvePosition) -> Result<(), DisconnectedError> {
+        self.send_action(Action::ConfirmManualSwitch(position))
+    }
+
+    /// Closes the connection once all critical commands have been sent, see
+    /// Action::CloseConnection. No further actions may be sent afterwards.
+    /// Does nothing if the connection is already closed. Reconnection is no
+    /// longer attempted if the connection was lost.
+    pub fn close_connection(&self) {
+        self.request_close();
+    }
+
+    /// Closes the connection (see close_connection), and waits up to
+    /// timeout for it to be closed. DeviceNotification::ConnectionClosed has
+    /// been delivered if this succeeds, and the device has been released
+    /// from external control.
+    pub fn close(mut self, timeout: Duration) -> Result<(), CloseError> {
+        let sent = self.request_close();
+        let result = self.wait_for_device_thread(timeout);
+        if let Err(CloseError::TimedOut) = result {
+            // Don't wait a second time when dropping self.
+            self.device_thread = None;
+        }
+        if !sent && result.is_ok() {
+            return Err(CloseError::Unclean(std::io::ErrorKind::NotConnected.into()));
+        }
+        result
+    }
+
+    /// Closes the connection, see close. Waits for up to DISCONNECT_TIMEOUT.
+    pub fn disconnect(self) -> Result<(), CloseError> {
+        self.close(DISCONNECT_TIMEOUT)
+    }
+
+    // Fails if the device thread has already exited.
+    fn send_action(&self, action: Action) -> Result<(), DisconnectedError> {
+        self.tx_action.send(action).map_err(|_| DisconnectedError)?;
+        self.wakeup.wake();
+        Ok(())
+    }
+
+    // Returns false if the device thread had already exited.
+    fn request_close(&self) -> bool {
+        if self.tx_action.send(Action::CloseConnection).is_err() {
+            return false;
+        }
+        // CloseConnection is only handled while connected, reconnection
+        // attempts must therefore be stopped separately.
+        self.wakeup.set_closing();
+        self.wakeup.wake();
+        true
+    }
+
+    // Waits for the device thread to exit (and joins it), and returns any
+    // errors that prevented the connection from being closed cleanly.
+    // Succeeds immediately if the thread was already joined or detached.
+    fn wait_for_device_thread(&mut self, timeout: Duration) -> Result<(), CloseError> {
+        if self.device_thread.is_none() {
+            return Ok(());
+        }
+        // Waiting on the device thread from within a callback would
+        // deadlock, as the device thread waits for callbacks to return
+        // before exiting.
+        if executor::is_callback_thread() {
+            self.device_thread = None;
+            return Ok(());
+        }
+        let deadline = Instant::now() + timeout;
+        let rx_thread_exited = self.rx_thread_exited.get_mut().unwrap();
+        let mut result = Ok(());
+        loop {
+            match rx_thread_exited.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(e) => result = Err(CloseError::Unclean(e)),
+                Err(RecvTimeoutError::Timeout) => return Err(CloseError::TimedOut),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        if let Some(device_thread) = self.device_thread.take() {
+            if device_thread.join().is_err() {
+                log::error!(device = self.id.lock().unwrap().index; "device thread panicked");
+            }
+        }
+        result
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        // The device thread would otherwise only notice that tx_action was
+        // dropped once the device sends its next message.
+        self.wakeup.close();
+        // Waiting is best-effort: the device thread is detached if it is
+        // wedged (e.g. in a callback, or writing to the port).
+        if let Err(CloseError::TimedOut) = self.wait_for_device_thread(DROP_TIMEOUT) {
+            log::warn!(
+                device = self.id.lock().unwrap().index;
+                "device thread did not exit within {DROP_TIMEOUT:?}, detaching it"
+            );
+        }
     }
 }
 
@@ -145,15 +679,28 @@ struct DevicePropertiesCollector {
     run_time_since_last_service_hours: Option<f64>,
     last_service_month: Option<u8>,
     last_service_year: Option<u16>,
+    ambient_purge_seconds: Option<usize>,
+    ambient_sample_seconds: Option<usize>,
+    mask_purge_seconds: Option<usize>,
+    // Keyed by exercise number, so that settings can arrive in any order.
+    mask_sample_seconds: BTreeMap<usize, usize>,
+    fit_factor_pass_levels: BTreeMap<usize, usize>,
+    n95_companion: bool,
 }
 
 impl DevicePropertiesCollector {
-    fn new() -> DevicePropertiesCollector {
+    fn new(n95_companion: bool) -> DevicePropertiesCollector {
         DevicePropertiesCollector {
             serial_number: None,
             run_time_since_last_service_hours: None,
             last_service_month: None,
             last_service_year: None,
+            ambient_purge_seconds: None,
+            ambient_sample_seconds: None,
+            mask_purge_seconds: None,
+            mask_sample_seconds: BTreeMap::new(),
+            fit_factor_pass_levels: BTreeMap::new(),
+            n95_companion,
         }
     }
 
@@ -177,19 +724,49 @@ impl DevicePropertiesCollector {
                     year => 1900 + year as u16,
                 });
             }
-            _ => (),
+            SettingMessage::AmbientPurgeTime { seconds } => {
+                self.ambient_purge_seconds = Some(seconds);
+            }
+            SettingMessage::AmbientSampleTime { seconds } => {
+                self.ambient_sample_seconds = Some(seconds);
+            }
+            SettingMessage::MaskSamplePurgeTime { seconds } => {
+                self.mask_purge_seconds = Some(seconds);
+            }
+            SettingMessage::MaskSampleTime { ex, seconds } => {
+                self.mask_sample_seconds.insert(ex, seconds);
+            }
+            SettingMessage::FitFactorPassLevel { ex, fit_factor } => {
+                self.fit_factor_pass_levels.insert(ex, fit_factor);
+            }
         }
 
-        if self.serial_number.is_some()
-            && self.run_time_since_last_service_hours.is_some()
-            && self.last_service_month.is_some()
-            && self.last_service_year.is_some()
-        {
+        // The serial number, run time, and service date are the last settings
+        // sent in response to RequestSettings.
+        if let (
+            Some(_),
+            Some(run_time_since_last_service_hours),
+            Some(last_service_month),
+            Some(last_service_year),
+        ) = (
+            &self.serial_number,
+            self.run_time_since_last_service_hours,
+            self.last_service_month,
+            self.last_service_year,
+        ) {
             Some(DeviceNotification::DeviceProperties(DeviceProperties {
+                // Only the 8020 family supports RequestSettings.
+                model: DeviceModel::Model8020,
                 serial_number: self.serial_number.take().unwrap(),
-                run_time_since_last_service_hours: self.run_time_since_last_service_hours.unwrap(),
-                last_service_month: self.last_service_month.unwrap(),
-                last_service_year: self.last_service_year.unwrap(),
+                run_time_since_last_service_hours,
+                last_service_month,
+                last_service_year,
+                ambient_purge_seconds: self.ambient_purge_seconds,
+                ambient_sample_seconds: self.ambient_sample_seconds,
+                mask_purge_seconds: self.mask_purge_seconds,
+                mask_sample_seconds: self.mask_sample_seconds.values().copied().collect(),
+                fit_factor_pass_levels: self.fit_factor_pass_levels.values().copied().collect(),
+                n95_companion: self.n95_companion,
             }))
         } else {
             None
@@ -197,220 +774,915 @@ impl DevicePropertiesCollector {
     }
 }
 
-fn start_device_thread(
+// Commands that are waiting to be sent to the device (see Connection::poll).
+// Tests hold a reference to the queue while the device thread drains it, hence
+// the RefCell.
+// Commands are paced (see ConnectOptions::command_interval), which means that
+// display updates (which are sent for every sample) can pile up. Therefore
+// control and valve commands are sent before any other commands, and display
+// updates replace equivalent queued updates that have not been sent yet.
+#[derive(Default)]
+struct CommandQueue(RefCell<VecDeque<Command>>);
+
+// Whether command is sent before all commands that aren't prioritised (see
+// CommandQueue). Prioritised commands are sent in the order they were queued.
+fn is_prioritised(command: &Command) -> bool {
+    matches!(command.class(), CommandClass::Control | CommandClass::Valve)
+}
+
+impl CommandQueue {
+    fn pop(&self) -> Option<Command> {
+        let mut commands = self.0.borrow_mut();
+        match commands.iter().position(is_prioritised) {
+            Some(index) => commands.remove(index),
+            None => commands.pop_front(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
+    // Drops all commands that aren't prioritised, i.e. that don't affect the
+    // device's state beyond the display.
+    fn clear_non_critical(&self) {
+        self.0.borrow_mut().retain(is_prioritised);
+    }
+}
+
+impl CommandSink for CommandQueue {
+    fn send(&self, command: Command) -> Result<(), mpsc::SendError<Command>> {
+        let mut commands = self.0.borrow_mut();
+        // Only the most recent display command may be replaced, e.g. an
+        // update that precedes ClearDisplay must still be sent before it.
+        if command.class() == CommandClass::Display && !matches!(command, Command::ClearDisplay) {
+            if let Some(queued) = commands
+                .iter_mut()
+                .rev()
+                .find(|queued| queued.class() == CommandClass::Display)
+            {
+                if std::mem::discriminant(queued) == std::mem::discriminant(&command) {
+                    *queued = command;
+                    return Ok(());
+                }
+            }
+        }
+        commands.push_back(command);
+        Ok(())
+    }
+}
+
+// Reopens the port after the connection was lost, see
+// ConnectOptions::auto_reconnect.
+type Reconnector = Box<dyn Fn() -> serialport::Result<Connection> + Send>;
+
+// How a connection (i.e. a single call to run_connection) ended.
+enum ConnectionOutcome {
+    // The connection was closed via Action::CloseConnection, or the Device
+    // was dropped.
+    Closed,
+    // The port was closed or failed, e.g. because the device was unplugged.
+    Lost,
+}
+
+// The device thread's end of Device::tx_action and Device::wakeup (and
+// Device::rx_thread_exited).
+struct ActionReceiver {
     rx_action: Receiver<Action>,
-    rx_message: Receiver<Option<Message>>,
-    tx_command: Sender<Command>,
-    device_callback: Option<impl Fn(DeviceNotification) + 'static + std::marker::Send>,
+    wakeup: Arc<Wakeup>,
+    // Reports errors that prevented the connection from being closed
+    // cleanly (i.e. the device from being released from external control),
+    // and is dropped (thereby disconnecting the channel) once the thread
+    // exits.
+    tx_thread_exited: Sender<std::io::Error>,
+}
+
+// How the device thread recovers from failures, see ConnectOptions.
+struct Recovery {
+    // The interval between reconnection attempts, and a function to reopen
+    // the port. None if auto_reconnect is disabled.
+    reconnector: Option<(Duration, Reconnector)>,
+    restart_on_fault: bool,
+}
+
+// The maximum number of times that the device loop is restarted after
+// panicking (see ConnectOptions::restart_on_fault), which avoids restarting
+// indefinitely if e.g. a callback panics consistently.
+const MAX_FAULT_RESTARTS: u32 = 3;
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+fn start_device_thread(
+    actions: ActionReceiver,
+    connection: Connection,
+    recovery: Recovery,
+    cadence_tracker: Arc<Mutex<CadenceTracker>>,
+    id: Arc<Mutex<DeviceId>>,
+    metrics: MetricsRecorder,
+    device_callback: Option<impl Fn(&DeviceId, DeviceNotification) + 'static + std::marker::Send>,
 ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
+        // Callbacks are run by the executor, which delivers all queued
+        // notifications when dropped (i.e. when this thread exits).
+        let executor = CallbackExecutor::start(
+            id.lock().unwrap().clone(),
+            device_callback.map(|callback| Box::new(callback) as DeviceCallback),
+        );
+        // Whether a test is running, such that it can be aborted if the
+        // device loop panics.
+        let test_running = Cell::new(false);
+        let stats = connection.stats.clone();
         let send_notification = |notification: DeviceNotification| {
-            if let Some(callback) = &device_callback {
-                callback(notification);
-            }
-        };
-        let send_command = |command: Command| {
-            if let Err(e) = tx_command.send(command) {
-                // Do not send ConnectionClosed here - if the sender has closed,
-                // then we've probably lost the serial connection. In this case
-                // rx_message will also close, and we use that as the canonical
-                // indicator of connection loss. (rx_message is preferred for
-                // this purpose as we poll it frequently, whereas tx is rare.)
-                // Alternatively... the sender thread may have crashed, which
-                // is obviously a disaster.
-                // TODO: consider handling sender thread crashes gracefully too?
-                eprintln!("tx_command failed: {e:?}");
+            metrics.notification(&notification);
+            match &notification {
+                DeviceNotification::DeviceProperties(properties) => {
+                    id.lock().unwrap().serial_number = Some(properties.serial_number.clone());
+                }
+                DeviceNotification::TestStarted => test_running.set(true),
+                DeviceNotification::TestCompleted { .. }
+                | DeviceNotification::TestCancelled
+                | DeviceNotification::TestAborted => test_running.set(false),
+                _ => (),
             }
+            executor.device_notification(notification);
+            stats.lock().unwrap().dropped_notifications = executor.dropped_notifications();
         };
-
-        send_command(Command::EnterExternalControl);
-        send_command(Command::RequestSettings);
-        // TODO: loop and wait for confirmation of EnterExternalControl.
-
-        let mut test: Option<Test> = None;
-        // TODO: verify whether this is a safe assumption. It may be safer to set
-        // AwaitingSpecimen and request specimen?
-        let mut valve_state = ValveState::Specimen;
-        let mut device_properties_collector = DevicePropertiesCollector::new();
+        let mut connection = connection;
+        let mut fault_restarts = 0;
         loop {
-            // The duration is largely arbitrary, and chosen to hopefully
-            // provide sufficient responsiveness.
-            let message = match rx_message.recv_timeout(core::time::Duration::from_millis(50)) {
-                Ok(None) => None,
-                Ok(Some(msg)) => Some(msg),
-                Err(error) => match error {
-                    mpsc::RecvTimeoutError::Timeout => None,
-                    _ => {
-                        send_notification(DeviceNotification::ConnectionClosed);
-                        return;
+            let outcome = match panic::catch_unwind(AssertUnwindSafe(|| {
+                run_connection(
+                    &actions,
+                    &mut connection,
+                    &cadence_tracker,
+                    &metrics,
+                    &executor,
+                    &send_notification,
+                )
+            })) {
+                Ok(outcome) => outcome,
+                Err(payload) => {
+                    let message = panic_message(&*payload);
+                    log::error!(device = connection.device; "device loop panicked: {message}");
+                    let restarted = recovery.restart_on_fault
+                        && fault_restarts < MAX_FAULT_RESTARTS
+                        && !actions.wakeup.is_closed();
+                    send_notification(DeviceNotification::Fault { message, restarted });
+                    if test_running.get() {
+                        send_notification(DeviceNotification::TestAborted);
                     }
-                },
+                    if restarted {
+                        fault_restarts += 1;
+                        continue;
+                    }
+                    // The panic may have happened at any point, i.e. the
+                    // device may still be under external control.
+                    let commands = CommandQueue::default();
+                    let _ = commands.send(Command::ExitExternalControl);
+                    if let Err(e) = connection.flush(&commands) {
+                        let _ = actions.tx_thread_exited.send(e);
+                    }
+                    ConnectionOutcome::Closed
+                }
             };
-            if let Some(Message::Sample(value)) = message {
-                send_notification(DeviceNotification::Sample {
-                    particle_conc: value,
-                });
+            let lost = matches!(outcome, ConnectionOutcome::Lost);
+            let (true, Some((interval, reconnect))) = (lost, &recovery.reconnector) else {
+                if lost {
+                    let _ = actions
+                        .tx_thread_exited
+                        .send(std::io::ErrorKind::NotConnected.into());
+                }
+                send_notification(DeviceNotification::ConnectionClosed);
+                return;
+            };
+            send_notification(DeviceNotification::ConnectionLost);
+            loop {
+                std::thread::sleep(*interval);
+                // Stop trying once the connection is being closed, or the
+                // Device has been dropped.
+                if actions.wakeup.is_closing() || actions.wakeup.is_closed() {
+                    let _ = actions
+                        .tx_thread_exited
+                        .send(std::io::ErrorKind::NotConnected.into());
+                    send_notification(DeviceNotification::ConnectionClosed);
+                    return;
+                }
+                match reconnect() {
+                    Ok(new_connection) => {
+                        connection = new_connection;
+                        break;
+                    }
+                    Err(e) => {
+                        log::warn!(device = id.lock().unwrap().index; "reconnection failed: {e:?}")
+                    }
+                }
             }
+            connection.stats.lock().unwrap().reconnects += 1;
+            send_notification(DeviceNotification::Reconnected);
+        }
+    })
+}
+
+// Wraps callback, such that discarded samples are counted in stats.
+fn count_discarded_samples(
+    stats: Arc<Mutex<ConnectionStats>>,
+    callback: test::TestCallback,
+) -> test::TestCallback {
+    Some(Box::new(move |notification: &TestNotification| {
+        if let TestNotification::SampleDiscarded { .. } = notification {
+            stats.lock().unwrap().discarded_samples += 1;
+        }
+        if let Some(callback) = &callback {
+            callback(notification);
+        }
+    }))
+}
+
+// Runs the device loop for a single connection, until the connection is lost
+// or the Device is dropped.
+fn run_connection(
+    actions: &ActionReceiver,
+    connection: &mut Connection,
+    cadence_tracker: &Mutex<CadenceTracker>,
+    metrics: &MetricsRecorder,
+    executor: &CallbackExecutor,
+    send_notification: &dyn Fn(DeviceNotification),
+) -> ConnectionOutcome {
+    // Commands are only ever queued here, and are sent by connection.poll()
+    // (which is also responsible for detecting connection loss).
+    let commands = CommandQueue::default();
+    let send_command = |command: Command| {
+        let _ = commands.send(command);
+    };
 
-            match rx_action.try_recv() {
+    send_command(Command::EnterExternalControl);
+    send_command(Command::RequestSettings);
+    // TODO: loop and wait for confirmation of EnterExternalControl.
+
+    let mut test: Option<Test> = None;
+    // TODO: verify whether this is a safe assumption. It may be safer to set
+    // AwaitingSpecimen and request specimen?
+    let mut valve_state = ValveState::Specimen;
+    let mut device_properties_collector = DevicePropertiesCollector::new(connection.n95_companion);
+    // When to probe the device next, until anything is received (see
+    // ConnectOptions::unresponsive_timeout).
+    let mut probe_at = connection
+        .unresponsive_timeout
+        .map(|timeout| Instant::now() + timeout);
+    let mut probes = 0;
+    loop {
+        // poll() blocks until there is something to do: a message was
+        // received, a command is due, an action was sent (see Wakeup), or
+        // the device needs probing.
+        let timeout = probe_at.map(|probe_at| probe_at.saturating_duration_since(Instant::now()));
+        let message = match connection.poll(&commands, &actions.wakeup, timeout) {
+            Ok(message) => message,
+            Err(e) => {
+                log::debug!(device = connection.device; "connection lost: {e}");
+                if test.is_some() {
+                    send_notification(DeviceNotification::TestCancelled);
+                }
+                return ConnectionOutcome::Lost;
+            }
+        };
+        if let (Some(at), Some(timeout)) = (probe_at, connection.unresponsive_timeout) {
+            if connection.received_data {
+                probe_at = None;
+            } else if Instant::now() >= at {
+                if probes == 1 {
+                    log::warn!(device = connection.device; "device is unresponsive");
+                    send_notification(DeviceNotification::DeviceUnresponsive);
+                }
+                // The initial commands were lost if the device was switched
+                // off, they are therefore resent in full.
+                send_command(Command::EnterExternalControl);
+                send_command(Command::RequestSettings);
+                probes += 1;
+                probe_at = Some(Instant::now() + timeout);
+            }
+        }
+        if let Some(Message::Sample(value)) = message {
+            let cadence_warning = cadence_tracker
+                .lock()
+                .unwrap()
+                .record(std::time::Instant::now());
+            send_notification(DeviceNotification::Sample {
+                particle_conc: value,
+            });
+            if let Some(cadence_warning) = cadence_warning {
+                log::warn!("sample cadence changed: {cadence_warning:?}");
+                send_notification(DeviceNotification::SampleCadence(cadence_warning));
+            }
+        }
+
+        if actions.wakeup.is_closed() {
+            return ConnectionOutcome::Closed;
+        }
+        // Several actions may have been sent since the last wakeup.
+        loop {
+            match actions.rx_action.try_recv() {
                 Ok(action) => match action {
                     Action::StartTest {
                         config,
+                        mut options,
                         test_callback,
                     } => {
+                        options.n95_companion |= connection.n95_companion;
+                        if options.sample_interval.is_none() {
+                            options.sample_interval = cadence_tracker
+                                .lock()
+                                .unwrap()
+                                .statistics()
+                                .recent_mean_interval;
+                        }
                         // Clients could send multiple StartTests (while
                         // previous tests are still running). That's OK,
                         // starting a new test is idempotent - and old tests
                         // will simply be dropped.
-                        test = match Test::create_and_start(
+                        // CommandQueue never fails, i.e. this is always Ok.
+                        test = Test::create_and_start(
                             config,
-                            &tx_command,
+                            options,
+                            &commands,
                             &mut valve_state,
-                            test_callback,
-                        ) {
-                            Ok(test) => Some(test),
-                            // No need to send ConnectionClosed here - see comment in
-                            // send_command above.
-                            Err(_) => None,
-                        };
+                            metrics.wrap_test_callback(count_discarded_samples(
+                                connection.stats.clone(),
+                                executor.start_test(test_callback),
+                            )),
+                        )
+                        .ok();
                         send_notification(DeviceNotification::TestStarted);
                     }
                     Action::CancelTest => {
+                        // Display updates for the cancelled test are irrelevant.
+                        commands.clear_non_critical();
                         send_command(Command::ClearDisplay);
                         send_notification(DeviceNotification::TestCancelled);
                         valve_state = ValveState::AwaitingSpecimen;
                         send_command(Command::ValveSpecimen);
                         test = None;
                     }
+                    Action::FlushCommands => commands.clear_non_critical(),
+                    Action::ConfirmManualSwitch(position) => {
+                        if let Some(test) = &mut test {
+                            test.confirm_manual_switch(position, &mut valve_state);
+                        }
+                    }
+                    Action::CloseConnection => {
+                        if test.is_some() {
+                            send_notification(DeviceNotification::TestCancelled);
+                        }
+                        commands.clear_non_critical();
+                        send_command(Command::ExitExternalControl);
+                        // We're closing either way, but failures are reported
+                        // to Device::close.
+                        if let Err(e) = connection.flush(&commands) {
+                            let _ = actions.tx_thread_exited.send(e);
+                        }
+                        return ConnectionOutcome::Closed;
+                    }
                 },
-                Err(std::sync::mpsc::TryRecvError::Empty) => (),
+                Err(std::sync::mpsc::TryRecvError::Empty) => break,
                 Err(std::sync::mpsc::TryRecvError::Disconnected) => {
-                    send_notification(DeviceNotification::ConnectionClosed);
-                    return;
+                    return ConnectionOutcome::Closed;
                 }
             }
+        }
 
-            let Some(message) = message else {
-                continue;
-            };
+        let Some(message) = message else {
+            continue;
+        };
 
-            if let Message::Setting(setting) = message {
-                if let Some(notification) = device_properties_collector.process(setting) {
-                    send_notification(notification);
-                }
-                continue;
+        if let Message::Setting(setting) = message {
+            if let Some(notification) = device_properties_collector.process(setting) {
+                send_notification(notification);
             }
+            continue;
+        }
 
-            if let Some(new_state) = match message {
+        // Tests track the valve themselves (see Test::step), and may ignore
+        // valve responses (see ValveMode::Manual).
+        if let (None, Some(new_state)) = (
+            &test,
+            match message {
                 Message::Response(Command::ValveAmbient) => Some(ValveState::Ambient),
                 Message::Response(Command::ValveSpecimen) => Some(ValveState::Specimen),
                 _ => None,
-            } {
-                valve_state = new_state;
-            }
-            test = match test {
-                Some(mut test) => match test.step(message, &mut valve_state) {
-                    Ok(StepOutcome::None) => Some(test),
-                    Ok(StepOutcome::TestComplete) => {
-                        send_notification(DeviceNotification::TestCompleted {
-                            fit_factors: test.exercise_ffs,
-                        });
-                        None
-                    }
-                    // No need to send ConnectionClosed here - see comment in
-                    // send_command above.
-                    Err(_) => None,
-                },
-                None => {
-                    if let Message::Sample(value) = message {
-                        send_command(Command::DisplayConcentration(value));
-                    }
+            },
+        ) {
+            valve_state = new_state;
+        }
+        test = match test {
+            Some(mut test) => match test.step(message, &mut valve_state) {
+                Ok(StepOutcome::None) => Some(test),
+                Ok(StepOutcome::TestComplete) => {
+                    send_notification(DeviceNotification::TestCompleted {
+                        result: test.result(),
+                    });
                     None
                 }
+                Ok(StepOutcome::TestAborted) => {
+                    send_notification(DeviceNotification::TestAborted);
+                    None
+                }
+                // CommandQueue never fails, see above.
+                Err(_) => None,
+            },
+            None => {
+                if let Message::Sample(value) = message {
+                    send_command(Command::DisplayConcentration(value));
+                }
+                None
             }
         }
-    })
+    }
 }
 
-fn start_sender_thread(
-    mut writer: Box<dyn serialport::SerialPort>,
-    rx_command: Receiver<Command>,
-) -> thread::JoinHandle<()> {
-    thread::spawn(move || loop {
-        let command = match rx_command.recv().unwrap().to_wire() {
+// The native port type, which (on unix) allows waiting for the port and for
+// actions simultaneously (see Connection::wait).
+#[cfg(unix)]
+type Port = serialport::TTYPort;
+#[cfg(windows)]
+type Port = serialport::COMPort;
+
+// Wakes the device thread when an action is sent or the Device is dropped,
+// such that the thread can otherwise block until the device sends a message
+// or a command is due. Waking is a no-op on non-unix platforms, where the
+// device thread instead wakes up every ConnectOptions::read_timeout.
+struct Wakeup {
+    closed: AtomicBool,
+    // Set once Device::close_connection was called.
+    closing: AtomicBool,
+    #[cfg(unix)]
+    tx: std::os::unix::net::UnixStream,
+    #[cfg(unix)]
+    rx: std::os::unix::net::UnixStream,
+}
+
+impl Wakeup {
+    fn new() -> std::io::Result<Wakeup> {
+        #[cfg(unix)]
+        {
+            let (tx, rx) = std::os::unix::net::UnixStream::pair()?;
+            tx.set_nonblocking(true)?;
+            rx.set_nonblocking(true)?;
+            Ok(Wakeup {
+                closed: AtomicBool::new(false),
+                closing: AtomicBool::new(false),
+                tx,
+                rx,
+            })
+        }
+        #[cfg(not(unix))]
+        Ok(Wakeup {
+            closed: AtomicBool::new(false),
+            closing: AtomicBool::new(false),
+        })
+    }
+
+    fn wake(&self) {
+        // Failures mean that the socket buffer is full, i.e. that a wakeup is
+        // already pending.
+        #[cfg(unix)]
+        let _ = (&self.tx).write(&[0]);
+    }
+
+    // Consumes all pending wakeups.
+    #[cfg(unix)]
+    fn clear(&self) {
+        let mut buf = [0u8; 64];
+        while matches!((&self.rx).read(&mut buf), Ok(read) if read > 0) {}
+    }
+
+    // Signals that the Device was dropped.
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.wake();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    fn set_closing(&self) {
+        self.closing.store(true, Ordering::Relaxed);
+    }
+
+    fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Relaxed)
+    }
+}
+
+// A single connection to a device, i.e. an open port. Reads and writes are
+// multiplexed by poll(), which is called in a loop by the device thread (see
+// run_connection).
+struct Connection {
+    reader: std::io::BufReader<Port>,
+    device: usize,
+    // Only needed without poll(), see wait().
+    #[cfg(not(unix))]
+    read_timeout: Duration,
+    command_interval: Duration,
+    command_class_intervals: HashMap<CommandClass, Duration>,
+    // The earliest time at which the next command may be sent.
+    next_command_at: Instant,
+    // The line that is currently being received, which may span several
+    // reads. The buffer is reused for all lines, as are the lines in
+    // recent_lines, i.e. receiving does not allocate once these have grown to
+    // the maximum line length.
+    buf: Vec<u8>,
+    // The most recently received lines, only retained for parse_failure_log.
+    recent_lines: VecDeque<String>,
+    parse_failure_log: Option<Arc<parse_failures::ParseFailureLog>>,
+    metrics: MetricsRecorder,
+    // Shared with Device (and subsequent connections), see Device::stats.
+    stats: Arc<Mutex<ConnectionStats>>,
+    // See ConnectOptions::n95_companion.
+    n95_companion: bool,
+    // See ConnectOptions::unresponsive_timeout.
+    unresponsive_timeout: Option<Duration>,
+    // Whether anything (even a partial or invalid line) has been received.
+    received_data: bool,
+}
+
+impl Connection {
+    fn open(
+        path: &str,
+        options: &ConnectOptions,
+        stats: Arc<Mutex<ConnectionStats>>,
+    ) -> serialport::Result<Connection> {
+        // See "PortaCount Plus Model 8020 Technical Addendum" for specs.
+        // Note: baud is configurable on the devices itself, 1200 is the default.
+        let port = serialport::new(path, options.baud_rate)
+            .data_bits(serialport::DataBits::Eight)
+            .parity(serialport::Parity::None)
+            .stop_bits(serialport::StopBits::One)
+            .flow_control(serialport::FlowControl::Hardware)
+            // See Connection::wait for how the timeout is used.
+            .timeout(options.read_timeout)
+            .open_native()?;
+        let metrics = MetricsRecorder::new(options);
+        metrics.connection_opened();
+
+        // OSX-only (possibly AppleUSBFTDI-only): if the device is already
+        // regularly transmitting data (e.g. because it's already in
+        // external-control mode), then the input buffer will start with some
+        // nulls and junk (in my case it's consistently:
+        // [0, 0, 0, 0, 0, 0, 0, 0, 0, 'é', 'r', 'é', 'é', 'j', LF] followed by
+        // normal programming). This breaks BufReader.
+        // The output buffer is also affected by some kind of similar issue,
+        // waiting a little and clearing buffers appears to work well enough so
+        // NBD. This isn't entirely surprising given that the port is opened
+        // first, followed by setting attributes (baud etc.) - but seemingly
+        // this process takes longer on OSX vs Linux.
+        if cfg!(target_os = "macos") {
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            let clear_result = port.clear(serialport::ClearBuffer::All);
+            log::debug!(device = options.index; "OSX clear-input-buffer-hack result: {clear_result:?}")
+        }
+
+        Ok(Connection {
+            reader: std::io::BufReader::new(port),
+            device: options.index,
+            #[cfg(not(unix))]
+            read_timeout: options.read_timeout,
+            command_interval: options.command_interval,
+            command_class_intervals: options.command_class_intervals.clone(),
+            next_command_at: Instant::now(),
+            buf: Vec::new(),
+            recent_lines: VecDeque::new(),
+            parse_failure_log: options.parse_failure_log.clone(),
+            metrics,
+            stats,
+            n95_companion: options.n95_companion,
+            unresponsive_timeout: options.unresponsive_timeout,
+            received_data: false,
+        })
+    }
+
+    // Sends the next queued command (if command_interval has passed since the
+    // previous command), and then waits for a message until either timeout
+    // passes (None to wait indefinitely), the next command is due, or wakeup
+    // is woken. Returns None if no (valid) message was received, and an error
+    // if the connection was lost.
+    fn poll(
+        &mut self,
+        commands: &CommandQueue,
+        wakeup: &Wakeup,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<Option<Message>> {
+        if Instant::now() >= self.next_command_at {
+            if let Some(command) = commands.pop() {
+                self.write_command(command)?;
+            }
+        }
+        let timeout = if commands.is_empty() {
+            timeout
+        } else {
+            let until_next_command = self
+                .next_command_at
+                .saturating_duration_since(Instant::now());
+            Some(timeout.map_or(until_next_command, |timeout| {
+                timeout.min(until_next_command)
+            }))
+        };
+        if self.wait(wakeup, timeout)? {
+            self.read_message()
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Blocks until the port is readable (returning true), or until timeout
+    // passes or wakeup is woken (returning false).
+    #[cfg(unix)]
+    fn wait(&mut self, wakeup: &Wakeup, timeout: Option<Duration>) -> std::io::Result<bool> {
+        use nix::poll::{PollFd, PollFlags};
+        use std::os::fd::AsRawFd;
+
+        if !self.reader.buffer().is_empty() {
+            return Ok(true);
+        }
+        let mut fds = [
+            PollFd::new(self.reader.get_ref().as_raw_fd(), PollFlags::POLLIN),
+            PollFd::new(wakeup.rx.as_raw_fd(), PollFlags::POLLIN),
+        ];
+        // Rounded up, such that we don't wake up (repeatedly) just before a
+        // command is due.
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+        });
+        match nix::poll::poll(&mut fds, timeout) {
+            Ok(_) => (),
+            Err(nix::errno::Errno::EINTR) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        if fds[1].revents().is_some_and(|events| !events.is_empty()) {
+            wakeup.clear();
+        }
+        // Hangups (e.g. because the device was unplugged) are treated as
+        // readable, such that the subsequent read fails.
+        Ok(fds[0].revents().is_some_and(|events| !events.is_empty()))
+    }
+
+    // Without poll(), we can only wait for the port, i.e. actions are only
+    // handled once read_timeout passes.
+    #[cfg(not(unix))]
+    fn wait(&mut self, _: &Wakeup, timeout: Option<Duration>) -> std::io::Result<bool> {
+        let timeout = timeout.map_or(self.read_timeout, |timeout| timeout.min(self.read_timeout));
+        // A zero timeout would turn into a busy loop on some platforms.
+        self.reader
+            .get_mut()
+            .set_timeout(timeout.max(Duration::from_millis(1)))?;
+        Ok(true)
+    }
+
+    // Sends all queued commands, respecting command_interval. Used when
+    // closing a connection, to ensure that e.g. ExitExternalControl is
+    // actually sent.
+    fn flush(&mut self, commands: &CommandQueue) -> std::io::Result<()> {
+        while let Some(command) = commands.pop() {
+            thread::sleep(
+                self.next_command_at
+                    .saturating_duration_since(Instant::now()),
+            );
+            self.write_command(command)?;
+        }
+        Ok(())
+    }
+
+    fn write_command(&mut self, command: Command) -> std::io::Result<()> {
+        let device = self.device;
+        let interval = self
+            .command_class_intervals
+            .get(&command.class())
+            .copied()
+            .unwrap_or(self.command_interval);
+        let command = match command.to_wire() {
             Ok(command) => command,
             Err(e) => {
-                eprintln!("Not sending invalid command: {e:?}");
-                continue;
+                log::error!(device; "Not sending invalid command: {e:?}");
+                return Ok(());
             }
         };
         assert!(
             command.is_ascii(),
             "commands must be ASCII, this is a libp8020 bug (got {command})"
         );
+        log::trace!(device, direction = "tx", line = command.as_str(); ">>> {command}");
 
-        writer
-            .write_all(command.as_bytes())
-            .expect("failed to write to port");
-        writer.write_all(b"\r").expect("failed to write to port");
+        let writer = self.reader.get_mut();
+        writer.write_all(command.as_bytes())?;
+        writer.write_all(b"\r")?;
+        self.metrics.command_sent();
+        self.stats.lock().unwrap().commands_sent += 1;
 
         // Flow control is a bit laggy or broken: sending a second message within
         // approx 52ms of a previous message will result in the second message being
         // ignored (which obviously breaks subsequent assumptions).
-        // To be safe I use a 100ms delay. (For my device, the threshold was right
-        // around 52ms, but it may be different for other devices/computers/OS's/
-        // whatever.)
+        // To be safe I use a 100ms delay by default (see
+        // ConnectOptions::command_interval and command_class_intervals). (For
+        // my device, the threshold was right around 52ms, but it may be
+        // different for other devices/computers/OS's/whatever.)
         // It's also entirely possible that the problem is with my serial/USB adapter.
         // TODO: figure out if we can wait for the echo instead? This is tricky,
         // because it relies on accurate response parsing and/or good heuristics?
-        std::thread::sleep(std::time::Duration::from_millis(100));
-    })
-}
+        self.next_command_at = Instant::now() + interval;
+        Ok(())
+    }
 
-fn start_receiver_thread(
-    mut reader: std::io::BufReader<Box<dyn serialport::SerialPort>>,
-    tx_message: Sender<Option<Message>>,
-) -> thread::JoinHandle<()> {
-    thread::spawn(move || {
-        let mut buf = String::new();
-        loop {
-            // read_line blocks until we get content OR until we reach the timeout (set
-            // above). To detect that the user wishes to close a device connection, we
-            // can check whether the channel is still open: if the connection is closed,
-            // then device thread will close (drop) the channel refered to by tx_message.
-            // The only way to check if the connection is closed is to try send()'ing.
-            // Therefore we periodically send None's to the channel to check if we should
-            // quit. To ensure that we check the connection sufficiently frequently, we
-            // rely on a short timeout on reader.
-            match reader.read_line(&mut buf) {
-                Ok(0) => {
-                    // This closes the channel for us, which in turns lets the
-                    // device thread know that the connection is closed.
-                    return;
-                }
-                Err(error) => match error.kind() {
-                    std::io::ErrorKind::TimedOut => {
-                        // "Is channel still open" check - see long comment above.
-                        tx_message.send(None).unwrap();
-                        continue;
-                    }
-                    _ => {
-                        // See Ok(0) above.
-                        return;
+    fn read_message(&mut self) -> std::io::Result<Option<Message>> {
+        let device = self.device;
+        // read_until blocks until we get a full line OR until we reach the
+        // timeout. Partial lines are retained in buf, and completed by
+        // subsequent reads.
+        match self.reader.read_until(b'\n', &mut self.buf) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Err(error) if error.kind() == std::io::ErrorKind::TimedOut => return Ok(None),
+            Err(error) => return Err(error),
+            Ok(_) => self.received_data = true,
+        };
+        // Messages are ASCII, anything else (e.g. junk after opening the port)
+        // fails to parse anyway, hence lossy conversion is fine. This only
+        // allocates for invalid UTF-8.
+        let raw_line = String::from_utf8_lossy(&self.buf);
+        // Removes the trailing <CR><LF>.
+        let line = raw_line.trim();
+        log::trace!(device, direction = "rx", line; "<<< {line}");
+        let parsed = protocol::parse_message(line);
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.last_message_at = Some(SystemTime::now());
+            match parsed {
+                Ok(Message::Sample(_)) => stats.samples_received += 1,
+                Ok(_) => (),
+                Err(_) => stats.parse_errors += 1,
+            }
+        }
+        let message = match parsed {
+            Ok(message) => Some(message),
+            Err(e) => {
+                self.metrics.parse_error();
+                log::warn!(device, direction = "rx", line; "command parsing failed: {e:?}");
+                if let Some(parse_failure_log) = &self.parse_failure_log {
+                    let error = format!("{e:?}");
+                    let context = self.recent_lines.make_contiguous();
+                    if let Err(e) = parse_failure_log.record(device, &raw_line, &error, context) {
+                        log::warn!(device; "unable to record parse failure: {e}");
                     }
+                }
+                None
+            }
+        };
+        if let Some(parse_failure_log) = &self.parse_failure_log {
+            let context_lines = parse_failure_log.context_lines();
+            if context_lines > 0 {
+                // Reuses the oldest line, once enough lines are retained.
+                let mut recent_line = if self.recent_lines.len() >= context_lines {
+                    self.recent_lines.pop_front().unwrap()
+                } else {
+                    String::new()
+                };
+                recent_line.clear();
+                recent_line.push_str(&raw_line);
+                self.recent_lines.push_back(recent_line);
+            }
+        }
+        self.buf.clear();
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(queue: &CommandQueue) -> Vec<Command> {
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn test_command_queue() {
+        let queue = CommandQueue::default();
+        for command in [
+            Command::DisplayConcentration(1.0),
+            Command::DisplayConcentration(2.0),
+            Command::Beep {
+                duration_deciseconds: 5,
+            },
+            Command::ValveAmbient,
+            Command::DisplayConcentration(3.0),
+            Command::ClearDisplay,
+            Command::DisplayConcentration(4.0),
+            Command::DisplayExercise(2),
+            Command::DisplayExercise(3),
+            Command::ExitExternalControl,
+        ] {
+            queue.send(command).unwrap();
+        }
+        assert_eq!(
+            drain(&queue),
+            [
+                Command::ValveAmbient,
+                Command::ExitExternalControl,
+                Command::DisplayConcentration(3.0),
+                Command::Beep {
+                    duration_deciseconds: 5
                 },
-                Ok(_) => (),
+                Command::ClearDisplay,
+                Command::DisplayConcentration(4.0),
+                Command::DisplayExercise(3),
+            ]
+        );
+
+        for command in [
+            Command::DisplayExercise(1),
+            Command::ValveSpecimen,
+            Command::Beep {
+                duration_deciseconds: 5,
+            },
+            Command::DisplayConcentration(1.0),
+        ] {
+            queue.send(command).unwrap();
+        }
+        queue.clear_non_critical();
+        assert_eq!(drain(&queue), [Command::ValveSpecimen]);
+    }
+
+    #[test]
+    fn test_count_discarded_samples() {
+        let stats = Arc::new(Mutex::new(ConnectionStats::default()));
+        let forwarded = Arc::new(Mutex::new(0));
+        let callback = {
+            let forwarded = forwarded.clone();
+            count_discarded_samples(
+                stats.clone(),
+                Some(Box::new(move |_: &TestNotification| {
+                    *forwarded.lock().unwrap() += 1;
+                })),
+            )
+        }
+        .unwrap();
+        callback(&TestNotification::SampleDiscarded {
+            value: 1.0,
+            reason: DiscardReason::AwaitingValveSwitch,
+        });
+        callback(&TestNotification::InterimFF {
+            exercise: 0,
+            fit_factor: 100.0,
+        });
+        assert_eq!(stats.lock().unwrap().discarded_samples, 1);
+        assert_eq!(*forwarded.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_firmware_fingerprint() {
+        let mut collector = DevicePropertiesCollector::new(false);
+        let settings = [
+            "STPA 00004",
+            "STA  00005",
+            "STPM 00011",
+            "STM0100030",
+            "STM0200030",
+            "SP 0100100",
+            "SS   80241234",
+            "SR   00042",
+            "SD   0314",
+        ];
+        let mut properties = None;
+        for setting in settings {
+            let Ok(Message::Setting(setting)) = protocol::parse_message(setting) else {
+                panic!("invalid setting: {setting}");
             };
-            // BufReader removes the trailing <LR>, we need to remove the remaining <CR>.
-            let message = buf.trim();
-            match protocol::parse_message(message) {
-                Ok(message) => tx_message.send(Some(message)).unwrap(),
-                Err(e) => {
-                    // TODO: log any unparseable messages to disk, to allow for later debugging.
-                    println!("command parsing failed: {e:?}")
-                }
+            if let Some(DeviceNotification::DeviceProperties(collected)) =
+                collector.process(setting)
+            {
+                properties = Some(collected);
             }
-            buf.clear();
         }
-    })
+        let mut properties = properties.unwrap();
+        assert_eq!(properties.firmware_fingerprint(), "s8-t1-m2-p1");
+
+        properties.serial_number = "12345".to_string();
+        properties.ambient_purge_seconds = None;
+        properties.ambient_sample_seconds = None;
+        properties.mask_purge_seconds = None;
+        assert_eq!(properties.firmware_fingerprint(), "s5-t0-m2-p1");
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let message = |f: fn()| panic_message(&*panic::catch_unwind(f).unwrap_err());
+        assert_eq!(message(|| panic!("static")), "static");
+        assert_eq!(message(|| panic!("formatted {}", 1)), "formatted 1");
+        assert_eq!(message(|| std::panic::panic_any(1)), "unknown panic");
+    }
 }
@@ -0,0 +1,387 @@
+//! Prometheus metrics for long-running deployments (e.g. monitors that
+//! continuously record concentrations): the current concentration, samples
+//! received, unparseable messages, commands sent, and test state of each
+//! device.
+//!
+//! Devices record into a shared Metrics instance (see
+//! ConnectOptions::metrics), which can be exposed via serve(), or rendered
+//! by the caller (see Metrics::render) to be included in an existing
+//! endpoint. Metrics are labelled with the device's index (see
+//! ConnectOptions::index), and persist across reconnections.
+//!
+//! Requires the metrics feature.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::test::TestCallback;
+use crate::{ConnectOptions, DeviceNotification, TestNotification, TestState};
+
+/// Metrics contains the metrics of all devices that were connected with it.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    devices: Mutex<BTreeMap<usize, Arc<DeviceMetrics>>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct DeviceMetrics {
+    // The bits of the most recent concentration, NaN until the first sample.
+    concentration: AtomicU64,
+    samples_received: AtomicU64,
+    parse_errors: AtomicU64,
+    commands_sent: AtomicU64,
+    connected: AtomicBool,
+    test_running: AtomicBool,
+    // 1-based, 0 if no exercise is running.
+    exercise: AtomicU64,
+    tests_completed: AtomicU64,
+    tests_cancelled: AtomicU64,
+    tests_aborted: AtomicU64,
+}
+
+impl DeviceMetrics {
+    fn new() -> DeviceMetrics {
+        DeviceMetrics {
+            concentration: AtomicU64::new(f64::NAN.to_bits()),
+            samples_received: AtomicU64::new(0),
+            parse_errors: AtomicU64::new(0),
+            commands_sent: AtomicU64::new(0),
+            connected: AtomicBool::new(false),
+            test_running: AtomicBool::new(false),
+            exercise: AtomicU64::new(0),
+            tests_completed: AtomicU64::new(0),
+            tests_cancelled: AtomicU64::new(0),
+            tests_aborted: AtomicU64::new(0),
+        }
+    }
+
+    fn end_test(&self, outcome: &AtomicU64) {
+        self.test_running.store(false, Ordering::Relaxed);
+        self.exercise.store(0, Ordering::Relaxed);
+        outcome.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct MetricDescription {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+}
+
+const METRICS: [MetricDescription; 8] = [
+    MetricDescription {
+        name: "p8020_concentration",
+        kind: "gauge",
+        help: "Most recently sampled particle concentration, in particles/cm3.",
+    },
+    MetricDescription {
+        name: "p8020_samples_received_total",
+        kind: "counter",
+        help: "Samples received from the device.",
+    },
+    MetricDescription {
+        name: "p8020_parse_errors_total",
+        kind: "counter",
+        help: "Messages received from the device that could not be parsed.",
+    },
+    MetricDescription {
+        name: "p8020_commands_sent_total",
+        kind: "counter",
+        help: "Commands sent to the device.",
+    },
+    MetricDescription {
+        name: "p8020_connected",
+        kind: "gauge",
+        help: "Whether the device is connected.",
+    },
+    MetricDescription {
+        name: "p8020_test_running",
+        kind: "gauge",
+        help: "Whether a test is running.",
+    },
+    MetricDescription {
+        name: "p8020_test_exercise",
+        kind: "gauge",
+        help: "The running exercise (1-based), or 0 if no exercise is running.",
+    },
+    MetricDescription {
+        name: "p8020_tests_total",
+        kind: "counter",
+        help: "Tests that ended, by outcome.",
+    },
+];
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub(crate) fn device(&self, index: usize) -> Arc<DeviceMetrics> {
+        self.devices
+            .lock()
+            .unwrap()
+            .entry(index)
+            .or_insert_with(|| Arc::new(DeviceMetrics::new()))
+            .clone()
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let devices = self.devices.lock().unwrap();
+        let mut rendered = String::new();
+        for metric in &METRICS {
+            rendered.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n",
+                metric.name, metric.help, metric.name, metric.kind
+            ));
+            for (index, device) in devices.iter() {
+                let labels = format!("device=\"{index}\"");
+                let load = |value: &AtomicU64| value.load(Ordering::Relaxed).to_string();
+                let flag = |value: &AtomicBool| u8::from(value.load(Ordering::Relaxed)).to_string();
+                let samples = match metric.name {
+                    "p8020_concentration" => {
+                        let concentration =
+                            f64::from_bits(device.concentration.load(Ordering::Relaxed));
+                        // Omitted until the first sample was received.
+                        if concentration.is_nan() {
+                            vec![]
+                        } else {
+                            vec![(labels, concentration.to_string())]
+                        }
+                    }
+                    "p8020_samples_received_total" => {
+                        vec![(labels, load(&device.samples_received))]
+                    }
+                    "p8020_parse_errors_total" => vec![(labels, load(&device.parse_errors))],
+                    "p8020_commands_sent_total" => vec![(labels, load(&device.commands_sent))],
+                    "p8020_connected" => vec![(labels, flag(&device.connected))],
+                    "p8020_test_running" => vec![(labels, flag(&device.test_running))],
+                    "p8020_test_exercise" => vec![(labels, load(&device.exercise))],
+                    "p8020_tests_total" => [
+                        ("completed", &device.tests_completed),
+                        ("cancelled", &device.tests_cancelled),
+                        ("aborted", &device.tests_aborted),
+                    ]
+                    .into_iter()
+                    .map(|(outcome, count)| {
+                        (format!("{labels},outcome=\"{outcome}\""), load(count))
+                    })
+                    .collect(),
+                    _ => unreachable!("unknown metric {}", metric.name),
+                };
+                for (labels, value) in samples {
+                    rendered.push_str(&format!("{}{{{labels}}} {value}\n", metric.name));
+                }
+            }
+        }
+        rendered
+    }
+}
+
+/// Serves metrics via HTTP at /metrics on address (e.g. "0.0.0.0:9020"), on
+/// a background thread. Returns the address that is being listened on
+/// (which is useful when binding to port 0).
+pub fn serve(metrics: Arc<Metrics>, address: impl ToSocketAddrs) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let local_address = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            // Scrapes are infrequent and cheap, there's no need to handle
+            // connections concurrently. The timeout prevents a stalled
+            // client from blocking all further scrapes.
+            let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(5)));
+            let mut reader = std::io::BufReader::new(&stream);
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).is_err() {
+                continue;
+            }
+            // Skip the headers: requests to /metrics never have a body.
+            let mut header = String::new();
+            while reader.read_line(&mut header).is_ok_and(|read| read > 2) {
+                header.clear();
+            }
+            let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+            let response = match path.split('?').next() {
+                Some("/metrics") => {
+                    let body = metrics.render();
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                }
+                _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+            };
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    Ok(local_address)
+}
+
+// Records the metrics of a single device, if enabled via
+// ConnectOptions::metrics.
+#[derive(Clone)]
+pub(crate) struct MetricsRecorder(Option<Arc<DeviceMetrics>>);
+
+impl MetricsRecorder {
+    pub(crate) fn new(options: &ConnectOptions) -> MetricsRecorder {
+        MetricsRecorder(
+            options
+                .metrics
+                .as_ref()
+                .map(|metrics| metrics.device(options.index)),
+        )
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        if let Some(metrics) = &self.0 {
+            metrics.connected.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn command_sent(&self) {
+        if let Some(metrics) = &self.0 {
+            metrics.commands_sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn parse_error(&self) {
+        if let Some(metrics) = &self.0 {
+            metrics.parse_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn notification(&self, notification: &DeviceNotification) {
+        let Some(metrics) = &self.0 else {
+            return;
+        };
+        match notification {
+            DeviceNotification::Sample { particle_conc } => {
+                metrics
+                    .concentration
+                    .store(particle_conc.to_bits(), Ordering::Relaxed);
+                metrics.samples_received.fetch_add(1, Ordering::Relaxed);
+            }
+            DeviceNotification::TestStarted => {
+                metrics.test_running.store(true, Ordering::Relaxed);
+            }
+            DeviceNotification::TestCompleted { .. } => metrics.end_test(&metrics.tests_completed),
+            DeviceNotification::TestCancelled => metrics.end_test(&metrics.tests_cancelled),
+            DeviceNotification::TestAborted => metrics.end_test(&metrics.tests_aborted),
+            DeviceNotification::ConnectionClosed | DeviceNotification::ConnectionLost => {
+                metrics.connected.store(false, Ordering::Relaxed);
+            }
+            DeviceNotification::Reconnected
+            | DeviceNotification::DeviceProperties(_)
+            | DeviceNotification::SampleCadence(_)
+            | DeviceNotification::Fault { .. }
+            | DeviceNotification::DeviceUnresponsive => (),
+        }
+    }
+
+    // Wraps callback, such that exercise changes are recorded too.
+    pub(crate) fn wrap_test_callback(&self, callback: TestCallback) -> TestCallback {
+        let Some(metrics) = self.0.clone() else {
+            return callback;
+        };
+        Some(Box::new(move |notification: &TestNotification| {
+            if let TestNotification::StateChange(TestState::StartedExercise(exercise)) =
+                notification
+            {
+                metrics
+                    .exercise
+                    .store(*exercise as u64 + 1, Ordering::Relaxed);
+            }
+            if let Some(callback) = &callback {
+                callback(notification);
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_render() {
+        let metrics = Arc::new(Metrics::new());
+        let recorder = MetricsRecorder::new(&ConnectOptions {
+            index: 1,
+            metrics: Some(metrics.clone()),
+            ..ConnectOptions::default()
+        });
+        recorder.connection_opened();
+        assert!(!metrics.render().contains("p8020_concentration{"));
+
+        recorder.command_sent();
+        recorder.parse_error();
+        recorder.notification(&DeviceNotification::TestStarted);
+        let callback = recorder.wrap_test_callback(None).unwrap();
+        callback(&TestNotification::StateChange(TestState::StartedExercise(
+            2,
+        )));
+        for particle_conc in [1000.0, 1234.5] {
+            recorder.notification(&DeviceNotification::Sample { particle_conc });
+        }
+        let rendered = metrics.render();
+        for expected in [
+            "# TYPE p8020_concentration gauge\n",
+            "p8020_concentration{device=\"1\"} 1234.5\n",
+            "p8020_samples_received_total{device=\"1\"} 2\n",
+            "p8020_parse_errors_total{device=\"1\"} 1\n",
+            "p8020_commands_sent_total{device=\"1\"} 1\n",
+            "p8020_connected{device=\"1\"} 1\n",
+            "p8020_test_running{device=\"1\"} 1\n",
+            "p8020_test_exercise{device=\"1\"} 3\n",
+            "p8020_tests_total{device=\"1\",outcome=\"cancelled\"} 0\n",
+        ] {
+            assert!(
+                rendered.contains(expected),
+                "{expected} missing:\n{rendered}"
+            );
+        }
+
+        recorder.notification(&DeviceNotification::TestCancelled);
+        recorder.notification(&DeviceNotification::ConnectionLost);
+        let rendered = metrics.render();
+        for expected in [
+            "p8020_connected{device=\"1\"} 0\n",
+            "p8020_test_running{device=\"1\"} 0\n",
+            "p8020_test_exercise{device=\"1\"} 0\n",
+            "p8020_tests_total{device=\"1\",outcome=\"cancelled\"} 1\n",
+        ] {
+            assert!(
+                rendered.contains(expected),
+                "{expected} missing:\n{rendered}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_serve() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.device(0);
+        let address = serve(metrics.clone(), "127.0.0.1:0").unwrap();
+        let get = |path: &str| {
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            stream
+                .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&metrics.render()));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
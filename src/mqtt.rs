@@ -0,0 +1,540 @@
+//! Publishing of samples, test progress, and results to an MQTT broker, e.g.
+//! for building monitoring or Home Assistant style dashboards.
+//!
+//! This is a minimal MQTT 3.1.1 client: messages are published with QoS 0
+//! (i.e. at most once), and nothing is subscribed to. Callers forward
+//! notifications (see MqttPublisher::publish_device_notification and
+//! MqttPublisher::publish_test_notification), typically from their device
+//! and test callbacks.
+//!
+//! Requires the mqtt feature.
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::json::{json_bool, json_number, json_string};
+use crate::{DeviceNotification, TestNotification, TestResult, TestState};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PINGREQ: u8 = 0xc0;
+const DISCONNECT: u8 = 0xe0;
+
+const RETAIN: u8 = 0x01;
+
+/// MqttTopics contains the topic that each kind of message is published to.
+/// "{device}" is replaced by the device label passed to the publish methods.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttTopics {
+    /// Each sample's concentration (particles/cm3), as a plain number
+    /// (default: p8020/{device}/sample).
+    pub sample: String,
+    /// The device/test state, as a plain string (default:
+    /// p8020/{device}/state). Retained. One of: exercise <n> (1-based),
+    /// completed, cancelled, aborted, disconnected, connection_lost, or
+    /// connected (after reconnecting).
+    pub state: String,
+    /// Each exercise's FF, as JSON, e.g. {"exercise":1,"fit_factor":123.4}
+    /// (default: p8020/{device}/exercise).
+    pub exercise: String,
+    /// The result of each completed test, as JSON (default:
+    /// p8020/{device}/result). Retained.
+    pub result: String,
+}
+
+impl Default for MqttTopics {
+    fn default() -> Self {
+        MqttTopics {
+            sample: "p8020/{device}/sample".to_string(),
+            state: "p8020/{device}/state".to_string(),
+            exercise: "p8020/{device}/exercise".to_string(),
+            result: "p8020/{device}/result".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MqttOptions {
+    pub host: String,
+    /// Default: 1883.
+    pub port: u16,
+    /// Default: p8020-<process id>.
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The broker disconnects clients that have been silent for longer than
+    /// 1.5 times this interval, a ping is therefore sent whenever nothing
+    /// was published for half this interval (default: 60s).
+    pub keep_alive: Duration,
+    pub topics: MqttTopics,
+}
+
+impl Default for MqttOptions {
+    fn default() -> Self {
+        MqttOptions {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: format!("p8020-{}", std::process::id()),
+            username: None,
+            password: None,
+            keep_alive: Duration::from_secs(60),
+            topics: MqttTopics::default(),
+        }
+    }
+}
+
+fn encode_remaining_length(mut length: usize, packet: &mut Vec<u8>) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            return;
+        }
+    }
+}
+
+fn encode_string(value: &[u8], body: &mut Vec<u8>) {
+    body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    body.extend_from_slice(value);
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    encode_remaining_length(body.len(), &mut packet);
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn connect_packet(options: &MqttOptions) -> Vec<u8> {
+    let mut flags = 0x02; // Clean session.
+    if options.username.is_some() {
+        flags |= 0x80;
+    }
+    if options.password.is_some() {
+        flags |= 0x40;
+    }
+    let mut body = Vec::new();
+    encode_string(b"MQTT", &mut body);
+    body.push(4); // Protocol level: 3.1.1.
+    body.push(flags);
+    let keep_alive = options.keep_alive.as_secs().min(u16::MAX as u64) as u16;
+    body.extend_from_slice(&keep_alive.to_be_bytes());
+    encode_string(options.client_id.as_bytes(), &mut body);
+    for value in [&options.username, &options.password].into_iter().flatten() {
+        encode_string(value.as_bytes(), &mut body);
+    }
+    packet(CONNECT, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    encode_string(topic.as_bytes(), &mut body);
+    body.extend_from_slice(payload);
+    packet(PUBLISH | if retain { RETAIN } else { 0 }, &body)
+}
+
+fn unix_seconds(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    format!("{seconds:.3}")
+}
+
+fn result_json(result: &TestResult) -> String {
+    let exercises: Vec<String> = result
+        .fit_factors
+        .iter()
+        .enumerate()
+        .map(|(exercise, fit_factor)| {
+            format!(
+                "{{\"name\":{},\"fit_factor\":{},\"passed\":{}}}",
+                json_string(
+                    result
+                        .exercise_names
+                        .get(exercise)
+                        .map_or("", String::as_str)
+                ),
+                json_number(*fit_factor),
+                json_bool(result.exercise_passed.get(exercise).copied().flatten())
+            )
+        })
+        .collect();
+    format!(
+        "{{\"config\":{},\"started_at\":{},\"completed_at\":{},\"overall_fit_factor\":{},\"passed\":{},\"exercises\":[{}]}}",
+        json_string(&result.config_short_name),
+        unix_seconds(result.started_at),
+        unix_seconds(result.completed_at),
+        json_number(result.overall_fit_factor),
+        json_bool(result.passed),
+        exercises.join(",")
+    )
+}
+
+struct Connection {
+    stream: TcpStream,
+    last_sent: Instant,
+}
+
+/// MqttPublisher is a connection to an MQTT broker. The connection is closed
+/// when the publisher is dropped.
+pub struct MqttPublisher {
+    connection: Arc<Mutex<Connection>>,
+    topics: MqttTopics,
+}
+
+impl MqttPublisher {
+    /// Connects to the broker, and waits for it to accept the connection.
+    pub fn connect(options: MqttOptions) -> std::io::Result<MqttPublisher> {
+        let address = (options.host.as_str(), options.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("unable to resolve {}", options.host),
+                )
+            })?;
+        let mut stream = TcpStream::connect_timeout(&address, Duration::from_secs(10))?;
+        stream.write_all(&connect_packet(&options))?;
+
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack)?;
+        if connack[0] != CONNACK || connack[1] != 2 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unexpected response from broker: {connack:02x?}"),
+            ));
+        }
+        if connack[3] != 0 {
+            let reason = match connack[3] {
+                1 => "unacceptable protocol version",
+                2 => "client id rejected",
+                3 => "server unavailable",
+                4 => "bad username or password",
+                5 => "not authorised",
+                _ => "unknown reason",
+            };
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("broker refused connection: {reason}"),
+            ));
+        }
+
+        let connection = Arc::new(Mutex::new(Connection {
+            stream: stream.try_clone()?,
+            last_sent: Instant::now(),
+        }));
+        // Drains anything the broker sends (i.e. ping responses), and sends
+        // pings while idle. Exits once the connection is closed.
+        if !options.keep_alive.is_zero() {
+            let connection = connection.clone();
+            let ping_interval = options.keep_alive / 2;
+            stream.set_read_timeout(Some(ping_interval))?;
+            std::thread::spawn(move || {
+                let mut buffer = [0; 64];
+                loop {
+                    match stream.read(&mut buffer) {
+                        Ok(0) => return,
+                        Ok(_) => (),
+                        Err(e)
+                            if matches!(
+                                e.kind(),
+                                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                            ) => {}
+                        Err(_) => return,
+                    }
+                    let mut connection = connection.lock().unwrap();
+                    if connection.last_sent.elapsed() >= ping_interval {
+                        if connection.stream.write_all(&[PINGREQ, 0]).is_err() {
+                            return;
+                        }
+                        connection.last_sent = Instant::now();
+                    }
+                }
+            });
+        }
+
+        Ok(MqttPublisher {
+            connection,
+            topics: options.topics,
+        })
+    }
+
+    /// Publishes payload to topic (verbatim, i.e. without substituting
+    /// "{device}").
+    pub fn publish(&self, topic: &str, payload: &[u8], retain: bool) -> std::io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        connection
+            .stream
+            .write_all(&publish_packet(topic, payload, retain))?;
+        connection.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn publish_to(
+        &self,
+        topic: &str,
+        device: &str,
+        payload: &str,
+        retain: bool,
+    ) -> std::io::Result<()> {
+        self.publish(
+            &topic.replace("{device}", device),
+            payload.as_bytes(),
+            retain,
+        )
+    }
+
+    /// Publishes samples, state changes, and results. device is the label
+    /// used in topics, e.g. the device's serial number or index. Other
+    /// notifications are ignored.
+    pub fn publish_device_notification(
+        &self,
+        device: &str,
+        notification: &DeviceNotification,
+    ) -> std::io::Result<()> {
+        let state = match notification {
+            DeviceNotification::Sample { particle_conc } => {
+                return self.publish_to(
+                    &self.topics.sample,
+                    device,
+                    &particle_conc.to_string(),
+                    false,
+                );
+            }
+            DeviceNotification::TestCompleted { result } => {
+                self.publish_to(&self.topics.result, device, &result_json(result), true)?;
+                "completed"
+            }
+            DeviceNotification::TestCancelled => "cancelled",
+            DeviceNotification::TestAborted => "aborted",
+            DeviceNotification::ConnectionClosed => "disconnected",
+            DeviceNotification::ConnectionLost => "connection_lost",
+            DeviceNotification::Reconnected => "connected",
+            // Test progress is published by publish_test_notification
+            // instead: device and test notifications are delivered
+            // independently, and a TestStarted might therefore arrive after
+            // the first exercise was started.
+            // Faults are followed by the resulting state changes (i.e.
+            // aborted and/or disconnected).
+            DeviceNotification::TestStarted
+            | DeviceNotification::DeviceProperties(_)
+            | DeviceNotification::SampleCadence(_)
+            | DeviceNotification::Fault { .. }
+            | DeviceNotification::DeviceUnresponsive => return Ok(()),
+        };
+        self.publish_to(&self.topics.state, device, state, true)
+    }
+
+    /// Publishes exercise changes (to the state topic) and exercise FFs.
+    /// Other notifications are ignored.
+    pub fn publish_test_notification(
+        &self,
+        device: &str,
+        notification: &TestNotification,
+    ) -> std::io::Result<()> {
+        match notification {
+            TestNotification::StateChange(TestState::StartedExercise(exercise)) => self.publish_to(
+                &self.topics.state,
+                device,
+                &format!("exercise {}", exercise + 1),
+                true,
+            ),
+            TestNotification::ExerciseResult {
+                exercise,
+                fit_factor,
+                ..
+            } => self.publish_to(
+                &self.topics.exercise,
+                device,
+                &format!(
+                    "{{\"exercise\":{},\"fit_factor\":{}}}",
+                    exercise + 1,
+                    json_number(*fit_factor)
+                ),
+                false,
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for MqttPublisher {
+    fn drop(&mut self) {
+        let connection = self.connection.lock().unwrap();
+        let _ = (&connection.stream).write_all(&[DISCONNECT, 0]);
+        let _ = connection.stream.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    // Reads a single packet, returning its header and body.
+    fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        let header = byte[0];
+        let mut length = 0;
+        let mut multiplier = 1;
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            length += (byte[0] & 0x7f) as usize * multiplier;
+            multiplier *= 128;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        (header, body)
+    }
+
+    fn parse_publish(body: &[u8]) -> (String, String) {
+        let topic_length = u16::from_be_bytes([body[0], body[1]]) as usize;
+        (
+            String::from_utf8(body[2..2 + topic_length].to_vec()).unwrap(),
+            String::from_utf8(body[2 + topic_length..].to_vec()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_remaining_length() {
+        for (length, expected) in [
+            (0, vec![0x00]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x01]),
+            (16_383, vec![0xff, 0x7f]),
+            (16_384, vec![0x80, 0x80, 0x01]),
+        ] {
+            let mut encoded = Vec::new();
+            encode_remaining_length(length, &mut encoded);
+            assert_eq!(encoded, expected, "{length}");
+        }
+    }
+
+    #[test]
+    fn test_publisher() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (header, body) = read_packet(&mut stream);
+            assert_eq!(header, CONNECT);
+            // Protocol name, level, flags (clean session, username), keep
+            // alive, client id, username.
+            assert_eq!(
+                body,
+                b"\x00\x04MQTT\x04\x82\x00\x3c\x00\x04test\x00\x04user".to_vec()
+            );
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+
+            let mut published = Vec::new();
+            loop {
+                let (header, body) = read_packet(&mut stream);
+                if header == DISCONNECT {
+                    return published;
+                }
+                let (topic, payload) = parse_publish(&body);
+                published.push((header & RETAIN == RETAIN, topic, payload));
+            }
+        });
+
+        let publisher = MqttPublisher::connect(MqttOptions {
+            host: "127.0.0.1".to_string(),
+            port,
+            client_id: "test".to_string(),
+            username: Some("user".to_string()),
+            topics: MqttTopics {
+                sample: "lab/{device}/concentration".to_string(),
+                ..MqttTopics::default()
+            },
+            ..MqttOptions::default()
+        })
+        .unwrap();
+        let notifications = [
+            DeviceNotification::TestStarted,
+            DeviceNotification::Sample {
+                particle_conc: 1234.5,
+            },
+            DeviceNotification::SampleCadence(crate::cadence::CadenceWarning::Recovered),
+            DeviceNotification::ConnectionLost,
+        ];
+        for notification in &notifications {
+            publisher
+                .publish_device_notification("80201234", notification)
+                .unwrap();
+        }
+        publisher
+            .publish_test_notification(
+                "80201234",
+                &TestNotification::StateChange(TestState::StartedExercise(0)),
+            )
+            .unwrap();
+        publisher
+            .publish_test_notification(
+                "80201234",
+                &TestNotification::ExerciseResult {
+                    exercise: 0,
+                    fit_factor: 123.4,
+                    error: 1.0,
+                    raw_fit_factor: 123.45,
+                },
+            )
+            .unwrap();
+        drop(publisher);
+
+        let expected = [
+            (false, "lab/80201234/concentration", "1234.5"),
+            (true, "p8020/80201234/state", "connection_lost"),
+            (true, "p8020/80201234/state", "exercise 1"),
+            (
+                false,
+                "p8020/80201234/exercise",
+                "{\"exercise\":1,\"fit_factor\":123.4}",
+            ),
+        ]
+        .map(|(retain, topic, payload)| (retain, topic.to_string(), payload.to_string()));
+        assert_eq!(broker.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_result_json() {
+        let result = TestResult {
+            fit_factors: vec![150.0, 80.5],
+            raw_fit_factors: vec![150.2, 80.5],
+            overall_fit_factor: 103.4,
+            raw_overall_fit_factor: 103.43,
+            exercise_passed: vec![Some(true), Some(false)],
+            passed: Some(false),
+            audit_log: Vec::new(),
+            errors: vec![1.0, 1.0],
+            exercise_names: vec!["Normal \"breathing\"".to_string(), "Talking".to_string()],
+            started_at: SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_500),
+            completed_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_100),
+            config_short_name: "osha".to_string(),
+            config_version: 1,
+            config_fingerprint: 0,
+        };
+        assert_eq!(
+            result_json(&result),
+            concat!(
+                "{\"config\":\"osha\",\"started_at\":1700000000.500,",
+                "\"completed_at\":1700000100.000,\"overall_fit_factor\":103.4,\"passed\":false,",
+                "\"exercises\":[{\"name\":\"Normal \\\"breathing\\\"\",\"fit_factor\":150,\"passed\":true},",
+                "{\"name\":\"Talking\",\"fit_factor\":80.5,\"passed\":false}]}"
+            )
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Capture of messages that could not be parsed, so that firmware quirks
+//! (which may only occur rarely) can be reported and fixed in the parser.
+//!
+//! Failures are appended to a file as JSON lines, each containing the raw
+//! line, when it was received, the device, the parse error, and the lines
+//! that were received immediately before it. The file is rotated once it
+//! reaches ParseFailureLogOptions::max_size.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::json::json_string;
+
+#[derive(Clone, Debug)]
+pub struct ParseFailureLogOptions {
+    /// The file to append to. Rotated files are stored alongside, with a
+    /// numeric suffix (e.g. parse-failures.log.1, with .1 being the newest).
+    pub path: PathBuf,
+    /// The size beyond which the file is rotated (default: 1MiB).
+    pub max_size: u64,
+    /// The number of rotated files to keep, older files are deleted
+    /// (default: 4).
+    pub max_rotated_files: usize,
+    /// The number of previously received lines to record alongside each
+    /// failure (default: 8).
+    pub context_lines: usize,
+}
+
+impl Default for ParseFailureLogOptions {
+    fn default() -> Self {
+        ParseFailureLogOptions {
+            path: PathBuf::from("p8020-parse-failures.log"),
+            max_size: 1024 * 1024,
+            max_rotated_files: 4,
+            context_lines: 8,
+        }
+    }
+}
+
+/// ParseFailureLog records unparseable messages, see the module docs. A
+/// single log may be shared between several devices (see
+/// ConnectOptions::parse_failure_log).
+#[derive(Debug)]
+pub struct ParseFailureLog {
+    options: ParseFailureLogOptions,
+    // The current file, and its size.
+    file: Mutex<(File, u64)>,
+}
+
+fn open_append(path: &Path) -> std::io::Result<(File, u64)> {
+    let file = File::options().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{index}"));
+    PathBuf::from(rotated)
+}
+
+impl ParseFailureLog {
+    pub fn open(options: ParseFailureLogOptions) -> std::io::Result<ParseFailureLog> {
+        let file = open_append(&options.path)?;
+        Ok(ParseFailureLog {
+            options,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// The number of previously received lines that should be passed to
+    /// record().
+    pub fn context_lines(&self) -> usize {
+        self.options.context_lines
+    }
+
+    /// Appends a single failure. line is the raw line as received (including
+    /// any line endings), context contains the lines received before it
+    /// (oldest first).
+    pub fn record(
+        &self,
+        device: usize,
+        line: &str,
+        error: &str,
+        context: &[String],
+    ) -> std::io::Result<()> {
+        let timestamp = time::OffsetDateTime::from(SystemTime::now())
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap();
+        let context: Vec<String> = context.iter().map(|line| json_string(line)).collect();
+        let entry = format!(
+            "{{\"timestamp\": {}, \"device\": {device}, \"line\": {}, \"error\": {}, \"context\": [{}]}}\n",
+            json_string(&timestamp),
+            json_string(line),
+            json_string(error),
+            context.join(", ")
+        );
+
+        let mut file = self.file.lock().unwrap();
+        if file.1 > 0 && file.1 + entry.len() as u64 > self.options.max_size {
+            *file = self.rotate()?;
+        }
+        file.0.write_all(entry.as_bytes())?;
+        file.1 += entry.len() as u64;
+        Ok(())
+    }
+
+    // Shifts all rotated files by one (dropping the oldest), moves the current
+    // file to .1, and opens a new file.
+    fn rotate(&self) -> std::io::Result<(File, u64)> {
+        let path = &self.options.path;
+        if self.options.max_rotated_files == 0 {
+            std::fs::remove_file(path)?;
+            return open_append(path);
+        }
+        let oldest = rotated_path(path, self.options.max_rotated_files);
+        if oldest.exists() {
+            std::fs::remove_file(oldest)?;
+        }
+        for index in (1..self.options.max_rotated_files).rev() {
+            let rotated = rotated_path(path, index);
+            if rotated.exists() {
+                std::fs::rename(rotated, rotated_path(path, index + 1))?;
+            }
+        }
+        std::fs::rename(path, rotated_path(path, 1))?;
+        open_append(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("p8020-parse-failures-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("failures.log");
+        let log = ParseFailureLog::open(ParseFailureLogOptions {
+            path: path.clone(),
+            max_size: 200,
+            max_rotated_files: 2,
+            ..ParseFailureLogOptions::default()
+        })
+        .unwrap();
+
+        let context = ["OK\r\n".to_string(), "C000123.45\r\n".to_string()];
+        log.record(3, "\u{0}\u{e9}r\r\n", "UnknownMessage", &context)
+            .unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.ends_with(concat!(
+            "\"device\": 3, \"line\": \"\\u0000\u{e9}r\\u000d\\u000a\", ",
+            "\"error\": \"UnknownMessage\", ",
+            "\"context\": [\"OK\\u000d\\u000a\", \"C000123.45\\u000d\\u000a\"]}\n"
+        )));
+
+        // Each entry exceeds half of max_size, i.e. every entry after the
+        // first triggers a rotation.
+        for _ in 0..3 {
+            log.record(3, "junk", "UnknownMessage", &context).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert!(rotated_path(&path, 1).exists());
+        assert!(rotated_path(&path, 2).exists());
+        assert!(!rotated_path(&path, 3).exists());
+        // The first entry has been rotated away.
+        for index in 1..=2 {
+            let rotated = std::fs::read_to_string(rotated_path(&path, index)).unwrap();
+            assert!(rotated.contains("\"line\": \"junk\""));
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
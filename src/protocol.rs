@@ -1,6 +1,7 @@
 use std::str::FromStr;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Indicator {
     pub in_progress: bool,
     pub fit_factor: bool,
@@ -28,6 +29,7 @@ impl Indicator {
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Command {
     EnterExternalControl,
     ExitExternalControl,
@@ -114,6 +116,49 @@ impl Command {
             Command::RequestSettings => Ok("S".to_string()),
         }
     }
+
+    pub fn class(&self) -> CommandClass {
+        match self {
+            Command::EnterExternalControl
+            | Command::ExitExternalControl
+            | Command::RequestSettings => CommandClass::Control,
+            Command::ValveAmbient | Command::ValveSpecimen => CommandClass::Valve,
+            Command::DisplayExercise(_)
+            | Command::DisplayConcentration(_)
+            | Command::Indicator(_)
+            | Command::ClearDisplay => CommandClass::Display,
+            Command::Beep { .. } => CommandClass::Beep,
+        }
+    }
+}
+
+/// CommandClass groups commands by their purpose, e.g. to pace them
+/// differently (see ConnectOptions::command_class_intervals).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CommandClass {
+    /// EnterExternalControl, ExitExternalControl, and RequestSettings.
+    Control,
+    /// ValveAmbient and ValveSpecimen.
+    Valve,
+    /// DisplayExercise, DisplayConcentration, Indicator, and ClearDisplay.
+    Display,
+    Beep,
+}
+
+impl FromStr for CommandClass {
+    type Err = String;
+
+    fn from_str(class: &str) -> Result<CommandClass, String> {
+        match class {
+            "control" => Ok(CommandClass::Control),
+            "valve" => Ok(CommandClass::Valve),
+            "display" => Ok(CommandClass::Display),
+            "beep" => Ok(CommandClass::Beep),
+            _ => Err(format!(
+                "unknown command class {class} (expected control, valve, display, or beep)"
+            )),
+        }
+    }
 }
 
 /// Message represents any message sent by the device. This can be a response,
@@ -123,7 +168,8 @@ impl Command {
 /// the addendum for details (e.g. the Error message can be received in response
 /// to any command that the PortaCount didn't understand; the Settings command
 /// triggers a list of settings across multiple messages; etc.).
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Message {
     Response(Command),
     /// Error response. Note: UnknownError might be returned instead of the
@@ -234,7 +280,8 @@ fn parse_command(command: &str) -> Result<Command, ParseError> {
 /// Note: the addendum specifies that each value will be within a specific
 /// range. However libp8020 does not actually validate that the device returned
 /// a setting within the specified range.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SettingMessage {
     // Spec: 4..=25
     AmbientPurgeTime {
@@ -736,6 +783,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_command_class() {
+        assert_eq!(Command::ValveAmbient.class(), CommandClass::Valve);
+        assert_eq!(
+            Command::DisplayConcentration(1.0).class(),
+            CommandClass::Display
+        );
+        assert_eq!("display".parse(), Ok(CommandClass::Display));
+        assert!("Display".parse::<CommandClass>().is_err());
+    }
+
     #[test]
     fn test_parse_message() {
         struct TestCase<'a> {
@@ -1216,7 +1274,7 @@ mod tests {
                 name: "SettingDateLastServiced_01_99",
                 input: "SD   00199",
                 expected_result: Ok(Message::Setting(SettingMessage::DateLastServiced {
-                    month: 01,
+                    month: 1,
                     year: 99,
                 })),
             },
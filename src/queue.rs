@@ -0,0 +1,156 @@
+//! A bounded queue for passing messages between threads, for use wherever
+//! messages may be produced faster than they are consumed (e.g. because of a
+//! slow network target). Unlike mpsc::sync_channel, the overflow behaviour is
+//! chosen per message: samples are superseded by newer samples and may
+//! therefore be dropped, whereas e.g. test results must never be dropped.
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+/// What to do with a message when the queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued message that was itself pushed with
+    /// DropOldest, or the new message if there is no such message.
+    DropOldest,
+    /// Block until the consumer makes space. Messages are never dropped.
+    Block,
+}
+
+struct State<T> {
+    items: VecDeque<(T, OverflowPolicy)>,
+    dropped: u64,
+    closed: bool,
+}
+
+/// BoundedQueue holds at most capacity messages, see OverflowPolicy for what
+/// happens beyond that. It is typically shared via an Arc, with any number of
+/// producers and a single consumer.
+pub struct BoundedQueue<T> {
+    capacity: usize,
+    state: Mutex<State<T>>,
+    // Notified whenever items are pushed or popped, or the queue is closed.
+    changed: Condvar,
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn new(capacity: usize) -> BoundedQueue<T> {
+        assert!(capacity > 0, "queue capacity must be positive");
+        BoundedQueue {
+            capacity,
+            state: Mutex::new(State {
+                items: VecDeque::with_capacity(capacity),
+                dropped: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Appends item, handling overflow as per policy. Returns false if the
+    /// queue has been closed (in which case item is discarded).
+    pub fn push(&self, item: T, policy: OverflowPolicy) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while !state.closed && state.items.len() >= self.capacity {
+            if policy == OverflowPolicy::Block {
+                state = self.changed.wait(state).unwrap();
+                continue;
+            }
+            state.dropped += 1;
+            match state
+                .items
+                .iter()
+                .position(|(_, policy)| *policy == OverflowPolicy::DropOldest)
+            {
+                Some(index) => {
+                    state.items.remove(index);
+                }
+                None => return true,
+            }
+        }
+        if state.closed {
+            return false;
+        }
+        state.items.push_back((item, policy));
+        self.changed.notify_all();
+        true
+    }
+
+    /// Blocks until at least one item is available, and returns all queued
+    /// items. Returns None once the queue is closed and empty.
+    pub fn pop_all(&self) -> Option<Vec<T>> {
+        let mut state = self.state.lock().unwrap();
+        while state.items.is_empty() {
+            if state.closed {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+        let items = state.items.drain(..).map(|(item, _)| item).collect();
+        self.changed.notify_all();
+        Some(items)
+    }
+
+    /// Rejects all further pushes (and unblocks pushes that are waiting).
+    /// Items that were already queued can still be popped.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+
+    /// The number of items that were dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_drop_oldest() {
+        let queue = BoundedQueue::new(3);
+        assert!(queue.push("result", OverflowPolicy::Block));
+        assert!(queue.push("sample 1", OverflowPolicy::DropOldest));
+        assert!(queue.push("sample 2", OverflowPolicy::DropOldest));
+        assert!(queue.push("sample 3", OverflowPolicy::DropOldest));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(
+            queue.pop_all(),
+            Some(vec!["result", "sample 2", "sample 3"])
+        );
+
+        // Nothing may be dropped to make space, i.e. the new sample is
+        // dropped instead.
+        for _ in 0..3 {
+            queue.push("result", OverflowPolicy::Block);
+        }
+        assert!(queue.push("sample 4", OverflowPolicy::DropOldest));
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.pop_all(), Some(vec!["result"; 3]));
+    }
+
+    #[test]
+    fn test_block_and_close() {
+        let queue = Arc::new(BoundedQueue::new(1));
+        queue.push(1, OverflowPolicy::Block);
+        let producer = {
+            let queue = queue.clone();
+            std::thread::spawn(move || queue.push(2, OverflowPolicy::Block))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!producer.is_finished());
+        assert_eq!(queue.pop_all(), Some(vec![1]));
+        assert!(producer.join().unwrap());
+
+        queue.close();
+        assert!(!queue.push(3, OverflowPolicy::Block));
+        // Items queued before closing are still delivered.
+        assert_eq!(queue.pop_all(), Some(vec![2]));
+        assert_eq!(queue.pop_all(), None);
+        assert_eq!(queue.dropped(), 0);
+    }
+}
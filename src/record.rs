@@ -0,0 +1,269 @@
+//! Fit test records containing the information that OSHA requires employers
+//! to retain for each fit test (see 29 CFR 1910.134(m)(2)): who was tested,
+//! the specific make, model, style, and size of respirator, the type of test
+//! and protocol (see Appendix A), the date, and the results.
+
+use std::time::SystemTime;
+
+use crate::json::{json_bool, json_number, json_optional_string, json_string};
+use crate::TestResult;
+
+/// The type of fit test performed by this library: a quantitative fit test
+/// (QNFT) using a condensation nuclei counter (CNC), i.e. a PortaCount.
+pub const TEST_TYPE: &str = "QNFT (CNC)";
+
+/// TestMetadata describes who and what was tested, i.e. everything about a
+/// test that the device cannot know.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TestMetadata {
+    /// The name or ID of the test subject.
+    pub subject: Option<String>,
+    /// The respirator's manufacturer.
+    pub respirator_make: Option<String>,
+    pub respirator_model: Option<String>,
+    /// E.g. "half mask", "full facepiece", or "filtering facepiece".
+    pub respirator_style: Option<String>,
+    pub respirator_size: Option<String>,
+    /// The name or ID of the person administering the test.
+    pub operator: Option<String>,
+}
+
+/// OshaRecord is the record of a single completed test.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OshaRecord {
+    pub metadata: TestMetadata,
+    /// The short name of the protocol (config) that was run.
+    pub protocol: String,
+    pub protocol_version: u32,
+    pub tested_at: SystemTime,
+    pub overall_fit_factor: f64,
+    /// The name and (rounded) FF of each exercise.
+    pub exercise_fit_factors: Vec<(String, f64)>,
+    /// None if the protocol does not define pass levels, see
+    /// TestResult::passed.
+    pub passed: Option<bool>,
+    /// The serial number of the device used for the test, if known.
+    pub instrument_serial_number: Option<String>,
+}
+
+impl OshaRecord {
+    pub fn new(result: &TestResult, metadata: &TestMetadata) -> OshaRecord {
+        OshaRecord {
+            metadata: metadata.clone(),
+            protocol: result.config_short_name.clone(),
+            protocol_version: result.config_version,
+            tested_at: result.started_at,
+            overall_fit_factor: result.overall_fit_factor,
+            exercise_fit_factors: result
+                .exercise_names
+                .iter()
+                .cloned()
+                .zip(result.fit_factors.iter().copied())
+                .collect(),
+            passed: result.passed,
+            instrument_serial_number: None,
+        }
+    }
+
+    fn result(&self) -> &'static str {
+        match self.passed {
+            Some(true) => "PASS",
+            Some(false) => "FAIL",
+            None => "",
+        }
+    }
+
+    // The date of the test (UTC), as required by OSHA, followed by the exact
+    // time.
+    fn dates(&self) -> (String, String) {
+        let tested_at = time::OffsetDateTime::from(self.tested_at);
+        let date = time::macros::format_description!("[year]-[month]-[day]");
+        (
+            tested_at.format(&date).unwrap(),
+            tested_at
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap(),
+        )
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+const CSV_HEADER: &str = "subject,respirator_make,respirator_model,respirator_style,respirator_size,test_type,protocol,protocol_version,date,tested_at,result,overall_fit_factor,exercise_fit_factors,operator,instrument_serial_number\n";
+
+/// Formats records as CSV, with one row per test. Exercise FFs are listed in
+/// a single column, separated by semicolons.
+pub fn to_csv(records: &[OshaRecord]) -> String {
+    let mut csv = CSV_HEADER.to_string();
+    for record in records {
+        let metadata = &record.metadata;
+        let (date, tested_at) = record.dates();
+        let exercise_fit_factors: Vec<String> = record
+            .exercise_fit_factors
+            .iter()
+            .map(|(_, fit_factor)| fit_factor.to_string())
+            .collect();
+        let fields = [
+            csv_field(metadata.subject.as_deref().unwrap_or("")),
+            csv_field(metadata.respirator_make.as_deref().unwrap_or("")),
+            csv_field(metadata.respirator_model.as_deref().unwrap_or("")),
+            csv_field(metadata.respirator_style.as_deref().unwrap_or("")),
+            csv_field(metadata.respirator_size.as_deref().unwrap_or("")),
+            csv_field(TEST_TYPE),
+            csv_field(&record.protocol),
+            record.protocol_version.to_string(),
+            date,
+            tested_at,
+            record.result().to_string(),
+            record.overall_fit_factor.to_string(),
+            exercise_fit_factors.join(";"),
+            csv_field(metadata.operator.as_deref().unwrap_or("")),
+            csv_field(record.instrument_serial_number.as_deref().unwrap_or("")),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Formats records as a JSON array, with one object per test.
+pub fn to_json(records: &[OshaRecord]) -> String {
+    let objects: Vec<String> = records
+        .iter()
+        .map(|record| {
+            let metadata = &record.metadata;
+            let (date, tested_at) = record.dates();
+            let exercises: Vec<String> = record
+                .exercise_fit_factors
+                .iter()
+                .map(|(name, fit_factor)| {
+                    format!(
+                        "{{\"name\": {}, \"fit_factor\": {}}}",
+                        json_string(name),
+                        json_number(*fit_factor)
+                    )
+                })
+                .collect();
+            format!(
+                concat!(
+                    "  {{\n",
+                    "    \"subject\": {},\n",
+                    "    \"respirator_make\": {},\n",
+                    "    \"respirator_model\": {},\n",
+                    "    \"respirator_style\": {},\n",
+                    "    \"respirator_size\": {},\n",
+                    "    \"test_type\": {},\n",
+                    "    \"protocol\": {},\n",
+                    "    \"protocol_version\": {},\n",
+                    "    \"date\": {},\n",
+                    "    \"tested_at\": {},\n",
+                    "    \"passed\": {},\n",
+                    "    \"overall_fit_factor\": {},\n",
+                    "    \"exercise_fit_factors\": [{}],\n",
+                    "    \"operator\": {},\n",
+                    "    \"instrument_serial_number\": {}\n",
+                    "  }}"
+                ),
+                json_optional_string(metadata.subject.as_deref()),
+                json_optional_string(metadata.respirator_make.as_deref()),
+                json_optional_string(metadata.respirator_model.as_deref()),
+                json_optional_string(metadata.respirator_style.as_deref()),
+                json_optional_string(metadata.respirator_size.as_deref()),
+                json_string(TEST_TYPE),
+                json_string(&record.protocol),
+                record.protocol_version,
+                json_string(&date),
+                json_string(&tested_at),
+                json_bool(record.passed),
+                json_number(record.overall_fit_factor),
+                exercises.join(", "),
+                json_optional_string(metadata.operator.as_deref()),
+                json_optional_string(record.instrument_serial_number.as_deref())
+            )
+        })
+        .collect();
+    if objects.is_empty() {
+        return "[]\n".to_string();
+    }
+    format!("[\n{}\n]\n", objects.join(",\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn record() -> OshaRecord {
+        let result = TestResult {
+            fit_factors: vec![150.0, 80.5],
+            raw_fit_factors: vec![150.2, 80.5],
+            overall_fit_factor: 103.4,
+            raw_overall_fit_factor: 103.43,
+            exercise_passed: vec![None, None],
+            passed: Some(true),
+            audit_log: Vec::new(),
+            errors: vec![1.0, 1.0],
+            exercise_names: vec!["Normal breathing".to_string(), "Talking".to_string()],
+            // 2023-11-14T22:13:20Z
+            started_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            completed_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_100),
+            config_short_name: "osha".to_string(),
+            config_version: 2,
+            config_fingerprint: 0,
+        };
+        let metadata = TestMetadata {
+            subject: Some("Doe, Jane".to_string()),
+            respirator_make: Some("3M".to_string()),
+            respirator_model: Some("6200".to_string()),
+            respirator_style: Some("half mask".to_string()),
+            respirator_size: Some("M".to_string()),
+            operator: None,
+        };
+        OshaRecord {
+            instrument_serial_number: Some("80201234".to_string()),
+            ..OshaRecord::new(&result, &metadata)
+        }
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let failed = OshaRecord {
+            passed: Some(false),
+            ..record()
+        };
+        assert_eq!(
+            to_csv(&[record(), failed]),
+            CSV_HEADER.to_string()
+                + "\"Doe, Jane\",3M,6200,half mask,M,QNFT (CNC),osha,2,2023-11-14,2023-11-14T22:13:20Z,PASS,103.4,150;80.5,,80201234\n"
+                + "\"Doe, Jane\",3M,6200,half mask,M,QNFT (CNC),osha,2,2023-11-14,2023-11-14T22:13:20Z,FAIL,103.4,150;80.5,,80201234\n"
+        );
+    }
+
+    #[test]
+    fn test_to_json() {
+        assert_eq!(to_json(&[]), "[]\n");
+        let json = to_json(&[record()]);
+        for expected in [
+            "\"subject\": \"Doe, Jane\",\n",
+            "\"respirator_style\": \"half mask\",\n",
+            "\"test_type\": \"QNFT (CNC)\",\n",
+            "\"date\": \"2023-11-14\",\n",
+            "\"passed\": true,\n",
+            "\"exercise_fit_factors\": [{\"name\": \"Normal breathing\", \"fit_factor\": 150}, {\"name\": \"Talking\", \"fit_factor\": 80.5}],\n",
+            "\"operator\": null,\n",
+        ] {
+            assert!(json.contains(expected), "{expected} missing:\n{json}");
+        }
+        #[cfg(feature = "serde")]
+        {
+            let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed[0]["overall_fit_factor"], 103.4);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Export of every sample taken during a test, annotated with the stage that
+//! it belongs to. This is primarily intended for research (e.g. to compare
+//! ambient concentration stability, or purge lengths), results are better
+//! exported via record or TestResult.
+
+use std::time::SystemTime;
+
+use crate::test_config::TestConfig;
+use crate::{SampleData, SampleType, TestNotification};
+
+/// AnnotatedSample is a single sample, along with when and where in the test
+/// it was taken.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnnotatedSample {
+    /// When the sample was received.
+    pub timestamp: SystemTime,
+    /// The index of the stage within TestConfig::stages.
+    pub stage: usize,
+    pub sample_type: SampleType,
+    /// The (0-indexed) exercise and its name, None for ambient samples.
+    pub exercise: Option<(usize, String)>,
+    pub concentration: f64,
+}
+
+/// SampleRecorder collects the samples of a single test, by being fed all of
+/// the test's notifications (see record()).
+#[derive(Clone, Debug)]
+pub struct SampleRecorder {
+    // The exercise name of each stage, None for ambient stages.
+    stage_names: Vec<Option<String>>,
+    samples: Vec<AnnotatedSample>,
+}
+
+impl SampleRecorder {
+    pub fn new(config: &TestConfig) -> SampleRecorder {
+        let mut exercise_names = config.exercise_names().into_iter();
+        SampleRecorder {
+            stage_names: config
+                .stages
+                .iter()
+                .map(|stage| {
+                    if stage.is_exercise() {
+                        exercise_names.next()
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+            samples: Vec::new(),
+        }
+    }
+
+    /// Records the sample contained in notification (if any), timestamped
+    /// with the current time. This must therefore be called as notifications
+    /// are received, e.g. from the test callback.
+    pub fn record(&mut self, notification: &TestNotification) {
+        let TestNotification::Sample(SampleData {
+            stage,
+            exercise,
+            value,
+            sample_type,
+        }) = notification
+        else {
+            return;
+        };
+        let exercise = match sample_type {
+            SampleType::AmbientPurge | SampleType::AmbientSample => None,
+            SampleType::SpecimenPurge | SampleType::SpecimenSample => self
+                .stage_names
+                .get(*stage)
+                .cloned()
+                .flatten()
+                .map(|name| (*exercise, name)),
+        };
+        self.samples.push(AnnotatedSample {
+            timestamp: SystemTime::now(),
+            stage: *stage,
+            sample_type: sample_type.clone(),
+            exercise,
+            concentration: *value,
+        });
+    }
+
+    pub fn samples(&self) -> &[AnnotatedSample] {
+        &self.samples
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+const CSV_HEADER: &str = "timestamp,stage,source,phase,exercise,exercise_name,concentration\n";
+
+/// Formats samples as CSV, with one row per sample. source is ambient or
+/// specimen, phase is purge or sample (purge samples are not used for FF
+/// calculations), and exercises are 1-indexed.
+pub fn to_csv(samples: &[AnnotatedSample]) -> String {
+    let mut csv = CSV_HEADER.to_string();
+    for sample in samples {
+        let (source, phase) = match sample.sample_type {
+            SampleType::AmbientPurge => ("ambient", "purge"),
+            SampleType::AmbientSample => ("ambient", "sample"),
+            SampleType::SpecimenPurge => ("specimen", "purge"),
+            SampleType::SpecimenSample => ("specimen", "sample"),
+        };
+        let (exercise, exercise_name) = match &sample.exercise {
+            Some((exercise, name)) => ((exercise + 1).to_string(), csv_field(name)),
+            None => (String::new(), String::new()),
+        };
+        let timestamp = time::OffsetDateTime::from(sample.timestamp)
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap();
+        csv.push_str(&format!(
+            "{timestamp},{},{source},{phase},{exercise},{exercise_name},{}\n",
+            sample.stage, sample.concentration
+        ));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{harness, TestOptions};
+
+    #[test]
+    fn test_record_and_export() {
+        let config = TestConfig::parse_from_csv(
+            &mut "TEST,\"Tiny\",tiny\nAMBIENT,1,1\nEXERCISE,1,2,\"Talking, loudly\"\nAMBIENT,0,1\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut recorder = SampleRecorder::new(&config);
+        let output = harness::run_concentrations(
+            config,
+            TestOptions::default(),
+            [1000.0, 1010.0, 20.0, 10.0, 12.0, 990.0],
+        );
+        assert!(output.completed);
+        for notification in &output.notifications {
+            recorder.record(notification);
+        }
+
+        let csv = to_csv(recorder.samples());
+        let rows: Vec<&str> = csv
+            .lines()
+            .map(|row| row.split_once(',').unwrap().1)
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                "stage,source,phase,exercise,exercise_name,concentration",
+                "0,ambient,purge,,,1000",
+                "0,ambient,sample,,,1010",
+                "1,specimen,purge,1,\"Talking, loudly\",20",
+                "1,specimen,sample,1,\"Talking, loudly\",10",
+                "1,specimen,sample,1,\"Talking, loudly\",12",
+                "2,ambient,sample,,,990",
+            ]
+        );
+    }
+}
@@ -0,0 +1,282 @@
+//! A simulated 8020, for demos and integration testing (see bin/simulate.rs,
+//! which exposes a Simulator on a pseudo-terminal).
+//!
+//! The simulator only implements the external control protocol as described
+//! in the Technical Addendum (plus the deviations observed on real devices,
+//! e.g. "VF" instead of "VO"). It does not simulate tests run directly on the
+//! device.
+
+use crate::protocol::{Command, Indicator};
+use crate::ValvePosition;
+
+/// SimulatorOptions describes the simulated device and its environment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulatorOptions {
+    /// Mean ambient concentration (default: 3000 particles/cm3).
+    pub ambient_concentration: f64,
+    /// The FF of the (simulated) mask being tested, i.e. the mean specimen
+    /// concentration is ambient_concentration / fit_factor (default: 200).
+    pub fit_factor: f64,
+    /// Relative noise applied to each sample, e.g. 0.1 means that samples
+    /// vary by up to 10% from the mean (default: 0.05).
+    pub noise: f64,
+    /// Seed for the noise, such that runs are reproducible.
+    pub seed: u64,
+    pub serial_number: String,
+    pub run_time_since_service_decaminutes: usize,
+    pub last_service_month: u8,
+    /// Modulo 100, see SettingMessage::DateLastServiced.
+    pub last_service_year: u8,
+    /// Mask sample time and FF pass level for each of the device's
+    /// exercises.
+    pub exercises: Vec<(usize, usize)>,
+}
+
+impl Default for SimulatorOptions {
+    fn default() -> Self {
+        SimulatorOptions {
+            ambient_concentration: 3000.0,
+            fit_factor: 200.0,
+            noise: 0.05,
+            seed: 8020,
+            serial_number: "80200000".to_string(),
+            run_time_since_service_decaminutes: 100,
+            last_service_month: 1,
+            last_service_year: 14,
+            exercises: vec![(60, 100); 8],
+        }
+    }
+}
+
+/// Simulator holds the state of a simulated device. Callers are responsible
+/// for delivering commands (see handle_command), and for requesting a sample
+/// once per second (see sample).
+pub struct Simulator {
+    options: SimulatorOptions,
+    external_control: bool,
+    valve: ValvePosition,
+    indicator: Indicator,
+    displayed_concentration: Option<f64>,
+    rng_state: u64,
+}
+
+impl Simulator {
+    pub fn new(options: SimulatorOptions) -> Simulator {
+        Simulator {
+            // xorshift requires a non-zero state.
+            rng_state: options.seed.max(1),
+            options,
+            external_control: false,
+            valve: ValvePosition::Specimen,
+            indicator: Indicator::empty(),
+            displayed_concentration: None,
+        }
+    }
+
+    pub fn external_control(&self) -> bool {
+        self.external_control
+    }
+
+    pub fn valve(&self) -> ValvePosition {
+        self.valve
+    }
+
+    pub fn indicator(&self) -> Indicator {
+        self.indicator
+    }
+
+    pub fn displayed_concentration(&self) -> Option<f64> {
+        self.displayed_concentration
+    }
+
+    /// Handles a single command (without line terminator), and returns the
+    /// messages sent in response.
+    pub fn handle_command(&mut self, command: &str) -> Vec<String> {
+        // Commands are parsed by mirroring how the device responds to them:
+        // most responses are identical to the command.
+        let parsed = match command {
+            "J" => Some(Command::EnterExternalControl),
+            "VO" => Some(Command::ValveSpecimen),
+            "S" => Some(Command::RequestSettings),
+            command => match crate::protocol::parse_message(command) {
+                Ok(crate::protocol::Message::Response(command)) => Some(command),
+                _ => None,
+            },
+        };
+        let Some(parsed) = parsed else {
+            return vec![format!("E{command}")];
+        };
+        if !self.external_control && parsed != Command::EnterExternalControl {
+            // Outside of external control mode, the device only listens for
+            // J.
+            return Vec::new();
+        }
+        match parsed {
+            Command::EnterExternalControl => {
+                self.external_control = true;
+                vec!["OK".to_string()]
+            }
+            Command::ExitExternalControl => {
+                self.external_control = false;
+                self.displayed_concentration = None;
+                self.indicator = Indicator::empty();
+                vec!["G".to_string()]
+            }
+            Command::ValveAmbient => {
+                self.valve = ValvePosition::Ambient;
+                vec!["VN".to_string()]
+            }
+            Command::ValveSpecimen => {
+                self.valve = ValvePosition::Specimen;
+                // See protocol::parse_command: real devices respond with VF.
+                vec!["VF".to_string()]
+            }
+            Command::DisplayConcentration(value) => {
+                self.displayed_concentration = Some(value);
+                vec![command.to_string()]
+            }
+            Command::ClearDisplay => {
+                self.displayed_concentration = None;
+                vec!["K".to_string()]
+            }
+            Command::Indicator(indicator) => {
+                self.indicator = indicator;
+                vec![command.to_string()]
+            }
+            Command::RequestSettings => self.settings(),
+            Command::Beep { .. } | Command::DisplayExercise(_) => vec![command.to_string()],
+        }
+    }
+
+    /// Returns the next sample message, or None if the device is not sending
+    /// samples (i.e. outside of external control mode).
+    pub fn sample(&mut self) -> Option<String> {
+        if !self.external_control {
+            return None;
+        }
+        let mean = match self.valve {
+            ValvePosition::Ambient => self.options.ambient_concentration,
+            ValvePosition::Specimen => self.options.ambient_concentration / self.options.fit_factor,
+        };
+        let noise = self.options.noise * (self.next_random() * 2.0 - 1.0);
+        let concentration = (mean * (1.0 + noise)).max(0.0);
+        Some(if concentration < 1_000_000.0 {
+            format!("{concentration:09.2}")
+        } else {
+            format!("{:08.0}.", concentration.min(99_999_999.0))
+        })
+    }
+
+    // The settings, in the order sent by the device (see
+    // DevicePropertiesCollector, which relies on the serial number and
+    // service details being sent last).
+    fn settings(&self) -> Vec<String> {
+        let options = &self.options;
+        let mut settings = vec![
+            "STPA 00004".to_string(),
+            "STA  00005".to_string(),
+            "STPM 00011".to_string(),
+        ];
+        for (i, (seconds, _)) in options.exercises.iter().enumerate() {
+            settings.push(format!("STM{:02}{seconds:05}", i + 1));
+        }
+        for (i, (_, fit_factor)) in options.exercises.iter().enumerate() {
+            settings.push(format!("SP {:02}{fit_factor:05}", i + 1));
+        }
+        settings.push(format!("SS   {}", options.serial_number));
+        settings.push(format!(
+            "SR   {:05}",
+            options.run_time_since_service_decaminutes
+        ));
+        settings.push(format!(
+            "SD   {:02}{:02}",
+            options.last_service_month, options.last_service_year
+        ));
+        settings
+    }
+
+    // xorshift64, returns a value within [0, 1).
+    fn next_random(&mut self) -> f64 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 7;
+        self.rng_state ^= self.rng_state << 17;
+        (self.rng_state >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{parse_message, Message, SettingMessage};
+
+    #[test]
+    fn test_external_control() {
+        let mut simulator = Simulator::new(SimulatorOptions::default());
+        assert_eq!(simulator.sample(), None);
+        assert!(simulator.handle_command("VN").is_empty());
+
+        assert_eq!(simulator.handle_command("J"), vec!["OK"]);
+        assert_eq!(simulator.handle_command("VN"), vec!["VN"]);
+        assert_eq!(simulator.valve(), ValvePosition::Ambient);
+        assert_eq!(simulator.handle_command("D000123.45"), vec!["D000123.45"]);
+        assert_eq!(simulator.displayed_concentration(), Some(123.45));
+        assert_eq!(simulator.handle_command("I01000000"), vec!["I01000000"]);
+        assert!(simulator.indicator().in_progress);
+        assert_eq!(simulator.handle_command("VO"), vec!["VF"]);
+        assert_eq!(simulator.handle_command("X"), vec!["EX"]);
+
+        assert_eq!(simulator.handle_command("G"), vec!["G"]);
+        assert!(!simulator.external_control());
+        assert_eq!(simulator.sample(), None);
+    }
+
+    #[test]
+    fn test_samples() {
+        let options = SimulatorOptions {
+            noise: 0.1,
+            ..SimulatorOptions::default()
+        };
+        let mut simulator = Simulator::new(options.clone());
+        simulator.handle_command("J");
+        simulator.handle_command("VN");
+        for _ in 0..100 {
+            let Ok(Message::Sample(sample)) = parse_message(&simulator.sample().unwrap()) else {
+                panic!("sample must be parseable");
+            };
+            assert!((sample - options.ambient_concentration).abs() <= 300.0);
+        }
+        simulator.handle_command("VF");
+        let specimen_mean = (0..100)
+            .map(|_| match parse_message(&simulator.sample().unwrap()) {
+                Ok(Message::Sample(sample)) => sample,
+                _ => panic!("sample must be parseable"),
+            })
+            .sum::<f64>()
+            / 100.0;
+        assert!((specimen_mean - 15.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut simulator = Simulator::new(SimulatorOptions::default());
+        simulator.handle_command("J");
+        let settings: Vec<SettingMessage> = simulator
+            .handle_command("S")
+            .iter()
+            .map(|setting| match parse_message(setting) {
+                Ok(Message::Setting(setting)) => setting,
+                other => panic!("unexpected message for {setting}: {other:?}"),
+            })
+            .collect();
+        assert_eq!(settings.len(), 3 + 8 * 2 + 3);
+        assert!(settings.contains(&SettingMessage::MaskSampleTime { ex: 8, seconds: 60 }));
+        assert!(settings.contains(&SettingMessage::FitFactorPassLevel {
+            ex: 1,
+            fit_factor: 100
+        }));
+        assert_eq!(
+            settings.last(),
+            Some(&SettingMessage::DateLastServiced { month: 1, year: 14 })
+        );
+    }
+}
@@ -0,0 +1,638 @@
+//! Persistence of fit testing records (subjects, respirators, sessions, and
+//! test results) in an SQLite database, with helpers for common queries.
+//!
+//! A session groups all tests performed for one subject wearing one
+//! respirator (e.g. a retest after adjusting the mask belongs to the same
+//! session). Timestamps are stored as milliseconds since the unix epoch, and
+//! are therefore truncated to millisecond precision.
+//!
+//! Requires the store feature, which links against the system's libsqlite3.
+
+mod sqlite;
+
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::TestResult;
+use sqlite::{Connection, Row, Value};
+
+/// The current version of the database schema, stored in the database's
+/// user_version. Databases created by a newer version of libp8020 are
+/// rejected.
+pub const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE subjects (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        notes TEXT NOT NULL
+    );
+    CREATE TABLE respirators (
+        id INTEGER PRIMARY KEY,
+        manufacturer TEXT NOT NULL,
+        model TEXT NOT NULL,
+        size TEXT NOT NULL
+    );
+    CREATE TABLE sessions (
+        id INTEGER PRIMARY KEY,
+        subject_id INTEGER NOT NULL REFERENCES subjects(id),
+        respirator_id INTEGER REFERENCES respirators(id),
+        started_at INTEGER NOT NULL,
+        notes TEXT NOT NULL
+    );
+    CREATE TABLE results (
+        id INTEGER PRIMARY KEY,
+        session_id INTEGER NOT NULL REFERENCES sessions(id),
+        config_short_name TEXT NOT NULL,
+        config_version INTEGER NOT NULL,
+        config_fingerprint INTEGER NOT NULL,
+        started_at INTEGER NOT NULL,
+        completed_at INTEGER NOT NULL,
+        overall_fit_factor REAL,
+        raw_overall_fit_factor REAL,
+        passed INTEGER
+    );
+    CREATE INDEX results_started_at ON results(started_at);
+    CREATE TABLE exercise_results (
+        result_id INTEGER NOT NULL REFERENCES results(id),
+        exercise INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        fit_factor REAL,
+        raw_fit_factor REAL,
+        error REAL,
+        passed INTEGER,
+        PRIMARY KEY (result_id, exercise)
+    );
+";
+
+const RESULT_COLUMNS: &str = "results.id, results.session_id, results.config_short_name,
+    results.config_version, results.config_fingerprint, results.started_at,
+    results.completed_at, results.overall_fit_factor, results.raw_overall_fit_factor,
+    results.passed";
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// An error reported by SQLite, see https://www.sqlite.org/rescode.html.
+    Sqlite { code: i32, message: String },
+    /// The database was created by a newer version of libp8020.
+    UnsupportedSchemaVersion(i64),
+    /// A value could not be stored, or a stored value could not be read.
+    Invalid(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subject {
+    pub id: i64,
+    pub name: String,
+    pub notes: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Respirator {
+    pub id: i64,
+    pub manufacturer: String,
+    pub model: String,
+    pub size: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    pub id: i64,
+    pub subject_id: i64,
+    pub respirator_id: Option<i64>,
+    pub started_at: SystemTime,
+    pub notes: String,
+}
+
+/// ExerciseRecord is the stored result of a single exercise, see TestResult
+/// for the meaning of each field.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExerciseRecord {
+    pub name: String,
+    pub fit_factor: f64,
+    pub raw_fit_factor: f64,
+    pub error: f64,
+    pub passed: Option<bool>,
+}
+
+/// ResultRecord is a stored TestResult. The audit log is not stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResultRecord {
+    pub id: i64,
+    pub session_id: i64,
+    pub config_short_name: String,
+    pub config_version: u32,
+    pub config_fingerprint: u64,
+    pub started_at: SystemTime,
+    pub completed_at: SystemTime,
+    pub overall_fit_factor: f64,
+    pub raw_overall_fit_factor: f64,
+    pub passed: Option<bool>,
+    pub exercises: Vec<ExerciseRecord>,
+}
+
+fn timestamp(time: SystemTime) -> Value {
+    let millis = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => duration.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    };
+    Value::Integer(millis)
+}
+
+fn bool_value(value: Option<bool>) -> Value {
+    value.map(i64::from).into()
+}
+
+fn invalid(column: usize, value: &Value) -> StoreError {
+    StoreError::Invalid(format!("unexpected value {value:?} in column {column}"))
+}
+
+fn integer(row: &Row, column: usize) -> Result<i64, StoreError> {
+    match &row[column] {
+        Value::Integer(value) => Ok(*value),
+        value => Err(invalid(column, value)),
+    }
+}
+
+fn optional_integer(row: &Row, column: usize) -> Result<Option<i64>, StoreError> {
+    match &row[column] {
+        Value::Null => Ok(None),
+        _ => integer(row, column).map(Some),
+    }
+}
+
+// SQLite stores NaN as NULL, NULL is therefore read back as NaN.
+fn real(row: &Row, column: usize) -> Result<f64, StoreError> {
+    match &row[column] {
+        Value::Null => Ok(f64::NAN),
+        Value::Real(value) => Ok(*value),
+        Value::Integer(value) => Ok(*value as f64),
+        value => Err(invalid(column, value)),
+    }
+}
+
+fn text(row: &Row, column: usize) -> Result<String, StoreError> {
+    match &row[column] {
+        Value::Text(value) => Ok(value.clone()),
+        value => Err(invalid(column, value)),
+    }
+}
+
+fn optional_bool(row: &Row, column: usize) -> Result<Option<bool>, StoreError> {
+    Ok(optional_integer(row, column)?.map(|value| value != 0))
+}
+
+fn time(row: &Row, column: usize) -> Result<SystemTime, StoreError> {
+    let millis = integer(row, column)?;
+    let offset = Duration::from_millis(millis.unsigned_abs());
+    Ok(if millis >= 0 {
+        SystemTime::UNIX_EPOCH + offset
+    } else {
+        SystemTime::UNIX_EPOCH - offset
+    })
+}
+
+/// Store is a connection to a database of fit testing records.
+pub struct Store {
+    connection: Connection,
+}
+
+impl Store {
+    /// Opens (or creates) the database at path.
+    pub fn open(path: &Path) -> Result<Store, StoreError> {
+        let path = path
+            .to_str()
+            .ok_or_else(|| StoreError::Invalid(format!("{} is not UTF-8", path.display())))?;
+        Store::init(Connection::open(path)?)
+    }
+
+    /// Opens a new, empty, in-memory database. Useful for testing.
+    pub fn open_in_memory() -> Result<Store, StoreError> {
+        Store::init(Connection::open(":memory:")?)
+    }
+
+    fn init(connection: Connection) -> Result<Store, StoreError> {
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+        let rows = connection.query("PRAGMA user_version", &[])?;
+        let version = integer(&rows[0], 0)?;
+        if version > SCHEMA_VERSION {
+            return Err(StoreError::UnsupportedSchemaVersion(version));
+        }
+        if version == 0 {
+            connection.execute_batch(&format!(
+                "BEGIN; {SCHEMA} PRAGMA user_version = {SCHEMA_VERSION}; COMMIT;"
+            ))?;
+        }
+        Ok(Store { connection })
+    }
+
+    // Runs f in a transaction, which is rolled back if f fails.
+    fn transaction<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        self.connection.execute_batch("BEGIN")?;
+        match f(&self.connection) {
+            Ok(value) => {
+                self.connection.execute_batch("COMMIT")?;
+                Ok(value)
+            }
+            Err(e) => {
+                let _ = self.connection.execute_batch("ROLLBACK");
+                Err(e)
+            }
+        }
+    }
+
+    pub fn add_subject(&self, name: &str, notes: &str) -> Result<Subject, StoreError> {
+        self.connection.execute(
+            "INSERT INTO subjects (name, notes) VALUES (?1, ?2)",
+            &[name.into(), notes.into()],
+        )?;
+        Ok(Subject {
+            id: self.connection.last_insert_rowid(),
+            name: name.to_string(),
+            notes: notes.to_string(),
+        })
+    }
+
+    /// Returns all subjects, ordered by name.
+    pub fn subjects(&self) -> Result<Vec<Subject>, StoreError> {
+        self.connection
+            .query(
+                "SELECT id, name, notes FROM subjects ORDER BY name, id",
+                &[],
+            )?
+            .iter()
+            .map(|row| {
+                Ok(Subject {
+                    id: integer(row, 0)?,
+                    name: text(row, 1)?,
+                    notes: text(row, 2)?,
+                })
+            })
+            .collect()
+    }
+
+    pub fn add_respirator(
+        &self,
+        manufacturer: &str,
+        model: &str,
+        size: &str,
+    ) -> Result<Respirator, StoreError> {
+        self.connection.execute(
+            "INSERT INTO respirators (manufacturer, model, size) VALUES (?1, ?2, ?3)",
+            &[manufacturer.into(), model.into(), size.into()],
+        )?;
+        Ok(Respirator {
+            id: self.connection.last_insert_rowid(),
+            manufacturer: manufacturer.to_string(),
+            model: model.to_string(),
+            size: size.to_string(),
+        })
+    }
+
+    /// Returns all respirators, ordered by manufacturer, model, and size.
+    pub fn respirators(&self) -> Result<Vec<Respirator>, StoreError> {
+        self.connection
+            .query(
+                "SELECT id, manufacturer, model, size FROM respirators
+                 ORDER BY manufacturer, model, size, id",
+                &[],
+            )?
+            .iter()
+            .map(|row| {
+                Ok(Respirator {
+                    id: integer(row, 0)?,
+                    manufacturer: text(row, 1)?,
+                    model: text(row, 2)?,
+                    size: text(row, 3)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Starts a new session (at the current time) for the given subject,
+    /// wearing the given respirator (if known).
+    pub fn start_session(
+        &self,
+        subject_id: i64,
+        respirator_id: Option<i64>,
+        notes: &str,
+    ) -> Result<Session, StoreError> {
+        let started_at = SystemTime::now();
+        self.connection.execute(
+            "INSERT INTO sessions (subject_id, respirator_id, started_at, notes)
+             VALUES (?1, ?2, ?3, ?4)",
+            &[
+                subject_id.into(),
+                respirator_id.into(),
+                timestamp(started_at),
+                notes.into(),
+            ],
+        )?;
+        let id = self.connection.last_insert_rowid();
+        // Return the stored (truncated) timestamp, such that the session
+        // matches what sessions_for_subject() returns.
+        let rows = self.connection.query(
+            "SELECT started_at FROM sessions WHERE id = ?1",
+            &[id.into()],
+        )?;
+        Ok(Session {
+            id,
+            subject_id,
+            respirator_id,
+            started_at: time(&rows[0], 0)?,
+            notes: notes.to_string(),
+        })
+    }
+
+    /// Returns all sessions for the given subject, oldest first.
+    pub fn sessions_for_subject(&self, subject_id: i64) -> Result<Vec<Session>, StoreError> {
+        self.connection
+            .query(
+                "SELECT id, subject_id, respirator_id, started_at, notes FROM sessions
+                 WHERE subject_id = ?1 ORDER BY started_at, id",
+                &[subject_id.into()],
+            )?
+            .iter()
+            .map(|row| {
+                Ok(Session {
+                    id: integer(row, 0)?,
+                    subject_id: integer(row, 1)?,
+                    respirator_id: optional_integer(row, 2)?,
+                    started_at: time(row, 3)?,
+                    notes: text(row, 4)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Stores the result of a test performed during the given session.
+    pub fn add_result(
+        &self,
+        session_id: i64,
+        result: &TestResult,
+    ) -> Result<ResultRecord, StoreError> {
+        let id = self.transaction(|connection| {
+            connection.execute(
+                "INSERT INTO results (session_id, config_short_name, config_version,
+                     config_fingerprint, started_at, completed_at, overall_fit_factor,
+                     raw_overall_fit_factor, passed)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                &[
+                    session_id.into(),
+                    result.config_short_name.as_str().into(),
+                    i64::from(result.config_version).into(),
+                    // Stored as the equivalent (possibly negative) i64.
+                    (result.config_fingerprint as i64).into(),
+                    timestamp(result.started_at),
+                    timestamp(result.completed_at),
+                    result.overall_fit_factor.into(),
+                    result.raw_overall_fit_factor.into(),
+                    bool_value(result.passed),
+                ],
+            )?;
+            let id = connection.last_insert_rowid();
+            for exercise in 0..result.fit_factors.len() {
+                connection.execute(
+                    "INSERT INTO exercise_results (result_id, exercise, name, fit_factor,
+                         raw_fit_factor, error, passed)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    &[
+                        id.into(),
+                        (exercise as i64).into(),
+                        result
+                            .exercise_names
+                            .get(exercise)
+                            .map_or("", String::as_str)
+                            .into(),
+                        result.fit_factors[exercise].into(),
+                        result.raw_fit_factors.get(exercise).copied().into(),
+                        result.errors.get(exercise).copied().into(),
+                        bool_value(result.exercise_passed.get(exercise).copied().flatten()),
+                    ],
+                )?;
+            }
+            Ok(id)
+        })?;
+        self.results_where("results.id = ?1", &[id.into()])?
+            .pop()
+            .ok_or_else(|| StoreError::Invalid(format!("result {id} was not stored")))
+    }
+
+    fn results_where(
+        &self,
+        filter: &str,
+        params: &[Value],
+    ) -> Result<Vec<ResultRecord>, StoreError> {
+        let rows = self.connection.query(
+            &format!(
+                "SELECT {RESULT_COLUMNS} FROM results
+                 JOIN sessions ON sessions.id = results.session_id
+                 WHERE {filter} ORDER BY results.started_at, results.id"
+            ),
+            params,
+        )?;
+        rows.iter()
+            .map(|row| {
+                let id = integer(row, 0)?;
+                Ok(ResultRecord {
+                    id,
+                    session_id: integer(row, 1)?,
+                    config_short_name: text(row, 2)?,
+                    config_version: u32::try_from(integer(row, 3)?)
+                        .map_err(|e| StoreError::Invalid(e.to_string()))?,
+                    config_fingerprint: integer(row, 4)? as u64,
+                    started_at: time(row, 5)?,
+                    completed_at: time(row, 6)?,
+                    overall_fit_factor: real(row, 7)?,
+                    raw_overall_fit_factor: real(row, 8)?,
+                    passed: optional_bool(row, 9)?,
+                    exercises: self.exercises(id)?,
+                })
+            })
+            .collect()
+    }
+
+    fn exercises(&self, result_id: i64) -> Result<Vec<ExerciseRecord>, StoreError> {
+        self.connection
+            .query(
+                "SELECT name, fit_factor, raw_fit_factor, error, passed FROM exercise_results
+                 WHERE result_id = ?1 ORDER BY exercise",
+                &[result_id.into()],
+            )?
+            .iter()
+            .map(|row| {
+                Ok(ExerciseRecord {
+                    name: text(row, 0)?,
+                    fit_factor: real(row, 1)?,
+                    raw_fit_factor: real(row, 2)?,
+                    error: real(row, 3)?,
+                    passed: optional_bool(row, 4)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns all results for the given subject (across all sessions),
+    /// oldest first.
+    pub fn results_for_subject(&self, subject_id: i64) -> Result<Vec<ResultRecord>, StoreError> {
+        self.results_where("sessions.subject_id = ?1", &[subject_id.into()])
+    }
+
+    /// Returns all results for tests started within from..to, oldest first.
+    pub fn results_between(
+        &self,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> Result<Vec<ResultRecord>, StoreError> {
+        self.results_where(
+            "results.started_at >= ?1 AND results.started_at < ?2",
+            &[timestamp(from), timestamp(to)],
+        )
+    }
+
+    /// Returns the fraction (0.0..=1.0) of tests started within from..to that
+    /// passed. Tests without a verdict (see TestResult::passed) are ignored.
+    /// None if there are no such tests.
+    pub fn pass_rate(&self, from: SystemTime, to: SystemTime) -> Result<Option<f64>, StoreError> {
+        let rows = self.connection.query(
+            "SELECT COUNT(*), TOTAL(passed) FROM results
+             WHERE passed IS NOT NULL AND started_at >= ?1 AND started_at < ?2",
+            &[timestamp(from), timestamp(to)],
+        )?;
+        let count = integer(&rows[0], 0)?;
+        let passed = real(&rows[0], 1)?;
+        Ok((count > 0).then(|| passed / count as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness;
+    use crate::test_config::{builtin, TestConfig};
+    use crate::TestOptions;
+
+    fn test_result(specimen_concentration: f64) -> TestResult {
+        let config = TestConfig {
+            pass_level: Some(100.0),
+            ..builtin::load("osha").unwrap()
+        };
+        let concentrations = config.stages.iter().flat_map(|stage| {
+            let counts = stage.counts();
+            let concentration = if stage.is_ambient_sample() {
+                1000.0
+            } else {
+                specimen_concentration
+            };
+            vec![concentration; counts.purge_count + counts.sample_count]
+        });
+        harness::run_concentrations(config.clone(), TestOptions::default(), concentrations)
+            .result
+            .unwrap()
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let path = std::env::temp_dir().join(format!("p8020-store-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let subject = Store::open(&path).unwrap().add_subject("A", "").unwrap();
+        // Reopening must not recreate (or otherwise modify) the schema.
+        assert_eq!(
+            Store::open(&path).unwrap().subjects().unwrap(),
+            vec![subject]
+        );
+
+        Connection::open(path.to_str().unwrap())
+            .unwrap()
+            .execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION + 1))
+            .unwrap();
+        assert!(matches!(
+            Store::open(&path),
+            Err(StoreError::UnsupportedSchemaVersion(version)) if version == SCHEMA_VERSION + 1
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_store() {
+        let store = Store::open_in_memory().unwrap();
+        let alice = store.add_subject("Alice", "").unwrap();
+        let bob = store.add_subject("Bob", "beard").unwrap();
+        assert_eq!(store.subjects().unwrap(), vec![alice.clone(), bob.clone()]);
+        let respirator = store.add_respirator("3M", "8833", "M/L").unwrap();
+        assert_eq!(store.respirators().unwrap(), vec![respirator.clone()]);
+
+        let session = store
+            .start_session(alice.id, Some(respirator.id), "")
+            .unwrap();
+        assert_eq!(
+            store.sessions_for_subject(alice.id).unwrap(),
+            vec![session.clone()]
+        );
+        assert!(store.sessions_for_subject(bob.id).unwrap().is_empty());
+        // Sessions must belong to an existing subject.
+        assert!(store.start_session(1234, None, "").is_err());
+
+        let result = test_result(2.0);
+        let record = store.add_result(session.id, &result).unwrap();
+        assert_eq!(record.session_id, session.id);
+        assert_eq!(record.config_short_name, result.config_short_name);
+        assert_eq!(record.config_fingerprint, result.config_fingerprint);
+        assert_eq!(record.overall_fit_factor, result.overall_fit_factor);
+        assert_eq!(record.passed, result.passed);
+        assert_eq!(record.exercises.len(), result.fit_factors.len());
+        for (exercise, stored) in record.exercises.iter().enumerate() {
+            assert_eq!(stored.name, result.exercise_names[exercise]);
+            assert_eq!(stored.fit_factor, result.fit_factors[exercise]);
+            assert_eq!(stored.raw_fit_factor, result.raw_fit_factors[exercise]);
+            assert_eq!(stored.error, result.errors[exercise]);
+        }
+        assert_eq!(
+            store.results_for_subject(alice.id).unwrap(),
+            vec![record.clone()]
+        );
+        assert!(store.results_for_subject(bob.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_queries() {
+        let store = Store::open_in_memory().unwrap();
+        let subject = store.add_subject("Alice", "").unwrap();
+        let session = store.start_session(subject.id, None, "").unwrap();
+        let day = Duration::from_secs(60 * 60 * 24);
+        let now = SystemTime::now();
+        assert_eq!(store.pass_rate(now - day, now + day).unwrap(), None);
+
+        // 1000/2 = 500 passes, 1000/100 = 10 fails.
+        let passing = test_result(2.0);
+        let failing = test_result(100.0);
+        let old = TestResult {
+            started_at: now - day * 10,
+            completed_at: now - day * 10,
+            ..passing.clone()
+        };
+        let mut records = Vec::new();
+        for result in [&old, &passing, &failing] {
+            records.push(store.add_result(session.id, result).unwrap());
+        }
+        assert_eq!(records[1].passed, Some(true));
+        assert_eq!(records[2].passed, Some(false));
+
+        assert_eq!(
+            store.results_between(now - day, now + day).unwrap(),
+            records[1..]
+        );
+        assert_eq!(
+            store.results_between(now - day * 20, now - day).unwrap(),
+            records[..1]
+        );
+        assert_eq!(store.pass_rate(now - day, now + day).unwrap(), Some(0.5));
+        assert_eq!(
+            store.pass_rate(now - day * 20, now + day).unwrap(),
+            Some(2.0 / 3.0)
+        );
+    }
+}
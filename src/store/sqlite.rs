@@ -0,0 +1,299 @@
+// A minimal safe wrapper around the system's libsqlite3, covering only what
+// the store needs: executing statements with positional parameters, and
+// collecting query results.
+
+use std::ffi::{c_char, c_double, c_int, c_void, CStr, CString};
+
+use super::StoreError;
+
+#[repr(C)]
+struct Sqlite3 {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct Sqlite3Stmt {
+    _private: [u8; 0],
+}
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+
+const SQLITE_INTEGER: c_int = 1;
+const SQLITE_FLOAT: c_int = 2;
+const SQLITE_NULL: c_int = 5;
+
+const SQLITE_OPEN_READWRITE: c_int = 0x2;
+const SQLITE_OPEN_CREATE: c_int = 0x4;
+
+// sqlite3_destructor_type, which is called to free bound values once sqlite
+// no longer needs them.
+type Destructor = Option<unsafe extern "C" fn(*mut c_void)>;
+
+// Returns SQLITE_TRANSIENT, which instructs sqlite to copy bound values
+// before returning. sqlite defines it as ((sqlite3_destructor_type)-1): the
+// value is only compared against, and never called. (This can't be a const,
+// as const evaluation rejects invalid function pointers.)
+fn sqlite_transient() -> Destructor {
+    Some(unsafe { std::mem::transmute::<isize, unsafe extern "C" fn(*mut c_void)>(-1) })
+}
+
+// sqlite3_exec's per-row callback.
+type ExecCallback =
+    Option<unsafe extern "C" fn(*mut c_void, c_int, *mut *mut c_char, *mut *mut c_char) -> c_int>;
+
+#[link(name = "sqlite3")]
+extern "C" {
+    fn sqlite3_open_v2(
+        filename: *const c_char,
+        db: *mut *mut Sqlite3,
+        flags: c_int,
+        vfs: *const c_char,
+    ) -> c_int;
+    fn sqlite3_close_v2(db: *mut Sqlite3) -> c_int;
+    fn sqlite3_errmsg(db: *mut Sqlite3) -> *const c_char;
+    fn sqlite3_exec(
+        db: *mut Sqlite3,
+        sql: *const c_char,
+        callback: ExecCallback,
+        arg: *mut c_void,
+        errmsg: *mut *mut c_char,
+    ) -> c_int;
+    fn sqlite3_prepare_v2(
+        db: *mut Sqlite3,
+        sql: *const c_char,
+        length: c_int,
+        stmt: *mut *mut Sqlite3Stmt,
+        tail: *mut *const c_char,
+    ) -> c_int;
+    fn sqlite3_step(stmt: *mut Sqlite3Stmt) -> c_int;
+    fn sqlite3_finalize(stmt: *mut Sqlite3Stmt) -> c_int;
+    fn sqlite3_bind_null(stmt: *mut Sqlite3Stmt, index: c_int) -> c_int;
+    fn sqlite3_bind_int64(stmt: *mut Sqlite3Stmt, index: c_int, value: i64) -> c_int;
+    fn sqlite3_bind_double(stmt: *mut Sqlite3Stmt, index: c_int, value: c_double) -> c_int;
+    fn sqlite3_bind_text(
+        stmt: *mut Sqlite3Stmt,
+        index: c_int,
+        value: *const c_char,
+        length: c_int,
+        destructor: Destructor,
+    ) -> c_int;
+    fn sqlite3_column_count(stmt: *mut Sqlite3Stmt) -> c_int;
+    fn sqlite3_column_type(stmt: *mut Sqlite3Stmt, index: c_int) -> c_int;
+    fn sqlite3_column_int64(stmt: *mut Sqlite3Stmt, index: c_int) -> i64;
+    fn sqlite3_column_double(stmt: *mut Sqlite3Stmt, index: c_int) -> c_double;
+    fn sqlite3_column_text(stmt: *mut Sqlite3Stmt, index: c_int) -> *const u8;
+    fn sqlite3_column_bytes(stmt: *mut Sqlite3Stmt, index: c_int) -> c_int;
+    fn sqlite3_last_insert_rowid(db: *mut Sqlite3) -> i64;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        Value::Integer(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Real(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::Text(value.to_string())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Value {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+pub(crate) type Row = Vec<Value>;
+
+pub(crate) struct Connection {
+    db: *mut Sqlite3,
+}
+
+// Connections may be used from any (single) thread at a time: libsqlite3 is
+// built in serialized mode by default, and Connection is not Sync.
+unsafe impl Send for Connection {}
+
+fn c_string(value: &str) -> Result<CString, StoreError> {
+    CString::new(value).map_err(|_| StoreError::Invalid(format!("{value:?} contains NUL")))
+}
+
+impl Connection {
+    pub(crate) fn open(path: &str) -> Result<Connection, StoreError> {
+        let path = c_string(path)?;
+        let mut db = std::ptr::null_mut();
+        let code = unsafe {
+            sqlite3_open_v2(
+                path.as_ptr(),
+                &mut db,
+                SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE,
+                std::ptr::null(),
+            )
+        };
+        // A handle is returned even on failure (unless allocation failed),
+        // and must be closed by the caller.
+        let connection = Connection { db };
+        if code != SQLITE_OK {
+            return Err(connection.error(code));
+        }
+        Ok(connection)
+    }
+
+    fn error(&self, code: c_int) -> StoreError {
+        let message = if self.db.is_null() {
+            "out of memory".to_string()
+        } else {
+            unsafe { CStr::from_ptr(sqlite3_errmsg(self.db)) }
+                .to_string_lossy()
+                .into_owned()
+        };
+        StoreError::Sqlite { code, message }
+    }
+
+    /// Executes one or more statements without parameters.
+    pub(crate) fn execute_batch(&self, sql: &str) -> Result<(), StoreError> {
+        let sql = c_string(sql)?;
+        let code = unsafe {
+            sqlite3_exec(
+                self.db,
+                sql.as_ptr(),
+                None,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        match code {
+            SQLITE_OK => Ok(()),
+            code => Err(self.error(code)),
+        }
+    }
+
+    /// Executes a single statement, returning all resulting rows (if any).
+    /// Parameters are bound to ?1, ?2, etc.
+    pub(crate) fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Row>, StoreError> {
+        let statement = Statement::prepare(self, sql)?;
+        for (index, param) in params.iter().enumerate() {
+            statement.bind(index as c_int + 1, param)?;
+        }
+        let mut rows = Vec::new();
+        loop {
+            match unsafe { sqlite3_step(statement.stmt) } {
+                SQLITE_ROW => rows.push(statement.row()),
+                SQLITE_DONE => return Ok(rows),
+                code => return Err(self.error(code)),
+            }
+        }
+    }
+
+    /// Executes a single statement, ignoring any resulting rows.
+    pub(crate) fn execute(&self, sql: &str, params: &[Value]) -> Result<(), StoreError> {
+        self.query(sql, params).map(|_| ())
+    }
+
+    pub(crate) fn last_insert_rowid(&self) -> i64 {
+        unsafe { sqlite3_last_insert_rowid(self.db) }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe {
+            sqlite3_close_v2(self.db);
+        }
+    }
+}
+
+struct Statement<'a> {
+    connection: &'a Connection,
+    stmt: *mut Sqlite3Stmt,
+}
+
+impl<'a> Statement<'a> {
+    fn prepare(connection: &'a Connection, sql: &str) -> Result<Statement<'a>, StoreError> {
+        let sql = c_string(sql)?;
+        let mut stmt = std::ptr::null_mut();
+        let code = unsafe {
+            sqlite3_prepare_v2(
+                connection.db,
+                sql.as_ptr(),
+                -1,
+                &mut stmt,
+                std::ptr::null_mut(),
+            )
+        };
+        if code != SQLITE_OK {
+            return Err(connection.error(code));
+        }
+        Ok(Statement { connection, stmt })
+    }
+
+    fn bind(&self, index: c_int, value: &Value) -> Result<(), StoreError> {
+        let code = unsafe {
+            match value {
+                Value::Null => sqlite3_bind_null(self.stmt, index),
+                Value::Integer(value) => sqlite3_bind_int64(self.stmt, index, *value),
+                Value::Real(value) => sqlite3_bind_double(self.stmt, index, *value),
+                Value::Text(value) => sqlite3_bind_text(
+                    self.stmt,
+                    index,
+                    value.as_ptr() as *const c_char,
+                    value.len() as c_int,
+                    sqlite_transient(),
+                ),
+            }
+        };
+        match code {
+            SQLITE_OK => Ok(()),
+            code => Err(self.connection.error(code)),
+        }
+    }
+
+    fn row(&self) -> Row {
+        let count = unsafe { sqlite3_column_count(self.stmt) };
+        (0..count)
+            .map(|index| unsafe {
+                match sqlite3_column_type(self.stmt, index) {
+                    SQLITE_NULL => Value::Null,
+                    SQLITE_INTEGER => Value::Integer(sqlite3_column_int64(self.stmt, index)),
+                    SQLITE_FLOAT => Value::Real(sqlite3_column_double(self.stmt, index)),
+                    // Blobs are never stored, and are therefore treated as
+                    // text.
+                    _ => {
+                        let text = sqlite3_column_text(self.stmt, index);
+                        let length = sqlite3_column_bytes(self.stmt, index) as usize;
+                        if text.is_null() {
+                            Value::Null
+                        } else {
+                            let bytes = std::slice::from_raw_parts(text, length);
+                            Value::Text(String::from_utf8_lossy(bytes).into_owned())
+                        }
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+impl Drop for Statement<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            sqlite3_finalize(self.stmt);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! A deterministic harness for running a TestConfig without a device. This is
+//! primarily intended for verifying FF calculations (both for libp8020 itself,
+//! and for authors of new protocols): feed a scripted sequence of messages (or
+//! just concentrations) in, and inspect the notifications, commands, and FFs
+//! that come out.
+
+use std::cell::RefCell;
+use std::sync::mpsc::SendError;
+use std::sync::{Arc, Mutex};
+
+use super::{CommandSink, StepOutcome, Test, TestNotification, TestOptions, TestResult, ValveMode};
+use crate::cadence::EXPECTED_SAMPLE_INTERVAL;
+use crate::protocol::{Command, Message};
+use crate::test_config::{TestConfig, TestStage};
+use crate::ValveState;
+
+/// RecordingCommandSink records all commands, allowing callers to verify
+/// which commands would have been sent to the device.
+pub struct RecordingCommandSink {
+    commands: RefCell<Vec<Command>>,
+}
+
+impl RecordingCommandSink {
+    pub fn new() -> RecordingCommandSink {
+        RecordingCommandSink {
+            commands: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Returns all commands recorded so far.
+    pub fn commands(&self) -> Vec<Command> {
+        self.commands.borrow().clone()
+    }
+}
+
+impl Default for RecordingCommandSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommandSink for RecordingCommandSink {
+    fn send(&self, command: Command) -> Result<(), SendError<Command>> {
+        self.commands.borrow_mut().push(command);
+        Ok(())
+    }
+}
+
+/// HarnessOutput contains everything produced by a test run via the harness.
+#[derive(Debug)]
+pub struct HarnessOutput {
+    /// All notifications, in the order that they were sent.
+    pub notifications: Vec<TestNotification>,
+    /// All commands, in the order that they were sent.
+    pub commands: Vec<Command>,
+    /// Final FFs for each exercise (only complete if completed == true).
+    pub exercise_ffs: Vec<f64>,
+    /// Whether the test completed before the script ran out.
+    pub completed: bool,
+    /// Whether the test was aborted (see StepOutcome::TestAborted).
+    pub aborted: bool,
+    /// The final test result, only available if the test completed.
+    pub result: Option<TestResult>,
+}
+
+/// Run config against a fully scripted sequence of messages. Valve responses
+/// must be included in the script: the harness does not simulate the device.
+/// Any messages remaining after test completion are ignored.
+pub fn run_messages(
+    config: TestConfig,
+    options: TestOptions,
+    messages: impl IntoIterator<Item = Message>,
+) -> HarnessOutput {
+    run(config, options, messages, false)
+}
+
+/// Run config against a sequence of concentrations, with valve switches being
+/// confirmed immediately (i.e. before the next concentration is delivered).
+/// This is the behaviour of an ideal device (or, for ValveMode::Manual, an
+/// ideal operator), and is what most callers will want.
+pub fn run_concentrations(
+    config: TestConfig,
+    options: TestOptions,
+    concentrations: impl IntoIterator<Item = f64>,
+) -> HarnessOutput {
+    run(
+        config,
+        options,
+        concentrations.into_iter().map(Message::Sample),
+        true,
+    )
+}
+
+/// Generates the concentrations for a test using config, with a constant
+/// concentration during each stage: all ambient stages use ambient, and each
+/// exercise uses the corresponding entry in exercises (purges included).
+/// Time-based durations are resolved using EXPECTED_SAMPLE_INTERVAL.
+/// Combine with run_concentrations, for example.
+pub fn stage_concentrations(config: &TestConfig, ambient: f64, exercises: &[f64]) -> Vec<f64> {
+    assert_eq!(
+        config.exercise_count(),
+        exercises.len(),
+        "must supply exactly one concentration per exercise"
+    );
+    let mut exercises = exercises.iter();
+    let mut out = Vec::new();
+    let config = config.resolve_durations(EXPECTED_SAMPLE_INTERVAL);
+    for stage in config.stages.iter() {
+        let (counts, value) = match stage {
+            TestStage::AmbientSample { counts } => (counts, ambient),
+            TestStage::Exercise { counts, .. } | TestStage::Grimace { counts } => {
+                (counts, *exercises.next().unwrap())
+            }
+        };
+        out.extend(std::iter::repeat_n(
+            value,
+            counts.purge_count + counts.sample_count,
+        ));
+    }
+    out
+}
+
+fn run(
+    config: TestConfig,
+    options: TestOptions,
+    messages: impl IntoIterator<Item = Message>,
+    confirm_valve_switches: bool,
+) -> HarnessOutput {
+    let sink = RecordingCommandSink::new();
+    let notifications = Arc::new(Mutex::new(Vec::new()));
+    let notifications_write = notifications.clone();
+    let test_callback = move |notification: &TestNotification| {
+        notifications_write
+            .lock()
+            .unwrap()
+            .push(notification.clone());
+    };
+    // The harness always starts with the valve in the default position,
+    // matching the device thread's assumptions.
+    let mut valve_state = ValveState::Specimen;
+    let mut test = Test::create_and_start(
+        config,
+        options,
+        &sink,
+        &mut valve_state,
+        Some(Box::new(test_callback)),
+    )
+    .expect("RecordingCommandSink never fails");
+
+    let mut confirmed_commands = 0;
+    let mut completed = false;
+    let mut aborted = false;
+    for message in messages {
+        if confirm_valve_switches {
+            let commands = sink.commands.borrow()[confirmed_commands..].to_vec();
+            confirmed_commands += commands.len();
+            for command in commands {
+                if matches!(command, Command::ValveAmbient | Command::ValveSpecimen) {
+                    test.step(Message::Response(command), &mut valve_state)
+                        .expect("RecordingCommandSink never fails");
+                }
+            }
+            if let (ValveMode::Manual, Some(position)) =
+                (test.options.valve_mode, test.pending_valve_switch)
+            {
+                test.confirm_manual_switch(position, &mut valve_state);
+            }
+        }
+        match test
+            .step(message, &mut valve_state)
+            .expect("RecordingCommandSink never fails")
+        {
+            StepOutcome::TestComplete => {
+                completed = true;
+                break;
+            }
+            StepOutcome::TestAborted => {
+                aborted = true;
+                break;
+            }
+            StepOutcome::None => (),
+        }
+    }
+
+    let result = if completed { Some(test.result()) } else { None };
+    let exercise_ffs = std::mem::take(&mut test.exercise_ffs);
+    drop(test);
+    let notifications = std::mem::take(&mut *notifications.lock().unwrap());
+    HarnessOutput {
+        notifications,
+        commands: sink.commands(),
+        exercise_ffs,
+        completed,
+        aborted,
+        result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config::builtin;
+
+    #[test]
+    fn test_builtin_osha_fast_ffp() {
+        let config =
+            TestConfig::parse_from_csv(&mut std::io::Cursor::new(builtin::OSHA_FAST_FFP)).unwrap();
+        let concentrations = stage_concentrations(&config, 1000.0, &[10.0, 5.0, 20.0, 1.0]);
+        let output = run_concentrations(config, TestOptions::default(), concentrations);
+        assert!(output.completed);
+        assert_eq!(output.exercise_ffs, vec![100.0, 200.0, 50.0, 1000.0]);
+        let results: Vec<(usize, f64)> = output
+            .notifications
+            .iter()
+            .filter_map(|notification| match notification {
+                TestNotification::ExerciseResult {
+                    exercise,
+                    fit_factor,
+                    ..
+                } => Some((*exercise, *fit_factor)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            results,
+            vec![(0, 100.0), (1, 200.0), (2, 50.0), (3, 1000.0)]
+        );
+    }
+
+    #[test]
+    fn test_incomplete_script() {
+        let config =
+            TestConfig::parse_from_csv(&mut std::io::Cursor::new(builtin::OSHA_FAST_FFP)).unwrap();
+        let output = run_concentrations(config, TestOptions::default(), [1000.0; 20]);
+        assert!(!output.completed);
+        assert!(output.exercise_ffs.is_empty());
+    }
+}
@@ -0,0 +1,3029 @@
+pub mod harness;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::mpsc::SendError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::protocol::{Command, Indicator, Message};
+use crate::test_config::{StageCounts, TestConfig, TestStage};
+use crate::ValveState;
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub enum TestState {
+    Pending,
+    StartedExercise(usize),
+    Finished,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub enum SampleType {
+    AmbientPurge,
+    AmbientSample,
+    SpecimenPurge,
+    SpecimenSample,
+}
+
+/// The two positions of the 8020's valve.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub enum ValvePosition {
+    /// Sampling through the ambient tube (valve ON, "VN").
+    Ambient,
+    /// Sampling through the specimen ("sample") tube (valve OFF, "VF").
+    Specimen,
+}
+
+impl ValvePosition {
+    fn command(&self) -> Command {
+        match self {
+            ValvePosition::Ambient => Command::ValveAmbient,
+            ValvePosition::Specimen => Command::ValveSpecimen,
+        }
+    }
+
+    fn awaiting_state(&self) -> ValveState {
+        match self {
+            ValvePosition::Ambient => ValveState::AwaitingAmbient,
+            ValvePosition::Specimen => ValveState::AwaitingSpecimen,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub struct SampleData {
+    /// The index of the stage (within TestConfig::stages) that the sample
+    /// belongs to.
+    pub stage: usize,
+    pub exercise: usize,
+    pub value: f64,
+    pub sample_type: SampleType,
+}
+
+enum StageResults {
+    AmbientSample {
+        purges: Vec<f64>,
+        samples: Vec<f64>,
+        // Note: purge_count may be extended during the stage when using
+        // adaptive purging, see extend_purge_if_unstable.
+        config: StageCounts,
+        configured_purge_count: usize,
+    },
+    Exercise {
+        purges: Vec<f64>,
+        samples: Vec<f64>,
+        // Note: sample_count may be reduced during the stage when using early
+        // stopping, see end_sampling_early.
+        config: StageCounts,
+    },
+}
+
+impl StageResults {
+    pub fn from(stage: &TestStage) -> StageResults {
+        match stage {
+            TestStage::AmbientSample { counts } => StageResults::AmbientSample {
+                purges: Vec::with_capacity(counts.purge_count),
+                samples: Vec::with_capacity(counts.sample_count),
+                config: counts.clone(),
+                configured_purge_count: counts.purge_count,
+            },
+            TestStage::Exercise { counts, .. } | TestStage::Grimace { counts } => {
+                StageResults::Exercise {
+                    purges: Vec::with_capacity(counts.purge_count),
+                    samples: Vec::with_capacity(counts.sample_count),
+                    config: counts.clone(),
+                }
+            }
+        }
+    }
+
+    pub fn is_ambient_sample(&self) -> bool {
+        matches!(self, StageResults::AmbientSample { .. })
+    }
+
+    pub fn is_exercise(&self) -> bool {
+        matches!(self, StageResults::Exercise { .. })
+    }
+
+    fn append(&mut self, value: f64) -> SampleType {
+        match self {
+            StageResults::AmbientSample {
+                purges,
+                samples,
+                config,
+                ..
+            }
+            | StageResults::Exercise {
+                purges,
+                samples,
+                config,
+            } => {
+                assert!(purges.len() < config.purge_count || samples.len() < config.sample_count);
+                if purges.len() < config.purge_count {
+                    purges.push(value);
+                    if self.is_ambient_sample() {
+                        SampleType::AmbientPurge
+                    } else {
+                        SampleType::SpecimenPurge
+                    }
+                } else {
+                    samples.push(value);
+                    if self.is_ambient_sample() {
+                        SampleType::AmbientSample
+                    } else {
+                        SampleType::SpecimenSample
+                    }
+                }
+            }
+        }
+    }
+
+    // Extends the purge period by one sample if the most recent purges
+    // haven't stabilised yet. Must only be called for ambient stages.
+    fn extend_purge_if_unstable(&mut self, adaptive_purge: &AdaptivePurge) {
+        let StageResults::AmbientSample {
+            purges,
+            config,
+            configured_purge_count,
+            ..
+        } = self
+        else {
+            panic!("adaptive purging is only supported for ambient stages");
+        };
+        if purges.len() != config.purge_count
+            || purges.len() >= *configured_purge_count + adaptive_purge.max_additional_purges
+        {
+            return;
+        }
+        if purges.len() >= adaptive_purge.window {
+            let recent = &purges[purges.len() - adaptive_purge.window..];
+            let min = recent.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = recent.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let mean = recent.iter().sum::<f64>() / recent.len() as f64;
+            if (max - min) <= mean * adaptive_purge.tolerance {
+                return;
+            }
+        }
+        log::info!("extending ambient purge, concentration has not stabilised yet");
+        config.purge_count += 1;
+    }
+
+    // Extends the sampling period by one sample. Used while awaiting an
+    // ExerciseBarrier in BarrierMode::Buffer.
+    fn extend_sampling(&mut self) {
+        match self {
+            StageResults::AmbientSample { config, .. } | StageResults::Exercise { config, .. } => {
+                config.sample_count += 1;
+            }
+        }
+    }
+
+    // Ends the sampling period of an exercise immediately, i.e. no further
+    // samples will be collected for this stage.
+    fn end_sampling_early(&mut self) {
+        let StageResults::Exercise {
+            purges,
+            samples,
+            config,
+        } = self
+        else {
+            panic!("early stopping is only supported for exercises");
+        };
+        assert_eq!(purges.len(), config.purge_count);
+        config.sample_count = samples.len();
+    }
+
+    fn is_complete(&self) -> bool {
+        match self {
+            StageResults::AmbientSample {
+                purges,
+                samples,
+                config,
+                ..
+            }
+            | StageResults::Exercise {
+                purges,
+                samples,
+                config,
+            } => purges.len() == config.purge_count && samples.len() == config.sample_count,
+        }
+    }
+
+    // The number of purges and samples still to be collected, according to
+    // the (possibly adjusted) counts.
+    fn remaining_count(&self) -> usize {
+        match self {
+            StageResults::AmbientSample {
+                purges,
+                samples,
+                config,
+                ..
+            }
+            | StageResults::Exercise {
+                purges,
+                samples,
+                config,
+            } => (config.purge_count + config.sample_count)
+                .saturating_sub(purges.len() + samples.len()),
+        }
+    }
+
+    fn has_samples(&self) -> bool {
+        match self {
+            StageResults::AmbientSample { samples, .. }
+            | StageResults::Exercise { samples, .. } => !samples.is_empty(),
+        }
+    }
+
+    /// Returns the average of this stage's samples, raised to
+    /// min_concentration if specified, or otherwise to the minimum measurable
+    /// concentration (see below).
+    pub fn avg(&self, min_concentration: Option<f64>) -> f64 {
+        match self {
+            StageResults::AmbientSample { samples, .. }
+            | StageResults::Exercise { samples, .. } => {
+                let avg = samples.iter().sum::<f64>() / samples.len() as f64;
+                // In theory, we might measure 0 particles throughout an exercise,
+                // which would lead to an infinite fit factor. The minimum measurable
+                // number of particles/cm3 is 1/n/1.67 (see Appendix D of the 8020
+                // Operations and Service Manual - p57(digital)/p51(paper) of
+                // https://tsi.com/getmedia/9b578bab-ace5-4820-a414-fb0a78712c67/Model_8020_8028_1980092?ext=.pdf
+                // Using this as a minimum means we would calculate the highest
+                // *measurable* fit-factor (with a lot of handwaving) as opposed
+                // to true fit-factor in this scenario, which is probably the most
+                // reasonable result.
+                // Note: of course all of this is bogus for machines whose
+                // flow-rates are off, or that have other issues.
+                avg.max(min_concentration.unwrap_or(60.0 / 100.0 / (samples.len() as f64)))
+            }
+        }
+    }
+
+    /// Returns the (unclamped) mean and sample standard deviation of this
+    /// stage's samples. Purges are ignored.
+    fn mean_and_std_dev(&self) -> (f64, f64) {
+        match self {
+            StageResults::AmbientSample { samples, .. }
+            | StageResults::Exercise { samples, .. } => {
+                let n = samples.len() as f64;
+                let mean = samples.iter().sum::<f64>() / n;
+                if samples.len() < 2 {
+                    return (mean, 0.0);
+                }
+                let variance = samples
+                    .iter()
+                    .map(|sample| (sample - mean).powi(2))
+                    .sum::<f64>()
+                    / (n - 1.0);
+                (mean, variance.sqrt())
+            }
+        }
+    }
+
+    fn sample_count(&self) -> usize {
+        match self {
+            StageResults::AmbientSample { samples, .. }
+            | StageResults::Exercise { samples, .. } => samples.len(),
+        }
+    }
+
+    pub fn err(&self, min_concentration: Option<f64>) -> f64 {
+        let avg = self.avg(min_concentration);
+        match self {
+            StageResults::AmbientSample { samples, .. }
+            | StageResults::Exercise { samples, .. } => {
+                // 8020 flow rate = 100cm3/min
+                1.0 / f64::sqrt(avg * (samples.len() as f64) * 100.0 / 60.0)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub enum TestNotification {
+    /// StateChange indicates that the test has changed state, e.g. a new
+    /// exercise was started. Note that just because a given exercise (or
+    /// the entire test) was completed, it is not safe to assume that all
+    /// data for that exercise (or the entire test) is available yet.
+    StateChange(TestState),
+    /// ExerciseResult indicates the final FF for the specified exercise, after
+    /// applying the test's RoundingPolicy. raw_fit_factor is the FF prior to
+    /// rounding, and error is the (absolute, unrounded) uncertainty.
+    ExerciseResult {
+        exercise: usize,
+        fit_factor: f64,
+        error: f64,
+        raw_fit_factor: f64,
+    },
+    /// Sample indicates a fresh sample from the 8020. This differs from
+    /// RawSample in that it contains metadata about how this reading is being
+    /// used and where it came from (ambient vs specimen, sample vs purge).
+    /// moreover, this data is only available during a test.
+    Sample(SampleData),
+    LiveFF {
+        exercise: usize,
+        index: usize,
+        fit_factor: f64,
+    },
+    /// InterimFF is the average FF at this moment in time calculated based on
+    /// all data collected so far, namely average specimen particles calculated
+    /// from all specimen samples during the current Exercise, divided by
+    /// average ambient particles from the last AmbientSample stage.
+    InterimFF { exercise: usize, fit_factor: f64 },
+    /// StageComplete indicates that a stage (stage is the index into
+    /// TestConfig.stages) has completed. average and standard_deviation are
+    /// calculated from all samples during this stage (purges are excluded).
+    StageComplete {
+        stage: usize,
+        average: f64,
+        sample_count: usize,
+        standard_deviation: f64,
+    },
+    /// AmbientResult indicates the ambient concentration measured during an
+    /// ambient sample stage (stage is the index into TestConfig.stages), and
+    /// its (absolute) uncertainty. This is the reference against which the
+    /// following exercises are measured - callers may wish to verify that it
+    /// is sufficiently high before the subject continues.
+    AmbientResult {
+        stage: usize,
+        average: f64,
+        error: f64,
+    },
+    /// ValveSwitchRequested indicates that the test has requested a valve
+    /// switch. Samples received until the switch is confirmed (see
+    /// ValveSwitchConfirmed) are discarded.
+    ValveSwitchRequested { position: ValvePosition },
+    /// ValveSwitchConfirmed indicates that the device has confirmed a
+    /// previously requested valve switch. discarded_samples is the number of
+    /// samples that were discarded while awaiting confirmation.
+    ValveSwitchConfirmed {
+        position: ValvePosition,
+        discarded_samples: usize,
+    },
+    /// SampleDiscarded indicates that a sample was received, but was not used
+    /// by the test (see DiscardPolicy).
+    SampleDiscarded { value: f64, reason: DiscardReason },
+    /// An exercise was ended before collecting all configured samples
+    /// because the FF was already conclusive, see EarlyStopping.
+    ExerciseEndedEarly {
+        exercise: usize,
+        sample_count: usize,
+    },
+    /// The average concentration of an ambient stage was below the
+    /// configured minimum, see MinimumAmbient. Sent before the test is
+    /// aborted (if applicable).
+    AmbientBelowMinimum {
+        stage: usize,
+        average: f64,
+        minimum: f64,
+    },
+    /// The highest pass level (of the test, or of any exercise) exceeds the
+    /// max FF, which is lowered when using an N95-Companion (see
+    /// TestOptions::n95_companion), i.e. the test can never pass. Sent when
+    /// the test starts.
+    PassLevelAboveCeiling {
+        pass_level: f64,
+        max_fit_factor: f64,
+    },
+    /// An estimate of how long remains until the test completes, sent when
+    /// the test starts and after every sample. samples_remaining counts all
+    /// purges and samples of the current and subsequent stages, and
+    /// seconds_remaining assumes that they arrive at
+    /// TestOptions::sample_interval. The estimate excludes samples that will
+    /// be discarded (e.g. during valve switches), and changes if stages are
+    /// extended or shortened (see AdaptivePurge and EarlyStopping).
+    TimeRemaining {
+        samples_remaining: usize,
+        seconds_remaining: f64,
+    },
+}
+
+/// BeepDuration is the duration of a beep in deciseconds, within 1..=99 (the
+/// range accepted by the 8020).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BeepDuration(u8);
+
+impl BeepDuration {
+    /// Returns None if deciseconds is not within 1..=99.
+    pub const fn new(deciseconds: u8) -> Option<BeepDuration> {
+        match deciseconds {
+            1..=99 => Some(BeepDuration(deciseconds)),
+            _ => None,
+        }
+    }
+
+    pub const fn deciseconds(self) -> u8 {
+        self.0
+    }
+}
+
+/// SoundPolicy determines which events cause the 8020 to beep during a test.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum SoundPolicy {
+    /// Never beep. Useful when testing in shared (e.g. clinical) spaces.
+    Silent,
+    /// Only beep at the start and end of the test.
+    Minimal,
+    /// Beep at the start of the test, at the start of every subsequent
+    /// exercise, and at the end of the test.
+    #[default]
+    Full,
+    /// Custom durations for each event. None disables the beep for that event.
+    Custom {
+        test_started: Option<BeepDuration>,
+        exercise_started: Option<BeepDuration>,
+        test_completed: Option<BeepDuration>,
+    },
+}
+
+enum BeepEvent {
+    TestStarted,
+    ExerciseStarted,
+    GrimaceStarted,
+    TestCompleted,
+}
+
+impl SoundPolicy {
+    fn beep_duration(&self, event: BeepEvent) -> Option<u8> {
+        match (self, event) {
+            (SoundPolicy::Silent, _) => None,
+            (SoundPolicy::Minimal, BeepEvent::ExerciseStarted | BeepEvent::GrimaceStarted) => None,
+            (SoundPolicy::Minimal | SoundPolicy::Full, BeepEvent::TestStarted) => Some(40),
+            (SoundPolicy::Full, BeepEvent::ExerciseStarted) => Some(10),
+            // Grimaces get a noticeably longer beep, as the subject needs to
+            // do something very different from the previous exercise.
+            (SoundPolicy::Full, BeepEvent::GrimaceStarted) => Some(30),
+            (SoundPolicy::Minimal | SoundPolicy::Full, BeepEvent::TestCompleted) => Some(99),
+            (SoundPolicy::Custom { test_started, .. }, BeepEvent::TestStarted) => {
+                test_started.map(BeepDuration::deciseconds)
+            }
+            (
+                SoundPolicy::Custom {
+                    exercise_started, ..
+                },
+                BeepEvent::ExerciseStarted | BeepEvent::GrimaceStarted,
+            ) => exercise_started.map(BeepDuration::deciseconds),
+            (SoundPolicy::Custom { test_completed, .. }, BeepEvent::TestCompleted) => {
+                test_completed.map(BeepDuration::deciseconds)
+            }
+        }
+    }
+}
+
+/// DisplayPolicy determines what is shown on the 8020's display during a test.
+/// The display is always cleared at the start and end of a test.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum DisplayPolicy {
+    /// Show the current exercise number.
+    #[default]
+    ExerciseNumber,
+    /// Show the latest particle concentration (ambient or specimen).
+    Concentration,
+    /// Show the interim FF for the current exercise. Nothing is shown until
+    /// the first specimen sample of the first exercise is available.
+    InterimFF,
+    /// Show nothing.
+    Blank,
+}
+
+/// RoundingPolicy determines how FFs are rounded when reported in
+/// ExerciseResult and TestResult. Raw FFs are always reported too. Live and
+/// interim FFs are never rounded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum RoundingPolicy {
+    /// Report FFs as calculated.
+    #[default]
+    None,
+    /// Truncate FFs to the specified number of decimal places. OSHA reporting
+    /// conventions call for truncation to integers (decimal_places = 0).
+    Truncate { decimal_places: u8 },
+    /// Round FFs (half away from zero) to the specified number of decimal
+    /// places.
+    Round { decimal_places: u8 },
+}
+
+impl RoundingPolicy {
+    pub fn apply(&self, fit_factor: f64) -> f64 {
+        match self {
+            RoundingPolicy::None => fit_factor,
+            RoundingPolicy::Truncate { decimal_places } => {
+                let factor = 10f64.powi(*decimal_places as i32);
+                (fit_factor * factor).trunc() / factor
+            }
+            RoundingPolicy::Round { decimal_places } => {
+                let factor = 10f64.powi(*decimal_places as i32);
+                (fit_factor * factor).round() / factor
+            }
+        }
+    }
+}
+
+/// AmbientStrategy determines how the ambient concentration for a given
+/// exercise is derived from the ambient stages before and after it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum AmbientStrategy {
+    /// Use the average of all ambient samples from the preceding and
+    /// following ambient stages.
+    #[default]
+    Pooled,
+    /// Linearly interpolate between the preceding and following ambient
+    /// stages' averages, at the midpoint of the exercise's sampling period.
+    /// This matches the approach used by the 8020 and FitPro, and is
+    /// therefore useful for comparing results 1:1 with FitPro.
+    Interpolated,
+}
+
+/// ValveMode determines how the test switches between sampling ambient air
+/// and the specimen.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ValveMode {
+    /// Switch the device's valve, see ValvePosition.
+    #[default]
+    Automatic,
+    /// For devices without a (working) valve, e.g. an 8010, or an 8020 with
+    /// a failed valve. Valve commands are never sent, instead the operator is
+    /// asked to connect the ambient or specimen tube (see
+    /// TestNotification::ValveSwitchRequested), and must confirm having done
+    /// so (see Device::confirm_manual_switch). Samples are discarded until
+    /// then, i.e. FFs are calculated from the samples that the operator
+    /// designated as ambient or specimen samples.
+    Manual,
+}
+
+/// DiscardPolicy determines which samples are discarded after a valve switch
+/// is requested. Samples from before a switch has completed would otherwise be
+/// attributed to the wrong stage.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum DiscardPolicy {
+    /// Discard all samples until the device confirms the valve switch.
+    #[default]
+    UntilConfirmed,
+    /// Discard exactly count samples after requesting a valve switch,
+    /// regardless of whether (or when) the switch is confirmed. The switch is
+    /// assumed to have completed after count samples. With ValveMode::Manual,
+    /// all samples are discarded until the operator confirms the switch, and
+    /// count samples are discarded thereafter (while the new tube settles).
+    Fixed { count: usize },
+}
+
+/// The reason for discarding a given sample.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(C)]
+pub enum DiscardReason {
+    /// The valve switch has not been confirmed yet
+    /// (DiscardPolicy::UntilConfirmed, or ValveMode::Manual).
+    AwaitingValveSwitch,
+    /// The valve switch was requested recently (DiscardPolicy::Fixed).
+    ValveSwitchSettling,
+    /// Other tests sharing the ExerciseBarrier have not yet reached the
+    /// start of this exercise.
+    AwaitingGroup,
+}
+
+/// AdaptivePurge configures adaptive ambient purging: instead of always using
+/// the configured purge count, ambient purging continues until the
+/// concentration has stabilised. This improves accuracy in environments where
+/// the ambient aerosol concentration fluctuates significantly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdaptivePurge {
+    /// The number of consecutive purge samples that must be within
+    /// tolerance of each other.
+    pub window: usize,
+    /// Maximum relative spread (max - min, divided by the mean) of the
+    /// window that is considered stable.
+    pub tolerance: f64,
+    /// Upper limit on the number of purges added in addition to the
+    /// configured purge count.
+    pub max_additional_purges: usize,
+}
+
+/// MinimumAmbient declares the minimum acceptable ambient concentration.
+/// FFs become increasingly meaningless as ambient concentration decreases
+/// (e.g. an ambient concentration of 100 makes it impossible to measure FFs
+/// beyond 100 or so, and the uncertainty of lower FFs grows too).
+#[derive(Clone, Debug, PartialEq)]
+pub struct MinimumAmbient {
+    /// Minimum average concentration (particles/cm3) for every ambient stage.
+    pub concentration: f64,
+    pub action: MinimumAmbientAction,
+}
+
+/// MinimumAmbientAction determines what happens when an ambient stage does
+/// not meet the MinimumAmbient requirement.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum MinimumAmbientAction {
+    /// Send an AmbientBelowMinimum notification, and continue the test.
+    #[default]
+    Warn,
+    /// Send an AmbientBelowMinimum notification, and abort the test.
+    Abort,
+}
+
+/// EarlyStopping configures statistical early stopping of exercises: an
+/// exercise ends as soon as its FF is conclusive, rather than always
+/// collecting the configured number of samples. Shortens tests for
+/// well-fitting masks considerably.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EarlyStopping {
+    /// The minimum number of samples to collect for every exercise,
+    /// regardless of how conclusive the FF is.
+    pub min_samples: usize,
+    /// End the exercise once the relative width of the (95%) confidence
+    /// interval of the FF falls below this. Use 0 to disable.
+    pub max_relative_error: f64,
+    /// End the exercise once the confidence interval lies entirely above or
+    /// below this FF. Defaults to the exercise's pass level (if any).
+    pub pass_level: Option<f64>,
+}
+
+/// BarrierMode determines what a test does with samples received while it is
+/// waiting for other tests at an ExerciseBarrier.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BarrierMode {
+    /// Start the exercise immediately, but discard samples until all tests
+    /// have reached it (DiscardReason::AwaitingGroup).
+    #[default]
+    Discard,
+    /// Keep sampling the preceding stage until all tests have completed
+    /// their respective preceding stage, i.e. that stage is extended by as
+    /// many samples as necessary, and no samples are discarded.
+    Buffer,
+}
+
+/// ExerciseBarrier aligns the start of each exercise across several tests
+/// (e.g. tests running on multiple devices, see DeviceGroup): a test that
+/// reaches the start of an exercise (including grimaces) waits until all
+/// participating tests have reached the start of that exercise (see
+/// BarrierMode). All participating tests must therefore contain the same
+/// number of exercises, but may otherwise differ (e.g. in purge counts).
+/// Participants are identified by their index (0..participants), see
+/// TestOptions::barrier_participant.
+#[derive(Debug)]
+pub struct ExerciseBarrier {
+    mode: BarrierMode,
+    // The last exercise that each participant has reached the start of, or
+    // None if it hasn't reached any exercise yet. Participants that left are
+    // removed.
+    reached: Mutex<BTreeMap<usize, Option<usize>>>,
+}
+
+impl ExerciseBarrier {
+    pub fn new(participants: usize) -> ExerciseBarrier {
+        ExerciseBarrier::with_mode(participants, BarrierMode::default())
+    }
+
+    pub fn with_mode(participants: usize, mode: BarrierMode) -> ExerciseBarrier {
+        ExerciseBarrier {
+            mode,
+            reached: Mutex::new((0..participants).map(|index| (index, None)).collect()),
+        }
+    }
+
+    pub fn mode(&self) -> BarrierMode {
+        self.mode
+    }
+
+    /// Records that a participant has reached the start of exercise. Does
+    /// nothing if the participant has left.
+    pub fn arrive(&self, participant: usize, exercise: usize) {
+        if let Some(reached) = self.reached.lock().unwrap().get_mut(&participant) {
+            *reached = Some(exercise);
+        }
+    }
+
+    /// Returns whether all (remaining) participants have reached the start of
+    /// exercise.
+    pub fn is_released(&self, exercise: usize) -> bool {
+        self.reached
+            .lock()
+            .unwrap()
+            .values()
+            .all(|reached| reached.is_some_and(|reached| reached >= exercise))
+    }
+
+    /// Removes a participant, e.g. because its test was cancelled. Other
+    /// participants no longer wait for it, regardless of which exercise it
+    /// had reached.
+    pub fn leave(&self, participant: usize) {
+        self.reached.lock().unwrap().remove(&participant);
+    }
+}
+
+// z-score for a two-sided 95% confidence interval.
+const CONFIDENCE_Z: f64 = 1.96;
+
+/// The maximum FF that can be measured with an N95-Companion: it only counts
+/// the particles that penetrate N95 filters, i.e. ambient concentrations are
+/// far lower than without the companion.
+pub const N95_COMPANION_MAX_FIT_FACTOR: f64 = 200.0;
+
+/// AuditEvent is a single event recorded in a test's audit log.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AuditEvent {
+    CommandSent(Command),
+    MessageReceived(Message),
+    Notification(TestNotification),
+    /// The operator confirmed a valve switch, see ValveMode::Manual.
+    ManualValveSwitch(ValvePosition),
+}
+
+/// AuditEntry is a timestamped AuditEvent.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuditEntry {
+    pub timestamp: SystemTime,
+    pub event: AuditEvent,
+}
+
+/// TestResult contains the final results of a completed test.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TestResult {
+    /// Final FFs for each exercise, after applying the RoundingPolicy.
+    pub fit_factors: Vec<f64>,
+    /// Final FFs for each exercise, as calculated (i.e. prior to rounding).
+    pub raw_fit_factors: Vec<f64>,
+    /// The overall FF, i.e. the weighted harmonic mean of all exercise FFs
+    /// (see TestStage::Exercise::weight), after applying the RoundingPolicy.
+    /// Exercises that are excluded from the overall FF are ignored.
+    pub overall_fit_factor: f64,
+    /// The overall FF prior to rounding.
+    pub raw_overall_fit_factor: f64,
+    /// Whether each exercise passed, i.e. whether its (rounded) FF meets the
+    /// exercise's pass level. None for exercises without a pass level.
+    pub exercise_passed: Vec<Option<bool>>,
+    /// The overall verdict: whether the overall FF meets the test's pass
+    /// level, and all exercises met their respective pass levels. None if
+    /// neither the test nor any exercise define a pass level.
+    pub passed: Option<bool>,
+    /// A transcript of all commands sent, messages received, and
+    /// notifications sent during the test (in order).
+    pub audit_log: Vec<AuditEntry>,
+    /// The (absolute, unrounded) uncertainty of each exercise's FF.
+    pub errors: Vec<f64>,
+    /// The name of each exercise.
+    pub exercise_names: Vec<String>,
+    pub started_at: SystemTime,
+    pub completed_at: SystemTime,
+    /// The short name of the config used for this test.
+    pub config_short_name: String,
+    /// The version of the config used for this test.
+    pub config_version: u32,
+    /// The fingerprint (see TestConfig::fingerprint) of the config as it was
+    /// run, i.e. after stage durations were resolved into sample counts.
+    pub config_fingerprint: u64,
+}
+
+/// TestOptions contains per-test settings that affect how a test is run, but
+/// not what is being tested (the latter is defined by TestConfig).
+#[derive(Clone, Debug, Default)]
+pub struct TestOptions {
+    pub sound_policy: SoundPolicy,
+    pub display_policy: DisplayPolicy,
+    pub rounding_policy: RoundingPolicy,
+    pub ambient_strategy: AmbientStrategy,
+    pub discard_policy: DiscardPolicy,
+    pub valve_mode: ValveMode,
+    /// Enables adaptive ambient purging if set.
+    pub adaptive_ambient_purge: Option<AdaptivePurge>,
+    /// Enables statistical early stopping of exercises if set.
+    pub early_stopping: Option<EarlyStopping>,
+    /// Enables checking of ambient concentrations if set.
+    pub minimum_ambient: Option<MinimumAmbient>,
+    /// The interval between samples, used to convert time-based stage
+    /// durations (see DurationUnit) into sample counts. If None, Device uses
+    /// the recently observed sample cadence, and the harness uses
+    /// EXPECTED_SAMPLE_INTERVAL.
+    pub sample_interval: Option<Duration>,
+    /// Aligns the start of each exercise with other tests if set.
+    pub exercise_barrier: Option<Arc<ExerciseBarrier>>,
+    /// This test's index among exercise_barrier's participants.
+    pub barrier_participant: usize,
+    /// Whether an N95-Companion (Model 8095) is attached and enabled. FFs
+    /// are then capped at N95_COMPANION_MAX_FIT_FACTOR (or the config's
+    /// max_fit_factor, if lower), see also
+    /// TestNotification::PassLevelAboveCeiling.
+    pub n95_companion: bool,
+}
+
+pub enum StepOutcome {
+    TestComplete,
+    /// The test was aborted, e.g. because of insufficient ambient
+    /// concentration. No results are available.
+    TestAborted,
+    None,
+}
+
+/// CommandSink accepts commands destined for the device. The device thread
+/// queues commands (which are then sent by its event loop), but any other
+/// implementation (e.g. one that simply records commands) can be used to run a
+/// Test independently of the device thread.
+pub trait CommandSink {
+    fn send(&self, command: Command) -> Result<(), SendError<Command>>;
+}
+
+// AuditingCommandSink forwards commands to the actual sink, and additionally
+// holds the test's audit log (which is therefore also used for recording
+// non-command events).
+struct AuditingCommandSink<'a> {
+    inner: &'a dyn CommandSink,
+    audit_log: RefCell<Vec<AuditEntry>>,
+}
+
+impl AuditingCommandSink<'_> {
+    fn record(&self, event: AuditEvent) {
+        self.audit_log.borrow_mut().push(AuditEntry {
+            timestamp: SystemTime::now(),
+            event,
+        });
+    }
+}
+
+impl CommandSink for AuditingCommandSink<'_> {
+    fn send(&self, command: Command) -> Result<(), SendError<Command>> {
+        self.record(AuditEvent::CommandSent(command.clone()));
+        self.inner.send(command)
+    }
+}
+
+pub type TestCallback = Option<Box<dyn Fn(&TestNotification) + 'static + std::marker::Send>>;
+
+pub struct Test<'a> {
+    config: TestConfig,
+    options: TestOptions,
+    test_callback: TestCallback,
+    // TODO: figure out a better way of representing all of this, it's a little confusing.
+    current_stage: usize,
+    results: Vec<StageResults>,
+    // Final FFs for each exercise. Caution: for non-periodic protocols, a given
+    // exercise's FF might not be calculated until several intermediate
+    // exerciseshave completed.
+    pub exercise_ffs: Vec<f64>,
+    // The (absolute) uncertainty of each FF in exercise_ffs.
+    exercise_errors: Vec<f64>,
+    // This is NOT the same as exercise_ffs.len(), see above.
+    exercises_completed: usize,
+    // The valve switch that we're awaiting confirmation for (if any), and
+    // the number of samples discarded while waiting.
+    pending_valve_switch: Option<ValvePosition>,
+    discarded_samples: usize,
+    // Only used for DiscardPolicy::Fixed.
+    discards_remaining: usize,
+    // The exercise whose start we're awaiting the ExerciseBarrier for (if
+    // any).
+    awaiting_barrier: Option<usize>,
+    tx_command: AuditingCommandSink<'a>,
+    started_at: SystemTime,
+    completed_at: Option<SystemTime>,
+}
+
+// This implementation is extremely specific to the 8020. However, it's not hard
+// to imagine converting this into something device-agnostic with a little spot
+// of tweaking (in conjunction with a CPC-abstraction-layer).
+impl Test<'_> {
+    fn create(
+        config: TestConfig,
+        options: TestOptions,
+        tx_command: &dyn CommandSink,
+        test_callback: TestCallback,
+    ) -> Test<'_> {
+        let mut config = config.resolve_durations(
+            options
+                .sample_interval
+                .unwrap_or(crate::cadence::EXPECTED_SAMPLE_INTERVAL),
+        );
+        let stage_count = config.stages.len();
+        assert!(
+            stage_count >= 3,
+            "invalid test config - must have at least 3 stages"
+        );
+        assert!(
+            config.stages[0].is_ambient_sample(),
+            "invalid test config - must end with ambient"
+        );
+        if options.n95_companion {
+            config.max_fit_factor = Some(
+                config
+                    .max_fit_factor
+                    .map_or(N95_COMPANION_MAX_FIT_FACTOR, |max| {
+                        max.min(N95_COMPANION_MAX_FIT_FACTOR)
+                    }),
+            );
+        }
+        let mut results = Vec::with_capacity(stage_count);
+        results.push(StageResults::from(&config.stages[0]));
+        Test {
+            config,
+            options,
+            test_callback,
+            current_stage: 0,
+            results,
+            exercise_ffs: Vec::with_capacity(stage_count),
+            exercise_errors: Vec::with_capacity(stage_count),
+            exercises_completed: 0,
+            pending_valve_switch: None,
+            discarded_samples: 0,
+            discards_remaining: 0,
+            awaiting_barrier: None,
+            tx_command: AuditingCommandSink {
+                inner: tx_command,
+                audit_log: RefCell::new(Vec::new()),
+            },
+            started_at: SystemTime::now(),
+            completed_at: None,
+        }
+    }
+
+    pub fn create_and_start<'a>(
+        config: TestConfig,
+        options: TestOptions,
+        tx_command: &'a dyn CommandSink,
+        valve_state: &mut ValveState,
+        test_callback: TestCallback,
+    ) -> Result<Test<'a>, SendError<Command>> {
+        let mut test = Self::create(config, options, tx_command, test_callback);
+        if test.options.n95_companion {
+            test.warn_unreachable_pass_level();
+        }
+        match valve_state {
+            ValveState::Ambient => (),
+            ValveState::AwaitingAmbient => {
+                test.pending_valve_switch = Some(ValvePosition::Ambient);
+            }
+            ValveState::Specimen | ValveState::AwaitingSpecimen => {
+                test.switch_valve(ValvePosition::Ambient, valve_state)?;
+            }
+        };
+        test.tx_command.send(Command::ClearDisplay)?;
+        test.tx_command.send(Command::Indicator(Indicator {
+            in_progress: true,
+            fit_factor: test.options.display_policy == DisplayPolicy::InterimFF,
+            ..Indicator::empty()
+        }))?;
+        if test.options.display_policy == DisplayPolicy::ExerciseNumber {
+            test.tx_command.send(Command::DisplayExercise(1))?;
+        }
+        test.send_notification(&TestNotification::StateChange(TestState::StartedExercise(
+            0,
+        )));
+        test.send_time_remaining();
+        test.beep(BeepEvent::TestStarted)?;
+        Ok(test)
+    }
+
+    // Configs whose pass levels exceed their own max FF are flagged when
+    // editing configs (see ConfigWarning), but the N95-Companion's ceiling
+    // only applies to tests.
+    fn warn_unreachable_pass_level(&self) {
+        let Some(max_fit_factor) = self.config.max_fit_factor else {
+            return;
+        };
+        let pass_level = self
+            .config
+            .stages
+            .iter()
+            .filter_map(|stage| match stage {
+                TestStage::Exercise { pass_level, .. } => *pass_level,
+                _ => None,
+            })
+            .chain(self.config.pass_level)
+            .fold(None, |highest: Option<f64>, pass_level| {
+                Some(highest.map_or(pass_level, |highest| highest.max(pass_level)))
+            });
+        if let Some(pass_level) = pass_level.filter(|pass_level| *pass_level > max_fit_factor) {
+            log::warn!("pass level ({pass_level}) above max FF ({max_fit_factor})");
+            self.send_notification(&TestNotification::PassLevelAboveCeiling {
+                pass_level,
+                max_fit_factor,
+            });
+        }
+    }
+
+    fn send_time_remaining(&self) {
+        let samples_remaining = self.results.last().unwrap().remaining_count()
+            + self.config.stages[self.current_stage + 1..]
+                .iter()
+                .map(|stage| {
+                    let counts = stage.counts();
+                    counts.purge_count + counts.sample_count
+                })
+                .sum::<usize>();
+        let sample_interval = self
+            .options
+            .sample_interval
+            .unwrap_or(crate::cadence::EXPECTED_SAMPLE_INTERVAL);
+        self.send_notification(&TestNotification::TimeRemaining {
+            samples_remaining,
+            seconds_remaining: samples_remaining as f64 * sample_interval.as_secs_f64(),
+        });
+    }
+
+    fn switch_valve(
+        &mut self,
+        position: ValvePosition,
+        valve_state: &mut ValveState,
+    ) -> Result<(), SendError<Command>> {
+        let manual = self.options.valve_mode == ValveMode::Manual;
+        if !manual {
+            self.tx_command.send(position.command())?;
+        }
+        *valve_state = position.awaiting_state();
+        self.pending_valve_switch = Some(position);
+        self.discarded_samples = 0;
+        self.discards_remaining = match self.options.discard_policy {
+            // Manual switches are settled after confirmation instead, see
+            // confirm_manual_switch.
+            DiscardPolicy::Fixed { count } if !manual => count,
+            DiscardPolicy::UntilConfirmed | DiscardPolicy::Fixed { .. } => 0,
+        };
+        self.send_notification(&TestNotification::ValveSwitchRequested { position });
+        Ok(())
+    }
+
+    fn confirm_valve_switch(&mut self, position: ValvePosition) {
+        // The device mirrors every valve command, including any that were sent
+        // before this test started - those aren't interesting.
+        if self.pending_valve_switch != Some(position) {
+            return;
+        }
+        self.pending_valve_switch = None;
+        self.send_notification(&TestNotification::ValveSwitchConfirmed {
+            position,
+            discarded_samples: self.discarded_samples,
+        });
+    }
+
+    /// Records that the operator has connected the tube for position, see
+    /// ValveMode::Manual. Ignored unless that switch was requested.
+    pub fn confirm_manual_switch(&mut self, position: ValvePosition, valve_state: &mut ValveState) {
+        if self.options.valve_mode != ValveMode::Manual
+            || self.pending_valve_switch != Some(position)
+        {
+            log::warn!("ignoring unexpected manual valve switch ({position:?})");
+            return;
+        }
+        *valve_state = match position {
+            ValvePosition::Ambient => ValveState::Ambient,
+            ValvePosition::Specimen => ValveState::Specimen,
+        };
+        if let DiscardPolicy::Fixed { count } = self.options.discard_policy {
+            self.discards_remaining = count;
+        }
+        self.tx_command
+            .record(AuditEvent::ManualValveSwitch(position));
+        self.confirm_valve_switch(position);
+    }
+
+    fn beep(&self, event: BeepEvent) -> Result<(), SendError<Command>> {
+        if let Some(duration_deciseconds) = self.options.sound_policy.beep_duration(event) {
+            self.tx_command.send(Command::Beep {
+                duration_deciseconds,
+            })?;
+        }
+        Ok(())
+    }
+
+    // update_display is called for every stored sample, and updates the
+    // device's display according to the DisplayPolicy. (Exercise numbers are
+    // handled separately, as they only change when an exercise starts.)
+    fn update_display(
+        &self,
+        value: f64,
+        interim_ff: Option<f64>,
+    ) -> Result<(), SendError<Command>> {
+        match self.options.display_policy {
+            DisplayPolicy::Concentration => {
+                self.tx_command.send(Command::DisplayConcentration(value))?;
+            }
+            DisplayPolicy::InterimFF => {
+                if let Some(interim_ff) = interim_ff {
+                    self.tx_command
+                        .send(Command::DisplayConcentration(interim_ff))?;
+                }
+            }
+            DisplayPolicy::ExerciseNumber | DisplayPolicy::Blank => (),
+        }
+        Ok(())
+    }
+
+    /// Returns the results of this test. Only meaningful once step() has
+    /// returned StepOutcome::TestComplete.
+    pub fn result(&self) -> TestResult {
+        let (weight_sum, weighted_inverse_sum) = self
+            .config
+            .overall_weights()
+            .iter()
+            .zip(self.exercise_ffs.iter())
+            .fold((0.0, 0.0), |(weight_sum, inverse_sum), (weight, ff)| {
+                (weight_sum + weight, inverse_sum + weight / ff)
+            });
+        let raw_overall_fit_factor = weight_sum / weighted_inverse_sum;
+        let fit_factors: Vec<f64> = self
+            .exercise_ffs
+            .iter()
+            .map(|ff| self.options.rounding_policy.apply(*ff))
+            .collect();
+        let exercise_passed: Vec<Option<bool>> = self
+            .config
+            .exercise_pass_levels()
+            .iter()
+            .zip(fit_factors.iter())
+            .map(|(pass_level, ff)| pass_level.map(|pass_level| *ff >= pass_level))
+            .collect();
+        let overall_fit_factor = self.options.rounding_policy.apply(raw_overall_fit_factor);
+        let passed = self
+            .config
+            .pass_level
+            .map(|pass_level| overall_fit_factor >= pass_level)
+            .into_iter()
+            .chain(exercise_passed.iter().flatten().copied())
+            .reduce(|a, b| a && b);
+        TestResult {
+            passed,
+            exercise_passed,
+            overall_fit_factor,
+            raw_overall_fit_factor,
+            audit_log: self.tx_command.audit_log.borrow().clone(),
+            fit_factors,
+            raw_fit_factors: self.exercise_ffs.clone(),
+            errors: self.exercise_errors.clone(),
+            exercise_names: self.config.exercise_names(),
+            started_at: self.started_at,
+            completed_at: self.completed_at.unwrap_or_else(SystemTime::now),
+            config_short_name: self.config.short_name.clone(),
+            config_version: self.config.version,
+            config_fingerprint: self.config.fingerprint(),
+        }
+    }
+
+    fn send_notification(&self, notification: &TestNotification) {
+        self.tx_command
+            .record(AuditEvent::Notification(notification.clone()));
+        if let Some(callback) = &self.test_callback {
+            callback(notification);
+        }
+    }
+
+    fn last_ambient(&self) -> &StageResults {
+        for stage_results in self.results.iter().rev() {
+            if let StageResults::AmbientSample { .. } = stage_results {
+                return stage_results;
+            }
+        }
+        panic!("encountered invalid test config with no ambient stage results")
+    }
+
+    // store_sample stores the sample without doing any further work - callers
+    // must ensure to perform any followup changes to the test (e.g. by moving
+    // to the next stage).
+    fn store_sample(&mut self, value: f64, valve_state: &mut ValveState) -> Option<SampleType> {
+        let mut awaiting_group = false;
+        if let (Some(exercise), Some(barrier)) =
+            (self.awaiting_barrier, &self.options.exercise_barrier)
+        {
+            match barrier.mode() {
+                BarrierMode::Discard => {
+                    if barrier.is_released(exercise) {
+                        self.awaiting_barrier = None;
+                    } else {
+                        awaiting_group = true;
+                    }
+                }
+                // The preceding stage is still running (and already
+                // complete), see await_barrier_in_buffer_mode.
+                BarrierMode::Buffer => {
+                    let stage_results = self.results.last_mut().unwrap();
+                    if stage_results.is_complete() {
+                        stage_results.extend_sampling();
+                    }
+                }
+            }
+        }
+        let discard_reason = if awaiting_group {
+            Some(DiscardReason::AwaitingGroup)
+        } else if self.discards_remaining > 0 {
+            self.discards_remaining -= 1;
+            Some(DiscardReason::ValveSwitchSettling)
+        } else {
+            match (valve_state.clone(), &self.options.discard_policy) {
+                (
+                    ValveState::AwaitingAmbient | ValveState::AwaitingSpecimen,
+                    DiscardPolicy::UntilConfirmed,
+                ) => Some(DiscardReason::AwaitingValveSwitch),
+                // Only the operator knows when a manual switch has completed.
+                (ValveState::AwaitingAmbient | ValveState::AwaitingSpecimen, _)
+                    if self.options.valve_mode == ValveMode::Manual =>
+                {
+                    Some(DiscardReason::AwaitingValveSwitch)
+                }
+                (ValveState::AwaitingAmbient, DiscardPolicy::Fixed { .. }) => {
+                    *valve_state = ValveState::Ambient;
+                    self.pending_valve_switch = None;
+                    None
+                }
+                (ValveState::AwaitingSpecimen, DiscardPolicy::Fixed { .. }) => {
+                    *valve_state = ValveState::Specimen;
+                    self.pending_valve_switch = None;
+                    None
+                }
+                _ => None,
+            }
+        };
+        if let Some(reason) = discard_reason {
+            log::debug!("discarded a sample ({reason:?})");
+            self.discarded_samples += 1;
+            self.send_notification(&TestNotification::SampleDiscarded { value, reason });
+            return None;
+        }
+
+        let stage_results = self.results.last_mut().unwrap();
+        match valve_state {
+            ValveState::AwaitingAmbient | ValveState::AwaitingSpecimen => {
+                unreachable!("samples must be discarded while awaiting valve switch")
+            }
+            ValveState::Ambient => {
+                assert!(
+                    stage_results.is_ambient_sample(),
+                    "valve state (ambient) does not match test stage (should be AmbientSample)"
+                );
+                if let Some(adaptive_purge) = &self.options.adaptive_ambient_purge {
+                    stage_results.extend_purge_if_unstable(adaptive_purge);
+                }
+            }
+            ValveState::Specimen => {
+                assert!(
+                    stage_results.is_exercise(),
+                    "valve state (specimen) does not match test stage (should be Exercise)"
+                );
+            }
+        }
+        Some(stage_results.append(value))
+    }
+
+    // Applies the config's FF ceiling (if any).
+    fn cap_fit_factor(&self, fit_factor: f64) -> f64 {
+        self.config
+            .max_fit_factor
+            .map_or(fit_factor, |max| fit_factor.min(max))
+    }
+
+    // Ends the current exercise if its FF is already conclusive according
+    // to the EarlyStopping options. Must be called after storing a sample.
+    fn end_exercise_if_conclusive(&mut self, early_stopping: &EarlyStopping) {
+        let stage_results = self.results.last().unwrap();
+        let StageResults::Exercise {
+            samples, config, ..
+        } = stage_results
+        else {
+            return;
+        };
+        if samples.len() < early_stopping.min_samples || samples.len() >= config.sample_count {
+            return;
+        }
+        let ambient = self.last_ambient();
+        let min_concentration = self.config.min_concentration;
+        let fit_factor = self
+            .cap_fit_factor(ambient.avg(min_concentration) / stage_results.avg(min_concentration));
+        let relative_error = CONFIDENCE_Z
+            * f64::hypot(
+                ambient.err(min_concentration),
+                stage_results.err(min_concentration),
+            );
+        let (lower, upper) = (
+            fit_factor * (1.0 - relative_error),
+            fit_factor * (1.0 + relative_error),
+        );
+        let pass_level = early_stopping
+            .pass_level
+            .or_else(|| self.config.exercise_pass_levels()[self.exercises_completed]);
+        let conclusive = relative_error <= early_stopping.max_relative_error
+            || pass_level.is_some_and(|pass_level| lower > pass_level || upper < pass_level);
+        if !conclusive {
+            return;
+        }
+        let sample_count = samples.len();
+        log::info!("ending exercise early, FF={fit_factor} [{lower}, {upper}]");
+        self.results.last_mut().unwrap().end_sampling_early();
+        self.send_notification(&TestNotification::ExerciseEndedEarly {
+            exercise: self.exercises_completed,
+            sample_count,
+        });
+    }
+
+    // Only relevant for BarrierMode::Buffer: called once the current stage is
+    // complete, and returns true if it must be extended because the next
+    // stage is an exercise that other tests haven't reached yet.
+    fn await_barrier_in_buffer_mode(&mut self, stage_is_ambient_sample: bool) -> bool {
+        let Some(barrier) = &self.options.exercise_barrier else {
+            return false;
+        };
+        let next_is_exercise = self
+            .config
+            .stages
+            .get(self.current_stage + 1)
+            .is_some_and(|stage| stage.is_exercise());
+        if barrier.mode() != BarrierMode::Buffer || !next_is_exercise {
+            return false;
+        }
+        let exercise = self.exercises_completed + usize::from(!stage_is_ambient_sample);
+        if self.awaiting_barrier != Some(exercise) {
+            barrier.arrive(self.options.barrier_participant, exercise);
+            self.awaiting_barrier = Some(exercise);
+        }
+        if barrier.is_released(exercise) {
+            self.awaiting_barrier = None;
+            return false;
+        }
+        true
+    }
+
+    // Returns the position of the midpoint of each stage's sampling period
+    // (i.e. excluding purges), measured in samples since the start of the
+    // test. Samples discarded while awaiting valve switches are not included.
+    fn sample_midpoints(&self) -> Vec<f64> {
+        let mut offset = 0;
+        self.results
+            .iter()
+            .map(|stage_results| match stage_results {
+                StageResults::AmbientSample {
+                    purges, samples, ..
+                }
+                | StageResults::Exercise {
+                    purges, samples, ..
+                } => {
+                    let midpoint = (offset + purges.len()) as f64 + samples.len() as f64 / 2.0;
+                    offset += purges.len() + samples.len();
+                    midpoint
+                }
+            })
+            .collect()
+    }
+
+    fn calculate_ffs(&mut self) {
+        let mut iter = self.results.iter().rev();
+        let ambient_samples = loop {
+            match iter.next() {
+                Some(StageResults::AmbientSample { samples, .. }) => {
+                    break samples.iter().copied();
+                }
+                Some(_) => (),
+                None => panic!(
+                    "must not call calculate_ffs without at least two ambient stages (found 0)"
+                ),
+            }
+        };
+        let ambient_samples = ambient_samples.chain(loop {
+            match iter.next() {
+                Some(StageResults::AmbientSample { samples, .. }) => {
+                    break samples.iter().copied();
+                }
+                Some(_) => (),
+                None => panic!(
+                    "must not call calculate_ffs without at least two ambient stages (found 0)"
+                ),
+            }
+        });
+
+        let mut exercise_averages_stack = Vec::new();
+        for (index, stage) in self.results.iter().enumerate().rev().skip(1) {
+            if !matches!(stage, StageResults::Exercise { .. }) {
+                break;
+            }
+            exercise_averages_stack.push((
+                index,
+                stage.avg(self.config.min_concentration),
+                stage.err(self.config.min_concentration),
+            ));
+        }
+
+        let ambients: Vec<f64> = ambient_samples.collect();
+        let ambient_avg = ambients.iter().sum::<f64>() / (ambients.len() as f64);
+
+        // Only needed for AmbientStrategy::Interpolated: the ambient stages
+        // immediately preceding and following the exercises (and the
+        // positions of their respective midpoints).
+        let after_index = self.results.len() - 1;
+        let before_index = exercise_averages_stack.last().map_or(after_index, |e| e.0) - 1;
+        let midpoints = self.sample_midpoints();
+
+        while let Some((index, exercise_avg, exercise_err)) = exercise_averages_stack.pop() {
+            let ambient_avg = match self.options.ambient_strategy {
+                AmbientStrategy::Pooled => ambient_avg,
+                AmbientStrategy::Interpolated => {
+                    let before = self.results[before_index].avg(self.config.min_concentration);
+                    let after = self.results[after_index].avg(self.config.min_concentration);
+                    let progress = (midpoints[index] - midpoints[before_index])
+                        / (midpoints[after_index] - midpoints[before_index]);
+                    before + (after - before) * progress
+                }
+            };
+            let ff = self.cap_fit_factor(ambient_avg / exercise_avg);
+            log::info!(
+                "Exercise {}: FF={}±{}",
+                self.exercise_ffs.len(),
+                ff,
+                ff * exercise_err,
+            );
+            self.send_notification(&TestNotification::ExerciseResult {
+                exercise: self.exercise_ffs.len(),
+                fit_factor: self.options.rounding_policy.apply(ff),
+                // TODO: fix this approximation - it's reasonable for high FF
+                // where specimen error dominates, but it's still off by almost
+                // 1% for ambient samples at ambient conc of 1000 (which will
+                // influence uncertainty for low FFs).
+                error: ff * exercise_err,
+                raw_fit_factor: ff,
+            });
+            self.exercise_ffs.push(ff);
+            self.exercise_errors.push(ff * exercise_err);
+        }
+    }
+
+    fn process_sample(
+        &mut self,
+        value: f64,
+        valve_state: &mut ValveState,
+    ) -> Result<StepOutcome, SendError<Command>> {
+        assert!(
+            (!(self.current_stage == self.config.stages.len()
+                && self.results.last().unwrap().is_complete())),
+            "process_sample must not be called after test completion"
+        );
+
+        let Some(stored_sample_type) = self.store_sample(value, valve_state) else {
+            return Ok(StepOutcome::None);
+        };
+        let is_specimen_sample = stored_sample_type == SampleType::SpecimenSample;
+        self.send_notification(&TestNotification::Sample(SampleData {
+            stage: self.current_stage,
+            exercise: self.exercises_completed,
+            value,
+            sample_type: stored_sample_type,
+        }));
+        if is_specimen_sample {
+            if let Some(early_stopping) = self.options.early_stopping.clone() {
+                self.end_exercise_if_conclusive(&early_stopping);
+            }
+        }
+        self.send_time_remaining();
+
+        // Only borrow the current stage's results for as long as necessary:
+        // cloning would be simpler, but would copy every sample stored so far
+        // on every single sample (which adds up during very long tests).
+        let stage_results = self.results.last().unwrap();
+        let stage_is_complete = stage_results.is_complete();
+        let stage_is_ambient_sample = stage_results.is_ambient_sample();
+        let mut interim_ff = None;
+        if let StageResults::Exercise { samples, .. } = stage_results {
+            assert!(self.last_ambient().has_samples(), "should not be executing exercise without at least one completed ambient sample stage");
+            if stage_results.has_samples() {
+                let ambient_avg = self.last_ambient().avg(self.config.min_concentration);
+                let live_ff = self.cap_fit_factor(ambient_avg / value.max(100.0 / 60.0));
+                self.send_notification(&TestNotification::LiveFF {
+                    exercise: self.exercises_completed,
+                    index: samples.len(),
+                    fit_factor: live_ff,
+                });
+                let fit_factor = self
+                    .cap_fit_factor(ambient_avg / stage_results.avg(self.config.min_concentration));
+                self.send_notification(&TestNotification::InterimFF {
+                    exercise: self.exercises_completed,
+                    fit_factor,
+                });
+                interim_ff = Some(fit_factor);
+            }
+        }
+        self.update_display(value, interim_ff)?;
+        if stage_is_complete && self.await_barrier_in_buffer_mode(stage_is_ambient_sample) {
+            return Ok(StepOutcome::None);
+        }
+        let stage_results = self.results.last().unwrap();
+        if stage_is_complete {
+            let (average, standard_deviation) = stage_results.mean_and_std_dev();
+            self.send_notification(&TestNotification::StageComplete {
+                stage: self.current_stage,
+                average,
+                sample_count: stage_results.sample_count(),
+                standard_deviation,
+            });
+            if stage_is_ambient_sample {
+                let average = stage_results.avg(self.config.min_concentration);
+                self.send_notification(&TestNotification::AmbientResult {
+                    stage: self.current_stage,
+                    average,
+                    error: average * stage_results.err(self.config.min_concentration),
+                });
+            }
+            if let (true, Some(minimum_ambient)) =
+                (stage_is_ambient_sample, &self.options.minimum_ambient)
+            {
+                let average = stage_results.avg(self.config.min_concentration);
+                if average < minimum_ambient.concentration {
+                    log::warn!(
+                        "ambient concentration ({average}) below minimum ({})",
+                        minimum_ambient.concentration
+                    );
+                    self.send_notification(&TestNotification::AmbientBelowMinimum {
+                        stage: self.current_stage,
+                        average,
+                        minimum: minimum_ambient.concentration,
+                    });
+                    if minimum_ambient.action == MinimumAmbientAction::Abort {
+                        self.switch_valve(ValvePosition::Specimen, valve_state)?;
+                        self.tx_command.send(Command::ClearDisplay)?;
+                        return Ok(StepOutcome::TestAborted);
+                    }
+                }
+            }
+            if self.exercises_completed > 0 && stage_is_ambient_sample {
+                self.calculate_ffs();
+            }
+
+            if self.current_stage == self.config.stages.len() - 1 {
+                self.switch_valve(ValvePosition::Specimen, valve_state)?;
+                self.tx_command.send(Command::ClearDisplay)?;
+                self.beep(BeepEvent::TestCompleted)?;
+                self.completed_at = Some(SystemTime::now());
+                return Ok(StepOutcome::TestComplete);
+            }
+
+            self.current_stage += 1;
+            self.results
+                .push(StageResults::from(&self.config.stages[self.current_stage]));
+
+            if self.results.last().unwrap().is_ambient_sample() {
+                log::debug!("starting ambient sample stage");
+                // We can always assume that valve_state=Sample.
+                self.switch_valve(ValvePosition::Ambient, valve_state)?;
+            } else {
+                log::debug!("starting exercise stage");
+                if !matches!(valve_state, ValveState::Specimen) {
+                    self.switch_valve(ValvePosition::Specimen, valve_state)?;
+                }
+            }
+
+            if !stage_is_ambient_sample {
+                self.exercises_completed += 1;
+                if self.results.len() != self.config.stages.len() {
+                    self.send_notification(&TestNotification::StateChange(
+                        TestState::StartedExercise(self.exercises_completed),
+                    ));
+                    let next_is_grimace = self.config.stages[self.current_stage..]
+                        .iter()
+                        .find(|stage| stage.is_exercise())
+                        .is_some_and(|stage| stage.is_grimace());
+                    if self.options.display_policy == DisplayPolicy::ExerciseNumber {
+                        // Grimaces aren't scored, so don't show an exercise
+                        // number for them.
+                        if next_is_grimace {
+                            self.tx_command.send(Command::ClearDisplay)?;
+                        } else {
+                            let device_exercise = ((self.exercises_completed + 1) % 20) as u8;
+                            self.tx_command
+                                .send(Command::DisplayExercise(device_exercise))?;
+                        }
+                    }
+                    if next_is_grimace {
+                        self.beep(BeepEvent::GrimaceStarted)?;
+                    } else {
+                        self.beep(BeepEvent::ExerciseStarted)?;
+                    }
+                }
+            }
+            // (In BarrierMode::Buffer, we already waited before starting
+            // the exercise.)
+            if let (true, Some(barrier)) = (
+                self.config.stages[self.current_stage].is_exercise(),
+                &self.options.exercise_barrier,
+            ) {
+                if barrier.mode() == BarrierMode::Discard {
+                    barrier.arrive(self.options.barrier_participant, self.exercises_completed);
+                    self.awaiting_barrier = Some(self.exercises_completed);
+                }
+            }
+        }
+        Ok(StepOutcome::None)
+    }
+
+    pub fn step(
+        &mut self,
+        message: Message,
+        valve_state: &mut ValveState,
+    ) -> Result<StepOutcome, SendError<Command>> {
+        self.tx_command
+            .record(AuditEvent::MessageReceived(message.clone()));
+        match message {
+            Message::Sample(value) => {
+                return self.process_sample(value, valve_state);
+            }
+            Message::Response(command) => match command {
+                // Without a valve, any valve responses are stale (e.g. from
+                // resetting the valve after a previous test was cancelled).
+                Command::ValveAmbient | Command::ValveSpecimen
+                    if self.options.valve_mode == ValveMode::Manual =>
+                {
+                    log::debug!("ignoring valve response in manual mode: {command:?}");
+                }
+                // The device thread only tracks valve_state while no test is
+                // running, i.e. tests are responsible for updating it.
+                Command::ValveAmbient => {
+                    *valve_state = ValveState::Ambient;
+                    self.confirm_valve_switch(ValvePosition::Ambient);
+                }
+                Command::ValveSpecimen => {
+                    *valve_state = ValveState::Specimen;
+                    self.confirm_valve_switch(ValvePosition::Specimen);
+                }
+                any => {
+                    log::debug!("ignoring command response: {any:?}");
+                }
+            },
+            Message::ErrorResponse(response) => {
+                log::warn!("ignoring command error response: {response:?}");
+            }
+            Message::UnknownError(response) => {
+                log::warn!("ignoring unknown error: {response}");
+            }
+            // These are already handled by the device_thread. They're irrelevant for a test.
+            Message::Setting(_) => (),
+        }
+        Ok(StepOutcome::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config::{DurationUnit, StageCounts};
+    use std::collections::BTreeMap;
+
+    fn minimal_config() -> TestConfig {
+        let counts = StageCounts {
+            purge_count: 0,
+            sample_count: 1,
+            unit: DurationUnit::Samples,
+        };
+        TestConfig {
+            name: "Minimal".to_string(),
+            short_name: "minimal".to_string(),
+            stages: vec![
+                TestStage::AmbientSample {
+                    counts: counts.clone(),
+                },
+                TestStage::Exercise {
+                    name: "foo".to_string(),
+                    counts: counts.clone(),
+                    weight: 1.0,
+                    exclude_from_overall: false,
+                    pass_level: None,
+                    localized_names: BTreeMap::new(),
+                },
+                TestStage::AmbientSample { counts },
+            ],
+            pass_level: None,
+            version: 1,
+            localized_names: BTreeMap::new(),
+            max_fit_factor: None,
+            min_concentration: None,
+        }
+    }
+
+    fn run_minimal_test(options: TestOptions) -> (Vec<Command>, Vec<f64>) {
+        run_minimal_test_with_ff(options, 100.0).0
+    }
+
+    // Runs the minimal test, such that the exercise FF is fit_factor. Returns
+    // both commands+raw FFs, and the TestResult.
+    fn run_minimal_test_with_ff(
+        options: TestOptions,
+        fit_factor: f64,
+    ) -> ((Vec<Command>, Vec<f64>), TestResult) {
+        let output = harness::run_messages(
+            minimal_config(),
+            options,
+            [
+                Message::Response(Command::ValveAmbient),
+                Message::Sample(1000.0),
+                Message::Response(Command::ValveSpecimen),
+                Message::Sample(1000.0 / fit_factor),
+                Message::Response(Command::ValveAmbient),
+                Message::Sample(1000.0),
+            ],
+        );
+        assert!(output.completed);
+        (
+            (output.commands, output.exercise_ffs),
+            output.result.unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_minimal_test_commands() {
+        let (commands, exercise_ffs) = run_minimal_test(TestOptions::default());
+        assert_eq!(exercise_ffs, vec![100.0]);
+        assert_eq!(
+            commands,
+            vec![
+                Command::ValveAmbient,
+                Command::ClearDisplay,
+                Command::Indicator(Indicator {
+                    in_progress: true,
+                    ..Indicator::empty()
+                }),
+                Command::DisplayExercise(1),
+                Command::Beep {
+                    duration_deciseconds: 40
+                },
+                Command::ValveSpecimen,
+                Command::ValveAmbient,
+                Command::ValveSpecimen,
+                Command::ClearDisplay,
+                Command::Beep {
+                    duration_deciseconds: 99
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_beep_duration() {
+        assert_eq!(BeepDuration::new(0), None);
+        assert_eq!(BeepDuration::new(1).map(BeepDuration::deciseconds), Some(1));
+        assert_eq!(
+            BeepDuration::new(99).map(BeepDuration::deciseconds),
+            Some(99)
+        );
+        assert_eq!(BeepDuration::new(100), None);
+    }
+
+    #[test]
+    fn test_sound_policy() {
+        struct TestCase<'a> {
+            name: &'a str,
+            input: SoundPolicy,
+            expected_beeps: Vec<u8>,
+        }
+        let tests = [
+            TestCase {
+                name: "Full",
+                input: SoundPolicy::Full,
+                expected_beeps: vec![40, 99],
+            },
+            TestCase {
+                name: "Silent",
+                input: SoundPolicy::Silent,
+                expected_beeps: vec![],
+            },
+            TestCase {
+                name: "Custom",
+                input: SoundPolicy::Custom {
+                    test_started: None,
+                    exercise_started: BeepDuration::new(5),
+                    test_completed: BeepDuration::new(20),
+                },
+                expected_beeps: vec![20],
+            },
+        ];
+        for case in tests {
+            let (commands, _) = run_minimal_test(TestOptions {
+                sound_policy: case.input,
+                ..TestOptions::default()
+            });
+            let got: Vec<u8> = commands
+                .iter()
+                .filter_map(|command| match command {
+                    Command::Beep {
+                        duration_deciseconds,
+                    } => Some(*duration_deciseconds),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                got, case.expected_beeps,
+                "{}: got={got:?}, want={:?}",
+                case.name, case.expected_beeps
+            );
+        }
+    }
+
+    #[test]
+    fn test_rounding_policy() {
+        struct TestCase<'a> {
+            name: &'a str,
+            input: RoundingPolicy,
+            fit_factor: f64,
+            expected_fit_factor: f64,
+        }
+        let tests = [
+            TestCase {
+                name: "None",
+                input: RoundingPolicy::None,
+                fit_factor: 123.456,
+                expected_fit_factor: 123.456,
+            },
+            TestCase {
+                name: "TruncateInteger",
+                input: RoundingPolicy::Truncate { decimal_places: 0 },
+                fit_factor: 99.9,
+                expected_fit_factor: 99.0,
+            },
+            TestCase {
+                name: "TruncateOneDecimal",
+                input: RoundingPolicy::Truncate { decimal_places: 1 },
+                fit_factor: 8.96,
+                expected_fit_factor: 8.9,
+            },
+            TestCase {
+                name: "RoundInteger",
+                input: RoundingPolicy::Round { decimal_places: 0 },
+                fit_factor: 99.6,
+                expected_fit_factor: 100.0,
+            },
+        ];
+        for case in tests {
+            let (_, result) = run_minimal_test_with_ff(
+                TestOptions {
+                    rounding_policy: case.input,
+                    ..TestOptions::default()
+                },
+                case.fit_factor,
+            );
+            assert!(
+                (result.raw_fit_factors[0] - case.fit_factor).abs() < 1e-9,
+                "{}: raw FF mismatch",
+                case.name
+            );
+            let got = result.fit_factors[0];
+            assert!(
+                (got - case.expected_fit_factor).abs() < 1e-9,
+                "{}: got={got:?}, want={:?}",
+                case.name,
+                case.expected_fit_factor
+            );
+        }
+    }
+
+    #[test]
+    fn test_ambient_strategy() {
+        let counts = StageCounts {
+            purge_count: 0,
+            sample_count: 1,
+            unit: DurationUnit::Samples,
+        };
+        let exercise = TestStage::Exercise {
+            name: "foo".to_string(),
+            counts: counts.clone(),
+            weight: 1.0,
+            exclude_from_overall: false,
+            pass_level: None,
+            localized_names: BTreeMap::new(),
+        };
+        let config = TestConfig {
+            stages: vec![
+                TestStage::AmbientSample {
+                    counts: counts.clone(),
+                },
+                exercise.clone(),
+                exercise,
+                TestStage::AmbientSample { counts },
+            ],
+            ..minimal_config()
+        };
+
+        struct TestCase<'a> {
+            name: &'a str,
+            input: AmbientStrategy,
+            expected_ffs: [f64; 2],
+        }
+        let tests = [
+            TestCase {
+                name: "Pooled",
+                input: AmbientStrategy::Pooled,
+                expected_ffs: [150.0, 150.0],
+            },
+            TestCase {
+                name: "Interpolated",
+                input: AmbientStrategy::Interpolated,
+                expected_ffs: [400.0 / 3.0, 500.0 / 3.0],
+            },
+        ];
+        for case in tests {
+            let output = harness::run_concentrations(
+                config.clone(),
+                TestOptions {
+                    ambient_strategy: case.input,
+                    ..TestOptions::default()
+                },
+                [1000.0, 10.0, 10.0, 2000.0],
+            );
+            let got = output.exercise_ffs;
+            assert!(
+                got.iter()
+                    .zip(case.expected_ffs)
+                    .all(|(got, want)| (got - want).abs() < 1e-9),
+                "{}: got={got:?}, want={:?}",
+                case.name,
+                case.expected_ffs
+            );
+        }
+    }
+
+    #[test]
+    fn test_stage_complete() {
+        let config = TestConfig {
+            stages: vec![
+                TestStage::AmbientSample {
+                    counts: StageCounts {
+                        purge_count: 1,
+                        sample_count: 2,
+                        unit: DurationUnit::Samples,
+                    },
+                },
+                TestStage::Exercise {
+                    name: "foo".to_string(),
+                    counts: StageCounts {
+                        purge_count: 0,
+                        sample_count: 1,
+                        unit: DurationUnit::Samples,
+                    },
+                    weight: 1.0,
+                    exclude_from_overall: false,
+                    pass_level: None,
+                    localized_names: BTreeMap::new(),
+                },
+                TestStage::AmbientSample {
+                    counts: StageCounts {
+                        purge_count: 0,
+                        sample_count: 1,
+                        unit: DurationUnit::Samples,
+                    },
+                },
+            ],
+            ..minimal_config()
+        };
+        let output = harness::run_concentrations(
+            config,
+            TestOptions::default(),
+            [5000.0, 900.0, 1100.0, 10.0, 1000.0],
+        );
+        let got: Vec<(usize, f64, usize, f64)> = output
+            .notifications
+            .iter()
+            .filter_map(|notification| match notification {
+                TestNotification::StageComplete {
+                    stage,
+                    average,
+                    sample_count,
+                    standard_deviation,
+                } => Some((*stage, *average, *sample_count, *standard_deviation)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(got.len(), 3);
+        assert_eq!((got[0].0, got[0].1, got[0].2), (0, 1000.0, 2));
+        assert!((got[0].3 - 200.0 / 2f64.sqrt()).abs() < 1e-9);
+        assert_eq!(got[1], (1, 10.0, 1, 0.0));
+        assert_eq!(got[2], (2, 1000.0, 1, 0.0));
+    }
+
+    #[test]
+    fn test_ambient_result() {
+        let output = harness::run_concentrations(
+            minimal_config(),
+            TestOptions::default(),
+            [600.0, 10.0, 1500.0],
+        );
+        let got: Vec<(usize, f64, f64)> = output
+            .notifications
+            .iter()
+            .filter_map(|notification| match notification {
+                TestNotification::AmbientResult {
+                    stage,
+                    average,
+                    error,
+                } => Some((*stage, *average, *error)),
+                _ => None,
+            })
+            .collect();
+        // 600 particles/cm3 for 1s = 1000 counted particles => 1/sqrt(1000)
+        // relative error.
+        assert_eq!(got.len(), 2);
+        assert_eq!((got[0].0, got[0].1), (0, 600.0));
+        assert!((got[0].2 - 600.0 / 1000f64.sqrt()).abs() < 1e-9);
+        assert_eq!((got[1].0, got[1].1), (2, 1500.0));
+    }
+
+    #[test]
+    fn test_exercise_barrier() {
+        let run = |barrier: &Arc<ExerciseBarrier>| {
+            harness::run_concentrations(
+                minimal_config(),
+                TestOptions {
+                    exercise_barrier: Some(barrier.clone()),
+                    ..TestOptions::default()
+                },
+                [600.0, 10.0, 1500.0],
+            )
+        };
+        let discards = |output: &harness::HarnessOutput| -> Vec<(f64, DiscardReason)> {
+            output
+                .notifications
+                .iter()
+                .filter_map(|notification| match notification {
+                    TestNotification::SampleDiscarded { value, reason } => Some((*value, *reason)),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // The other participant hasn't reached the exercise: the test can't
+        // proceed.
+        let barrier = Arc::new(ExerciseBarrier::new(2));
+        let output = run(&barrier);
+        assert!(!output.completed);
+        assert_eq!(
+            discards(&output),
+            vec![
+                (10.0, DiscardReason::AwaitingGroup),
+                (1500.0, DiscardReason::AwaitingGroup)
+            ]
+        );
+
+        // The other participant is already waiting: the exercise starts
+        // immediately.
+        let barrier = Arc::new(ExerciseBarrier::new(2));
+        barrier.arrive(1, 0);
+        let output = run(&barrier);
+        assert!(output.completed);
+        assert!(discards(&output).is_empty());
+        assert_eq!(output.exercise_ffs, vec![1050.0 / 10.0]);
+
+        // The other participant left.
+        let barrier = Arc::new(ExerciseBarrier::new(2));
+        barrier.leave(1);
+        assert!(run(&barrier).completed);
+    }
+
+    #[test]
+    fn test_exercise_barrier_leave_while_waiting() {
+        let barrier = ExerciseBarrier::new(3);
+        barrier.arrive(0, 0);
+        barrier.arrive(1, 0);
+        barrier.arrive(2, 0);
+        assert!(barrier.is_released(0));
+
+        // 0 leaves while waiting at exercise 1: its arrival must not stand in
+        // for 2, which hasn't reached exercise 1 yet.
+        barrier.arrive(0, 1);
+        barrier.arrive(1, 1);
+        barrier.leave(0);
+        assert!(!barrier.is_released(1));
+        barrier.arrive(2, 1);
+        assert!(barrier.is_released(1));
+
+        // Arrivals after leaving are ignored.
+        barrier.arrive(0, 2);
+        barrier.arrive(1, 2);
+        assert!(!barrier.is_released(2));
+        barrier.leave(2);
+        assert!(barrier.is_released(2));
+    }
+
+    #[test]
+    fn test_time_remaining() {
+        let options = TestOptions {
+            sample_interval: Some(Duration::from_secs(2)),
+            ..TestOptions::default()
+        };
+        let output = harness::run_concentrations(minimal_config(), options, [1000.0, 10.0, 1000.0]);
+        assert!(output.completed);
+        let remaining: Vec<(usize, f64)> = output
+            .notifications
+            .iter()
+            .filter_map(|notification| match notification {
+                TestNotification::TimeRemaining {
+                    samples_remaining,
+                    seconds_remaining,
+                } => Some((*samples_remaining, *seconds_remaining)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(remaining, [(3, 6.0), (2, 4.0), (1, 2.0), (0, 0.0)]);
+    }
+
+    #[test]
+    fn test_n95_companion() {
+        let options = TestOptions {
+            n95_companion: true,
+            ..TestOptions::default()
+        };
+        let output = harness::run_concentrations(
+            minimal_config(),
+            options.clone(),
+            [10000.0, 10.0, 10000.0],
+        );
+        assert!(output.completed);
+        assert_eq!(output.exercise_ffs, vec![N95_COMPANION_MAX_FIT_FACTOR]);
+        assert!(!output.notifications.iter().any(|notification| matches!(
+            notification,
+            TestNotification::PassLevelAboveCeiling { .. }
+        )));
+
+        // A lower ceiling in the config takes precedence, and unreachable
+        // pass levels are reported.
+        let config = TestConfig {
+            max_fit_factor: Some(150.0),
+            pass_level: Some(500.0),
+            ..minimal_config()
+        };
+        let output = harness::run_concentrations(config, options, [10000.0, 10.0, 10000.0]);
+        assert_eq!(output.exercise_ffs, vec![150.0]);
+        assert!(output
+            .notifications
+            .contains(&TestNotification::PassLevelAboveCeiling {
+                pass_level: 500.0,
+                max_fit_factor: 150.0,
+            }));
+    }
+
+    #[test]
+    fn test_manual_valve_mode() {
+        let options = TestOptions {
+            valve_mode: ValveMode::Manual,
+            ..TestOptions::default()
+        };
+        let output =
+            harness::run_concentrations(minimal_config(), options.clone(), [1000.0, 10.0, 1000.0]);
+        assert!(output.completed);
+        assert_eq!(output.exercise_ffs, vec![100.0]);
+        assert!(!output
+            .commands
+            .iter()
+            .any(|command| matches!(command, Command::ValveAmbient | Command::ValveSpecimen)));
+
+        // Samples are discarded until the operator confirms each switch, and
+        // then for DiscardPolicy::Fixed's count.
+        let sink = harness::RecordingCommandSink::new();
+        let notifications = Arc::new(Mutex::new(Vec::new()));
+        let notifications_write = notifications.clone();
+        let mut valve_state = ValveState::Specimen;
+        let mut test = Test::create_and_start(
+            minimal_config(),
+            TestOptions {
+                discard_policy: DiscardPolicy::Fixed { count: 1 },
+                ..options
+            },
+            &sink,
+            &mut valve_state,
+            Some(Box::new(move |notification: &TestNotification| {
+                notifications_write
+                    .lock()
+                    .unwrap()
+                    .push(notification.clone());
+            })),
+        )
+        .unwrap();
+        test.step(Message::Sample(5.0), &mut valve_state).unwrap();
+        // Stale valve responses, and unexpected confirmations, are ignored.
+        test.step(Message::Response(Command::ValveSpecimen), &mut valve_state)
+            .unwrap();
+        assert!(matches!(valve_state, ValveState::AwaitingAmbient));
+        test.confirm_manual_switch(ValvePosition::Specimen, &mut valve_state);
+        assert!(matches!(valve_state, ValveState::AwaitingAmbient));
+        let mut outcome = StepOutcome::None;
+        for (position, concentration) in [
+            (ValvePosition::Ambient, 1000.0),
+            (ValvePosition::Specimen, 10.0),
+            (ValvePosition::Ambient, 1000.0),
+        ] {
+            assert!(matches!(outcome, StepOutcome::None));
+            test.confirm_manual_switch(position, &mut valve_state);
+            test.step(Message::Sample(2000.0), &mut valve_state)
+                .unwrap();
+            outcome = test
+                .step(Message::Sample(concentration), &mut valve_state)
+                .unwrap();
+        }
+        assert!(matches!(outcome, StepOutcome::TestComplete));
+        assert_eq!(test.exercise_ffs, vec![100.0]);
+        assert!(sink
+            .commands()
+            .iter()
+            .all(|command| !matches!(command, Command::ValveAmbient | Command::ValveSpecimen)));
+        let discards: Vec<DiscardReason> = notifications
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|notification| match notification {
+                TestNotification::SampleDiscarded { reason, .. } => Some(*reason),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            discards,
+            [
+                DiscardReason::AwaitingValveSwitch,
+                DiscardReason::ValveSwitchSettling,
+                DiscardReason::ValveSwitchSettling,
+                DiscardReason::ValveSwitchSettling,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exercise_barrier_buffer_mode() {
+        let run = |barrier: &Arc<ExerciseBarrier>| {
+            harness::run_concentrations(
+                minimal_config(),
+                TestOptions {
+                    exercise_barrier: Some(barrier.clone()),
+                    ..TestOptions::default()
+                },
+                [600.0, 800.0, 10.0, 1500.0],
+            )
+        };
+
+        // The other participant hasn't completed the ambient stage: all
+        // samples extend the ambient stage, none are discarded.
+        let barrier = Arc::new(ExerciseBarrier::with_mode(2, BarrierMode::Buffer));
+        let output = run(&barrier);
+        assert!(!output.completed);
+        assert!(!output
+            .notifications
+            .iter()
+            .any(|notification| matches!(notification, TestNotification::SampleDiscarded { .. })));
+        let ambient_samples = output
+            .notifications
+            .iter()
+            .filter(|notification| {
+                matches!(
+                    notification,
+                    TestNotification::Sample(SampleData {
+                        sample_type: SampleType::AmbientSample,
+                        ..
+                    })
+                )
+            })
+            .count();
+        assert_eq!(ambient_samples, 4);
+
+        // The other participant completes its ambient stage during our third
+        // sample: the exercise starts with our fourth sample.
+        let barrier = Arc::new(ExerciseBarrier::with_mode(2, BarrierMode::Buffer));
+        let output = harness::run_concentrations(
+            minimal_config(),
+            TestOptions {
+                exercise_barrier: Some(barrier.clone()),
+                ..TestOptions::default()
+            },
+            [600.0, 800.0, 700.0, 10.0, 1500.0]
+                .into_iter()
+                .enumerate()
+                .map(|(index, concentration)| {
+                    if index == 2 {
+                        barrier.arrive(1, 0);
+                    }
+                    concentration
+                }),
+        );
+        assert!(output.completed);
+        assert_eq!(
+            output.exercise_ffs,
+            vec![(600.0 + 800.0 + 700.0 + 1500.0) / 4.0 / 10.0]
+        );
+    }
+
+    #[test]
+    fn test_valve_switch_notifications() {
+        let output = harness::run_messages(
+            minimal_config(),
+            TestOptions::default(),
+            [
+                Message::Sample(1000.0),
+                Message::Response(Command::ValveAmbient),
+                Message::Sample(1000.0),
+                Message::Sample(500.0),
+                Message::Sample(50.0),
+                Message::Response(Command::ValveSpecimen),
+                Message::Sample(10.0),
+                Message::Response(Command::ValveAmbient),
+                Message::Sample(1000.0),
+            ],
+        );
+        assert!(output.completed);
+        let got: Vec<TestNotification> = output
+            .notifications
+            .into_iter()
+            .filter(|notification| {
+                matches!(
+                    notification,
+                    TestNotification::ValveSwitchRequested { .. }
+                        | TestNotification::ValveSwitchConfirmed { .. }
+                )
+            })
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                TestNotification::ValveSwitchRequested {
+                    position: ValvePosition::Ambient
+                },
+                TestNotification::ValveSwitchConfirmed {
+                    position: ValvePosition::Ambient,
+                    discarded_samples: 1
+                },
+                TestNotification::ValveSwitchRequested {
+                    position: ValvePosition::Specimen
+                },
+                TestNotification::ValveSwitchConfirmed {
+                    position: ValvePosition::Specimen,
+                    discarded_samples: 2
+                },
+                TestNotification::ValveSwitchRequested {
+                    position: ValvePosition::Ambient
+                },
+                TestNotification::ValveSwitchConfirmed {
+                    position: ValvePosition::Ambient,
+                    discarded_samples: 0
+                },
+                TestNotification::ValveSwitchRequested {
+                    position: ValvePosition::Specimen
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_discard_policy() {
+        struct TestCase<'a> {
+            name: &'a str,
+            input: DiscardPolicy,
+            messages: Vec<Message>,
+            expected_discards: Vec<(f64, DiscardReason)>,
+        }
+        let tests = [
+            TestCase {
+                name: "UntilConfirmed",
+                input: DiscardPolicy::UntilConfirmed,
+                messages: vec![
+                    Message::Sample(1.0),
+                    Message::Response(Command::ValveAmbient),
+                    Message::Sample(1000.0),
+                    Message::Sample(2.0),
+                    Message::Response(Command::ValveSpecimen),
+                    Message::Sample(10.0),
+                    Message::Response(Command::ValveAmbient),
+                    Message::Sample(1000.0),
+                ],
+                expected_discards: vec![
+                    (1.0, DiscardReason::AwaitingValveSwitch),
+                    (2.0, DiscardReason::AwaitingValveSwitch),
+                ],
+            },
+            TestCase {
+                name: "FixedConfirmedEarly",
+                input: DiscardPolicy::Fixed { count: 2 },
+                messages: vec![
+                    Message::Response(Command::ValveAmbient),
+                    Message::Sample(1.0),
+                    Message::Sample(2.0),
+                    Message::Sample(1000.0),
+                    Message::Response(Command::ValveSpecimen),
+                    Message::Sample(3.0),
+                    Message::Sample(4.0),
+                    Message::Sample(10.0),
+                    Message::Response(Command::ValveAmbient),
+                    Message::Sample(5.0),
+                    Message::Sample(6.0),
+                    Message::Sample(1000.0),
+                ],
+                expected_discards: vec![
+                    (1.0, DiscardReason::ValveSwitchSettling),
+                    (2.0, DiscardReason::ValveSwitchSettling),
+                    (3.0, DiscardReason::ValveSwitchSettling),
+                    (4.0, DiscardReason::ValveSwitchSettling),
+                    (5.0, DiscardReason::ValveSwitchSettling),
+                    (6.0, DiscardReason::ValveSwitchSettling),
+                ],
+            },
+            TestCase {
+                name: "FixedNeverConfirmed",
+                input: DiscardPolicy::Fixed { count: 1 },
+                messages: vec![
+                    Message::Sample(1.0),
+                    Message::Sample(1000.0),
+                    Message::Sample(2.0),
+                    Message::Sample(10.0),
+                    Message::Sample(3.0),
+                    Message::Sample(1000.0),
+                ],
+                expected_discards: vec![
+                    (1.0, DiscardReason::ValveSwitchSettling),
+                    (2.0, DiscardReason::ValveSwitchSettling),
+                    (3.0, DiscardReason::ValveSwitchSettling),
+                ],
+            },
+        ];
+        for case in tests {
+            let output = harness::run_messages(
+                minimal_config(),
+                TestOptions {
+                    discard_policy: case.input,
+                    ..TestOptions::default()
+                },
+                case.messages,
+            );
+            assert!(output.completed, "{}: test did not complete", case.name);
+            assert_eq!(output.exercise_ffs, vec![100.0], "{}", case.name);
+            let got: Vec<(f64, DiscardReason)> = output
+                .notifications
+                .iter()
+                .filter_map(|notification| match notification {
+                    TestNotification::SampleDiscarded { value, reason } => Some((*value, *reason)),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                got, case.expected_discards,
+                "{}: got={got:?}, want={:?}",
+                case.name, case.expected_discards
+            );
+        }
+    }
+
+    #[test]
+    fn test_adaptive_ambient_purge() {
+        let config = TestConfig {
+            stages: vec![
+                TestStage::AmbientSample {
+                    counts: StageCounts {
+                        purge_count: 1,
+                        sample_count: 1,
+                        unit: DurationUnit::Samples,
+                    },
+                },
+                TestStage::Exercise {
+                    name: "foo".to_string(),
+                    counts: StageCounts {
+                        purge_count: 0,
+                        sample_count: 1,
+                        unit: DurationUnit::Samples,
+                    },
+                    weight: 1.0,
+                    exclude_from_overall: false,
+                    pass_level: None,
+                    localized_names: BTreeMap::new(),
+                },
+                TestStage::AmbientSample {
+                    counts: StageCounts {
+                        purge_count: 1,
+                        sample_count: 1,
+                        unit: DurationUnit::Samples,
+                    },
+                },
+            ],
+            ..minimal_config()
+        };
+        let output = harness::run_concentrations(
+            config,
+            TestOptions {
+                adaptive_ambient_purge: Some(AdaptivePurge {
+                    window: 2,
+                    tolerance: 0.1,
+                    max_additional_purges: 3,
+                }),
+                ..TestOptions::default()
+            },
+            [
+                // First ambient stage: stabilises after 4 purges.
+                5000.0, 3000.0, 1000.0, 1010.0, 1000.0, // Exercise
+                10.0,   // Final ambient stage: stabilises after 2 purges.
+                1000.0, 1000.0, 1000.0,
+            ],
+        );
+        assert!(output.completed);
+        assert_eq!(output.exercise_ffs, vec![100.0]);
+        let purges = output
+            .notifications
+            .iter()
+            .filter(|notification| {
+                matches!(
+                    notification,
+                    TestNotification::Sample(SampleData {
+                        sample_type: SampleType::AmbientPurge,
+                        ..
+                    })
+                )
+            })
+            .count();
+        assert_eq!(purges, 6);
+
+        // The purge may only be extended by max_additional_purges.
+        let output = harness::run_concentrations(
+            minimal_config(),
+            TestOptions {
+                adaptive_ambient_purge: Some(AdaptivePurge {
+                    window: 2,
+                    tolerance: 0.1,
+                    max_additional_purges: 1,
+                }),
+                ..TestOptions::default()
+            },
+            [5000.0, 1000.0, 10.0, 3000.0, 1000.0],
+        );
+        assert!(output.completed);
+        assert_eq!(output.exercise_ffs, vec![100.0]);
+    }
+
+    #[test]
+    fn test_early_stopping() {
+        let config = TestConfig {
+            stages: vec![
+                TestStage::AmbientSample {
+                    counts: StageCounts {
+                        purge_count: 0,
+                        sample_count: 5,
+                        unit: DurationUnit::Samples,
+                    },
+                },
+                TestStage::Exercise {
+                    name: "foo".to_string(),
+                    counts: StageCounts {
+                        purge_count: 1,
+                        sample_count: 10,
+                        unit: DurationUnit::Samples,
+                    },
+                    weight: 1.0,
+                    exclude_from_overall: false,
+                    pass_level: None,
+                    localized_names: BTreeMap::new(),
+                },
+                TestStage::AmbientSample {
+                    counts: StageCounts {
+                        purge_count: 0,
+                        sample_count: 1,
+                        unit: DurationUnit::Samples,
+                    },
+                },
+            ],
+            ..minimal_config()
+        };
+        struct TestCase {
+            name: &'static str,
+            early_stopping: EarlyStopping,
+            exercise_conc: f64,
+            expected_sample_count: usize,
+        }
+        let test_cases = vec![
+            TestCase {
+                name: "well above pass level",
+                early_stopping: EarlyStopping {
+                    min_samples: 3,
+                    max_relative_error: 0.0,
+                    pass_level: Some(100.0),
+                },
+                exercise_conc: 1.0,
+                expected_sample_count: 3,
+            },
+            TestCase {
+                name: "well below pass level",
+                early_stopping: EarlyStopping {
+                    min_samples: 2,
+                    max_relative_error: 0.0,
+                    pass_level: Some(100.0),
+                },
+                exercise_conc: 100.0,
+                expected_sample_count: 2,
+            },
+            TestCase {
+                name: "close to pass level",
+                early_stopping: EarlyStopping {
+                    min_samples: 2,
+                    max_relative_error: 0.0,
+                    pass_level: Some(100.0),
+                },
+                exercise_conc: 10.0,
+                expected_sample_count: 10,
+            },
+            TestCase {
+                name: "narrow confidence interval",
+                early_stopping: EarlyStopping {
+                    min_samples: 1,
+                    max_relative_error: 0.2,
+                    pass_level: None,
+                },
+                // Error is dominated by exercise counts: 1.96/sqrt(n*100*100/60)
+                // drops below 0.2 at n=1.
+                exercise_conc: 100.0,
+                expected_sample_count: 1,
+            },
+        ];
+        for test_case in test_cases {
+            let mut concentrations = vec![1000.0; 5];
+            concentrations.extend(std::iter::repeat_n(
+                test_case.exercise_conc,
+                1 + test_case.expected_sample_count,
+            ));
+            concentrations.push(1000.0);
+            let output = harness::run_concentrations(
+                config.clone(),
+                TestOptions {
+                    early_stopping: Some(test_case.early_stopping),
+                    ..TestOptions::default()
+                },
+                concentrations,
+            );
+            assert!(output.completed, "{}", test_case.name);
+            assert_eq!(
+                output.exercise_ffs,
+                vec![1000.0 / test_case.exercise_conc],
+                "{}",
+                test_case.name
+            );
+            let sample_count = output
+                .notifications
+                .iter()
+                .filter(|notification| {
+                    matches!(
+                        notification,
+                        TestNotification::Sample(SampleData {
+                            sample_type: SampleType::SpecimenSample,
+                            ..
+                        })
+                    )
+                })
+                .count();
+            assert_eq!(
+                sample_count, test_case.expected_sample_count,
+                "{}",
+                test_case.name
+            );
+            assert_eq!(
+                output
+                    .notifications
+                    .contains(&TestNotification::ExerciseEndedEarly {
+                        exercise: 0,
+                        sample_count: test_case.expected_sample_count,
+                    }),
+                test_case.expected_sample_count < 10,
+                "{}",
+                test_case.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_overall_fit_factor() {
+        struct TestCase {
+            name: &'static str,
+            weights: [f64; 2],
+            exclude_from_overall: [bool; 2],
+            expected_result: f64,
+        }
+        let test_cases = [
+            TestCase {
+                name: "unweighted",
+                weights: [1.0, 1.0],
+                exclude_from_overall: [false, false],
+                expected_result: 2.0 / (1.0 / 100.0 + 1.0 / 25.0),
+            },
+            TestCase {
+                name: "weighted",
+                weights: [3.0, 1.0],
+                exclude_from_overall: [false, false],
+                expected_result: 4.0 / (3.0 / 100.0 + 1.0 / 25.0),
+            },
+            TestCase {
+                name: "excluded",
+                weights: [1.0, 1.0],
+                exclude_from_overall: [false, true],
+                expected_result: 100.0,
+            },
+        ];
+        for test_case in test_cases {
+            let config = minimal_config();
+            let stages = vec![
+                config.stages[0].clone(),
+                TestStage::Exercise {
+                    name: "a".to_string(),
+                    counts: StageCounts {
+                        purge_count: 0,
+                        sample_count: 1,
+                        unit: DurationUnit::Samples,
+                    },
+                    weight: test_case.weights[0],
+                    exclude_from_overall: test_case.exclude_from_overall[0],
+                    pass_level: None,
+                    localized_names: BTreeMap::new(),
+                },
+                TestStage::Exercise {
+                    name: "b".to_string(),
+                    counts: StageCounts {
+                        purge_count: 0,
+                        sample_count: 1,
+                        unit: DurationUnit::Samples,
+                    },
+                    weight: test_case.weights[1],
+                    exclude_from_overall: test_case.exclude_from_overall[1],
+                    pass_level: None,
+                    localized_names: BTreeMap::new(),
+                },
+                config.stages[2].clone(),
+            ];
+            let output = harness::run_concentrations(
+                TestConfig { stages, ..config },
+                TestOptions::default(),
+                [1000.0, 10.0, 40.0, 1000.0],
+            );
+            let result = output.result.unwrap();
+            assert_eq!(
+                result.raw_overall_fit_factor, test_case.expected_result,
+                "{}",
+                test_case.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_grimace() {
+        let config = TestConfig::parse_from_csv(&mut std::io::Cursor::new(
+            "TEST,foo,foo\nAMBIENT,0,1\nEXERCISE,0,1,a\nAMBIENT,0,1\nGRIMACE,0,1\nAMBIENT,0,1\n",
+        ))
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let output = harness::run_concentrations(
+            config,
+            TestOptions::default(),
+            [1000.0, 10.0, 1000.0, 1000.0, 1000.0],
+        );
+        let result = output.result.unwrap();
+        assert_eq!(result.raw_fit_factors, vec![100.0, 1.0]);
+        assert_eq!(result.raw_overall_fit_factor, 100.0);
+        // Grimace: no exercise number, long beep.
+        assert!(output.commands.contains(&Command::Beep {
+            duration_deciseconds: 30
+        }));
+        assert!(!output.commands.contains(&Command::DisplayExercise(2)));
+    }
+
+    #[test]
+    fn test_minimum_ambient() {
+        struct TestCase {
+            name: &'static str,
+            action: MinimumAmbientAction,
+            concentrations: Vec<f64>,
+            expected_completed: bool,
+            expected_warnings: usize,
+        }
+        let test_cases = vec![
+            TestCase {
+                name: "sufficient ambient",
+                action: MinimumAmbientAction::Abort,
+                concentrations: vec![1000.0, 10.0, 1000.0],
+                expected_completed: true,
+                expected_warnings: 0,
+            },
+            TestCase {
+                name: "warn",
+                action: MinimumAmbientAction::Warn,
+                concentrations: vec![50.0, 10.0, 1000.0],
+                expected_completed: true,
+                expected_warnings: 1,
+            },
+            TestCase {
+                name: "abort",
+                action: MinimumAmbientAction::Abort,
+                concentrations: vec![50.0, 10.0, 1000.0],
+                expected_completed: false,
+                expected_warnings: 1,
+            },
+        ];
+        for test_case in test_cases {
+            let output = harness::run_concentrations(
+                minimal_config(),
+                TestOptions {
+                    minimum_ambient: Some(MinimumAmbient {
+                        concentration: 100.0,
+                        action: test_case.action,
+                    }),
+                    ..TestOptions::default()
+                },
+                test_case.concentrations,
+            );
+            assert_eq!(
+                output.completed, test_case.expected_completed,
+                "{}",
+                test_case.name
+            );
+            assert_eq!(
+                output.aborted, !test_case.expected_completed,
+                "{}",
+                test_case.name
+            );
+            let warnings = output
+                .notifications
+                .iter()
+                .filter(|notification| {
+                    matches!(notification, TestNotification::AmbientBelowMinimum { .. })
+                })
+                .count();
+            assert_eq!(warnings, test_case.expected_warnings, "{}", test_case.name);
+        }
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let output = harness::run_concentrations(
+            minimal_config(),
+            TestOptions::default(),
+            [1000.0, 10.0, 1000.0],
+        );
+        let audit_log = output.result.unwrap().audit_log;
+        assert!(audit_log
+            .windows(2)
+            .all(|entries| entries[0].timestamp <= entries[1].timestamp));
+        let commands: Vec<Command> = audit_log
+            .iter()
+            .filter_map(|entry| match &entry.event {
+                AuditEvent::CommandSent(command) => Some(command.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(commands, output.commands);
+        let notifications: Vec<TestNotification> = audit_log
+            .iter()
+            .filter_map(|entry| match &entry.event {
+                AuditEvent::Notification(notification) => Some(notification.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(notifications, output.notifications);
+        let samples: Vec<f64> = audit_log
+            .iter()
+            .filter_map(|entry| match &entry.event {
+                AuditEvent::MessageReceived(Message::Sample(value)) => Some(*value),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(samples, vec![1000.0, 10.0, 1000.0]);
+    }
+
+    #[test]
+    fn test_exercise_pass_levels() {
+        let config = TestConfig::parse_from_csv(&mut std::io::Cursor::new(
+            "TEST,foo,foo\nAMBIENT,0,1\nEXERCISE,0,1,a,pass=100\nEXERCISE,0,1,b,pass=100\nEXERCISE,0,1,c\nAMBIENT,0,1\n",
+        ))
+        .unwrap();
+        let output = harness::run_concentrations(
+            config,
+            TestOptions {
+                rounding_policy: RoundingPolicy::Truncate { decimal_places: 0 },
+                ..TestOptions::default()
+            },
+            // FFs: 100.x (passes after truncation), 99.x, 1000
+            [1000.0, 9.99, 10.01, 1.0, 1000.0],
+        );
+        assert_eq!(
+            output.result.unwrap().exercise_passed,
+            vec![Some(true), Some(false), None]
+        );
+    }
+
+    #[test]
+    fn test_overall_pass_level() {
+        struct TestCase {
+            name: &'static str,
+            csv: &'static str,
+            expected_result: Option<bool>,
+        }
+        let test_cases = [
+            TestCase {
+                name: "no pass levels",
+                csv: "TEST,foo,foo\nAMBIENT,0,1\nEXERCISE,0,1,a\nEXERCISE,0,1,b\nAMBIENT,0,1\n",
+                expected_result: None,
+            },
+            TestCase {
+                name: "overall pass",
+                csv: "TEST,foo,foo,pass=100\nAMBIENT,0,1\nEXERCISE,0,1,a\nEXERCISE,0,1,b\nAMBIENT,0,1\n",
+                expected_result: Some(true),
+            },
+            TestCase {
+                name: "overall fail",
+                csv: "TEST,foo,foo,pass=500\nAMBIENT,0,1\nEXERCISE,0,1,a\nEXERCISE,0,1,b\nAMBIENT,0,1\n",
+                expected_result: Some(false),
+            },
+            TestCase {
+                name: "overall pass, exercise fail",
+                csv: "TEST,foo,foo,pass=100\nAMBIENT,0,1\nEXERCISE,0,1,a\nEXERCISE,0,1,b,pass=500\nAMBIENT,0,1\n",
+                expected_result: Some(false),
+            },
+        ];
+        for test_case in test_cases {
+            let config =
+                TestConfig::parse_from_csv(&mut std::io::Cursor::new(test_case.csv)).unwrap();
+            // FFs: 200, 100 => overall FF 133
+            let output = harness::run_concentrations(
+                config,
+                TestOptions::default(),
+                [1000.0, 5.0, 10.0, 1000.0],
+            );
+            assert_eq!(
+                output.result.unwrap().passed,
+                test_case.expected_result,
+                "{}",
+                test_case.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_measurement_limits() {
+        struct TestCase {
+            name: &'static str,
+            csv: &'static str,
+            expected_result: Vec<f64>,
+        }
+        let test_cases = [
+            TestCase {
+                name: "default limits",
+                csv: "TEST,foo,foo\nAMBIENT,0,1\nEXERCISE,0,1,a\nEXERCISE,0,1,b\nAMBIENT,0,1\n",
+                // 0 particles => clamped to 0.6/cm3.
+                expected_result: vec![100.0, 1000.0 / 0.6],
+            },
+            TestCase {
+                name: "max FF",
+                csv: "TEST,foo,foo\nMAXFF,200\nAMBIENT,0,1\nEXERCISE,0,1,a\nEXERCISE,0,1,b\nAMBIENT,0,1\n",
+                expected_result: vec![100.0, 200.0],
+            },
+            TestCase {
+                name: "min concentration",
+                csv: "TEST,foo,foo\nMIN_CONCENTRATION,2\nAMBIENT,0,1\nEXERCISE,0,1,a\nEXERCISE,0,1,b\nAMBIENT,0,1\n",
+                expected_result: vec![100.0, 500.0],
+            },
+        ];
+        for test_case in test_cases {
+            let config =
+                TestConfig::parse_from_csv(&mut std::io::Cursor::new(test_case.csv)).unwrap();
+            let output = harness::run_concentrations(
+                config,
+                TestOptions::default(),
+                [1000.0, 10.0, 0.0, 1000.0],
+            );
+            assert_eq!(
+                output.result.unwrap().raw_fit_factors,
+                test_case.expected_result,
+                "{}",
+                test_case.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_result_metadata() {
+        let config = minimal_config();
+        let output = harness::run_concentrations(
+            config.clone(),
+            TestOptions::default(),
+            [1000.0, 10.0, 1000.0],
+        );
+        let result = output.result.unwrap();
+        assert_eq!(result.config_short_name, config.short_name);
+        assert_eq!(result.config_version, config.version);
+        assert_eq!(result.config_fingerprint, config.fingerprint());
+        assert_eq!(result.exercise_names, vec!["foo"]);
+        assert!(result.started_at <= result.completed_at);
+        // 10 particles/cm3 over 1 sample => relative error of 1/sqrt(10*100/60).
+        assert_eq!(result.errors.len(), 1);
+        assert!((result.errors[0] - 100.0 / f64::sqrt(1000.0 / 60.0)).abs() < 1e-9);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let output = harness::run_concentrations(
+            minimal_config(),
+            TestOptions::default(),
+            [1000.0, 10.0, 1000.0],
+        );
+        let result = output.result.unwrap();
+        let json = serde_json::to_string(&result).unwrap();
+        assert_eq!(serde_json::from_str::<TestResult>(&json).unwrap(), result);
+        let json = serde_json::to_string(&output.notifications).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<TestNotification>>(&json).unwrap(),
+            output.notifications
+        );
+    }
+}
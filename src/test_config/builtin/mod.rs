@@ -1,3 +1,5 @@
+use crate::test_config::TestConfig;
+
 pub const OSHA: &str = include_str!("osha.csv");
 pub const OSHA_LEGACY: &str = include_str!("osha_legacy.csv");
 pub const OSHA_FAST_FFP: &str = include_str!("osha_fast_ffp.csv");
@@ -12,10 +14,19 @@ pub const BUILTIN_CONFIGS: [&str; 5] = [
     CRASH_2_5,
 ];
 
+/// Returns the builtin config with the specified short name, if any.
+pub fn load(short_name: &str) -> Option<TestConfig> {
+    BUILTIN_CONFIGS.iter().find_map(|config_csv| {
+        let mut cursor = std::io::Cursor::new(config_csv.as_bytes());
+        let config = TestConfig::parse_from_csv(&mut cursor).expect("builtin configs must parse");
+        assert!(config.validate().is_ok(), "builtin configs must be valid");
+        (config.short_name == short_name).then_some(config)
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_config::TestConfig;
 
     #[test]
     fn test_builtin_configs_load_and_validate() {
@@ -26,4 +37,15 @@ mod tests {
             assert!(result.unwrap().validate().is_ok());
         }
     }
+
+    #[test]
+    fn test_builtin_short_names_unique() {
+        assert!(crate::test_config::merge_with_builtins(Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn test_load() {
+        assert_eq!(load("osha").unwrap().exercise_count(), 8);
+        assert_eq!(load("nonexistent"), None);
+    }
 }
@@ -1,17 +1,85 @@
 pub mod builtin;
 
+use std::collections::BTreeMap;
 use std::str::FromStr;
+use std::time::Duration;
+
+/// DurationUnit determines how StageCounts are interpreted.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum DurationUnit {
+    /// Counts are numbers of samples.
+    #[default]
+    Samples,
+    /// Counts are durations in seconds, and are converted into numbers of
+    /// samples when the test starts, using the device's observed sample
+    /// cadence (see TestConfig::resolve_durations).
+    Seconds,
+}
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StageCounts {
     pub purge_count: usize,
     pub sample_count: usize,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unit: DurationUnit,
+}
+
+impl StageCounts {
+    fn resolve(&self, sample_interval: Duration) -> StageCounts {
+        let DurationUnit::Seconds = self.unit else {
+            return self.clone();
+        };
+        let to_samples =
+            |seconds: usize| (seconds as f64 / sample_interval.as_secs_f64()).round() as usize;
+        StageCounts {
+            purge_count: to_samples(self.purge_count),
+            // Never round a non-empty stage down to nothing.
+            sample_count: to_samples(self.sample_count).max(self.sample_count.min(1)),
+            unit: DurationUnit::Samples,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
 pub enum TestStage {
-    AmbientSample { counts: StageCounts },
-    Exercise { name: String, counts: StageCounts },
+    AmbientSample {
+        counts: StageCounts,
+    },
+    Exercise {
+        name: String,
+        counts: StageCounts,
+        /// Relative weight of this exercise in the overall FF (default: 1.0).
+        #[cfg_attr(feature = "serde", serde(default = "default_weight"))]
+        weight: f64,
+        /// Whether this exercise is excluded from the overall FF (e.g. the
+        /// OSHA grimace exercise).
+        #[cfg_attr(feature = "serde", serde(default))]
+        exclude_from_overall: bool,
+        /// The minimum (rounded) FF required to pass this exercise, if any.
+        #[cfg_attr(feature = "serde", serde(default))]
+        pass_level: Option<f64>,
+        /// Translations of name, keyed by locale (e.g. "fr" or "fr-CA").
+        #[cfg_attr(feature = "serde", serde(default))]
+        localized_names: BTreeMap<String, String>,
+    },
+    /// Grimace is a short exercise that is intended to break the seal
+    /// (followed by the next exercise verifying that the mask reseats
+    /// correctly). Grimace FFs are calculated and reported like any other
+    /// exercise, but are never included in the overall FF.
+    Grimace {
+        counts: StageCounts,
+    },
 }
 
 impl TestStage {
@@ -19,21 +87,260 @@ impl TestStage {
         matches!(self, TestStage::AmbientSample { .. })
     }
 
+    /// Returns true for any stage sampled through the specimen tube, i.e.
+    /// includes grimaces.
     pub fn is_exercise(&self) -> bool {
-        matches!(self, TestStage::Exercise { .. })
+        matches!(self, TestStage::Exercise { .. } | TestStage::Grimace { .. })
+    }
+
+    pub fn is_grimace(&self) -> bool {
+        matches!(self, TestStage::Grimace { .. })
+    }
+
+    pub fn counts(&self) -> &StageCounts {
+        match self {
+            TestStage::AmbientSample { counts }
+            | TestStage::Exercise { counts, .. }
+            | TestStage::Grimace { counts } => counts,
+        }
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TestConfig {
     pub name: String,
     pub short_name: String,
     pub stages: Vec<TestStage>,
+    /// The minimum (rounded) overall FF required to pass the test, if any.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub pass_level: Option<f64>,
+    /// The revision of this config, which should be incremented whenever a
+    /// config is changed (results can then be associated with a specific
+    /// revision of a protocol). Defaults to 1.
+    #[cfg_attr(feature = "serde", serde(default = "default_version"))]
+    pub version: u32,
+    /// Translations of name, keyed by locale (e.g. "fr" or "fr-CA"). See
+    /// name_for().
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub localized_names: BTreeMap<String, String>,
+    /// The maximum reportable FF, if any. Higher FFs (exercise, interim, and
+    /// live) are reduced to this ceiling.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_fit_factor: Option<f64>,
+    /// The minimum measurable concentration (particles/cm3), if any. Lower
+    /// average concentrations are raised to this floor when calculating FFs.
+    /// Defaults to the equivalent of a single particle over the sampling
+    /// period.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub min_concentration: Option<f64>,
 }
 
+#[cfg(feature = "serde")]
+fn default_weight() -> f64 {
+    1.0
+}
+
+#[cfg(feature = "serde")]
+fn default_version() -> u32 {
+    1
+}
+
+/// The current (and only) version of the JSON/TOML config schema. Documents
+/// must declare their schema version, which allows the schema to evolve
+/// without silently misinterpreting older (or newer) configs.
+#[cfg(feature = "serde")]
+pub const SCHEMA_VERSION: u32 = 1;
+
+// The top-level structure of JSON/TOML configs, e.g.:
+// {"version": 1, "name": "Foo", "short_name": "foo", "stages": [
+//   {"type": "ambient_sample", "counts": {"purge_count": 4, "sample_count": 5}},
+//   ...
+// ]}
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct ConfigDocument {
+    version: u32,
+    #[serde(flatten)]
+    config: TestConfig,
+}
+
+/// ValidationError describes why a config is invalid. Stage indices are
+/// 0-based, and refer to TestConfig::stages.
 #[derive(Debug, PartialEq, Eq)]
 pub enum ValidationError {
-    InvalidConfig,
+    /// Every test needs at least an ambient stage, an exercise, and another
+    /// ambient stage.
+    TooFewStages,
+    MissingLeadingAmbient,
+    MissingTrailingAmbient,
+    /// The stage at index is an ambient stage, and follows another ambient
+    /// stage.
+    ConsecutiveAmbientStages {
+        index: usize,
+    },
+    EmptySampleCount {
+        index: usize,
+    },
+    /// The exercise's weight is not a positive number.
+    InvalidWeight {
+        index: usize,
+    },
+    /// The exercise's pass level is not a number >= 1.
+    InvalidExercisePassLevel {
+        index: usize,
+    },
+    /// The test's pass level is not a number >= 1.
+    InvalidPassLevel,
+    /// All exercises are excluded from the overall FF.
+    NoScoredExercises,
+    /// The max FF is not a number >= 1.
+    InvalidMaxFitFactor,
+    /// The minimum concentration is not a positive number.
+    InvalidMinConcentration,
+}
+
+/// ConfigWarning describes a likely mistake in a (valid) config, see
+/// TestConfig::warnings(). Stage indices are 0-based, and refer to
+/// TestConfig::stages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigWarning {
+    /// The ambient stage at index follows an exercise, but has no purge, i.e.
+    /// its first samples will still contain specimen air.
+    MissingAmbientPurge { index: usize },
+    /// The exercise (or grimace) at index follows an ambient stage, but has no
+    /// purge, i.e. its first samples will still contain ambient air.
+    MissingSpecimenPurge { index: usize },
+    /// A pass level exceeds the max FF, i.e. can never be reached. index is
+    /// the exercise's stage, or None for the test's pass level.
+    PassLevelAboveMaxFitFactor { index: Option<usize> },
+}
+
+/// DuplicateShortNameError indicates that multiple configs share the same
+/// short name (which is used to identify configs, e.g. when loading a
+/// builtin).
+#[derive(Debug, PartialEq, Eq)]
+pub struct DuplicateShortNameError {
+    pub short_name: String,
+    /// The sources (as supplied by the caller, or "builtin") of all configs
+    /// using short_name.
+    pub sources: Vec<String>,
+}
+
+/// Combines the builtin configs with user-supplied configs, each labelled
+/// with its source (e.g. a file path), ensuring that short names are unique
+/// across all configs. Builtins are returned first.
+pub fn merge_with_builtins(
+    user_configs: Vec<(String, TestConfig)>,
+) -> Result<Vec<TestConfig>, DuplicateShortNameError> {
+    let builtins = builtin::BUILTIN_CONFIGS.iter().map(|config_csv| {
+        let mut cursor = std::io::Cursor::new(config_csv.as_bytes());
+        let config = TestConfig::parse_from_csv(&mut cursor).expect("builtin configs must parse");
+        ("builtin".to_string(), config)
+    });
+    let all: Vec<(String, TestConfig)> = builtins.chain(user_configs).collect();
+    for (index, (_, config)) in all.iter().enumerate() {
+        let sources: Vec<String> = all
+            .iter()
+            .filter(|(_, other)| other.short_name == config.short_name)
+            .map(|(source, _)| source.clone())
+            .collect();
+        if sources.len() > 1 {
+            // Only report each collision once, for its first occurrence.
+            if all[..index]
+                .iter()
+                .all(|(_, other)| other.short_name != config.short_name)
+            {
+                return Err(DuplicateShortNameError {
+                    short_name: config.short_name.clone(),
+                    sources,
+                });
+            }
+        }
+    }
+    Ok(all.into_iter().map(|(_, config)| config).collect())
+}
+
+/// ExerciseInfo describes a single exercise (including grimaces) within a
+/// config, see TestConfig::exercises().
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExerciseInfo {
+    /// The 0-based index of this exercise, i.e. its position amongst all
+    /// exercises (and its index in TestResult::fit_factors).
+    pub index: usize,
+    /// The 0-based index of this exercise's stage within TestConfig::stages.
+    pub stage_index: usize,
+    pub name: String,
+    pub counts: StageCounts,
+    /// The exercise's weight in the overall FF (0 for grimaces).
+    pub weight: f64,
+    /// The minimum (rounded) FF required to pass this exercise, if any.
+    pub pass_level: Option<f64>,
+    /// Whether this exercise is excluded from the overall FF (always true for
+    /// grimaces).
+    pub exclude_from_overall: bool,
+    pub is_grimace: bool,
+}
+
+/// ConfigDifference describes a single protocol-relevant difference between
+/// two configs, as reported by TestConfig::diff(). "this" refers to the
+/// config diff() was called on, and "other" to its argument. Stage indices are
+/// 0-based, and refer to TestConfig::stages.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigDifference {
+    PassLevel {
+        this: Option<f64>,
+        other: Option<f64>,
+    },
+    MaxFitFactor {
+        this: Option<f64>,
+        other: Option<f64>,
+    },
+    MinConcentration {
+        this: Option<f64>,
+        other: Option<f64>,
+    },
+    /// The stage at index only exists in this config.
+    StageRemoved {
+        index: usize,
+        stage: TestStage,
+    },
+    /// The stage at index only exists in the other config.
+    StageAdded {
+        index: usize,
+        stage: TestStage,
+    },
+    /// The stages at index are of different types (e.g. ambient vs exercise).
+    StageType {
+        index: usize,
+        this: TestStage,
+        other: TestStage,
+    },
+    Counts {
+        index: usize,
+        this: StageCounts,
+        other: StageCounts,
+    },
+    ExerciseName {
+        index: usize,
+        this: String,
+        other: String,
+    },
+    Weight {
+        index: usize,
+        this: f64,
+        other: f64,
+    },
+    ExcludeFromOverall {
+        index: usize,
+        this: bool,
+        other: bool,
+    },
+    ExercisePassLevel {
+        index: usize,
+        this: Option<f64>,
+        other: Option<f64>,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -41,6 +348,7 @@ pub enum ParseError<'a> {
     IoError(String),
     InvalidExerciseStage(&'a str),
     InvalidAmbientStage(&'a str),
+    InvalidGrimaceStage(&'a str),
     InvalidTestHeader(&'a str),
     Other(String),
 }
@@ -50,6 +358,74 @@ const PARSE_ERROR_MESSAGE_BAD_TRAILING_QUOTATION: &str = r#"Separator must occur
 const PARSE_ERROR_MESSAGE_UNCLOSED_QUOTATION: &str = "All quotations must be closed";
 const PARSE_ERROR_MESSAGE_UNQUOTED_HASH: &str = r##"Raw hash symbols (#) are not allowed inline, enclose the token (cell) in quotes if necessary, e.g. "#ok" or "also #ok""##;
 
+/// CsvDialect determines how CSV configs are tokenised.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CsvDialect {
+    /// The (simplified) default format: quotes may only enclose entire
+    /// fields, and raw hash symbols are only allowed in comment lines.
+    #[default]
+    Default,
+    /// RFC 4180, as produced by spreadsheets (e.g. Excel's "CSV UTF-8"
+    /// export): quoted fields may contain line breaks, hash symbols are
+    /// allowed anywhere, and blank (or empty-field-only) rows are ignored.
+    /// Rows whose first field starts with a hash symbol are comments.
+    Rfc4180,
+}
+
+// Tokenises a single (possibly multi-line) RFC 4180 record.
+fn tokenise_record_rfc4180<'a>(record: &str) -> Result<Vec<String>, ParseError<'a>> {
+    enum FieldState {
+        Start,
+        Unquoted,
+        Quoted,
+        // A quote inside a quoted field: either the end of the field, or the
+        // first half of an escaped quote.
+        QuoteInQuoted,
+    }
+
+    let mut out = vec![String::new()];
+    let mut state = FieldState::Start;
+    for c in record.chars() {
+        let current_field = out.last_mut().unwrap();
+        state = match (state, c) {
+            (FieldState::Start | FieldState::Unquoted | FieldState::QuoteInQuoted, ',') => {
+                out.push(String::new());
+                FieldState::Start
+            }
+            (FieldState::Start, '"') => FieldState::Quoted,
+            (FieldState::Unquoted, '"') => {
+                return Err(ParseError::Other(
+                    PARSE_ERROR_MESSAGE_BAD_LEADING_QUOTATION.to_string(),
+                ));
+            }
+            (FieldState::Start | FieldState::Unquoted, c) => {
+                current_field.push(c);
+                FieldState::Unquoted
+            }
+            (FieldState::Quoted, '"') => FieldState::QuoteInQuoted,
+            (FieldState::Quoted, c) => {
+                current_field.push(c);
+                FieldState::Quoted
+            }
+            (FieldState::QuoteInQuoted, '"') => {
+                current_field.push('"');
+                FieldState::Quoted
+            }
+            (FieldState::QuoteInQuoted, _) => {
+                return Err(ParseError::Other(
+                    PARSE_ERROR_MESSAGE_BAD_TRAILING_QUOTATION.to_string(),
+                ));
+            }
+        };
+    }
+    if let FieldState::Quoted = state {
+        return Err(ParseError::Other(
+            PARSE_ERROR_MESSAGE_UNCLOSED_QUOTATION.to_string(),
+        ));
+    }
+    Ok(out)
+}
+
 // Reusing an existing CSV parser would be the sensible approach, but... full
 // CSV support simply isn't necessary. (It's not hard to change this decision in
 // future if necessary anyway.)
@@ -130,24 +506,98 @@ fn tokenise_line<'a>(line: &str) -> Result<Vec<String>, ParseError<'a>> {
     Ok(out)
 }
 
+// 64-bit FNV-1a, used for config fingerprints. (std's DefaultHasher is not
+// guaranteed to be stable across Rust releases, which would make fingerprints
+// useless for comparing results recorded by different builds.)
+struct Fingerprinter(u64);
+
+impl Fingerprinter {
+    fn new() -> Self {
+        Fingerprinter(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.bytes(&(value as u64).to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.bytes(&value.to_bits().to_le_bytes());
+    }
+
+    fn optional_f64(&mut self, value: Option<f64>) {
+        match value {
+            Some(value) => {
+                self.bytes(&[1]);
+                self.f64(value);
+            }
+            None => self.bytes(&[0]),
+        }
+    }
+
+    // Strings are length-prefixed, so that e.g. ("ab", "c") and ("a", "bc")
+    // produce different fingerprints.
+    fn str(&mut self, value: &str) {
+        self.usize(value.len());
+        self.bytes(value.as_bytes());
+    }
+
+    fn localized_names(&mut self, localized_names: &BTreeMap<String, String>) {
+        self.usize(localized_names.len());
+        for (locale, name) in localized_names {
+            self.str(locale);
+            self.str(name);
+        }
+    }
+
+    fn counts(&mut self, counts: &StageCounts) {
+        self.usize(counts.purge_count);
+        self.usize(counts.sample_count);
+        self.bytes(&[match counts.unit {
+            DurationUnit::Samples => 0,
+            DurationUnit::Seconds => 1,
+        }]);
+    }
+}
+
+fn localized<'a>(
+    default: &'a str,
+    localized_names: &'a BTreeMap<String, String>,
+    locale: &str,
+) -> &'a str {
+    let language = locale.split(['-', '_']).next().unwrap_or(locale);
+    localized_names
+        .get(locale)
+        .or_else(|| localized_names.get(language))
+        .map_or(default, |name| name.as_str())
+}
+
 impl TestConfig {
-    // TODO: add Option<Vec<ConfigWarning>>, and implement warning generation.
-    // TODO: make ValidationError more useful.
+    /// Validates the config, see also warnings() for issues that don't
+    /// prevent a config from being used.
     pub fn validate(&self) -> Result<(), ValidationError> {
         if self.stages.len() < 3 {
-            return Err(ValidationError::InvalidConfig);
+            return Err(ValidationError::TooFewStages);
         }
         if !matches!(
             self.stages.first().unwrap(),
             TestStage::AmbientSample { .. }
-        ) || !matches!(self.stages.last().unwrap(), TestStage::AmbientSample { .. })
-        {
-            return Err(ValidationError::InvalidConfig);
+        ) {
+            return Err(ValidationError::MissingLeadingAmbient);
+        }
+        if !matches!(self.stages.last().unwrap(), TestStage::AmbientSample { .. }) {
+            return Err(ValidationError::MissingTrailingAmbient);
         }
 
         {
             let mut previous_stage: Option<&TestStage> = None;
-            for stage in self.stages.iter() {
+            for (index, stage) in self.stages.iter().enumerate() {
                 if previous_stage.is_some()
                     && matches!(stage, TestStage::AmbientSample { .. })
                     && matches!(
@@ -155,7 +605,7 @@ impl TestConfig {
                         TestStage::AmbientSample { .. }
                     )
                 {
-                    return Err(ValidationError::InvalidConfig);
+                    return Err(ValidationError::ConsecutiveAmbientStages { index });
                 }
                 previous_stage = Some(stage);
 
@@ -165,26 +615,133 @@ impl TestConfig {
                 // We don't enforce a minimum purge time - each Exercise can legitimately skip
                 // purging (that's the case for the abbreviated protocols). Skipping the
                 // ambient purge is probably a bad idea, but it doesn't break anything.
-                let sample_count = match stage {
-                    TestStage::AmbientSample { counts, .. }
-                    | TestStage::Exercise { counts, .. } => counts.sample_count,
-                };
-                if sample_count < 1 {
-                    return Err(ValidationError::InvalidConfig);
+                if stage.counts().sample_count < 1 {
+                    return Err(ValidationError::EmptySampleCount { index });
+                }
+                if let TestStage::Exercise {
+                    weight, pass_level, ..
+                } = stage
+                {
+                    if !(weight.is_finite() && *weight > 0.0) {
+                        return Err(ValidationError::InvalidWeight { index });
+                    }
+                    if pass_level
+                        .is_some_and(|pass_level| !(pass_level.is_finite() && pass_level >= 1.0))
+                    {
+                        return Err(ValidationError::InvalidExercisePassLevel { index });
+                    }
                 }
             }
         }
+        if self
+            .pass_level
+            .is_some_and(|pass_level| !(pass_level.is_finite() && pass_level >= 1.0))
+        {
+            return Err(ValidationError::InvalidPassLevel);
+        }
+        if self
+            .max_fit_factor
+            .is_some_and(|max| !(max.is_finite() && max >= 1.0))
+        {
+            return Err(ValidationError::InvalidMaxFitFactor);
+        }
+        if self
+            .min_concentration
+            .is_some_and(|min| !(min.is_finite() && min > 0.0))
+        {
+            return Err(ValidationError::InvalidMinConcentration);
+        }
+        // The overall FF is meaningless without at least one exercise.
+        if !self.stages.iter().any(|stage| {
+            matches!(
+                stage,
+                TestStage::Exercise {
+                    exclude_from_overall: false,
+                    ..
+                }
+            )
+        }) {
+            return Err(ValidationError::NoScoredExercises);
+        }
         Ok(())
     }
 
-    pub fn parse_from_csv(csv: &mut dyn std::io::BufRead) -> Result<TestConfig, ParseError> {
+    /// Returns likely mistakes in this config. Unlike validate(), warnings
+    /// do not prevent the config from being used.
+    pub fn warnings(&self) -> Vec<ConfigWarning> {
+        let mut warnings = Vec::new();
+        let mut previous_stage: Option<&TestStage> = None;
+        for (index, stage) in self.stages.iter().enumerate() {
+            let purge_count = stage.counts().purge_count;
+            match (previous_stage, stage) {
+                (Some(previous), TestStage::AmbientSample { .. })
+                    if previous.is_exercise() && purge_count == 0 =>
+                {
+                    warnings.push(ConfigWarning::MissingAmbientPurge { index });
+                }
+                (Some(previous), stage)
+                    if previous.is_ambient_sample() && stage.is_exercise() && purge_count == 0 =>
+                {
+                    warnings.push(ConfigWarning::MissingSpecimenPurge { index });
+                }
+                _ => (),
+            }
+            if let (
+                TestStage::Exercise {
+                    pass_level: Some(pass_level),
+                    ..
+                },
+                Some(max_fit_factor),
+            ) = (stage, self.max_fit_factor)
+            {
+                if *pass_level > max_fit_factor {
+                    warnings.push(ConfigWarning::PassLevelAboveMaxFitFactor { index: Some(index) });
+                }
+            }
+            previous_stage = Some(stage);
+        }
+        if let (Some(pass_level), Some(max_fit_factor)) = (self.pass_level, self.max_fit_factor) {
+            if pass_level > max_fit_factor {
+                warnings.push(ConfigWarning::PassLevelAboveMaxFitFactor { index: None });
+            }
+        }
+        warnings
+    }
+
+    pub fn parse_from_csv(csv: &mut dyn std::io::BufRead) -> Result<TestConfig, ParseError<'_>> {
+        Self::parse_from_csv_with_dialect(csv, CsvDialect::Default)
+    }
+
+    /// Parses a CSV config using the specified dialect, see CsvDialect.
+    pub fn parse_from_csv_with_dialect(
+        csv: &mut dyn std::io::BufRead,
+        dialect: CsvDialect,
+    ) -> Result<TestConfig, ParseError<'_>> {
         // This could be implemented using a csv parser. But... aside from NIH,
         // I'm averse to including more deps just to save 5 lines.
         // Ooops... looks like it's actually about 20 lines (modulo
         // application-specific logic).
 
         let mut stages = Vec::new();
-        let mut test_header: Option<(String, String)> = None;
+        struct TestHeader {
+            name: String,
+            short_name: String,
+            pass_level: Option<f64>,
+            version: u32,
+            localized_names: BTreeMap<String, String>,
+        }
+        let mut test_header: Option<TestHeader> = None;
+        // (repetitions, index of the first stage in the block) for each
+        // REPEAT that hasn't been closed yet. Blocks are expanded when the
+        // corresponding END is reached.
+        let mut repeat_stack: Vec<(usize, usize)> = Vec::new();
+        // Set by EXTENDS: the pass level to use if the TEST header doesn't
+        // specify one.
+        let mut base_pass_level = None;
+        // Set by REPLACE: the index of the stage that the next stage replaces.
+        let mut pending_replacement: Option<usize> = None;
+        let mut max_fit_factor = None;
+        let mut min_concentration = None;
 
         let mut line = String::with_capacity(64);
         loop {
@@ -198,16 +755,62 @@ impl TestConfig {
                 Err(e) => return Err(ParseError::IoError(e.to_string())),
             };
 
-            let data = line.trim();
-            if data.is_empty() || data.chars().nth(0).unwrap() == '#' {
-                continue;
-            }
-
             // Note: any additional columns are ignored for reasons of forward
             // compatibility. However, we do not allow comments in any column.
-            let tokens = tokenise_line(data)?;
+            let tokens = match dialect {
+                CsvDialect::Default => {
+                    let data = line.trim();
+                    if data.is_empty() || data.chars().nth(0).unwrap() == '#' {
+                        continue;
+                    }
+                    tokenise_line(data)?
+                }
+                CsvDialect::Rfc4180 => {
+                    // Quoted fields may contain line breaks, i.e. a record
+                    // continues until all quotes have been closed.
+                    while line.matches('"').count() % 2 == 1 {
+                        match csv.read_line(&mut line) {
+                            Ok(0) => {
+                                return Err(ParseError::Other(
+                                    PARSE_ERROR_MESSAGE_UNCLOSED_QUOTATION.to_string(),
+                                ))
+                            }
+                            Ok(_) => (),
+                            Err(e) => return Err(ParseError::IoError(e.to_string())),
+                        }
+                    }
+                    let record = line.trim_end_matches(['\r', '\n']);
+                    // Excel prepends a BOM to UTF-8 CSV exports.
+                    let record = record.strip_prefix('\u{feff}').unwrap_or(record);
+                    let tokens = tokenise_record_rfc4180(record)?;
+                    // Spreadsheets pad rows with empty fields, including rows
+                    // that are otherwise blank.
+                    if tokens.iter().all(|token| token.trim().is_empty())
+                        || tokens[0].starts_with('#')
+                    {
+                        continue;
+                    }
+                    tokens
+                }
+            };
             let cols: Vec<&str> = tokens.iter().map(|col| col.as_str()).collect();
 
+            // Stages may be specified in seconds instead of samples, using
+            // the _SECONDS variant of each stage (e.g. EXERCISE_SECONDS).
+            let unit = if cols[0].ends_with("_SECONDS") {
+                DurationUnit::Seconds
+            } else {
+                DurationUnit::Samples
+            };
+            if pending_replacement.is_some()
+                && !["AMBIENT", "EXERCISE", "GRIMACE"]
+                    .contains(&cols[0].trim_end_matches("_SECONDS"))
+            {
+                return Err(ParseError::Other(
+                    "REPLACE must be followed by a stage".to_string(),
+                ));
+            }
+            let stage_count = stages.len();
             match cols[0] {
                 "TEST" => {
                     if cols.len() < 3 {
@@ -215,9 +818,46 @@ impl TestConfig {
                             "test header (TEST line) must contain >= 3 fields",
                         ));
                     }
-                    test_header = Some((String::from(cols[1]), String::from(cols[2])));
+                    let mut pass_level = None;
+                    let mut version = 1;
+                    let mut localized_names = BTreeMap::new();
+                    // As with exercises, optional key=value columns may follow.
+                    for option in cols[3..].iter().filter_map(|col| col.split_once('=')) {
+                        match option {
+                            ("pass", value) => {
+                                pass_level = if let Ok(p) = f64::from_str(value) {
+                                    Some(p)
+                                } else {
+                                    return Err(ParseError::InvalidTestHeader(
+                                        "test pass level must be a number",
+                                    ));
+                                };
+                            }
+                            ("version", value) => {
+                                version = if let Ok(v) = u32::from_str(value) {
+                                    v
+                                } else {
+                                    return Err(ParseError::InvalidTestHeader(
+                                        "test version must be a non-negative integer",
+                                    ));
+                                };
+                            }
+                            (key, value) if key.starts_with("name.") => {
+                                localized_names
+                                    .insert(key["name.".len()..].to_string(), value.to_string());
+                            }
+                            _ => (),
+                        }
+                    }
+                    test_header = Some(TestHeader {
+                        name: String::from(cols[1]),
+                        short_name: String::from(cols[2]),
+                        pass_level,
+                        version,
+                        localized_names,
+                    });
                 }
-                "AMBIENT" => {
+                "AMBIENT" | "AMBIENT_SECONDS" => {
                     if cols.len() < 3 {
                         return Err(ParseError::InvalidAmbientStage(
                             "ambient stage must contain >= 3 fields",
@@ -243,10 +883,11 @@ impl TestConfig {
                         counts: StageCounts {
                             purge_count: purge_count as usize,
                             sample_count: sample_count as usize,
+                            unit: unit.clone(),
                         },
                     });
                 }
-                "EXERCISE" => {
+                "EXERCISE" | "EXERCISE_SECONDS" => {
                     if cols.len() < 4 {
                         return Err(ParseError::InvalidExerciseStage(
                             "exercise stage must contain >= 4 fields",
@@ -264,6 +905,39 @@ impl TestConfig {
                     } else {
                         return Err(ParseError::InvalidExerciseStage("exercise stage purge count must be an integer between 0 and {u16::MAX}"));
                     };
+                    let mut weight = 1.0;
+                    let mut pass_level = None;
+                    let mut localized_names = BTreeMap::new();
+                    let exclude_from_overall = cols[4..].contains(&"exclude_from_overall");
+                    // Optional key=value columns follow the name. Unknown keys are
+                    // ignored, as with any other additional column.
+                    for option in cols[4..].iter().filter_map(|col| col.split_once('=')) {
+                        match option {
+                            ("weight", value) => {
+                                weight = if let Ok(w) = f64::from_str(value) {
+                                    w
+                                } else {
+                                    return Err(ParseError::InvalidExerciseStage(
+                                        "exercise weight must be a number",
+                                    ));
+                                };
+                            }
+                            ("pass", value) => {
+                                pass_level = if let Ok(p) = f64::from_str(value) {
+                                    Some(p)
+                                } else {
+                                    return Err(ParseError::InvalidExerciseStage(
+                                        "exercise pass level must be a number",
+                                    ));
+                                };
+                            }
+                            (key, value) if key.starts_with("name.") => {
+                                localized_names
+                                    .insert(key["name.".len()..].to_string(), value.to_string());
+                            }
+                            _ => (),
+                        }
+                    }
                     stages.push(TestStage::Exercise {
                         name: if !cols[3].is_empty() {
                             cols[3].to_string()
@@ -273,9 +947,109 @@ impl TestConfig {
                         counts: StageCounts {
                             purge_count: purge_count as usize,
                             sample_count: sample_count as usize,
+                            unit: unit.clone(),
+                        },
+                        weight,
+                        exclude_from_overall,
+                        pass_level,
+                        localized_names,
+                    });
+                }
+                "GRIMACE" | "GRIMACE_SECONDS" => {
+                    if cols.len() < 3 {
+                        return Err(ParseError::InvalidGrimaceStage(
+                            "grimace stage must contain >= 3 fields",
+                        ));
+                    }
+                    let purge_count = if let Ok(i) = u8::from_str(cols[1]) {
+                        i
+                    } else {
+                        return Err(ParseError::InvalidGrimaceStage(
+                            "grimace stage purge count must be an integer between 0 and 255",
+                        ));
+                    };
+                    let sample_count = if let Ok(i) = u16::from_str(cols[2]) {
+                        i
+                    } else {
+                        return Err(ParseError::InvalidGrimaceStage("grimace stage sample count must be an integer between 0 and {u16::MAX}"));
+                    };
+                    stages.push(TestStage::Grimace {
+                        counts: StageCounts {
+                            purge_count: purge_count as usize,
+                            sample_count: sample_count as usize,
+                            unit: unit.clone(),
                         },
                     });
                 }
+                "EXTENDS" => {
+                    if !stages.is_empty() {
+                        return Err(ParseError::Other(
+                            "EXTENDS must precede all stages".to_string(),
+                        ));
+                    }
+                    let Some(base) = cols.get(1).and_then(|short_name| builtin::load(short_name))
+                    else {
+                        return Err(ParseError::Other(
+                            "EXTENDS must specify the short name of a builtin config".to_string(),
+                        ));
+                    };
+                    stages = base.stages;
+                    base_pass_level = base.pass_level;
+                    max_fit_factor = max_fit_factor.or(base.max_fit_factor);
+                    min_concentration = min_concentration.or(base.min_concentration);
+                }
+                "REPLACE" => {
+                    if !repeat_stack.is_empty() {
+                        return Err(ParseError::Other(
+                            "REPLACE is not supported within REPEAT".to_string(),
+                        ));
+                    }
+                    match cols.get(1).map(|col| usize::from_str(col)) {
+                        Some(Ok(index)) if index < stages.len() => {
+                            pending_replacement = Some(index);
+                        }
+                        _ => {
+                            return Err(ParseError::Other(
+                                "REPLACE must specify the index of an existing stage".to_string(),
+                            ));
+                        }
+                    }
+                }
+                "MAXFF" => match cols.get(1).map(|col| f64::from_str(col)) {
+                    Some(Ok(value)) => max_fit_factor = Some(value),
+                    _ => {
+                        return Err(ParseError::Other("MAXFF must be a number".to_string()));
+                    }
+                },
+                "MIN_CONCENTRATION" => match cols.get(1).map(|col| f64::from_str(col)) {
+                    Some(Ok(value)) => min_concentration = Some(value),
+                    _ => {
+                        return Err(ParseError::Other(
+                            "MIN_CONCENTRATION must be a number".to_string(),
+                        ));
+                    }
+                },
+                "REPEAT" => {
+                    let repetitions = match cols.get(1).map(|col| u16::from_str(col)) {
+                        Some(Ok(n)) if n >= 1 => n,
+                        _ => {
+                            return Err(ParseError::Other(
+                                "REPEAT count must be an integer between 1 and {u16::MAX}"
+                                    .to_string(),
+                            ));
+                        }
+                    };
+                    repeat_stack.push((repetitions as usize, stages.len()));
+                }
+                "END" => {
+                    let Some((repetitions, start)) = repeat_stack.pop() else {
+                        return Err(ParseError::Other("END without matching REPEAT".to_string()));
+                    };
+                    let block = stages[start..].to_vec();
+                    for _ in 1..repetitions {
+                        stages.extend(block.iter().cloned());
+                    }
+                }
                 // We must fail on lines that we do not understand. This means we won't be
                 // forward-compatible against new stages/commands/whatever - but we have no
                 // choice because skipping commands could result in a test that doesn't match
@@ -289,6 +1063,19 @@ impl TestConfig {
                     return Err(ParseError::Other(msg));
                 }
             }
+            if stages.len() > stage_count {
+                if let Some(index) = pending_replacement.take() {
+                    stages.swap_remove(index);
+                }
+            }
+        }
+        if pending_replacement.is_some() {
+            return Err(ParseError::Other(
+                "REPLACE must be followed by a stage".to_string(),
+            ));
+        }
+        if !repeat_stack.is_empty() {
+            return Err(ParseError::Other("REPEAT without matching END".to_string()));
         }
         if test_header.is_none() {
             return Err(ParseError::InvalidTestHeader(
@@ -296,14 +1083,467 @@ impl TestConfig {
             ));
         }
 
-        let (name, short_name) = test_header.unwrap();
+        let test_header = test_header.unwrap();
         Ok(TestConfig {
-            name,
-            short_name,
+            name: test_header.name,
+            short_name: test_header.short_name,
             stages,
+            pass_level: test_header.pass_level.or(base_pass_level),
+            version: test_header.version,
+            localized_names: test_header.localized_names,
+            max_fit_factor,
+            min_concentration,
         })
     }
 
+    /// Parses a config from JSON, see SCHEMA_VERSION for the format.
+    #[cfg(feature = "serde")]
+    pub fn parse_json(json: &str) -> Result<TestConfig, ParseError<'static>> {
+        let document: ConfigDocument =
+            serde_json::from_str(json).map_err(|e| ParseError::Other(e.to_string()))?;
+        Self::from_document(document)
+    }
+
+    /// Parses a config from TOML, see SCHEMA_VERSION for the format.
+    #[cfg(feature = "serde")]
+    pub fn parse_toml(toml: &str) -> Result<TestConfig, ParseError<'static>> {
+        let document: ConfigDocument =
+            toml::from_str(toml).map_err(|e| ParseError::Other(e.to_string()))?;
+        Self::from_document(document)
+    }
+
+    #[cfg(feature = "serde")]
+    fn from_document(document: ConfigDocument) -> Result<TestConfig, ParseError<'static>> {
+        if document.version != SCHEMA_VERSION {
+            return Err(ParseError::Other(format!(
+                "unsupported config schema version {} (supported: {SCHEMA_VERSION})",
+                document.version
+            )));
+        }
+        Ok(document.config)
+    }
+
+    /// Returns a copy of this config with all time-based stage durations
+    /// converted into sample counts, assuming that samples arrive every
+    /// sample_interval.
+    pub fn resolve_durations(&self, sample_interval: Duration) -> TestConfig {
+        TestConfig {
+            stages: self
+                .stages
+                .iter()
+                .map(|stage| match stage {
+                    TestStage::AmbientSample { counts } => TestStage::AmbientSample {
+                        counts: counts.resolve(sample_interval),
+                    },
+                    TestStage::Exercise {
+                        name,
+                        counts,
+                        weight,
+                        exclude_from_overall,
+                        pass_level,
+                        localized_names,
+                    } => TestStage::Exercise {
+                        name: name.clone(),
+                        counts: counts.resolve(sample_interval),
+                        weight: *weight,
+                        exclude_from_overall: *exclude_from_overall,
+                        pass_level: *pass_level,
+                        localized_names: localized_names.clone(),
+                    },
+                    TestStage::Grimace { counts } => TestStage::Grimace {
+                        counts: counts.resolve(sample_interval),
+                    },
+                })
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the expected duration of a test using this config, assuming
+    /// that samples arrive every sample_interval (see
+    /// cadence::EXPECTED_SAMPLE_INTERVAL). Delays such as waiting for valve
+    /// switches are not included.
+    pub fn estimated_duration(&self, sample_interval: Duration) -> Duration {
+        let samples: usize = self
+            .resolve_durations(sample_interval)
+            .stages
+            .iter()
+            .map(|stage| stage.counts().purge_count + stage.counts().sample_count)
+            .sum();
+        sample_interval * samples as u32
+    }
+
+    /// Formats this config as CSV, such that parse_from_csv() returns an
+    /// identical config. REPEAT and EXTENDS are not used, i.e. all stages are
+    /// written out.
+    pub fn to_csv(&self) -> String {
+        // All text is quoted, which also protects hash symbols and commas.
+        let quote = |text: &str| format!("\"{}\"", text.replace('"', "\"\""));
+        let localized = |localized_names: &BTreeMap<String, String>| {
+            localized_names
+                .iter()
+                .map(|(locale, name)| format!(",{}", quote(&format!("name.{locale}={name}"))))
+                .collect::<String>()
+        };
+        let counts =
+            |counts: &StageCounts| format!("{},{}", counts.purge_count, counts.sample_count);
+        let suffix = |counts: &StageCounts| match counts.unit {
+            DurationUnit::Samples => "",
+            DurationUnit::Seconds => "_SECONDS",
+        };
+
+        let mut csv = format!("TEST,{},{}", quote(&self.name), quote(&self.short_name));
+        if let Some(pass_level) = self.pass_level {
+            csv.push_str(&format!(",pass={pass_level}"));
+        }
+        if self.version != 1 {
+            csv.push_str(&format!(",version={}", self.version));
+        }
+        csv.push_str(&localized(&self.localized_names));
+        csv.push('\n');
+        if let Some(max_fit_factor) = self.max_fit_factor {
+            csv.push_str(&format!("MAXFF,{max_fit_factor}\n"));
+        }
+        if let Some(min_concentration) = self.min_concentration {
+            csv.push_str(&format!("MIN_CONCENTRATION,{min_concentration}\n"));
+        }
+        for stage in &self.stages {
+            match stage {
+                TestStage::AmbientSample { counts: c } => {
+                    csv.push_str(&format!("AMBIENT{},{}", suffix(c), counts(c)));
+                }
+                TestStage::Exercise {
+                    name,
+                    counts: c,
+                    weight,
+                    exclude_from_overall,
+                    pass_level,
+                    localized_names,
+                } => {
+                    csv.push_str(&format!(
+                        "EXERCISE{},{},{}",
+                        suffix(c),
+                        counts(c),
+                        quote(name)
+                    ));
+                    if *weight != 1.0 {
+                        csv.push_str(&format!(",weight={weight}"));
+                    }
+                    if let Some(pass_level) = pass_level {
+                        csv.push_str(&format!(",pass={pass_level}"));
+                    }
+                    if *exclude_from_overall {
+                        csv.push_str(",exclude_from_overall");
+                    }
+                    csv.push_str(&localized(localized_names));
+                }
+                TestStage::Grimace { counts: c } => {
+                    csv.push_str(&format!("GRIMACE{},{}", suffix(c), counts(c)));
+                }
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// Formats this config as (pretty-printed) JSON, see SCHEMA_VERSION for
+    /// the format. Note that the format has no equivalent of
+    /// TestConfig::version, which is therefore lost.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        let counts = |counts: &StageCounts| {
+            serde_json::json!({
+                "purge_count": counts.purge_count,
+                "sample_count": counts.sample_count,
+                "unit": match counts.unit {
+                    DurationUnit::Samples => "samples",
+                    DurationUnit::Seconds => "seconds",
+                },
+            })
+        };
+        let stages: Vec<serde_json::Value> = self
+            .stages
+            .iter()
+            .map(|stage| match stage {
+                TestStage::AmbientSample { counts: c } => serde_json::json!({
+                    "type": "ambient_sample",
+                    "counts": counts(c),
+                }),
+                TestStage::Exercise {
+                    name,
+                    counts: c,
+                    weight,
+                    exclude_from_overall,
+                    pass_level,
+                    localized_names,
+                } => serde_json::json!({
+                    "type": "exercise",
+                    "name": name,
+                    "counts": counts(c),
+                    "weight": weight,
+                    "exclude_from_overall": exclude_from_overall,
+                    "pass_level": pass_level,
+                    "localized_names": localized_names,
+                }),
+                TestStage::Grimace { counts: c } => serde_json::json!({
+                    "type": "grimace",
+                    "counts": counts(c),
+                }),
+            })
+            .collect();
+        let document = serde_json::json!({
+            "version": SCHEMA_VERSION,
+            "name": self.name,
+            "short_name": self.short_name,
+            "stages": stages,
+            "pass_level": self.pass_level,
+            "localized_names": self.localized_names,
+            "max_fit_factor": self.max_fit_factor,
+            "min_concentration": self.min_concentration,
+        });
+        serde_json::to_string_pretty(&document).expect("configs must be serialisable")
+    }
+
+    /// Returns the name of this test in the specified locale, falling back
+    /// to the language without region (e.g. "fr" for "fr-CA"), and finally
+    /// to the untranslated name.
+    pub fn name_for(&self, locale: &str) -> &str {
+        localized(&self.name, &self.localized_names, locale)
+    }
+
+    /// Returns the exercise names in the specified locale, with the same
+    /// fallback behaviour as name_for().
+    pub fn exercise_names_for(&self, locale: &str) -> Vec<String> {
+        self.stages
+            .iter()
+            .filter_map(|stage| match stage {
+                TestStage::Exercise {
+                    name,
+                    localized_names,
+                    ..
+                } => Some(localized(name, localized_names, locale).to_string()),
+                TestStage::Grimace { .. } => Some("Grimace".to_string()),
+                TestStage::AmbientSample { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Returns the stage-by-stage differences between this config and other,
+    /// e.g. to verify that a user-supplied config matches a regulatory
+    /// protocol. Only differences that affect how a test is run or scored are
+    /// reported: names, short names, versions, and translations are ignored
+    /// (with the exception of exercise names). An empty result means that
+    /// both configs describe the same protocol.
+    pub fn diff(&self, other: &TestConfig) -> Vec<ConfigDifference> {
+        let mut differences = Vec::new();
+        if self.pass_level != other.pass_level {
+            differences.push(ConfigDifference::PassLevel {
+                this: self.pass_level,
+                other: other.pass_level,
+            });
+        }
+        if self.max_fit_factor != other.max_fit_factor {
+            differences.push(ConfigDifference::MaxFitFactor {
+                this: self.max_fit_factor,
+                other: other.max_fit_factor,
+            });
+        }
+        if self.min_concentration != other.min_concentration {
+            differences.push(ConfigDifference::MinConcentration {
+                this: self.min_concentration,
+                other: other.min_concentration,
+            });
+        }
+        for index in 0..self.stages.len().max(other.stages.len()) {
+            let (this_stage, other_stage) = match (self.stages.get(index), other.stages.get(index))
+            {
+                (Some(this_stage), Some(other_stage)) => (this_stage, other_stage),
+                (Some(stage), None) => {
+                    differences.push(ConfigDifference::StageRemoved {
+                        index,
+                        stage: stage.clone(),
+                    });
+                    continue;
+                }
+                (None, Some(stage)) => {
+                    differences.push(ConfigDifference::StageAdded {
+                        index,
+                        stage: stage.clone(),
+                    });
+                    continue;
+                }
+                (None, None) => unreachable!("index is within the longer list of stages"),
+            };
+            let (this_counts, other_counts) = match (this_stage, other_stage) {
+                (
+                    TestStage::AmbientSample {
+                        counts: this_counts,
+                    },
+                    TestStage::AmbientSample {
+                        counts: other_counts,
+                    },
+                )
+                | (
+                    TestStage::Grimace {
+                        counts: this_counts,
+                    },
+                    TestStage::Grimace {
+                        counts: other_counts,
+                    },
+                ) => (this_counts, other_counts),
+                (
+                    TestStage::Exercise {
+                        name: this_name,
+                        counts: this_counts,
+                        weight: this_weight,
+                        exclude_from_overall: this_excluded,
+                        pass_level: this_pass_level,
+                        ..
+                    },
+                    TestStage::Exercise {
+                        name: other_name,
+                        counts: other_counts,
+                        weight: other_weight,
+                        exclude_from_overall: other_excluded,
+                        pass_level: other_pass_level,
+                        ..
+                    },
+                ) => {
+                    if this_name != other_name {
+                        differences.push(ConfigDifference::ExerciseName {
+                            index,
+                            this: this_name.clone(),
+                            other: other_name.clone(),
+                        });
+                    }
+                    if this_weight != other_weight {
+                        differences.push(ConfigDifference::Weight {
+                            index,
+                            this: *this_weight,
+                            other: *other_weight,
+                        });
+                    }
+                    if this_excluded != other_excluded {
+                        differences.push(ConfigDifference::ExcludeFromOverall {
+                            index,
+                            this: *this_excluded,
+                            other: *other_excluded,
+                        });
+                    }
+                    if this_pass_level != other_pass_level {
+                        differences.push(ConfigDifference::ExercisePassLevel {
+                            index,
+                            this: *this_pass_level,
+                            other: *other_pass_level,
+                        });
+                    }
+                    (this_counts, other_counts)
+                }
+                _ => {
+                    differences.push(ConfigDifference::StageType {
+                        index,
+                        this: this_stage.clone(),
+                        other: other_stage.clone(),
+                    });
+                    continue;
+                }
+            };
+            if this_counts != other_counts {
+                differences.push(ConfigDifference::Counts {
+                    index,
+                    this: this_counts.clone(),
+                    other: other_counts.clone(),
+                });
+            }
+        }
+        differences
+    }
+
+    /// Returns a stable hash of every field of this config. The fingerprint
+    /// is identical across platforms and library versions, which allows
+    /// stored results to be tied to the exact config that was used.
+    pub fn fingerprint(&self) -> u64 {
+        let mut fingerprinter = Fingerprinter::new();
+        fingerprinter.str(&self.name);
+        fingerprinter.str(&self.short_name);
+        fingerprinter.bytes(&self.version.to_le_bytes());
+        fingerprinter.localized_names(&self.localized_names);
+        fingerprinter.optional_f64(self.pass_level);
+        fingerprinter.optional_f64(self.max_fit_factor);
+        fingerprinter.optional_f64(self.min_concentration);
+        fingerprinter.usize(self.stages.len());
+        for stage in &self.stages {
+            match stage {
+                TestStage::AmbientSample { counts } => {
+                    fingerprinter.bytes(&[0]);
+                    fingerprinter.counts(counts);
+                }
+                TestStage::Exercise {
+                    name,
+                    counts,
+                    weight,
+                    exclude_from_overall,
+                    pass_level,
+                    localized_names,
+                } => {
+                    fingerprinter.bytes(&[1]);
+                    fingerprinter.str(name);
+                    fingerprinter.counts(counts);
+                    fingerprinter.f64(*weight);
+                    fingerprinter.bytes(&[*exclude_from_overall as u8]);
+                    fingerprinter.optional_f64(*pass_level);
+                    fingerprinter.localized_names(localized_names);
+                }
+                TestStage::Grimace { counts } => {
+                    fingerprinter.bytes(&[2]);
+                    fingerprinter.counts(counts);
+                }
+            }
+        }
+        fingerprinter.0
+    }
+
+    /// Returns a description of every exercise (including grimaces), in
+    /// order.
+    pub fn exercises(&self) -> Vec<ExerciseInfo> {
+        self.stages
+            .iter()
+            .enumerate()
+            .filter_map(|(stage_index, stage)| match stage {
+                TestStage::Exercise {
+                    name,
+                    counts,
+                    weight,
+                    exclude_from_overall,
+                    pass_level,
+                    ..
+                } => Some(ExerciseInfo {
+                    index: 0,
+                    stage_index,
+                    name: name.clone(),
+                    counts: counts.clone(),
+                    weight: *weight,
+                    pass_level: *pass_level,
+                    exclude_from_overall: *exclude_from_overall,
+                    is_grimace: false,
+                }),
+                TestStage::Grimace { counts } => Some(ExerciseInfo {
+                    index: 0,
+                    stage_index,
+                    name: "Grimace".to_string(),
+                    counts: counts.clone(),
+                    weight: 0.0,
+                    pass_level: None,
+                    exclude_from_overall: true,
+                    is_grimace: true,
+                }),
+                TestStage::AmbientSample { .. } => None,
+            })
+            .enumerate()
+            .map(|(index, info)| ExerciseInfo { index, ..info })
+            .collect()
+    }
+
     pub fn exercise_count(&self) -> usize {
         self.stages
             .iter()
@@ -311,17 +1551,58 @@ impl TestConfig {
             .count()
     }
 
+    pub fn exercise_weights(&self) -> Vec<f64> {
+        self.stages
+            .iter()
+            .filter_map(|stage| match stage {
+                TestStage::Exercise { weight, .. } => Some(*weight),
+                TestStage::Grimace { .. } => Some(0.0),
+                TestStage::AmbientSample { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Returns each exercise's pass level (None for exercises without a pass
+    /// level, including grimaces).
+    pub fn exercise_pass_levels(&self) -> Vec<Option<f64>> {
+        self.stages
+            .iter()
+            .filter_map(|stage| match stage {
+                TestStage::Exercise { pass_level, .. } => Some(*pass_level),
+                TestStage::Grimace { .. } => Some(None),
+                TestStage::AmbientSample { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Returns each exercise's effective weight in the overall FF, i.e. its
+    /// weight, or 0 if it is excluded from the overall FF.
+    pub fn overall_weights(&self) -> Vec<f64> {
+        self.stages
+            .iter()
+            .filter_map(|stage| match stage {
+                TestStage::Exercise {
+                    exclude_from_overall: true,
+                    ..
+                }
+                | TestStage::Grimace { .. } => Some(0.0),
+                TestStage::Exercise { weight, .. } => Some(*weight),
+                TestStage::AmbientSample { .. } => None,
+            })
+            .collect()
+    }
+
     pub fn exercise_names(&self) -> Vec<String> {
         self.stages
             .iter()
             .filter(|stage| stage.is_exercise())
-            .map(|stage| {
-                let TestStage::Exercise { name, .. } = stage else {
-                    panic!("exercises should've been filtered out already");
-                };
-                name
+            .map(|stage| match stage {
+                TestStage::Exercise { name, .. } => name.clone(),
+                TestStage::Grimace { .. } => "Grimace".to_string(),
+                TestStage::AmbientSample { .. } => {
+                    panic!("exercises should've been filtered out already")
+                }
             })
-            .cloned()
             .collect()
     }
 }
@@ -344,44 +1625,584 @@ mod tests {
                         counts: StageCounts {
                             purge_count: 4,
                             sample_count: 5,
+                            unit: DurationUnit::Samples,
+                        },
+                    },
+                    TestStage::Exercise {
+                        counts: StageCounts {
+                            purge_count: 11,
+                            sample_count: 30,
+                            unit: DurationUnit::Samples,
+                        },
+                        name: "Bending Over".to_string(),
+                        weight: 1.0,
+                        exclude_from_overall: false,
+                        pass_level: None,
+                        localized_names: BTreeMap::new(),
+                    },
+                    TestStage::Exercise {
+                        counts: StageCounts {
+                            purge_count: 0,
+                            sample_count: 30,
+                            unit: DurationUnit::Samples,
+                        },
+                        name: "Talking".to_string(),
+                        weight: 1.0,
+                        exclude_from_overall: false,
+                        pass_level: None,
+                        localized_names: BTreeMap::new(),
+                    },
+                    TestStage::Exercise {
+                        counts: StageCounts {
+                            purge_count: 0,
+                            sample_count: 30,
+                            unit: DurationUnit::Samples,
+                        },
+                        name: "Head Side-to-Side".to_string(),
+                        weight: 1.0,
+                        exclude_from_overall: false,
+                        pass_level: None,
+                        localized_names: BTreeMap::new(),
+                    },
+                    TestStage::Exercise {
+                        counts: StageCounts {
+                            purge_count: 0,
+                            sample_count: 30,
+                            unit: DurationUnit::Samples,
+                        },
+                        name: "Head Up-and-Down".to_string(),
+                        weight: 1.0,
+                        exclude_from_overall: false,
+                        pass_level: None,
+                        localized_names: BTreeMap::new(),
+                    },
+                    TestStage::AmbientSample {
+                        counts: StageCounts {
+                            purge_count: 4,
+                            sample_count: 5,
+                            unit: DurationUnit::Samples,
+                        },
+                    },
+                ],
+                pass_level: None,
+                version: 1,
+                localized_names: BTreeMap::new(),
+                max_fit_factor: None,
+                min_concentration: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_exercise_weight() {
+        let csv = "TEST,foo,foo,pass=500\nAMBIENT,0,1\nEXERCISE,0,1,a,weight=0.5,unknown=1,pass=100\nEXERCISE,0,1,b\nEXERCISE,0,1,c,exclude_from_overall\nGRIMACE,0,1\nAMBIENT,0,1\n";
+        let config = TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv)).unwrap();
+        assert_eq!(config.exercise_weights(), vec![0.5, 1.0, 1.0, 0.0]);
+        assert_eq!(config.overall_weights(), vec![0.5, 1.0, 0.0, 0.0]);
+        assert_eq!(config.exercise_names(), vec!["a", "b", "c", "Grimace"]);
+        assert_eq!(config.pass_level, Some(500.0));
+        assert_eq!(
+            config.exercise_pass_levels(),
+            vec![Some(100.0), None, None, None]
+        );
+
+        let csv = "TEST,foo,foo\nAMBIENT,0,1\nEXERCISE,0,1,a,weight=heavy\nAMBIENT,0,1\n";
+        assert!(matches!(
+            TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv)),
+            Err(ParseError::InvalidExerciseStage(_))
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_parse_json_and_toml() {
+        let expected = TestConfig::parse_from_csv(&mut std::io::Cursor::new(
+            "TEST,Foo,foo\nAMBIENT,4,5\nEXERCISE,11,40,Talking,weight=2\nGRIMACE,11,15\nAMBIENT,4,5\n",
+        ))
+        .unwrap();
+
+        let json = r#"{
+            "version": 1,
+            "name": "Foo",
+            "short_name": "foo",
+            "stages": [
+                {"type": "ambient_sample", "counts": {"purge_count": 4, "sample_count": 5}},
+                {"type": "exercise", "name": "Talking", "weight": 2.0,
+                 "counts": {"purge_count": 11, "sample_count": 40}},
+                {"type": "grimace", "counts": {"purge_count": 11, "sample_count": 15}},
+                {"type": "ambient_sample", "counts": {"purge_count": 4, "sample_count": 5}}
+            ]
+        }"#;
+        assert_eq!(TestConfig::parse_json(json), Ok(expected.clone()));
+
+        let toml = r#"
+            version = 1
+            name = "Foo"
+            short_name = "foo"
+
+            [[stages]]
+            type = "ambient_sample"
+            counts = { purge_count = 4, sample_count = 5 }
+
+            [[stages]]
+            type = "exercise"
+            name = "Talking"
+            weight = 2.0
+            counts = { purge_count = 11, sample_count = 40 }
+
+            [[stages]]
+            type = "grimace"
+            counts = { purge_count = 11, sample_count = 15 }
+
+            [[stages]]
+            type = "ambient_sample"
+            counts = { purge_count = 4, sample_count = 5 }
+        "#;
+        assert_eq!(TestConfig::parse_toml(toml), Ok(expected));
+
+        assert!(TestConfig::parse_json(&json.replace("\"version\": 1", "\"version\": 2")).is_err());
+        assert!(
+            TestConfig::parse_json(r#"{"name": "Foo", "short_name": "foo", "stages": []}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_repeat() {
+        struct TestCase<'a> {
+            name: &'a str,
+            input: &'a str,
+            expected_result: Result<Vec<&'a str>, ()>,
+        }
+        let tests = [
+            &TestCase {
+                name: "simple",
+                input: "AMBIENT,0,1\nREPEAT,3\nEXERCISE,0,1,a\nAMBIENT,0,1\nEND\n",
+                expected_result: Ok(vec!["a", "a", "a"]),
+            },
+            &TestCase {
+                name: "nested",
+                input: "AMBIENT,0,1\nREPEAT,2\nEXERCISE,0,1,a\nREPEAT,2\nEXERCISE,0,1,b\nEND\nAMBIENT,0,1\nEND\n",
+                expected_result: Ok(vec!["a", "b", "b", "a", "b", "b"]),
+            },
+            &TestCase {
+                name: "unclosed",
+                input: "AMBIENT,0,1\nREPEAT,2\nEXERCISE,0,1,a\nAMBIENT,0,1\n",
+                expected_result: Err(()),
+            },
+            &TestCase {
+                name: "unopened",
+                input: "AMBIENT,0,1\nEXERCISE,0,1,a\nAMBIENT,0,1\nEND\n",
+                expected_result: Err(()),
+            },
+            &TestCase {
+                name: "zero",
+                input: "AMBIENT,0,1\nREPEAT,0\nEXERCISE,0,1,a\nAMBIENT,0,1\nEND\n",
+                expected_result: Err(()),
+            },
+        ];
+        for case in tests {
+            let csv = format!("TEST,foo,foo\n{}", case.input);
+            let got = TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv))
+                .map(|config| config.exercise_names())
+                .map_err(|_| ());
+            let expected = case
+                .expected_result
+                .clone()
+                .map(|names| names.iter().map(|name| name.to_string()).collect());
+            assert_eq!(got, expected, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_parse_extends() {
+        let csv = "TEST,\"OSHA (modified)\",osha_mod,pass=100\nEXTENDS,osha\nREPLACE,1\nEXERCISE,11,20,\"Quiet breathing\"\nEXERCISE,11,40,Extra\nAMBIENT,4,5\n";
+        let config = TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv)).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.short_name, "osha_mod");
+        assert_eq!(config.pass_level, Some(100.0));
+        let names = config.exercise_names();
+        assert_eq!(names.len(), 9);
+        assert_eq!(names[0], "Quiet breathing");
+        assert_eq!(names[1], "Deep breathing");
+        assert_eq!(names[8], "Extra");
+
+        for invalid in [
+            "TEST,a,a\nAMBIENT,4,5\nEXTENDS,osha\n",
+            "TEST,a,a\nEXTENDS,nonexistent\n",
+            "TEST,a,a\nEXTENDS,osha\nREPLACE,1000\nAMBIENT,4,5\n",
+            "TEST,a,a\nEXTENDS,osha\nREPLACE,1\n",
+        ] {
+            assert!(
+                TestConfig::parse_from_csv(&mut std::io::Cursor::new(invalid)).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_version() {
+        let parse_version = |csv: String| {
+            TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv))
+                .map(|config| config.version)
+                .ok()
+        };
+        let stages = "AMBIENT,0,1\nEXERCISE,0,1,a\nAMBIENT,0,1\n";
+        assert_eq!(parse_version(format!("TEST,a,a\n{stages}")), Some(1));
+        assert_eq!(
+            parse_version(format!("TEST,a,a,version=3\n{stages}")),
+            Some(3)
+        );
+        assert_eq!(parse_version(format!("TEST,a,a,version=x\n{stages}")), None);
+    }
+
+    #[test]
+    fn test_diff() {
+        let parse = |csv: &str| {
+            TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv.as_bytes())).unwrap()
+        };
+        let base = parse("TEST,Foo,foo\nAMBIENT,4,5\nEXERCISE,11,40,Talking\nAMBIENT,4,5\n");
+        struct TestCase {
+            name: &'static str,
+            csv: &'static str,
+            expected_result: Vec<ConfigDifference>,
+        }
+        let exercise = |name: &str, purge_count, sample_count| TestStage::Exercise {
+            name: name.to_string(),
+            counts: StageCounts {
+                purge_count,
+                sample_count,
+                unit: DurationUnit::Samples,
+            },
+            weight: 1.0,
+            exclude_from_overall: false,
+            pass_level: None,
+            localized_names: BTreeMap::new(),
+        };
+        let test_cases = vec![
+            TestCase {
+                name: "only metadata differs",
+                csv: "TEST,Bar,bar,version=2,name.fr=Le bar\nAMBIENT,4,5\nEXERCISE,11,40,Talking\nAMBIENT,4,5\n",
+                expected_result: vec![],
+            },
+            TestCase {
+                name: "pass levels, names, counts and flags",
+                csv: "TEST,Foo,foo,pass=100\nAMBIENT,4,6\nEXERCISE,11,40,Talking loudly,weight=2,pass=50,exclude_from_overall\nAMBIENT,4,5\n",
+                expected_result: vec![
+                    ConfigDifference::PassLevel {
+                        this: None,
+                        other: Some(100.0),
+                    },
+                    ConfigDifference::Counts {
+                        index: 0,
+                        this: StageCounts {
+                            purge_count: 4,
+                            sample_count: 5,
+                            unit: DurationUnit::Samples,
                         },
-                    },
-                    TestStage::Exercise {
-                        counts: StageCounts {
-                            purge_count: 11,
-                            sample_count: 30,
+                        other: StageCounts {
+                            purge_count: 4,
+                            sample_count: 6,
+                            unit: DurationUnit::Samples,
                         },
-                        name: "Bending Over".to_string(),
                     },
-                    TestStage::Exercise {
-                        counts: StageCounts {
-                            purge_count: 0,
-                            sample_count: 30,
-                        },
-                        name: "Talking".to_string(),
+                    ConfigDifference::ExerciseName {
+                        index: 1,
+                        this: "Talking".to_string(),
+                        other: "Talking loudly".to_string(),
                     },
-                    TestStage::Exercise {
+                    ConfigDifference::Weight {
+                        index: 1,
+                        this: 1.0,
+                        other: 2.0,
+                    },
+                    ConfigDifference::ExcludeFromOverall {
+                        index: 1,
+                        this: false,
+                        other: true,
+                    },
+                    ConfigDifference::ExercisePassLevel {
+                        index: 1,
+                        this: None,
+                        other: Some(50.0),
+                    },
+                ],
+            },
+            TestCase {
+                name: "units differ",
+                csv: "TEST,Foo,foo\nAMBIENT,4,5\nEXERCISE_SECONDS,11,40,Talking\nAMBIENT,4,5\n",
+                expected_result: vec![ConfigDifference::Counts {
+                    index: 1,
+                    this: StageCounts {
+                        purge_count: 11,
+                        sample_count: 40,
+                        unit: DurationUnit::Samples,
+                    },
+                    other: StageCounts {
+                        purge_count: 11,
+                        sample_count: 40,
+                        unit: DurationUnit::Seconds,
+                    },
+                }],
+            },
+            TestCase {
+                name: "stage types differ",
+                csv: "TEST,Foo,foo\nAMBIENT,4,5\nGRIMACE,11,40\nAMBIENT,4,5\n",
+                expected_result: vec![ConfigDifference::StageType {
+                    index: 1,
+                    this: exercise("Talking", 11, 40),
+                    other: TestStage::Grimace {
                         counts: StageCounts {
-                            purge_count: 0,
-                            sample_count: 30,
+                            purge_count: 11,
+                            sample_count: 40,
+                            unit: DurationUnit::Samples,
                         },
-                        name: "Head Side-to-Side".to_string(),
                     },
-                    TestStage::Exercise {
-                        counts: StageCounts {
-                            purge_count: 0,
-                            sample_count: 30,
+                }],
+            },
+            TestCase {
+                name: "additional stages",
+                csv: "TEST,Foo,foo\nAMBIENT,4,5\nEXERCISE,11,40,Talking\nAMBIENT,4,5\nEXERCISE,0,30,Bending\nAMBIENT,4,5\n",
+                expected_result: vec![
+                    ConfigDifference::StageAdded {
+                        index: 3,
+                        stage: exercise("Bending", 0, 30),
+                    },
+                    ConfigDifference::StageAdded {
+                        index: 4,
+                        stage: TestStage::AmbientSample {
+                            counts: StageCounts {
+                                purge_count: 4,
+                                sample_count: 5,
+                                unit: DurationUnit::Samples,
+                            },
                         },
-                        name: "Head Up-and-Down".to_string(),
                     },
-                    TestStage::AmbientSample {
+                ],
+            },
+        ];
+        for test_case in test_cases {
+            let other = parse(test_case.csv);
+            assert_eq!(
+                base.diff(&other),
+                test_case.expected_result,
+                "{}",
+                test_case.name
+            );
+        }
+
+        // Removed stages are reported from the other direction.
+        let longer = parse(
+            "TEST,Foo,foo\nAMBIENT,4,5\nEXERCISE,11,40,Talking\nAMBIENT,4,5\nEXERCISE,0,30,Bending\nAMBIENT,4,5\n",
+        );
+        assert_eq!(
+            longer.diff(&base),
+            vec![
+                ConfigDifference::StageRemoved {
+                    index: 3,
+                    stage: exercise("Bending", 0, 30),
+                },
+                ConfigDifference::StageRemoved {
+                    index: 4,
+                    stage: TestStage::AmbientSample {
                         counts: StageCounts {
                             purge_count: 4,
                             sample_count: 5,
+                            unit: DurationUnit::Samples,
                         },
                     },
-                ],
+                },
+            ]
+        );
+        for config_csv in builtin::BUILTIN_CONFIGS {
+            let config = parse(config_csv);
+            assert_eq!(config.diff(&config), vec![], "{}", config.short_name);
+        }
+    }
+
+    #[test]
+    fn test_exercises() {
+        let csv = "TEST,Foo,foo\nAMBIENT,4,5\nEXERCISE,11,40,Talking,weight=2,pass=100\nAMBIENT,4,5\nGRIMACE,11,15\nEXERCISE_SECONDS,0,30,Bending,exclude_from_overall\nAMBIENT,4,5\n";
+        let config = TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv)).unwrap();
+        assert_eq!(
+            config.exercises(),
+            vec![
+                ExerciseInfo {
+                    index: 0,
+                    stage_index: 1,
+                    name: "Talking".to_string(),
+                    counts: StageCounts {
+                        purge_count: 11,
+                        sample_count: 40,
+                        unit: DurationUnit::Samples,
+                    },
+                    weight: 2.0,
+                    pass_level: Some(100.0),
+                    exclude_from_overall: false,
+                    is_grimace: false,
+                },
+                ExerciseInfo {
+                    index: 1,
+                    stage_index: 3,
+                    name: "Grimace".to_string(),
+                    counts: StageCounts {
+                        purge_count: 11,
+                        sample_count: 15,
+                        unit: DurationUnit::Samples,
+                    },
+                    weight: 0.0,
+                    pass_level: None,
+                    exclude_from_overall: true,
+                    is_grimace: true,
+                },
+                ExerciseInfo {
+                    index: 2,
+                    stage_index: 4,
+                    name: "Bending".to_string(),
+                    counts: StageCounts {
+                        purge_count: 0,
+                        sample_count: 30,
+                        unit: DurationUnit::Seconds,
+                    },
+                    weight: 1.0,
+                    pass_level: None,
+                    exclude_from_overall: true,
+                    is_grimace: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_with_builtins() {
+        let parse = |csv: &str| {
+            TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv.to_string())).unwrap()
+        };
+        let stages = "AMBIENT,0,1\nEXERCISE,0,1,a\nAMBIENT,0,1\n";
+        let configs = merge_with_builtins(vec![(
+            "mine.csv".to_string(),
+            parse(&format!("TEST,Mine,mine\n{stages}")),
+        )])
+        .unwrap();
+        assert_eq!(configs.len(), builtin::BUILTIN_CONFIGS.len() + 1);
+
+        assert_eq!(
+            merge_with_builtins(vec![
+                (
+                    "a.csv".to_string(),
+                    parse(&format!("TEST,Mine,mine\n{stages}"))
+                ),
+                (
+                    "b.csv".to_string(),
+                    parse(&format!("TEST,OSHA,osha\n{stages}"))
+                ),
+                (
+                    "c.csv".to_string(),
+                    parse(&format!("TEST,Mine,mine\n{stages}"))
+                ),
+            ]),
+            Err(DuplicateShortNameError {
+                short_name: "osha".to_string(),
+                sources: vec!["builtin".to_string(), "b.csv".to_string()],
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_measurement_limits() {
+        let csv = "TEST,N99,n99\nMAXFF,10000\nMIN_CONCENTRATION,0.05\nAMBIENT,4,5\nEXERCISE,11,40,Talking\nAMBIENT,4,5\n";
+        let config = TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv)).unwrap();
+        assert_eq!(config.max_fit_factor, Some(10000.0));
+        assert_eq!(config.min_concentration, Some(0.05));
+
+        let csv = "TEST,N95,n95\nMAXFF,high\n";
+        assert_eq!(
+            TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv)),
+            Err(ParseError::Other("MAXFF must be a number".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let parse = |csv: &str| {
+            TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv.as_bytes())).unwrap()
+        };
+        let config = parse("TEST,Foo,foo\nAMBIENT,4,5\nEXERCISE,11,40,Talking\nAMBIENT,4,5\n");
+        // Fingerprints must never change, otherwise previously stored results
+        // can no longer be matched against their config.
+        assert_eq!(config.fingerprint(), 0xc84f_537f_7592_0763);
+        assert_eq!(config.fingerprint(), config.clone().fingerprint());
+        for variant in [
+            "TEST,Foo,foo,version=2\nAMBIENT,4,5\nEXERCISE,11,40,Talking\nAMBIENT,4,5\n",
+            "TEST,Foo,foo\nAMBIENT,4,5\nEXERCISE,11,41,Talking\nAMBIENT,4,5\n",
+            "TEST,Foo,foo\nAMBIENT,4,5\nEXERCISE_SECONDS,11,40,Talking\nAMBIENT,4,5\n",
+            "TEST,Foo,foo\nAMBIENT,4,5\nEXERCISE,11,40,Talking,weight=2\nAMBIENT,4,5\n",
+            "TEST,Foo,foo\nAMBIENT,4,5\nGRIMACE,11,40\nAMBIENT,4,5\n",
+            "TEST,Foo,foo\nMAXFF,200\nAMBIENT,4,5\nEXERCISE,11,40,Talking\nAMBIENT,4,5\n",
+        ] {
+            assert_ne!(
+                config.fingerprint(),
+                parse(variant).fingerprint(),
+                "{variant}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_localized_names() {
+        let csv = "TEST,\"Fit test\",fit,\"name.fr=Essai d'ajustement\",name.de=Dichtsitzprüfung\nAMBIENT,0,1\nREPEAT,2\nEXERCISE,0,1,Talking,name.fr=Parler,name.fr-CA=Jaser\nAMBIENT,0,1\nEND\n";
+        let config = TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv)).unwrap();
+        assert_eq!(config.name_for("fr"), "Essai d'ajustement");
+        assert_eq!(config.name_for("fr-CA"), "Essai d'ajustement");
+        assert_eq!(config.name_for("de_AT"), "Dichtsitzprüfung");
+        assert_eq!(config.name_for("en"), "Fit test");
+        assert_eq!(config.exercise_names_for("fr"), vec!["Parler", "Parler"]);
+        assert_eq!(config.exercise_names_for("fr-CA"), vec!["Jaser", "Jaser"]);
+        assert_eq!(config.exercise_names_for("fr-BE"), vec!["Parler", "Parler"]);
+        assert_eq!(config.exercise_names_for("en"), config.exercise_names());
+    }
+
+    #[test]
+    fn test_resolve_durations() {
+        let csv = "TEST,foo,foo\nAMBIENT_SECONDS,4,5\nEXERCISE_SECONDS,10,40,a\nGRIMACE,11,15\nAMBIENT,4,5\n";
+        let config = TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv)).unwrap();
+        assert_eq!(
+            config.stages[1],
+            TestStage::Exercise {
+                name: "a".to_string(),
+                counts: StageCounts {
+                    purge_count: 10,
+                    sample_count: 40,
+                    unit: DurationUnit::Seconds,
+                },
+                weight: 1.0,
+                exclude_from_overall: false,
+                pass_level: None,
+                localized_names: BTreeMap::new(),
+            }
+        );
+
+        // A slightly slow device: 1.25s/sample.
+        let resolved = config.resolve_durations(Duration::from_millis(1250));
+        let counts: Vec<(usize, usize, DurationUnit)> = resolved
+            .stages
+            .iter()
+            .map(|stage| match stage {
+                TestStage::AmbientSample { counts }
+                | TestStage::Exercise { counts, .. }
+                | TestStage::Grimace { counts } => {
+                    (counts.purge_count, counts.sample_count, counts.unit.clone())
+                }
             })
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (3, 4, DurationUnit::Samples),
+                (8, 32, DurationUnit::Samples),
+                (11, 15, DurationUnit::Samples),
+                (4, 5, DurationUnit::Samples),
+            ]
         );
     }
 
@@ -391,8 +2212,18 @@ mod tests {
             name: "foo".to_string(),
             short_name: "bar".to_string(),
             stages: vec![],
+            pass_level: None,
+            version: 1,
+            localized_names: BTreeMap::new(),
+            max_fit_factor: None,
+            min_concentration: None,
         };
 
+        let valid_config = TestConfig::parse_from_csv(&mut std::io::Cursor::new(
+            builtin::OSHA_FAST_FFP.as_bytes(),
+        ))
+        .unwrap();
+
         struct TestCase<'a> {
             name: &'a str,
             input: &'a TestConfig,
@@ -402,7 +2233,7 @@ mod tests {
             &TestCase {
                 name: "NoStages",
                 input: &base_config,
-                expected_result: Err(ValidationError::InvalidConfig),
+                expected_result: Err(ValidationError::TooFewStages),
             },
             &TestCase {
                 name: "OneAmbientStage",
@@ -411,11 +2242,12 @@ mod tests {
                         counts: StageCounts {
                             purge_count: 0,
                             sample_count: 1,
+                            unit: DurationUnit::Samples,
                         },
                     }],
                     ..base_config.clone()
                 },
-                expected_result: Err(ValidationError::InvalidConfig),
+                expected_result: Err(ValidationError::TooFewStages),
             },
             &TestCase {
                 name: "TwoAmbientStages",
@@ -425,18 +2257,20 @@ mod tests {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                         TestStage::AmbientSample {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                     ],
                     ..base_config.clone()
                 },
-                expected_result: Err(ValidationError::InvalidConfig),
+                expected_result: Err(ValidationError::TooFewStages),
             },
             &TestCase {
                 name: "ThreeAmbientStages",
@@ -446,24 +2280,27 @@ mod tests {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                         TestStage::AmbientSample {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                         TestStage::AmbientSample {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                     ],
                     ..base_config.clone()
                 },
-                expected_result: Err(ValidationError::InvalidConfig),
+                expected_result: Err(ValidationError::ConsecutiveAmbientStages { index: 1 }),
             },
             &TestCase {
                 name: "TwoAmbientStagesinSequence",
@@ -473,6 +2310,7 @@ mod tests {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                         TestStage::Exercise {
@@ -480,24 +2318,31 @@ mod tests {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
+                            weight: 1.0,
+                            exclude_from_overall: false,
+                            pass_level: None,
+                            localized_names: BTreeMap::new(),
                         },
                         TestStage::AmbientSample {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                         TestStage::AmbientSample {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                     ],
                     ..base_config.clone()
                 },
-                expected_result: Err(ValidationError::InvalidConfig),
+                expected_result: Err(ValidationError::ConsecutiveAmbientStages { index: 3 }),
             },
             &TestCase {
                 name: "MinimumViableTest",
@@ -507,6 +2352,7 @@ mod tests {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                         TestStage::Exercise {
@@ -514,12 +2360,18 @@ mod tests {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
+                            weight: 1.0,
+                            exclude_from_overall: false,
+                            pass_level: None,
+                            localized_names: BTreeMap::new(),
                         },
                         TestStage::AmbientSample {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                     ],
@@ -535,6 +2387,7 @@ mod tests {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 0,
+                                unit: DurationUnit::Samples,
                             },
                         },
                         TestStage::Exercise {
@@ -542,18 +2395,24 @@ mod tests {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
+                            weight: 1.0,
+                            exclude_from_overall: false,
+                            pass_level: None,
+                            localized_names: BTreeMap::new(),
                         },
                         TestStage::AmbientSample {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                     ],
                     ..base_config.clone()
                 },
-                expected_result: Err(ValidationError::InvalidConfig),
+                expected_result: Err(ValidationError::EmptySampleCount { index: 0 }),
             },
             &TestCase {
                 name: "TwoExercisesFastTest",
@@ -563,13 +2422,124 @@ mod tests {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
+                            },
+                        },
+                        TestStage::Exercise {
+                            name: "foo".to_string(),
+                            counts: StageCounts {
+                                purge_count: 0,
+                                sample_count: 1,
+                                unit: DurationUnit::Samples,
+                            },
+                            weight: 1.0,
+                            exclude_from_overall: false,
+                            pass_level: None,
+                            localized_names: BTreeMap::new(),
+                        },
+                        TestStage::Exercise {
+                            name: "foo".to_string(),
+                            counts: StageCounts {
+                                purge_count: 0,
+                                sample_count: 1,
+                                unit: DurationUnit::Samples,
+                            },
+                            weight: 1.0,
+                            exclude_from_overall: false,
+                            pass_level: None,
+                            localized_names: BTreeMap::new(),
+                        },
+                        TestStage::AmbientSample {
+                            counts: StageCounts {
+                                purge_count: 0,
+                                sample_count: 1,
+                                unit: DurationUnit::Samples,
+                            },
+                        },
+                    ],
+                    ..base_config.clone()
+                },
+                expected_result: Ok(()),
+            },
+            &TestCase {
+                name: "ZeroWeight",
+                input: &TestConfig {
+                    stages: vec![
+                        TestStage::AmbientSample {
+                            counts: StageCounts {
+                                purge_count: 0,
+                                sample_count: 1,
+                                unit: DurationUnit::Samples,
+                            },
+                        },
+                        TestStage::Exercise {
+                            name: "foo".to_string(),
+                            counts: StageCounts {
+                                purge_count: 0,
+                                sample_count: 1,
+                                unit: DurationUnit::Samples,
+                            },
+                            weight: 0.0,
+                            exclude_from_overall: false,
+                            pass_level: None,
+                            localized_names: BTreeMap::new(),
+                        },
+                        TestStage::AmbientSample {
+                            counts: StageCounts {
+                                purge_count: 0,
+                                sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
+                    ],
+                    ..base_config.clone()
+                },
+                expected_result: Err(ValidationError::InvalidWeight { index: 1 }),
+            },
+            &TestCase {
+                name: "LeadingExercise",
+                input: &TestConfig {
+                    stages: vec![
                         TestStage::Exercise {
                             name: "foo".to_string(),
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
+                            },
+                            weight: 1.0,
+                            exclude_from_overall: false,
+                            pass_level: None,
+                            localized_names: BTreeMap::new(),
+                        },
+                        TestStage::AmbientSample {
+                            counts: StageCounts {
+                                purge_count: 0,
+                                sample_count: 1,
+                                unit: DurationUnit::Samples,
+                            },
+                        },
+                        TestStage::AmbientSample {
+                            counts: StageCounts {
+                                purge_count: 0,
+                                sample_count: 1,
+                                unit: DurationUnit::Samples,
+                            },
+                        },
+                    ],
+                    ..base_config.clone()
+                },
+                expected_result: Err(ValidationError::MissingLeadingAmbient),
+            },
+            &TestCase {
+                name: "AllExercisesExcluded",
+                input: &TestConfig {
+                    stages: vec![
+                        TestStage::AmbientSample {
+                            counts: StageCounts {
+                                purge_count: 0,
+                                sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                         TestStage::Exercise {
@@ -577,17 +2547,48 @@ mod tests {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
+                            weight: 1.0,
+                            exclude_from_overall: true,
+                            pass_level: None,
+                            localized_names: BTreeMap::new(),
                         },
                         TestStage::AmbientSample {
                             counts: StageCounts {
                                 purge_count: 0,
                                 sample_count: 1,
+                                unit: DurationUnit::Samples,
                             },
                         },
                     ],
                     ..base_config.clone()
                 },
+                expected_result: Err(ValidationError::NoScoredExercises),
+            },
+            &TestCase {
+                name: "MaxFitFactorBelowOne",
+                input: &TestConfig {
+                    max_fit_factor: Some(0.5),
+                    ..valid_config.clone()
+                },
+                expected_result: Err(ValidationError::InvalidMaxFitFactor),
+            },
+            &TestCase {
+                name: "ZeroMinConcentration",
+                input: &TestConfig {
+                    min_concentration: Some(0.0),
+                    ..valid_config.clone()
+                },
+                expected_result: Err(ValidationError::InvalidMinConcentration),
+            },
+            &TestCase {
+                name: "MeasurementLimits",
+                input: &TestConfig {
+                    max_fit_factor: Some(10000.0),
+                    min_concentration: Some(0.01),
+                    ..valid_config.clone()
+                },
                 expected_result: Ok(()),
             },
         ];
@@ -601,6 +2602,81 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_rfc4180() {
+        let csv = "\u{feff}TEST,\"Excel \"\"export\"\"\",excel,,\r\n# comment,,,\r\n,,,\r\nAMBIENT,4,5,\r\nEXERCISE,11,40,\"Step #1,\r\nthen talk\",\r\nAMBIENT,4,5,\r\n";
+        let config = TestConfig::parse_from_csv_with_dialect(
+            &mut std::io::Cursor::new(csv),
+            CsvDialect::Rfc4180,
+        )
+        .unwrap();
+        assert_eq!(config.name, "Excel \"export\"");
+        assert_eq!(config.short_name, "excel");
+        assert_eq!(config.exercise_names(), vec!["Step #1,\r\nthen talk"]);
+        assert_eq!(config.validate(), Ok(()));
+
+        // The same file is rejected by the default dialect.
+        assert!(TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv)).is_err());
+    }
+
+    #[test]
+    fn test_tokenise_record_rfc4180() {
+        struct TestCase<'a> {
+            name: &'a str,
+            input: &'a str,
+            expected_result: Result<Vec<&'a str>, ParseError<'a>>,
+        }
+        let test_cases = [
+            TestCase {
+                name: "empty_fields",
+                input: "a,,b,",
+                expected_result: Ok(vec!["a", "", "b", ""]),
+            },
+            TestCase {
+                name: "spaces_and_hashes_are_retained",
+                input: " a #1 ,b",
+                expected_result: Ok(vec![" a #1 ", "b"]),
+            },
+            TestCase {
+                name: "escaped_quotes_and_line_breaks",
+                input: "\"a \"\"b\"\"\nc\",d",
+                expected_result: Ok(vec!["a \"b\"\nc", "d"]),
+            },
+            TestCase {
+                name: "quote_in_unquoted_field",
+                input: "a\"b\"",
+                expected_result: Err(ParseError::Other(
+                    PARSE_ERROR_MESSAGE_BAD_LEADING_QUOTATION.to_string(),
+                )),
+            },
+            TestCase {
+                name: "text_after_closing_quote",
+                input: "\"a\"b",
+                expected_result: Err(ParseError::Other(
+                    PARSE_ERROR_MESSAGE_BAD_TRAILING_QUOTATION.to_string(),
+                )),
+            },
+            TestCase {
+                name: "unclosed_quotation",
+                input: "\"a,b",
+                expected_result: Err(ParseError::Other(
+                    PARSE_ERROR_MESSAGE_UNCLOSED_QUOTATION.to_string(),
+                )),
+            },
+        ];
+        for test_case in test_cases {
+            let expected_result = test_case
+                .expected_result
+                .map(|tokens| tokens.iter().map(|token| token.to_string()).collect());
+            assert_eq!(
+                tokenise_record_rfc4180(test_case.input),
+                expected_result,
+                "{}",
+                test_case.name
+            );
+        }
+    }
+
     #[test]
     fn test_tokenise_line() {
         struct TestCase<'a> {
@@ -728,4 +2804,91 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_warnings() {
+        let parse = |csv: &str| TestConfig::parse_from_csv(&mut std::io::Cursor::new(csv)).unwrap();
+        for config_csv in builtin::BUILTIN_CONFIGS {
+            let config = parse(config_csv);
+            assert_eq!(config.warnings(), vec![], "{}", config.short_name);
+        }
+
+        let config = parse(
+            "TEST,Foo,foo,pass=500\nMAXFF,200\nAMBIENT,0,5\nEXERCISE,0,40,a,pass=300\nAMBIENT,0,5\n",
+        );
+        assert_eq!(
+            config.warnings(),
+            vec![
+                ConfigWarning::MissingSpecimenPurge { index: 1 },
+                ConfigWarning::PassLevelAboveMaxFitFactor { index: Some(1) },
+                ConfigWarning::MissingAmbientPurge { index: 2 },
+                ConfigWarning::PassLevelAboveMaxFitFactor { index: None },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_estimated_duration() {
+        let config = builtin::load("osha").unwrap();
+        // 9 ambient stages (4+5), 7 exercises (11+40), and a grimace (11+15).
+        assert_eq!(
+            config.estimated_duration(Duration::from_secs(1)),
+            Duration::from_secs(9 * 9 + 7 * 51 + 26)
+        );
+        let config = TestConfig::parse_from_csv(&mut std::io::Cursor::new(
+            "TEST,Foo,foo\nAMBIENT_SECONDS,4,6\nEXERCISE,0,10,a\nAMBIENT_SECONDS,4,6\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            config.estimated_duration(Duration::from_secs(2)),
+            Duration::from_secs(10 + 20 + 10)
+        );
+    }
+
+    #[test]
+    fn test_to_csv() {
+        for config_csv in builtin::BUILTIN_CONFIGS {
+            let config = TestConfig::parse_from_csv(&mut config_csv.as_bytes()).unwrap();
+            let csv = config.to_csv();
+            assert_eq!(
+                TestConfig::parse_from_csv(&mut csv.as_bytes()),
+                Ok(config.clone()),
+                "{csv}"
+            );
+        }
+
+        let csv = concat!(
+            "TEST,\"Foo, \"\"#1\"\"\",foo,pass=100,version=3,name.fr=Fou\n",
+            "MAXFF,10000\n",
+            "MIN_CONCENTRATION,0.5\n",
+            "AMBIENT_SECONDS,4,5\n",
+            "EXERCISE,11,40,Talking,weight=2.5,pass=50,exclude_from_overall,name.de=Reden\n",
+            "GRIMACE_SECONDS,11,15\n",
+            "AMBIENT,4,5\n",
+        );
+        let config = TestConfig::parse_from_csv(&mut csv.as_bytes()).unwrap();
+        let round_tripped = config.to_csv();
+        assert_eq!(
+            TestConfig::parse_from_csv(&mut round_tripped.as_bytes()),
+            Ok(config),
+            "{round_tripped}"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_to_json() {
+        for config_csv in builtin::BUILTIN_CONFIGS.iter().chain([&concat!(
+            "TEST,Foo,foo,pass=100,name.fr=Fou\n",
+            "MAXFF,10000\n",
+            "AMBIENT_SECONDS,4,5\n",
+            "EXERCISE,11,40,Talking,weight=2.5,pass=50,exclude_from_overall,name.de=Reden\n",
+            "GRIMACE,11,15\n",
+            "AMBIENT,4,5\n",
+        )]) {
+            let config = TestConfig::parse_from_csv(&mut config_csv.as_bytes()).unwrap();
+            let json = config.to_json();
+            assert_eq!(TestConfig::parse_json(&json), Ok(config), "{json}");
+        }
+    }
 }
@@ -0,0 +1,549 @@
+//! A WebSocket broadcaster, which mirrors device and test notifications (as
+//! JSON) to all connected clients, e.g. to add a remote viewer to an existing
+//! application:
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use p8020::websocket::{self, Broadcaster};
+//! let broadcaster = Arc::new(Broadcaster::new());
+//! websocket::serve(broadcaster.clone(), "0.0.0.0:8021").unwrap();
+//! // Then forward notifications from the device and test callbacks, see
+//! // Broadcaster::broadcast_device_notification and
+//! // Broadcaster::broadcast_test_notification.
+//! ```
+//!
+//! Every message is a JSON object with a "type" (e.g. "sample" or
+//! "exercise_result") and "device" (DeviceId::index) field. Clients are
+//! write-only: anything they send is ignored.
+//!
+//! Requires the websocket feature.
+
+use std::io::{BufRead, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::json::{json_bool, json_number, json_string};
+use crate::{
+    DeviceId, DeviceNotification, DiscardReason, SampleType, TestNotification, TestState,
+    ValvePosition,
+};
+
+/// Clients that do not accept a message within this time are disconnected,
+/// such that a single stalled client cannot block broadcasts.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Broadcaster sends messages to all connected WebSocket clients. Clients
+/// are added either via serve, or via Broadcaster::upgrade for applications
+/// that already run an HTTP server.
+#[derive(Debug, Default)]
+pub struct Broadcaster {
+    subscribers: Mutex<Vec<TcpStream>>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Broadcaster {
+        Broadcaster::default()
+    }
+
+    /// Completes the WebSocket handshake for a request that has already been
+    /// read (key is the value of its Sec-WebSocket-Key header), and adds the
+    /// client.
+    pub fn upgrade(&self, mut stream: TcpStream, key: &str) -> std::io::Result<()> {
+        write!(
+            stream,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        )?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        self.subscribers.lock().unwrap().push(stream);
+        Ok(())
+    }
+
+    /// Reads the (HTTP) request from stream, and adds the client if it is a
+    /// WebSocket upgrade request. Any other request is rejected.
+    pub fn accept(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = std::io::BufReader::new(&stream);
+        let mut key = None;
+        let mut line = String::new();
+        // Skip the request line, only the headers are relevant.
+        reader.read_line(&mut line)?;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                    key = Some(value.trim().to_string());
+                }
+            }
+        }
+        let Some(key) = key else {
+            let body = "expected a WebSocket upgrade";
+            write!(
+                stream,
+                "HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )?;
+            return Ok(());
+        };
+        self.upgrade(stream, &key)
+    }
+
+    /// The number of connected clients.
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+
+    /// Sends text to all clients, disconnecting any clients that cannot be
+    /// written to.
+    pub fn broadcast(&self, text: &str) {
+        let frame = text_frame(text);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain_mut(|subscriber| subscriber.write_all(&frame).is_ok());
+    }
+
+    pub fn broadcast_device_notification(&self, id: &DeviceId, notification: &DeviceNotification) {
+        if let Some(event) = device_event_json(id.index, notification) {
+            self.broadcast(&event);
+        }
+    }
+
+    /// device identifies the device that the test is running on (see
+    /// DeviceId::index).
+    pub fn broadcast_test_notification(&self, device: usize, notification: &TestNotification) {
+        if let Some(event) = test_event_json(device, notification) {
+            self.broadcast(&event);
+        }
+    }
+}
+
+/// Accepts WebSocket clients on address (e.g. "0.0.0.0:8021"), on a
+/// background thread. Returns the address that is being listened on (which
+/// is useful when binding to port 0).
+pub fn serve(
+    broadcaster: Arc<Broadcaster>,
+    address: impl ToSocketAddrs,
+) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let local_address = listener.local_addr()?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            if let Err(e) = broadcaster.accept(stream) {
+                log::warn!("failed to accept WebSocket client: {e}");
+            }
+        }
+    });
+    Ok(local_address)
+}
+
+fn event(kind: &str, device: usize, fields: &[(&str, String)]) -> String {
+    let mut event = format!("{{\"type\": {}, \"device\": {device}", json_string(kind));
+    for (name, value) in fields {
+        event.push_str(&format!(", \"{name}\": {value}"));
+    }
+    event.push('}');
+    event
+}
+
+// The cadence is only relevant for diagnostics, and is therefore omitted.
+fn device_event_json(device: usize, notification: &DeviceNotification) -> Option<String> {
+    Some(match notification {
+        DeviceNotification::Sample { particle_conc } => event(
+            "sample",
+            device,
+            &[("concentration", json_number(*particle_conc))],
+        ),
+        DeviceNotification::TestStarted => event("test_started", device, &[]),
+        DeviceNotification::TestCompleted { result } => event(
+            "test_completed",
+            device,
+            &[
+                (
+                    "fit_factors",
+                    format!(
+                        "[{}]",
+                        result
+                            .fit_factors
+                            .iter()
+                            .map(|fit_factor| json_number(*fit_factor))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    ),
+                ),
+                ("overall_fit_factor", json_number(result.overall_fit_factor)),
+            ],
+        ),
+        DeviceNotification::TestCancelled => event("test_cancelled", device, &[]),
+        DeviceNotification::TestAborted => event("test_aborted", device, &[]),
+        DeviceNotification::ConnectionClosed => event("connection_closed", device, &[]),
+        DeviceNotification::ConnectionLost => event("connection_lost", device, &[]),
+        DeviceNotification::Reconnected => event("reconnected", device, &[]),
+        DeviceNotification::DeviceProperties(properties) => event(
+            "device_properties",
+            device,
+            &[
+                ("serial_number", json_string(&properties.serial_number)),
+                (
+                    "firmware_fingerprint",
+                    json_string(&properties.firmware_fingerprint()),
+                ),
+                ("n95_companion", json_bool(Some(properties.n95_companion))),
+            ],
+        ),
+        DeviceNotification::Fault { message, restarted } => event(
+            "fault",
+            device,
+            &[
+                ("message", json_string(message)),
+                ("restarted", json_bool(Some(*restarted))),
+            ],
+        ),
+        DeviceNotification::DeviceUnresponsive => event("device_unresponsive", device, &[]),
+        DeviceNotification::SampleCadence(_) => return None,
+    })
+}
+
+fn valve_position_json(position: &ValvePosition) -> String {
+    json_string(match position {
+        ValvePosition::Ambient => "ambient",
+        ValvePosition::Specimen => "specimen",
+    })
+}
+
+fn test_event_json(device: usize, notification: &TestNotification) -> Option<String> {
+    Some(match notification {
+        TestNotification::StateChange(TestState::StartedExercise(exercise)) => event(
+            "exercise_started",
+            device,
+            &[("exercise", exercise.to_string())],
+        ),
+        // Completion is reported via DeviceNotification::TestCompleted, which
+        // includes the results.
+        TestNotification::StateChange(_) => return None,
+        TestNotification::ExerciseResult {
+            exercise,
+            fit_factor,
+            error,
+            ..
+        } => event(
+            "exercise_result",
+            device,
+            &[
+                ("exercise", exercise.to_string()),
+                ("fit_factor", json_number(*fit_factor)),
+                ("error", json_number(*error)),
+            ],
+        ),
+        TestNotification::Sample(sample) => event(
+            "test_sample",
+            device,
+            &[
+                ("stage", sample.stage.to_string()),
+                ("exercise", sample.exercise.to_string()),
+                (
+                    "sample_type",
+                    json_string(match sample.sample_type {
+                        SampleType::AmbientPurge => "ambient_purge",
+                        SampleType::AmbientSample => "ambient_sample",
+                        SampleType::SpecimenPurge => "specimen_purge",
+                        SampleType::SpecimenSample => "specimen_sample",
+                    }),
+                ),
+                ("concentration", json_number(sample.value)),
+            ],
+        ),
+        TestNotification::LiveFF {
+            exercise,
+            fit_factor,
+            ..
+        } => event(
+            "live_ff",
+            device,
+            &[
+                ("exercise", exercise.to_string()),
+                ("fit_factor", json_number(*fit_factor)),
+            ],
+        ),
+        TestNotification::InterimFF {
+            exercise,
+            fit_factor,
+        } => event(
+            "interim_ff",
+            device,
+            &[
+                ("exercise", exercise.to_string()),
+                ("fit_factor", json_number(*fit_factor)),
+            ],
+        ),
+        TestNotification::StageComplete {
+            stage,
+            average,
+            sample_count,
+            standard_deviation,
+        } => event(
+            "stage_complete",
+            device,
+            &[
+                ("stage", stage.to_string()),
+                ("average", json_number(*average)),
+                ("sample_count", sample_count.to_string()),
+                ("standard_deviation", json_number(*standard_deviation)),
+            ],
+        ),
+        TestNotification::AmbientResult {
+            stage,
+            average,
+            error,
+        } => event(
+            "ambient_result",
+            device,
+            &[
+                ("stage", stage.to_string()),
+                ("average", json_number(*average)),
+                ("error", json_number(*error)),
+            ],
+        ),
+        TestNotification::ValveSwitchRequested { position } => event(
+            "valve_switch_requested",
+            device,
+            &[("position", valve_position_json(position))],
+        ),
+        TestNotification::ValveSwitchConfirmed {
+            position,
+            discarded_samples,
+        } => event(
+            "valve_switch_confirmed",
+            device,
+            &[
+                ("position", valve_position_json(position)),
+                ("discarded_samples", discarded_samples.to_string()),
+            ],
+        ),
+        TestNotification::SampleDiscarded { value, reason } => event(
+            "sample_discarded",
+            device,
+            &[
+                ("concentration", json_number(*value)),
+                (
+                    "reason",
+                    json_string(match reason {
+                        DiscardReason::AwaitingValveSwitch => "awaiting_valve_switch",
+                        DiscardReason::ValveSwitchSettling => "valve_switch_settling",
+                        DiscardReason::AwaitingGroup => "awaiting_group",
+                    }),
+                ),
+            ],
+        ),
+        TestNotification::ExerciseEndedEarly {
+            exercise,
+            sample_count,
+        } => event(
+            "exercise_ended_early",
+            device,
+            &[
+                ("exercise", exercise.to_string()),
+                ("sample_count", sample_count.to_string()),
+            ],
+        ),
+        TestNotification::AmbientBelowMinimum {
+            stage,
+            average,
+            minimum,
+        } => event(
+            "ambient_below_minimum",
+            device,
+            &[
+                ("stage", stage.to_string()),
+                ("average", json_number(*average)),
+                ("minimum", json_number(*minimum)),
+            ],
+        ),
+        TestNotification::PassLevelAboveCeiling {
+            pass_level,
+            max_fit_factor,
+        } => event(
+            "pass_level_above_ceiling",
+            device,
+            &[
+                ("pass_level", json_number(*pass_level)),
+                ("max_fit_factor", json_number(*max_fit_factor)),
+            ],
+        ),
+        TestNotification::TimeRemaining {
+            samples_remaining,
+            seconds_remaining,
+        } => event(
+            "time_remaining",
+            device,
+            &[
+                ("samples_remaining", samples_remaining.to_string()),
+                ("seconds_remaining", json_number(*seconds_remaining)),
+            ],
+        ),
+    })
+}
+
+// Minimal SHA-1, which is only needed for the WebSocket handshake.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(value);
+        }
+    }
+    let mut digest = [0u8; 20];
+    for (i, value) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn accept_key(key: &str) -> String {
+    // See RFC 6455, section 1.3.
+    const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    base64(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+fn text_frame(text: &str) -> Vec<u8> {
+    let payload = text.as_bytes();
+    // FIN + text frame.
+    let mut frame = vec![0x81];
+    match payload.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_accept_key() {
+        // The example from RFC 6455, section 1.3.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let broadcaster = Arc::new(Broadcaster::new());
+        let address = serve(broadcaster.clone(), "127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        write!(
+            client,
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        )
+        .unwrap();
+        let mut reader = std::io::BufReader::new(client);
+        let mut response = String::new();
+        while !response.ends_with("\r\n\r\n") {
+            reader.read_line(&mut response).unwrap();
+        }
+        assert!(response.starts_with("HTTP/1.1 101"), "{response}");
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        while broadcaster.subscriber_count() == 0 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let id = DeviceId {
+            index: 2,
+            serial_number: None,
+        };
+        broadcaster.broadcast_device_notification(
+            &id,
+            &DeviceNotification::Sample {
+                particle_conc: 1234.5,
+            },
+        );
+        broadcaster.broadcast_test_notification(
+            2,
+            &TestNotification::StateChange(TestState::StartedExercise(1)),
+        );
+        for expected in [
+            "{\"type\": \"sample\", \"device\": 2, \"concentration\": 1234.5}",
+            "{\"type\": \"exercise_started\", \"device\": 2, \"exercise\": 1}",
+        ] {
+            let mut header = [0u8; 2];
+            reader.read_exact(&mut header).unwrap();
+            assert_eq!(header, [0x81, expected.len() as u8]);
+            let mut payload = vec![0u8; expected.len()];
+            reader.read_exact(&mut payload).unwrap();
+            assert_eq!(String::from_utf8(payload).unwrap(), expected);
+        }
+    }
+}